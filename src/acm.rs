@@ -0,0 +1,366 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Upgrades of the spoke clusters managed by an Advanced Cluster Management hub.
+//!
+//! The hub has no kubeconfigs for its spokes. Instead, the spokes' versions and available
+//! updates are read from their ManagedClusterInfo and upgrades are requested by pointing each
+//! spoke's ClusterCurator at the selected version. With --max-concurrent-upgrades, spokes whose
+//! desired version isn't their current one count as updating, and the others wait for one of them
+//! to finish before their ClusterCurator is touched.
+//!
+//! Each spoke is decided on by the same policy as the local cluster (see `fleet`), with its
+//! ManagedCluster's annotations pausing, snoozing or approving its updates. A spoke which isn't
+//! available, or whose last upgrade failed, is held back by the health gates.
+
+use crate::fleet::{self, Fleet};
+use crate::options::Options;
+use crate::reconciler;
+use crate::status::{FleetCluster, Status};
+use chrono::Utc;
+use kube::api::{self, Api, ListParams, ObjectMeta, PatchParams, PostParams, TypeMeta};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{self, ClusterUpdate};
+use openshift_update::gates::Slots;
+use openshift_update::health::Health;
+use openshift_update::kubeapi;
+use openshift_update::retry;
+use openshift_update::state::State;
+use openshift_update::Error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct ManagedClusterStatus {
+    #[serde(default)]
+    conditions: Vec<Condition>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct Condition {
+    #[serde(rename = "type")]
+    type_: String,
+    status: String,
+}
+
+type ManagedCluster = api::Object<api::Void, ManagedClusterStatus>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct ManagedClusterInfoStatus {
+    #[serde(rename = "distributionInfo", default)]
+    distribution_info: DistributionInfo,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct DistributionInfo {
+    ocp: Option<OcpDistributionInfo>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct OcpDistributionInfo {
+    #[serde(default)]
+    version: String,
+    #[serde(rename = "desiredVersion", default)]
+    desired_version: String,
+    #[serde(rename = "upgradeFailed", default)]
+    upgrade_failed: bool,
+    #[serde(
+        rename = "versionAvailableUpdates",
+        default,
+        deserialize_with = "clusterversion::skip_invalid"
+    )]
+    version_available_updates: Vec<ClusterUpdate>,
+}
+
+type ManagedClusterInfo = api::Object<api::Void, ManagedClusterInfoStatus>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct ClusterCuratorSpec {
+    #[serde(rename = "desiredCuration", default)]
+    desired_curation: Option<String>,
+    #[serde(default)]
+    upgrade: Option<UpgradeSpec>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct UpgradeSpec {
+    #[serde(rename = "desiredUpdate", default)]
+    desired_update: Option<String>,
+}
+
+type ClusterCurator = api::Object<ClusterCuratorSpec, api::Void>;
+
+pub fn run(client: APIClient, options: &Options, status: &Arc<Mutex<Status>>) -> Result<(), Error> {
+    if options.force {
+        warn!("ClusterCurators cannot force updates; ignoring --force");
+    }
+
+    let clusters = Api::<ManagedCluster>::customResource(client.clone(), "managedclusters")
+        .group("cluster.open-cluster-management.io")
+        .version("v1");
+    // Each spoke's ManagedClusterInfo lives in the namespace named after it.
+    let infos = Api::<ManagedClusterInfo>::customResource(client.clone(), "managedclusterinfos")
+        .group("internal.open-cluster-management.io")
+        .version("v1beta1");
+    let params = ListParams {
+        label_selector: options.acm_selector.clone(),
+        ..Default::default()
+    };
+
+    let fleet = Fleet::new(options);
+    let mut saved: HashMap<String, State> = HashMap::new();
+    loop {
+        let backoff = options.backoff();
+        match backoff
+            .retry("list ManagedClusters", || {
+                Ok(kubeapi::call("list", "managedclusters", || {
+                    clusters.list(&params)
+                })?)
+            })
+            .and_then(|list| {
+                let infos = backoff.retry("list ManagedClusterInfos", || {
+                    Ok(kubeapi::call("list", "managedclusterinfos", || {
+                        infos.list(&ListParams::default())
+                    })?)
+                })?;
+                Ok((list, infos))
+            }) {
+            Ok((list, infos)) => {
+                let mut infos: HashMap<String, OcpDistributionInfo> = infos
+                    .items
+                    .into_iter()
+                    .filter_map(|info| {
+                        let ocp = info.status?.distribution_info.ocp?;
+                        Some((info.metadata.name, ocp))
+                    })
+                    .collect();
+                let updating = list
+                    .items
+                    .iter()
+                    .filter_map(|cluster| infos.get(&cluster.metadata.name))
+                    .filter(|ocp| updating(ocp))
+                    .count();
+                let mut slots = Slots::new(options.max_concurrent_upgrades, updating);
+                let mut rows = Vec::new();
+                for cluster in list.items {
+                    let ocp = infos.remove(&cluster.metadata.name);
+                    let mut row = FleetCluster::new(&cluster.metadata.name);
+                    match reconcile(
+                        &client,
+                        &fleet,
+                        saved.entry(cluster.metadata.name.clone()).or_default(),
+                        &mut slots,
+                        &cluster,
+                        ocp,
+                        &mut row,
+                    ) {
+                        Err(error) if retry::unauthorized(&error) => return Err(error),
+                        Err(error) => {
+                            error!(
+                                "Failed to reconcile ManagedCluster {}: {}",
+                                cluster.metadata.name, error
+                            );
+                            row.hold("error", error.to_string());
+                        }
+                        Ok(()) => {}
+                    }
+                    rows.push(row);
+                }
+                // Forget the spokes which left the hub (or the selector).
+                saved.retain(|name, _| rows.iter().any(|row| &row.name == name));
+                let mut status = status.lock().expect("status lock");
+                status.clusters = rows;
+                status.evaluated_at = Some(Utc::now());
+            }
+            Err(error) if retry::unauthorized(&error) => return Err(error),
+            Err(error) => error!("Failed to list ManagedClusters: {}", error),
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Returns whether the spoke has been asked for a version it isn't at yet, and hasn't failed to
+/// get there.
+fn updating(ocp: &OcpDistributionInfo) -> bool {
+    !ocp.upgrade_failed && !ocp.desired_version.is_empty() && ocp.desired_version != ocp.version
+}
+
+fn reconcile(
+    client: &APIClient,
+    fleet: &Fleet,
+    saved: &mut State,
+    slots: &mut Slots,
+    cluster: &ManagedCluster,
+    ocp: Option<OcpDistributionInfo>,
+    row: &mut FleetCluster,
+) -> Result<(), Error> {
+    let name = &cluster.metadata.name;
+
+    let available = cluster.status.as_ref().is_some_and(|status| {
+        status
+            .conditions
+            .iter()
+            .any(|c| c.type_ == "ManagedClusterConditionAvailable" && c.status == "True")
+    });
+    let ocp = match ocp {
+        Some(ocp) => ocp,
+        None if !available => {
+            debug!("Skipping unavailable ManagedCluster {}", name);
+            row.hold(
+                "unavailable",
+                "the ManagedCluster isn't available".to_string(),
+            );
+            return Ok(());
+        }
+        None => {
+            debug!("ManagedCluster {} is not an OpenShift cluster", name);
+            row.hold("unsupported", "not an OpenShift cluster".to_string());
+            return Ok(());
+        }
+    };
+    row.version = Some(ocp.version.clone()).filter(|version| !version.is_empty());
+    row.last_result = if ocp.upgrade_failed {
+        Some(format!("{} Failed", ocp.desired_version))
+    } else if !ocp.desired_version.is_empty() && !updating(&ocp) {
+        Some(format!("{} Completed", ocp.version))
+    } else {
+        None
+    };
+
+    if updating(&ocp) {
+        debug!(
+            "Waiting for {} to update to {}...",
+            name, ocp.desired_version
+        );
+        row.hold("updating", format!("updating to {}", ocp.desired_version));
+        return Ok(());
+    }
+
+    let failed = Some(&ocp.desired_version)
+        .filter(|_| ocp.upgrade_failed)
+        .and_then(|version| clusterversion::parse_version(version));
+    if let Some(failed) = failed.filter(|failed| !saved.poisoned.contains_key(failed)) {
+        reconciler::poison(fleet.options, saved, &failed, format!("failed on {}", name));
+    }
+
+    let cluster_id = cluster.metadata.labels.get("clusterID").unwrap_or(name);
+    let mut state = fleet::cluster_state(&cluster.metadata, cluster_id, None, Utc::now());
+    state.version = row.version.clone();
+    if !available {
+        state.health.push(Health {
+            gate: "unavailable",
+            passed: false,
+            reason: "the ManagedCluster isn't available".to_string(),
+        });
+    }
+    if ocp.upgrade_failed {
+        state.health.push(Health {
+            gate: "failed",
+            passed: false,
+            reason: format!("the upgrade to {} failed", ocp.desired_version),
+        });
+    }
+    let decision = fleet.decide(state, ocp.version_available_updates, saved);
+    let update = match fleet::record(row, decision) {
+        Some(update) => update,
+        None => return Ok(()),
+    };
+
+    // Like its ManagedClusterInfo, the spoke's ClusterCurator lives in the namespace named after it.
+    let curators = Api::<ClusterCurator>::customResource(client.clone(), "clustercurators")
+        .group("cluster.open-cluster-management.io")
+        .version("v1beta1")
+        .within(name);
+    let spec = ClusterCuratorSpec {
+        desired_curation: Some("upgrade".to_string()),
+        upgrade: Some(UpgradeSpec {
+            desired_update: Some(update.version.to_string()),
+        }),
+    };
+
+    match kubeapi::call("get", "clustercurators", || curators.get(name)) {
+        Ok(curator) => {
+            let requested = curator
+                .spec
+                .upgrade
+                .as_ref()
+                .and_then(|upgrade| upgrade.desired_update.as_ref());
+            if curator.spec.desired_curation.as_deref() == Some("upgrade")
+                && requested == Some(&update.version.to_string())
+            {
+                row.hold("updating", "the ClusterCurator is upgrading".to_string());
+                return Ok(());
+            }
+            if !slots.take() {
+                info!(
+                    "Too many clusters are updating; {} waits to update to {}",
+                    name, update.version
+                );
+                row.hold("concurrency", "too many clusters are updating".to_string());
+                return Ok(());
+            }
+
+            info!("Attempting to update {} to {}", name, update.version);
+            let patch = serde_json::to_vec(&ClusterCurator {
+                types: curator.types,
+                metadata: curator.metadata,
+                spec,
+                status: None,
+            })
+            .expect("Serialize to JSON");
+            kubeapi::call("patch", "clustercurators", || {
+                curators.patch(name, &PatchParams::default(), patch)
+            })?;
+        }
+        Err(error) => match error.api_error() {
+            Some(ref api_error) if api_error.code == 404 => {
+                if !slots.take() {
+                    info!(
+                        "Too many clusters are updating; {} waits to update to {}",
+                        name, update.version
+                    );
+                    row.hold("concurrency", "too many clusters are updating".to_string());
+                    return Ok(());
+                }
+                info!("Attempting to update {} to {}", name, update.version);
+                let curator = serde_json::to_vec(&ClusterCurator {
+                    types: TypeMeta {
+                        apiVersion: Some("cluster.open-cluster-management.io/v1beta1".into()),
+                        kind: Some("ClusterCurator".into()),
+                    },
+                    metadata: ObjectMeta {
+                        name: name.clone(),
+                        namespace: Some(name.clone()),
+                        ..Default::default()
+                    },
+                    spec,
+                    status: None,
+                })
+                .expect("Serialize to JSON");
+                kubeapi::call("create", "clustercurators", || {
+                    curators.create(&PostParams::default(), curator)
+                })?;
+            }
+            _ => return Err(error.into()),
+        },
+    }
+    row.reason = Some("requested the update".to_string());
+
+    Ok(())
+}
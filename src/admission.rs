@@ -0,0 +1,197 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Admission review of edits to the ClusterVersion.
+//!
+//! While the cluster is applying an update the operator requested, somebody else changing the
+//! desired update redirects the cluster to another version mid-flight. The webhook served at
+//! `POST /validate/clusterversion` warns about such edits (or with `--manual-edits block`,
+//! rejects them). The operator's own patches, which are recorded under its field manager, and
+//! edits made once the update has completed are let through.
+
+#[cfg(feature = "metrics-server")]
+use crate::options::Options;
+#[cfg(feature = "metrics-server")]
+use openshift_update::clusterversion::{ClusterUpdate, ClusterVersion, ManagedFieldsEntry};
+use std::str::FromStr;
+
+/// What to do about a conflicting edit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edits {
+    Warn,
+    Block,
+}
+
+impl FromStr for Edits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Edits::Warn),
+            "block" => Ok(Edits::Block),
+            _ => Err(format!("{:?} is neither \"warn\" nor \"block\"", s)),
+        }
+    }
+}
+
+#[cfg(feature = "metrics-server")]
+#[derive(serde::Deserialize)]
+struct ManagedObject {
+    metadata: ManagedMetadata,
+}
+
+#[cfg(feature = "metrics-server")]
+#[derive(serde::Deserialize)]
+struct ManagedMetadata {
+    #[serde(rename = "managedFields", default)]
+    managed_fields: Vec<ManagedFieldsEntry>,
+}
+
+/// The response to an AdmissionReview of a change to a ClusterVersion.
+#[cfg(feature = "metrics-server")]
+pub fn review(review: &serde_json::Value, options: &Options) -> serde_json::Value {
+    let request = &review["request"];
+    let conflict = match request["name"].as_str() {
+        Some(name) if name == options.cluster_version_name => conflict(request, options),
+        _ => None,
+    };
+
+    let mut response = serde_json::json!({
+        "uid": request["uid"],
+        "allowed": true,
+    });
+    match (conflict, options.manual_edits) {
+        (None, _) => {}
+        (Some(conflict), Edits::Warn) => response["warnings"] = serde_json::json!([conflict]),
+        (Some(conflict), Edits::Block) => {
+            response["allowed"] = serde_json::json!(false);
+            response["status"] = serde_json::json!({ "code": 409, "message": conflict });
+        }
+    }
+    serde_json::json!({
+        "apiVersion": review["apiVersion"].as_str().unwrap_or("admission.k8s.io/v1"),
+        "kind": "AdmissionReview",
+        "response": response,
+    })
+}
+
+/// Why the change conflicts with the update the operator is managing, if it does.
+#[cfg(feature = "metrics-server")]
+fn conflict(request: &serde_json::Value, options: &Options) -> Option<String> {
+    if request["operation"] != "UPDATE"
+        || request["options"]["fieldManager"] == options.field_manager.as_str()
+    {
+        return None;
+    }
+    let old: ClusterVersion = serde_json::from_value(request["oldObject"].clone()).ok()?;
+    let new: ClusterVersion = serde_json::from_value(request["object"].clone()).ok()?;
+    let desired = |version: &ClusterVersion| {
+        version
+            .spec
+            .desired_update
+            .as_ref()
+            .map(|update: &ClusterUpdate| (update.version.clone(), update.image.clone()))
+    };
+    if desired(&old) == desired(&new) {
+        return None;
+    }
+
+    // Only the updates the operator requested are its to protect.
+    let managed: ManagedObject = serde_json::from_value(request["oldObject"].clone()).ok()?;
+    let requested = managed.metadata.managed_fields.iter().any(|entry| {
+        entry.manager == options.field_manager && entry.manages(&["spec", "desiredUpdate"])
+    });
+    let updating = old
+        .status
+        .as_ref()
+        .and_then(|status| status.history.first())
+        .is_some_and(|entry| entry.completion_time.is_none());
+    if !requested || !updating {
+        return None;
+    }
+
+    let target = old.spec.desired_update.as_ref()?;
+    Some(format!(
+        "{} is updating the cluster to {}; changing spec.desiredUpdate now redirects the update \
+         mid-flight",
+        options.field_manager, target.version
+    ))
+}
+
+#[cfg(all(test, feature = "metrics-server"))]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn request(field_manager: &str, version: &str) -> serde_json::Value {
+        let mut old: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-in-progress.json"
+        ))
+        .expect("valid fixture");
+        old["spec"]["desiredUpdate"] = serde_json::json!({
+            "version": "4.1.16",
+            "image": "quay.io/openshift-release-dev/ocp-release:4.1.16",
+        });
+        old["metadata"]["managedFields"] = serde_json::json!([{
+            "manager": "openshift-update",
+            "operation": "Update",
+            "fieldsV1": { "f:spec": { "f:desiredUpdate": {} } },
+        }]);
+        let mut new = old.clone();
+        new["spec"]["desiredUpdate"] = serde_json::json!({
+            "version": version,
+            "image": format!("quay.io/openshift-release-dev/ocp-release:{}", version),
+        });
+        serde_json::json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "name": "version",
+                "operation": "UPDATE",
+                "options": { "fieldManager": field_manager },
+                "oldObject": old,
+                "object": new,
+            },
+        })
+    }
+
+    #[test]
+    fn warns_about_redirecting_an_update() {
+        let options = Options::from_iter(&["openshift-update"]);
+        let response = review(&request("kubectl-edit", "4.1.17"), &options);
+        assert_eq!(
+            response["response"],
+            serde_json::json!({
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "allowed": true,
+                "warnings": [
+                    "openshift-update is updating the cluster to 4.1.16; changing \
+                     spec.desiredUpdate now redirects the update mid-flight"
+                ],
+            })
+        );
+
+        // The operator's own patches, and edits which leave the desired update alone, are fine.
+        for (manager, version) in &[("openshift-update", "4.1.17"), ("kubectl-edit", "4.1.16")] {
+            let response = review(&request(manager, version), &options);
+            assert_eq!(response["response"].get("warnings"), None);
+        }
+
+        let options = Options::from_iter(&["openshift-update", "--manual-edits", "block"]);
+        let response = review(&request("kubectl-edit", "4.1.17"), &options);
+        assert_eq!(response["response"]["allowed"], false);
+        assert_eq!(response["response"]["status"]["code"], 409);
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A health check of the applications deployed by Argo CD.
+//!
+//! The Application objects are read directly, across all namespaces, rather than through Argo
+//! CD's own API, so that nothing beyond read access to them is needed.
+
+use crate::health::{Health, HealthCheck};
+use crate::kubeapi;
+use crate::retry::Backoff;
+use crate::Error;
+use kube::api::{Api, ListParams, Object};
+use kube::client::APIClient;
+
+/// Project matching every application.
+pub const ALL_PROJECTS: &str = "*";
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ApplicationSpec {
+    #[serde(default)]
+    pub project: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ApplicationStatus {
+    #[serde(default)]
+    pub health: Status,
+    #[serde(default)]
+    pub sync: Status,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct Status {
+    #[serde(default)]
+    pub status: String,
+}
+
+pub type Application = Object<ApplicationSpec, ApplicationStatus>;
+
+/// Fails while any application in one of the projects is Degraded or OutOfSync.
+pub struct ArgoCd {
+    pub client: APIClient,
+    pub projects: Vec<String>,
+    pub backoff: Backoff,
+}
+
+impl HealthCheck for ArgoCd {
+    fn gate(&self) -> &'static str {
+        "argocd"
+    }
+
+    fn check(&self) -> Result<Health, Error> {
+        let api = Api::<Application>::customResource(self.client.clone(), "applications")
+            .group("argoproj.io")
+            .version("v1alpha1");
+        let applications = self.backoff.retry("list Argo CD applications", || {
+            let list = kubeapi::call("list", "applications", || api.list(&ListParams::default()))?;
+            Ok(list.items)
+        })?;
+        Ok(health(&applications, &self.projects))
+    }
+}
+
+/// The verdict on the applications which are in one of the projects.
+pub fn health(applications: &[Application], projects: &[String]) -> Health {
+    let selected: Vec<&Application> = applications
+        .iter()
+        .filter(|application| {
+            projects
+                .iter()
+                .any(|project| project == ALL_PROJECTS || project == &application.spec.project)
+        })
+        .collect();
+    let ailing: Vec<String> = selected
+        .iter()
+        .filter_map(|application| {
+            let status = application.status.clone().unwrap_or_default();
+            let problems: Vec<&str> = [status.health.status.as_str(), status.sync.status.as_str()]
+                .iter()
+                .cloned()
+                .filter(|status| *status == "Degraded" || *status == "OutOfSync")
+                .collect();
+            if problems.is_empty() {
+                None
+            } else {
+                Some(format!(
+                    "{}/{} ({})",
+                    application
+                        .metadata
+                        .namespace
+                        .as_deref()
+                        .unwrap_or_default(),
+                    application.metadata.name,
+                    problems.join(", ")
+                ))
+            }
+        })
+        .collect();
+
+    Health {
+        gate: "argocd",
+        passed: ailing.is_empty(),
+        reason: if ailing.is_empty() {
+            format!(
+                "{} applications in projects {} are healthy and in sync",
+                selected.len(),
+                projects.join(", ")
+            )
+        } else {
+            format!("ailing applications: {}", ailing.join(", "))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_ailing_applications() {
+        let applications: Vec<Application> = serde_json::from_value(serde_json::json!([
+            {
+                "metadata": { "name": "web", "namespace": "argocd" },
+                "spec": { "project": "shop" },
+                "status": { "health": { "status": "Degraded" }, "sync": { "status": "Synced" } },
+            },
+            {
+                "metadata": { "name": "api", "namespace": "argocd" },
+                "spec": { "project": "shop" },
+                "status": { "health": { "status": "Healthy" }, "sync": { "status": "Synced" } },
+            },
+            {
+                "metadata": { "name": "monitoring", "namespace": "argocd" },
+                "spec": { "project": "platform" },
+                "status": { "health": { "status": "Degraded" }, "sync": { "status": "OutOfSync" } },
+            },
+        ]))
+        .expect("valid applications");
+
+        let shop = health(&applications, &["shop".to_string()]);
+        assert!(!shop.passed);
+        assert_eq!(shop.reason, "ailing applications: argocd/web (Degraded)");
+
+        let all = health(&applications, &[ALL_PROJECTS.to_string()]);
+        assert_eq!(
+            all.reason,
+            "ailing applications: argocd/web (Degraded), argocd/monitoring (Degraded, OutOfSync)"
+        );
+
+        assert!(health(&applications, &["billing".to_string()]).passed);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A health check of the hosts of a bare-metal cluster.
+//!
+//! Every node is rebooted during an update, which on bare metal is done through the host's
+//! baseboard management controller (BMC). A host which is already in error, which is usually a
+//! BMC that can't be reached or refuses its credentials, or which is still being provisioned
+//! often fails to come back, and the node is lost for good.
+
+use crate::health::{Health, HealthCheck};
+use crate::kubeapi;
+use crate::retry::Backoff;
+use crate::Error;
+use kube::api::{ListParams, Object, RawApi, Void};
+use kube::client::APIClient;
+
+/// Where the installer creates the cluster's BareMetalHosts.
+pub const NAMESPACE: &str = "openshift-machine-api";
+
+/// Provisioning states through which a host is passing, rather than settled in.
+const TRANSITIONAL: &[&str] = &[
+    "registering",
+    "inspecting",
+    "match profile",
+    "preparing",
+    "provisioning",
+    "deprovisioning",
+    "powering off before delete",
+    "deleting",
+];
+
+/// How many of the hosts are named in the reason.
+const WORST: usize = 5;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct HostStatus {
+    #[serde(rename = "operationalStatus", default)]
+    pub operational_status: String,
+    #[serde(rename = "errorType", default)]
+    pub error_type: String,
+    #[serde(rename = "errorMessage", default)]
+    pub error_message: String,
+    #[serde(default)]
+    pub provisioning: Provisioning,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct Provisioning {
+    #[serde(default)]
+    pub state: String,
+}
+
+pub type BareMetalHost = Object<Void, HostStatus>;
+
+#[derive(serde::Deserialize)]
+struct HostList {
+    items: Vec<BareMetalHost>,
+}
+
+/// Fails while any of the cluster's BareMetalHosts is in error or is being provisioned.
+pub struct BareMetalHosts {
+    pub client: APIClient,
+    pub backoff: Backoff,
+}
+
+impl HealthCheck for BareMetalHosts {
+    fn gate(&self) -> &'static str {
+        "bare-metal-hosts"
+    }
+
+    fn check(&self) -> Result<Health, Error> {
+        let hosts = self.backoff.retry("list bare-metal hosts", || {
+            let request = RawApi::customResource("baremetalhosts")
+                .group("metal3.io")
+                .version("v1alpha1")
+                .within(NAMESPACE)
+                .list(&ListParams::default())?;
+            let list = kubeapi::call("list", "baremetalhosts", || {
+                self.client.request::<HostList>(request)
+            })?;
+            Ok(list.items)
+        })?;
+        Ok(health(&hosts))
+    }
+}
+
+/// What is wrong with the host, if anything.
+fn problem(host: &BareMetalHost) -> Option<String> {
+    let status = host.status.as_ref()?;
+    if status.operational_status == "error" || !status.error_type.is_empty() {
+        return Some(
+            match (status.error_type.as_str(), status.error_message.as_str()) {
+                ("", "") => "in error".to_string(),
+                (type_, "") | ("", type_) => type_.to_string(),
+                (type_, message) => format!("{}: {}", type_, message),
+            },
+        );
+    }
+    if TRANSITIONAL.contains(&status.provisioning.state.as_str()) {
+        return Some(status.provisioning.state.clone());
+    }
+    None
+}
+
+/// The verdict on the hosts, naming the first few which have a problem.
+pub fn health(hosts: &[BareMetalHost]) -> Health {
+    let problems: Vec<String> = hosts
+        .iter()
+        .filter_map(|host| {
+            problem(host).map(|problem| format!("{} ({})", host.metadata.name, problem))
+        })
+        .collect();
+    if problems.is_empty() {
+        return Health {
+            gate: "bare-metal-hosts",
+            passed: true,
+            reason: format!("all {} hosts are healthy", hosts.len()),
+        };
+    }
+    Health {
+        gate: "bare-metal-hosts",
+        passed: false,
+        reason: format!(
+            "{} of {} hosts are in error or being provisioned, including {}",
+            problems.len(),
+            hosts.len(),
+            problems
+                .iter()
+                .take(WORST)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(name: &str, status: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "metadata": { "name": name, "namespace": NAMESPACE },
+            "spec": {},
+            "status": status,
+        })
+    }
+
+    #[test]
+    fn names_broken_hosts() {
+        let hosts: Vec<BareMetalHost> = serde_json::from_value(serde_json::json!([
+            host(
+                "master-0",
+                serde_json::json!({
+                    "operationalStatus": "OK",
+                    "provisioning": { "state": "externally provisioned" },
+                })
+            ),
+            host(
+                "worker-0",
+                serde_json::json!({
+                    "operationalStatus": "OK",
+                    "provisioning": { "state": "provisioned" },
+                })
+            ),
+            host(
+                "worker-1",
+                serde_json::json!({
+                    "operationalStatus": "error",
+                    "errorType": "registration error",
+                    "errorMessage": "failed to connect to BMC",
+                    "provisioning": { "state": "registering" },
+                })
+            ),
+            host(
+                "worker-2",
+                serde_json::json!({
+                    "operationalStatus": "OK",
+                    "provisioning": { "state": "inspecting" },
+                })
+            ),
+        ]))
+        .expect("valid hosts");
+
+        assert!(health(&hosts[..2]).passed);
+        let unhealthy = health(&hosts);
+        assert!(!unhealthy.passed);
+        assert_eq!(
+            unhealthy.reason,
+            "2 of 4 hosts are in error or being provisioned, including worker-1 (registration \
+             error: failed to connect to BMC), worker-2 (inspecting)"
+        );
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A health check of whether the cluster has room to reschedule the pods drained from its nodes.
+//!
+//! Namespaces which have used up their ResourceQuotas can't recreate the pods evicted while a
+//! node is drained, and nor can pods whose PersistentVolumeClaims are stuck Pending be started
+//! elsewhere. Either commonly wedges the roll of the nodes part of the way through an update.
+
+use crate::health::{Health, HealthCheck};
+use crate::kubeapi;
+use crate::retry::Backoff;
+use crate::Error;
+use chrono::{DateTime, Duration, Utc};
+use kube::api::{ListParams, Object, RawApi, Void};
+use kube::client::APIClient;
+use std::collections::BTreeMap;
+
+/// How long a claim may be Pending before it is considered stuck, which leaves time for volumes
+/// to be provisioned.
+const GRACE_MINUTES: i64 = 10;
+
+/// Annotation set by the scheduler on claims whose binding waits for a pod to use them.
+const SELECTED_NODE_ANNOTATION: &str = "volume.kubernetes.io/selected-node";
+
+/// How many of the quotas and claims are named in the reason.
+const WORST: usize = 5;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct QuotaStatus {
+    #[serde(default)]
+    pub hard: BTreeMap<String, String>,
+    #[serde(default)]
+    pub used: BTreeMap<String, String>,
+}
+
+pub type ResourceQuota = Object<Void, QuotaStatus>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ClaimSpec {
+    #[serde(rename = "storageClassName")]
+    pub storage_class_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ClaimStatus {
+    #[serde(default)]
+    pub phase: String,
+}
+
+/// The parts of an object's metadata which are needed, including its creation time (which the
+/// generic metadata omits).
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct Metadata {
+    pub name: String,
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(rename = "creationTimestamp")]
+    pub creation_timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct PersistentVolumeClaim {
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub spec: ClaimSpec,
+    pub status: Option<ClaimStatus>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct StorageClass {
+    pub metadata: Metadata,
+    #[serde(rename = "volumeBindingMode")]
+    pub volume_binding_mode: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct List<T> {
+    items: Vec<T>,
+}
+
+/// Fails while any namespace has used up its quota for pods or their resources, or any claim
+/// has been Pending for longer than a few minutes.
+pub struct Capacity {
+    pub client: APIClient,
+    pub backoff: Backoff,
+}
+
+impl Capacity {
+    fn list<T: serde::de::DeserializeOwned>(
+        &self,
+        resource: &str,
+        api: RawApi,
+    ) -> Result<T, Error> {
+        self.backoff.retry(&format!("list {}", resource), || {
+            let request = api.list(&ListParams::default())?;
+            Ok(kubeapi::call("list", resource, || {
+                self.client.request::<T>(request)
+            })?)
+        })
+    }
+}
+
+impl HealthCheck for Capacity {
+    fn gate(&self) -> &'static str {
+        "capacity"
+    }
+
+    fn check(&self) -> Result<Health, Error> {
+        let quotas: List<ResourceQuota> = self.list("resourcequotas", RawApi::v1ResourceQuota())?;
+        let claims: List<PersistentVolumeClaim> =
+            self.list("persistentvolumeclaims", RawApi::v1PersistentVolumeClaim())?;
+        let classes: List<StorageClass> = self.list(
+            "storageclasses",
+            RawApi::customResource("storageclasses")
+                .group("storage.k8s.io")
+                .version("v1"),
+        )?;
+        // Claims of these classes stay Pending until a pod uses them, which isn't a problem.
+        let waiting: Vec<&str> = classes
+            .items
+            .iter()
+            .filter(|class| class.volume_binding_mode.as_deref() == Some("WaitForFirstConsumer"))
+            .map(|class| class.metadata.name.as_str())
+            .collect();
+        Ok(health(&quotas.items, &claims.items, &waiting, Utc::now()))
+    }
+}
+
+/// Whether a quota of the resource limits the pods which can be created in a namespace.
+fn limits_pods(resource: &str) -> bool {
+    match resource {
+        "pods" | "cpu" | "memory" | "ephemeral-storage" | "count/pods" => true,
+        resource => resource.starts_with("requests.") || resource.starts_with("limits."),
+    }
+}
+
+/// The value of a quantity (e.g. "500m", "2Gi" or "1e3"), if it is valid.
+pub fn quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let split = quantity
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(quantity.len());
+    let (number, suffix) = match quantity.split_at(split) {
+        // An exponent rather than a suffix.
+        (_, suffix) if suffix.starts_with(['e', 'E']) && suffix[1..].parse::<i32>().is_ok() => {
+            (quantity, "")
+        }
+        split => split,
+    };
+    let multiplier = match suffix {
+        "" => 1.0,
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        "Ki" => 1024_f64,
+        "Mi" => 1024_f64.powi(2),
+        "Gi" => 1024_f64.powi(3),
+        "Ti" => 1024_f64.powi(4),
+        "Pi" => 1024_f64.powi(5),
+        "Ei" => 1024_f64.powi(6),
+        _ => return None,
+    };
+    number.parse::<f64>().ok().map(|number| number * multiplier)
+}
+
+/// The resources limiting pods of which the quota has used all, if any.
+fn exhausted(quota: &ResourceQuota) -> Vec<&str> {
+    let status = match &quota.status {
+        Some(status) => status,
+        None => return Vec::new(),
+    };
+    status
+        .hard
+        .iter()
+        .filter(|(resource, _)| limits_pods(resource))
+        .filter_map(|(resource, hard)| {
+            let hard = quantity(hard)?;
+            let used = quantity(status.used.get(resource)?)?;
+            // A quota of zero forbids the resource outright, which an update doesn't change.
+            if hard > 0.0 && used >= hard {
+                Some(resource.as_str())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether the claim has been Pending for too long, rather than waiting for a pod to use it.
+fn stuck(claim: &PersistentVolumeClaim, waiting: &[&str], now: DateTime<Utc>) -> bool {
+    let pending = claim
+        .status
+        .as_ref()
+        .is_some_and(|status| status.phase == "Pending");
+    let unused = claim
+        .spec
+        .storage_class_name
+        .as_deref()
+        .is_some_and(|class| waiting.contains(&class))
+        && !claim
+            .metadata
+            .annotations
+            .contains_key(SELECTED_NODE_ANNOTATION);
+    let old = claim
+        .metadata
+        .creation_timestamp
+        .as_ref()
+        .is_none_or(|created| now - *created > Duration::minutes(GRACE_MINUTES));
+    pending && !unused && old
+}
+
+/// The verdict on the quotas and claims, naming the first few of those which are a problem.
+pub fn health(
+    quotas: &[ResourceQuota],
+    claims: &[PersistentVolumeClaim],
+    waiting: &[&str],
+    now: DateTime<Utc>,
+) -> Health {
+    let name = |namespace: &Option<String>, name: &str| {
+        format!("{}/{}", namespace.as_deref().unwrap_or_default(), name)
+    };
+    let mut problems: Vec<String> = Vec::new();
+    let full: Vec<String> = quotas
+        .iter()
+        .filter_map(|quota| match exhausted(quota).as_slice() {
+            [] => None,
+            resources => Some(format!(
+                "{} ({})",
+                name(&quota.metadata.namespace, &quota.metadata.name),
+                resources.join(", ")
+            )),
+        })
+        .collect();
+    if !full.is_empty() {
+        problems.push(format!(
+            "{} quotas are used up, including {}",
+            full.len(),
+            full.iter()
+                .take(WORST)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    let pending: Vec<String> = claims
+        .iter()
+        .filter(|claim| stuck(claim, waiting, now))
+        .map(|claim| name(&claim.metadata.namespace, &claim.metadata.name))
+        .collect();
+    if !pending.is_empty() {
+        problems.push(format!(
+            "{} claims have been Pending for over {} minutes, including {}",
+            pending.len(),
+            GRACE_MINUTES,
+            pending
+                .iter()
+                .take(WORST)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    if problems.is_empty() {
+        Health {
+            gate: "capacity",
+            passed: true,
+            reason: format!(
+                "none of {} quotas are used up and none of {} claims are stuck Pending",
+                quotas.len(),
+                claims.len()
+            ),
+        }
+    } else {
+        Health {
+            gate: "capacity",
+            passed: false,
+            reason: problems.join("; "),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quantities() {
+        assert_eq!(quantity("2"), Some(2.0));
+        assert_eq!(quantity("500m"), Some(0.5));
+        assert_eq!(quantity("1Gi"), Some(1024_f64.powi(3)));
+        assert_eq!(quantity("1e3"), Some(1000.0));
+        assert_eq!(quantity("2E"), Some(2e18));
+        assert_eq!(quantity("1Ei"), Some(1024_f64.powi(6)));
+        assert_eq!(quantity("1Qi"), None);
+    }
+
+    #[test]
+    fn names_used_up_quotas_and_stuck_claims() {
+        let now = DateTime::parse_from_rfc3339("2019-10-01T12:00:00Z")
+            .expect("valid time")
+            .with_timezone(&Utc);
+        let quotas: Vec<ResourceQuota> = serde_json::from_value(serde_json::json!([
+            {
+                "metadata": { "name": "compute", "namespace": "shop" },
+                "spec": {},
+                "status": {
+                    "hard": { "pods": "10", "requests.memory": "4Gi", "count/secrets": "5" },
+                    "used": { "pods": "4", "requests.memory": "4096Mi", "count/secrets": "5" },
+                },
+            },
+            {
+                "metadata": { "name": "forbidden", "namespace": "sandbox" },
+                "spec": {},
+                "status": { "hard": { "pods": "0" }, "used": { "pods": "0" } },
+            },
+        ]))
+        .expect("valid quotas");
+        let claim = |name: &str, class: &str, phase: &str, created: &str| {
+            serde_json::json!({
+                "metadata": { "name": name, "namespace": "db", "creationTimestamp": created },
+                "spec": { "storageClassName": class },
+                "status": { "phase": phase },
+            })
+        };
+        let claims: Vec<PersistentVolumeClaim> = serde_json::from_value(serde_json::json!([
+            claim("data", "gp2", "Pending", "2019-10-01T10:00:00Z"),
+            claim("fresh", "gp2", "Pending", "2019-10-01T11:55:00Z"),
+            claim("bound", "gp2", "Bound", "2019-10-01T10:00:00Z"),
+            claim("unused", "gp3-csi", "Pending", "2019-10-01T10:00:00Z"),
+        ]))
+        .expect("valid claims");
+
+        let health = health(&quotas, &claims, &["gp3-csi"], now);
+        assert!(!health.passed);
+        assert_eq!(
+            health.reason,
+            "1 quotas are used up, including shop/compute (requests.memory); \
+             1 claims have been Pending for over 10 minutes, including db/data"
+        );
+
+        let health = super::health(&quotas[1..], &claims[1..], &["gp3-csi"], now);
+        assert!(health.passed);
+    }
+}
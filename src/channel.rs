@@ -0,0 +1,88 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Management of the cluster's update channel.
+
+use crate::options::Options;
+use kube::api::PatchParams;
+use kube::client::APIClient;
+use openshift_update::clusterversion;
+use openshift_update::graph::{self, GraphClient};
+use openshift_update::http::HttpClient;
+use openshift_update::Error;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub enum Channel {
+    #[structopt(name = "set")]
+    /// Move the cluster to another update channel
+    Set {
+        /// Name of the channel (e.g. stable-4.2)
+        channel: String,
+
+        #[structopt(long = "skip-validation")]
+        /// Don't check with the update graph that the channel contains the cluster's version
+        skip_validation: bool,
+    },
+}
+
+pub fn run(client: APIClient, options: &Options, command: &Channel) -> Result<(), Error> {
+    let Channel::Set {
+        channel,
+        skip_validation,
+    } = command;
+
+    let versions = clusterversion::api(client.clone());
+    let version = versions.get(&options.cluster_version_name)?;
+
+    if !skip_validation {
+        let current = version
+            .status
+            .as_ref()
+            .and_then(|status| status.history.first())
+            .and_then(|latest| latest.version.clone())
+            .ok_or_else(|| Error::Config("the cluster doesn't report its version".to_string()))?;
+        let upstream = version
+            .spec
+            .upstream
+            .as_deref()
+            .unwrap_or(graph::DEFAULT_UPSTREAM);
+        let graph = HttpClient {
+            http: options.http(&client)?,
+            backoff: options.backoff(),
+        }
+        .fetch(upstream, channel, &current)?;
+        let contained = semver::Version::parse(&current)
+            .map(|current| graph.contains(&current))
+            .unwrap_or(false);
+        if !contained {
+            return Err(Error::Config(format!(
+                "channel {} doesn't contain the cluster's version {}",
+                channel, current
+            )));
+        }
+    }
+
+    let patch = serde_json::json!({ "spec": { "channel": channel } });
+    versions.patch(
+        &options.cluster_version_name,
+        &PatchParams::default(),
+        serde_json::to_vec(&patch)?,
+    )?;
+    match &version.spec.channel {
+        Some(previous) => println!("Moved the cluster from {} to {}", previous, channel),
+        None => println!("Moved the cluster to {}", channel),
+    }
+    Ok(())
+}
@@ -0,0 +1,415 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validation of the operator's configuration without starting it, for catching mistakes in CI.
+//!
+//! Unknown options and values which don't parse (durations, addresses, numbers) are already
+//! rejected, with the offending option, when the command line is parsed. This goes further:
+//! the files named by options are read and parsed the way the operator would, and options which
+//! contradict each other or have no effect are reported.
+
+use crate::options::Options;
+use crate::output::Output;
+use openshift_update::http::HttpConfig;
+#[cfg(feature = "notifications")]
+use openshift_update::snmp::Oid;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct CheckConfig {
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the report: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct Problem {
+    option: &'static str,
+    message: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Report {
+    valid: bool,
+    problems: Vec<Problem>,
+}
+
+/// Print the report, returning whether the configuration is valid.
+pub fn run(options: &Options, command: &CheckConfig) -> bool {
+    let problems = problems(options);
+    let report = Report {
+        valid: problems.is_empty(),
+        problems,
+    };
+    command.output.print(&report, render);
+    report.valid
+}
+
+/// The options which are set but need a cargo feature this build doesn't include, with the
+/// feature each needs.
+pub fn unsupported(options: &Options) -> Vec<(&'static str, &'static str)> {
+    [
+        ("--acm", options.acm, "fleet", cfg!(feature = "fleet")),
+        (
+            "--hosted-clusters",
+            options.hosted_clusters,
+            "fleet",
+            cfg!(feature = "fleet"),
+        ),
+        ("--ocm", options.ocm, "fleet", cfg!(feature = "fleet")),
+        (
+            "--listen",
+            options.listen.is_some(),
+            "metrics-server",
+            cfg!(feature = "metrics-server"),
+        ),
+        (
+            "--grpc-listen",
+            options.grpc_listen.is_some(),
+            "metrics-server",
+            cfg!(feature = "metrics-server"),
+        ),
+        (
+            "--webhook-listen",
+            options.webhook_listen.is_some(),
+            "metrics-server",
+            cfg!(feature = "metrics-server"),
+        ),
+        (
+            "--events-stdout",
+            options.events_stdout,
+            "notifications",
+            cfg!(feature = "notifications"),
+        ),
+        (
+            "--matrix-homeserver",
+            options.matrix_homeserver.is_some(),
+            "notifications",
+            cfg!(feature = "notifications"),
+        ),
+        (
+            "--google-chat-webhook-file",
+            options.google_chat_webhook_file.is_some(),
+            "notifications",
+            cfg!(feature = "notifications"),
+        ),
+        (
+            "--opsgenie-api-key-file",
+            options.opsgenie_api_key_file.is_some(),
+            "notifications",
+            cfg!(feature = "notifications"),
+        ),
+        (
+            "--snmp-target",
+            options.snmp_target.is_some(),
+            "notifications",
+            cfg!(feature = "notifications"),
+        ),
+    ]
+    .iter()
+    .filter(|(_, set, _, built)| *set && !built)
+    .map(|(option, _, feature, _)| (*option, *feature))
+    .collect()
+}
+
+fn problems(options: &Options) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |option, message| problems.push(Problem { option, message });
+
+    for (option, feature) in unsupported(options) {
+        problem(
+            option,
+            format!(
+                "requires the {} feature, which this build doesn't include",
+                feature
+            ),
+        );
+    }
+
+    #[cfg(feature = "notifications")]
+    if options.snmp_target.is_some() {
+        if let Err(message) = options.snmp_enterprise_oid.parse::<Oid>() {
+            problem("--snmp-enterprise-oid", message);
+        }
+    }
+
+    if let Some(path) = &options.ca_bundle {
+        match read(path) {
+            Ok(bundle) if !bundle.contains("-----BEGIN CERTIFICATE-----") => problem(
+                "--ca-bundle",
+                format!("{} has no certificates", path.display()),
+            ),
+            Ok(bundle) => {
+                let config = HttpConfig {
+                    ca_bundle: Some(bundle),
+                    ..Default::default()
+                };
+                if let Err(error) = config.client() {
+                    problem("--ca-bundle", format!("{}: {}", path.display(), error));
+                }
+            }
+            Err(message) => problem("--ca-bundle", message),
+        }
+    }
+    let password = match &options.client_identity_password_file {
+        Some(path) => match read(path) {
+            Ok(password) => Some(password.trim().to_string()),
+            Err(message) => {
+                problem("--client-identity-password-file", message);
+                None
+            }
+        },
+        None => Some(String::new()),
+    };
+    if let (Some(path), Some(password)) = (&options.client_identity, password) {
+        match fs::read(path) {
+            Ok(archive) => {
+                let config = HttpConfig {
+                    identity: Some((archive, password)),
+                    ..Default::default()
+                };
+                if let Err(error) = config.client() {
+                    problem(
+                        "--client-identity",
+                        format!("{}: {}", path.display(), error),
+                    );
+                }
+            }
+            Err(error) => problem(
+                "--client-identity",
+                format!("failed to read {}: {}", path.display(), error),
+            ),
+        }
+    }
+    // Files fetched from Vault are only written once the operator starts.
+    let fetched = |path: &&PathBuf| {
+        options
+            .vault_secrets
+            .iter()
+            .any(|mapping| &mapping.file == *path)
+    };
+    for (option, path) in &[
+        ("--api-token-file", &options.api_token_file),
+        ("--ocm-token-file", &options.ocm_token_file),
+        ("--prometheus-token-file", &options.prometheus_token_file),
+    ] {
+        if let Some(path) = path.as_ref().filter(|path| !fetched(path)) {
+            match read(path) {
+                Ok(token) if token.trim().is_empty() => {
+                    problem(option, format!("{} is empty", path.display()))
+                }
+                Ok(_) => {}
+                Err(message) => problem(option, message),
+            }
+        }
+    }
+
+    if let Some(path) = options
+        .registry_auth_file
+        .as_ref()
+        .filter(|path| !fetched(path))
+    {
+        match read(path).map(|auths| serde_json::from_str::<serde_json::Value>(&auths)) {
+            Ok(Ok(auths)) if auths.get("auths").is_some_and(|auths| auths.is_object()) => {}
+            Ok(Ok(_)) => problem(
+                "--registry-auth-file",
+                format!("{} has no auths", path.display()),
+            ),
+            Ok(Err(error)) => problem(
+                "--registry-auth-file",
+                format!("{} is not valid JSON: {}", path.display(), error),
+            ),
+            Err(message) => problem("--registry-auth-file", message),
+        }
+    }
+
+    if !options.kube_api_qps.is_finite() || options.kube_api_qps < 0.0 {
+        problem(
+            "--kube-api-qps",
+            format!(
+                "{} is not a rate (use 0 for no limit)",
+                options.kube_api_qps
+            ),
+        );
+    }
+    if options.backoff_initial > options.backoff_max {
+        problem(
+            "--backoff-initial",
+            "is longer than --backoff-max".to_string(),
+        );
+    }
+    for (option, url) in &[
+        ("--ocm-url", Some(&options.ocm_url)),
+        ("--ocm-token-url", Some(&options.ocm_token_url)),
+        ("--rollout-url", options.rollout_url.as_ref()),
+    ] {
+        let url = match url {
+            Some(url) => url,
+            None => continue,
+        };
+        if let Err(error) = reqwest::Url::parse(url) {
+            problem(option, format!("{} is not a URL: {}", url, error));
+        }
+    }
+
+    let modes: Vec<&str> = [
+        ("--acm", options.acm),
+        ("--hosted-clusters", options.hosted_clusters),
+        ("--ocm", options.ocm),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(mode, _)| *mode)
+    .collect();
+    if modes.len() > 1 {
+        problem(modes[1], format!("can't be combined with {}", modes[0]));
+    }
+    let ignored = [
+        ("--acm-selector", options.acm_selector.is_some(), "--acm"),
+        (
+            "--hosted-cluster-namespace",
+            options.hosted_cluster_namespace.is_some(),
+            "--hosted-clusters",
+        ),
+        ("--ocm-cluster", options.ocm_cluster.is_some(), "--ocm"),
+    ];
+    for (option, set, mode) in &ignored {
+        if *set && !modes.contains(mode) {
+            problem(option, format!("has no effect without {}", mode));
+        }
+    }
+    match options.max_concurrent_upgrades {
+        Some(_) if !options.acm && !options.hosted_clusters => problem(
+            "--max-concurrent-upgrades",
+            "has no effect without --acm or --hosted-clusters".to_string(),
+        ),
+        Some(0) => problem(
+            "--max-concurrent-upgrades",
+            "of 0 would never start an update".to_string(),
+        ),
+        _ => {}
+    }
+    if !modes.is_empty() {
+        for (option, set) in &[
+            ("--interactive", options.interactive),
+            ("--once", options.once),
+            ("--wait", options.wait),
+            ("--timeout", options.timeout.is_some()),
+            ("--heartbeat-file", options.heartbeat_file.is_some()),
+            ("--record", options.record.is_some()),
+            ("--replay", options.replay.is_some()),
+            ("--preflight-interval", options.preflight_interval.is_some()),
+            ("--self-update-image", options.self_update_image.is_some()),
+            ("--rollout-url", options.rollout_url.is_some()),
+            ("--gitops-repo", options.gitops_repo.is_some()),
+            ("--policy-config-map", options.policy_config_map.is_some()),
+            ("--argocd-project", !options.argocd_projects.is_empty()),
+            ("--max-stuck-pods", options.max_stuck_pods.is_some()),
+            ("--check-bare-metal-hosts", options.check_bare_metal_hosts),
+            ("--check-capacity", options.check_capacity),
+            ("--storage-matrix", options.storage_matrix.is_some()),
+            ("--check-dns", options.check_dns),
+            ("--slo-file", options.slo_file.is_some()),
+        ] {
+            if *set {
+                problem(option, format!("has no effect with {}", modes[0]));
+            }
+        }
+    }
+
+    problems
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map_err(|error| format!("failed to read {}: {}", path.display(), error))
+}
+
+fn render(report: &Report) -> String {
+    let mut text = String::new();
+    for problem in &report.problems {
+        writeln!(text, "{}: {}", problem.option, problem.message).expect("write to string");
+    }
+    if report.valid {
+        writeln!(text, "The configuration is valid").expect("write to string");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "fleet")]
+    fn reports_problems() {
+        let options = Options::from_iter(&[
+            "openshift-update",
+            "--acm",
+            "--hosted-clusters",
+            "--ocm-cluster",
+            "abc",
+            "--backoff-initial",
+            "10m",
+            "--ca-bundle",
+            "tests/fixtures/graph.json",
+        ]);
+        let options: Vec<&str> = problems(&options)
+            .iter()
+            .map(|problem| problem.option)
+            .collect();
+        assert_eq!(
+            options,
+            vec![
+                "--ca-bundle",
+                "--backoff-initial",
+                "--hosted-clusters",
+                "--ocm-cluster"
+            ]
+        );
+        assert!(problems(&Options::from_iter(&["openshift-update"])).is_empty());
+
+        let limit = |args: &[&str]| {
+            let args = ["openshift-update", "--max-concurrent-upgrades"]
+                .iter()
+                .chain(args);
+            problems(&Options::from_iter(args))
+                .into_iter()
+                .map(|problem| problem.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            limit(&["2"]),
+            vec!["has no effect without --acm or --hosted-clusters"]
+        );
+        assert_eq!(
+            limit(&["0", "--acm"]),
+            vec!["of 0 would never start an update"]
+        );
+        assert!(limit(&["2", "--hosted-clusters"]).is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "fleet"))]
+    fn refuses_fleet_modes() {
+        let options = Options::from_iter(&["openshift-update", "--acm", "--ocm-cluster", "abc"]);
+        assert_eq!(unsupported(&options), vec![("--acm", "fleet")]);
+        assert!(problems(&options)
+            .iter()
+            .any(|problem| problem.option == "--acm"));
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The client of the update graph, which fetches a channel's graph from the update service with
+//! the Cincinnati protocol. Only built with the cincinnati feature.
+
+use crate::graph::{Graph, GraphClient};
+use crate::http::HttpClient;
+use crate::Error;
+use reqwest::header::ACCEPT;
+
+impl GraphClient for HttpClient {
+    fn fetch(&self, upstream: &str, channel: &str, version: &str) -> Result<Graph, Error> {
+        self.backoff.retry("fetch update graph", || {
+            Ok(self
+                .http
+                .get(upstream)
+                .query(&[("channel", channel), ("version", version)])
+                .header(ACCEPT, "application/json")
+                .send()?
+                .error_for_status()?
+                .json()?)
+        })
+    }
+}
@@ -0,0 +1,985 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The ClusterVersion and the other config.openshift.io objects the operator reads.
+
+use crate::kubeapi;
+use crate::retry::{self, Backoff};
+use crate::Error;
+use chrono::{DateTime, Utc};
+use kube::api::{self, Api, PatchParams, Reflector};
+use kube::client::APIClient;
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cmp::Ordering;
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ClusterVersionSpec {
+    #[serde(rename = "clusterID", default, skip_serializing_if = "Option::is_none")]
+    pub cluster_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+
+    /// The update graph to fetch updates from, if not the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+
+    #[serde(
+        rename = "desiredUpdate",
+        default,
+        deserialize_with = "invalid_as_none"
+    )]
+    pub desired_update: Option<ClusterUpdate>,
+
+    // Never serialized, so that patches of the spec leave the overrides alone.
+    #[serde(default, skip_serializing)]
+    pub overrides: Vec<ComponentOverride>,
+}
+
+/// A component whose management has been taken over from the cluster version operator.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ComponentOverride {
+    pub kind: String,
+    #[serde(default)]
+    pub group: String,
+    #[serde(default)]
+    pub namespace: String,
+    pub name: String,
+    #[serde(default)]
+    pub unmanaged: bool,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ClusterVersionStatus {
+    #[serde(
+        rename = "availableUpdates",
+        default,
+        deserialize_with = "skip_invalid_optional"
+    )]
+    pub available_updates: Option<Vec<ClusterUpdate>>,
+    #[serde(default)]
+    pub history: Vec<HistoricalEntry>,
+    #[serde(default)]
+    pub conditions: Vec<ClusterStatusCondition>,
+    /// Updates which are only recommended if the cluster isn't exposed to their known risks.
+    #[serde(
+        rename = "conditionalUpdates",
+        default,
+        deserialize_with = "skip_invalid"
+    )]
+    pub conditional_updates: Vec<ConditionalUpdate>,
+}
+
+/// An update, as found in the ClusterVersion's spec or status.
+///
+/// Custom and CI releases don't always have semantic versions (e.g. "v4.1.14" or "4.2"), so the
+/// versions are parsed leniently (see `parse_version`), and written back the way the cluster had
+/// them. Updates whose versions can't be parsed at all (e.g. "nightly") are kept rather than
+/// dropped, identified by their image instead (see `unversioned`). They order before every
+/// release and count as pre-releases, so they're only ever applied with --allow-prerelease, and
+/// never over a release with a version.
+#[derive(Clone, Debug, Eq)]
+pub struct ClusterUpdate {
+    pub force: bool,
+    pub image: String,
+    pub version: semver::Version,
+    /// The version as the cluster wrote it, if it had to be normalized to parse.
+    pub raw_version: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct RawClusterUpdate<V> {
+    #[serde(default)]
+    force: bool,
+    image: V,
+    version: V,
+}
+
+impl<'de> Deserialize<'de> for ClusterUpdate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawClusterUpdate::<String>::deserialize(deserializer)?;
+        let version = match parse_version(&raw.version) {
+            Some(version) => version,
+            None => {
+                warn!(
+                    "Identifying {} by its image, since its version ({:?}) can't be parsed",
+                    raw.image, raw.version
+                );
+                unversioned(&raw.image)
+            }
+        };
+        let raw_version = Some(raw.version).filter(|raw| raw != &version.to_string());
+        Ok(ClusterUpdate {
+            force: raw.force,
+            image: raw.image,
+            version,
+            raw_version,
+        })
+    }
+}
+
+impl Serialize for ClusterUpdate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let version = self.version_string();
+        RawClusterUpdate {
+            force: self.force,
+            image: self.image.as_str(),
+            version: version.as_str(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Parse a release version, accepting the near misses of custom and CI builds: a leading "v", a
+/// missing patch version, and extra components (kept as build metadata).
+pub fn parse_version(version: &str) -> Option<semver::Version> {
+    if let Ok(version) = semver::Version::parse(version) {
+        return Some(version);
+    }
+    let version = version.trim().trim_start_matches(['v', 'V']);
+    let (core, suffix) = version.split_at(version.find(['-', '+']).unwrap_or(version.len()));
+    let (pre, build) = match suffix.split_once('+') {
+        Some((pre, build)) => (pre, build),
+        None => (suffix, ""),
+    };
+
+    let mut parts: Vec<&str> = core.split('.').collect();
+    if parts.len() < 2 || parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    let mut build: Vec<&str> = build.split('.').filter(|part| !part.is_empty()).collect();
+    if parts.len() > 3 {
+        build.splice(0..0, parts.split_off(3));
+    }
+    parts.resize(3, "0");
+
+    let mut normalized = format!("{}{}", parts.join("."), pre);
+    if !build.is_empty() {
+        normalized.push('+');
+        normalized.push_str(&build.join("."));
+    }
+    semver::Version::parse(&normalized).ok()
+}
+
+/// The stand-in for the version of a release which has none that can be parsed: 0.0.0, which
+/// orders before every real release, with a pre-release naming the image's digest (or, for an
+/// image referred to by tag, the whole pull spec) so that such releases stay distinct from one
+/// another and order consistently.
+pub fn unversioned(image: &str) -> semver::Version {
+    let identity = match image.split_once('@') {
+        Some((_, digest)) => digest,
+        None => image,
+    };
+    let identity: String = identity
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    semver::Version {
+        major: 0,
+        minor: 0,
+        patch: 0,
+        pre: vec![
+            semver::Identifier::AlphaNumeric("unversioned".to_string()),
+            semver::Identifier::AlphaNumeric(identity),
+        ],
+        build: Vec::new(),
+    }
+}
+
+impl ClusterUpdate {
+    /// Whether the release's version couldn't be parsed, so it's identified by its image.
+    pub fn is_unversioned(&self) -> bool {
+        self.raw_version.is_some() && self.version == unversioned(&self.image)
+    }
+
+    /// The version as the cluster wrote it.
+    pub fn version_string(&self) -> String {
+        match &self.raw_version {
+            Some(raw) => raw.clone(),
+            None => self.version.to_string(),
+        }
+    }
+}
+
+/// Deserialize a list, dropping (with a warning) the items which can't be read rather than
+/// failing on the whole object.
+pub fn skip_invalid<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    Ok(Vec::<serde_json::Value>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(lenient)
+        .collect())
+}
+
+/// Like `skip_invalid`, but for a list which may be null.
+fn skip_invalid_optional<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    Ok(Option::<Vec<serde_json::Value>>::deserialize(deserializer)?
+        .map(|items| items.into_iter().filter_map(lenient).collect()))
+}
+
+/// Deserialize an optional value, treating one which can't be read as missing.
+fn invalid_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    Ok(Option::<serde_json::Value>::deserialize(deserializer)?.and_then(lenient))
+}
+
+fn lenient<T: DeserializeOwned>(value: serde_json::Value) -> Option<T> {
+    match serde_json::from_value(value.clone()) {
+        Ok(item) => Some(item),
+        Err(error) => {
+            warn!("Ignoring unreadable {}: {}", value, error);
+            None
+        }
+    }
+}
+
+impl Ord for ClusterUpdate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.version.cmp(&other.version)
+    }
+}
+
+impl PartialOrd for ClusterUpdate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ClusterUpdate {
+    fn eq(&self, other: &Self) -> bool {
+        self.version == other.version
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ConditionalUpdate {
+    pub release: ClusterUpdate,
+    #[serde(default)]
+    pub risks: Vec<ConditionalUpdateRisk>,
+    #[serde(default)]
+    pub conditions: Vec<ClusterStatusCondition>,
+}
+
+impl ConditionalUpdate {
+    /// Whether the cluster found the update safe to apply, or `None` if it has yet to evaluate
+    /// the risks.
+    pub fn recommended(&self) -> Option<bool> {
+        match self
+            .conditions
+            .iter()
+            .find(|condition| condition.type_ == "Recommended")
+            .map(|condition| condition.status.as_str())
+        {
+            Some("True") => Some(true),
+            Some("False") => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ConditionalUpdateRisk {
+    pub name: String,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct HistoricalEntry {
+    #[serde(rename = "startedTime")]
+    pub started_time: Option<DateTime<Utc>>,
+    #[serde(rename = "completionTime")]
+    pub completion_time: Option<DateTime<Utc>>,
+    pub image: Option<String>,
+    /// "Completed" if the update was fully applied, otherwise "Partial".
+    pub state: Option<String>,
+    pub version: Option<String>,
+}
+
+impl HistoricalEntry {
+    /// The entry's version, parsed the same way as the versions of updates (so that an entry for
+    /// a release whose version had to be normalized, or identified by its image, still matches
+    /// the update to it).
+    pub fn parsed_version(&self) -> Option<semver::Version> {
+        match (self.version.as_deref().and_then(parse_version), &self.image) {
+            (Some(version), _) => Some(version),
+            (None, Some(image)) => Some(unversioned(image)),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether the entry is for the update to `version`.
+    pub fn is_for(&self, version: &semver::Version) -> bool {
+        self.parsed_version().as_ref() == Some(version)
+    }
+}
+
+/// How an update requested of the cluster turned out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// The cluster has yet to accept the update.
+    NotStarted,
+    /// The update is still being applied.
+    InProgress,
+    Completed,
+    /// The update never completed, and the cluster has since moved on to another version.
+    Abandoned {
+        superseded_by: String,
+    },
+}
+
+impl ClusterVersionStatus {
+    /// How the update to `version` turned out, judging by the cluster's history.
+    pub fn outcome(&self, version: &semver::Version) -> Outcome {
+        let position = self.history.iter().position(|entry| entry.is_for(version));
+        match position {
+            Some(i) if self.history[i].state.as_deref() == Some("Completed") => Outcome::Completed,
+            Some(i) if i > 0 => Outcome::Abandoned {
+                superseded_by: self.history[0].version.clone().unwrap_or_default(),
+            },
+            Some(_) => Outcome::InProgress,
+            None => Outcome::NotStarted,
+        }
+    }
+
+    /// Why the cluster-version operator failed to retrieve the available updates, if it did.
+    /// Without this, a failure is indistinguishable from there being no updates.
+    pub fn retrieval_failure(&self) -> Option<String> {
+        self.condition("RetrievedUpdates")
+            .filter(|condition| condition.status == "False")
+            .map(|condition| {
+                format!(
+                    "{}: {}",
+                    condition.reason.as_deref().unwrap_or("Unknown"),
+                    condition.message.as_deref().unwrap_or("no message")
+                )
+            })
+    }
+
+    /// The condition of the given type, if the cluster reports it.
+    pub fn condition(&self, type_: &str) -> Option<&ClusterStatusCondition> {
+        self.conditions
+            .iter()
+            .find(|condition| condition.type_ == type_)
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ClusterStatusCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
+pub type ClusterVersion = api::Object<ClusterVersionSpec, ClusterVersionStatus>;
+
+/// Access to the cluster's ClusterVersion.
+///
+/// This allows the reconcile logic to be exercised against fixtures instead of an API server.
+pub trait ClusterVersionClient {
+    fn get(&self) -> Result<ClusterVersion, Error>;
+
+    /// Read which field managers have set which of the ClusterVersion's fields.
+    fn managed_fields(&self) -> Result<Vec<ManagedFieldsEntry>, Error>;
+
+    /// Merge the given ClusterVersion into the cluster's.
+    fn patch(&self, version: &ClusterVersion) -> Result<(), Error>;
+
+    /// Set (or with `None`, remove) an annotation of the ClusterVersion, leaving the rest of it
+    /// alone.
+    fn annotate(&self, annotation: &str, value: Option<&str>) -> Result<(), Error>;
+
+    /// Wait for the ClusterVersion to change and return its latest state.
+    fn watch(&self) -> Result<Option<ClusterVersion>, Error>;
+}
+
+/// The field manager which the operator's patches are recorded under, unless overridden with
+/// `--field-manager`.
+pub const FIELD_MANAGER: &str = "openshift-update";
+
+/// One of the entries of an object's managedFields, recording which fields a manager has set.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ManagedFieldsEntry {
+    pub manager: String,
+    #[serde(default)]
+    pub operation: Option<String>,
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+    /// The managed fields, in the FieldsV1 format (e.g. `{"f:spec":{"f:desiredUpdate":{}}}`).
+    #[serde(rename = "fieldsV1", default)]
+    pub fields: serde_json::Value,
+}
+
+impl ManagedFieldsEntry {
+    /// Whether the manager has set the field at `path`, or any field beneath it.
+    pub fn manages(&self, path: &[&str]) -> bool {
+        path.iter()
+            .try_fold(&self.fields, |fields, name| {
+                fields.get(format!("f:{}", name))
+            })
+            .is_some()
+    }
+}
+
+// The metadata modelled by kube doesn't include the managed fields.
+#[derive(serde::Deserialize)]
+struct ManagedObject {
+    metadata: ManagedMetadata,
+}
+
+#[derive(serde::Deserialize)]
+struct ManagedMetadata {
+    #[serde(rename = "managedFields", default)]
+    managed_fields: Vec<ManagedFieldsEntry>,
+}
+
+/// API group of the ClusterVersion and the other cluster-wide configuration.
+pub const API_GROUP: &str = "config.openshift.io";
+
+/// Version of the API group the operator is written against.
+pub const API_VERSION: &str = "v1";
+
+/// The ClusterVersions of the cluster.
+pub fn api(client: APIClient) -> Api<ClusterVersion> {
+    Api::customResource(client, "clusterversions")
+        .group(API_GROUP)
+        .version(API_VERSION)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ApiGroup {
+    versions: Vec<GroupVersion>,
+    #[serde(rename = "preferredVersion")]
+    preferred_version: Option<GroupVersion>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct GroupVersion {
+    version: String,
+}
+
+/// Check that the API server serves `API_VERSION` of `API_GROUP`, so that a cluster which has
+/// moved on fails clearly at startup rather than on the first request.
+///
+/// Only the fields which the operator acts on are modelled, and everything else is ignored when
+/// reading objects. Patches are merge patches of just the changed fields, so fields added by newer
+/// releases are left untouched.
+pub fn check_api_version(client: &APIClient) -> Result<(), Error> {
+    let request = http::Request::get(format!("/apis/{}", API_GROUP))
+        .body(Vec::new())
+        .map_err(|error| Error::Config(error.to_string()))?;
+    match kubeapi::call("get", "apigroups", || client.request::<ApiGroup>(request)) {
+        Ok(group) => negotiate(&group),
+        Err(error) => {
+            let error = Error::from(error);
+            if retry::not_found(&error) {
+                Err(Error::Config(format!(
+                    "the cluster doesn't serve {}; is it OpenShift?",
+                    API_GROUP
+                )))
+            } else {
+                Err(error)
+            }
+        }
+    }
+}
+
+fn negotiate(group: &ApiGroup) -> Result<(), Error> {
+    if !group.versions.iter().any(|v| v.version == API_VERSION) {
+        let served: Vec<&str> = group.versions.iter().map(|v| v.version.as_str()).collect();
+        return Err(Error::Config(format!(
+            "the cluster serves {} {} but not {}",
+            API_GROUP,
+            served.join(", "),
+            API_VERSION
+        )));
+    }
+    match &group.preferred_version {
+        Some(preferred) if preferred.version != API_VERSION => info!(
+            "The cluster prefers {}/{}; using {}",
+            API_GROUP, preferred.version, API_VERSION
+        ),
+        _ => debug!("Using {}/{}", API_GROUP, API_VERSION),
+    }
+    Ok(())
+}
+
+/// A ClusterVersionClient backed by the Kubernetes API.
+pub struct KubeClient {
+    client: APIClient,
+    api: Api<ClusterVersion>,
+    name: String,
+    field_manager: String,
+    reflector: Reflector<ClusterVersion>,
+    backoff: Backoff,
+}
+
+impl KubeClient {
+    /// A client for the ClusterVersion called `name` (normally "version").
+    pub fn new(client: APIClient, name: &str, backoff: Backoff) -> Result<KubeClient, Error> {
+        let api = api(client.clone());
+        let reflector = backoff.retry("list ClusterVersions", || {
+            Ok(kubeapi::call("list", "clusterversions", || {
+                Reflector::new(api.clone())
+                    .fields(&format!("metadata.name=={}", name))
+                    .init()
+            })?)
+        })?;
+        Ok(KubeClient {
+            client,
+            api,
+            name: name.to_string(),
+            field_manager: FIELD_MANAGER.to_string(),
+            reflector,
+            backoff,
+        })
+    }
+
+    /// Record the client's patches of the ClusterVersion under `manager`.
+    pub fn field_manager(mut self, manager: &str) -> KubeClient {
+        self.field_manager = manager.to_string();
+        self
+    }
+
+    pub fn api(&self) -> &Api<ClusterVersion> {
+        &self.api
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl ClusterVersionClient for KubeClient {
+    fn get(&self) -> Result<ClusterVersion, Error> {
+        self.backoff.retry("get ClusterVersion", || {
+            Ok(kubeapi::call("get", "clusterversions", || {
+                self.api.get(&self.name)
+            })?)
+        })
+    }
+
+    fn patch(&self, version: &ClusterVersion) -> Result<(), Error> {
+        let patch = serde_json::to_vec(version)?;
+        let params = PatchParams {
+            field_manager: Some(self.field_manager.clone()),
+            ..Default::default()
+        };
+        self.backoff.retry("patch ClusterVersion", || {
+            kubeapi::call("patch", "clusterversions", || {
+                self.api.patch(&self.name, &params, patch.clone())
+            })?;
+            Ok(())
+        })
+    }
+
+    fn managed_fields(&self) -> Result<Vec<ManagedFieldsEntry>, Error> {
+        self.backoff
+            .retry("get ClusterVersion's managed fields", || {
+                let request = http::Request::get(format!(
+                    "/apis/{}/{}/clusterversions/{}",
+                    API_GROUP, API_VERSION, self.name
+                ))
+                .body(Vec::new())
+                .map_err(|error| Error::Config(error.to_string()))?;
+                let object = kubeapi::call("get", "clusterversions", || {
+                    self.client.request::<ManagedObject>(request)
+                })?;
+                Ok(object.metadata.managed_fields)
+            })
+    }
+
+    fn annotate(&self, annotation: &str, value: Option<&str>) -> Result<(), Error> {
+        let patch = serde_json::to_vec(&serde_json::json!({
+            "metadata": { "annotations": { annotation: value } }
+        }))?;
+        self.backoff.retry("annotate ClusterVersion", || {
+            kubeapi::call("patch", "clusterversions", || {
+                self.api
+                    .patch(&self.name, &PatchParams::default(), patch.clone())
+            })?;
+            Ok(())
+        })
+    }
+
+    fn watch(&self) -> Result<Option<ClusterVersion>, Error> {
+        self.backoff.retry("watch ClusterVersion", || {
+            Ok(kubeapi::call("watch", "clusterversions", || {
+                self.reflector.poll()
+            })?)
+        })?;
+        let mut versions = self.reflector.read()?;
+        // The field selector matches by name, so anything else means the API server ignored it.
+        versions.retain(|version| version.metadata.name == self.name);
+        match versions.len() {
+            0 | 1 => Ok(versions.pop()),
+            count => Err(Error::Config(format!(
+                "found {} ClusterVersions named {}",
+                count, self.name
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct InfrastructureStatus {
+    #[serde(rename = "controlPlaneTopology", default)]
+    control_plane_topology: Option<String>,
+    #[serde(rename = "infrastructureName", default)]
+    infrastructure_name: Option<String>,
+}
+
+type Infrastructure = api::Object<api::Void, InfrastructureStatus>;
+
+fn infrastructure(client: &APIClient) -> Result<Infrastructure, kube::Error> {
+    Api::<Infrastructure>::customResource(client.clone(), "infrastructures")
+        .group("config.openshift.io")
+        .version("v1")
+        .get("cluster")
+}
+
+/// Determine whether the cluster runs its control plane on a single node.
+pub fn single_node(client: &APIClient) -> bool {
+    match infrastructure(client) {
+        Ok(infrastructure) => {
+            infrastructure
+                .status
+                .and_then(|status| status.control_plane_topology)
+                .as_deref()
+                == Some("SingleReplica")
+        }
+        Err(error) => {
+            warn!(
+                "Failed to read Infrastructure, assuming multiple nodes: {}",
+                error
+            );
+            false
+        }
+    }
+}
+
+/// The name the cluster's infrastructure was created with, which is unique to the cluster.
+pub fn infrastructure_name(client: &APIClient) -> Option<String> {
+    match infrastructure(client) {
+        Ok(infrastructure) => infrastructure
+            .status
+            .and_then(|status| status.infrastructure_name),
+        Err(error) => {
+            warn!("Failed to read the infrastructure name: {}", error);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(json: &str) -> ClusterVersion {
+        serde_json::from_str(json).expect("valid fixture")
+    }
+
+    #[test]
+    fn negotiates_api_versions() {
+        let group = |json| serde_json::from_value::<ApiGroup>(json).expect("valid group");
+        assert!(negotiate(&group(serde_json::json!({
+            "versions": [{ "version": "v2" }, { "version": "v1" }],
+            "preferredVersion": { "version": "v2" },
+        })))
+        .is_ok());
+        assert_eq!(
+            negotiate(&group(
+                serde_json::json!({ "versions": [{ "version": "v2" }] })
+            ))
+            .expect_err("v1 isn't served")
+            .to_string(),
+            "invalid configuration: the cluster serves config.openshift.io v2 but not v1"
+        );
+    }
+
+    #[test]
+    fn tolerates_unknown_fields() {
+        let mut version = fixture(
+            &serde_json::json!({
+                "apiVersion": "config.openshift.io/v1",
+                "kind": "ClusterVersion",
+                "metadata": { "name": "version" },
+                "spec": {
+                    "clusterID": "abc",
+                    "capabilities": { "baselineCapabilitySet": "None" },
+                    "desiredUpdate": {
+                        "version": "4.1.15",
+                        "image": "quay.io/openshift-release-dev/ocp-release:4.1.15",
+                        "architecture": "Multi",
+                    },
+                },
+                "status": {
+                    "availableUpdates": null,
+                    "capabilities": { "enabledCapabilities": ["marketplace"] },
+                },
+            })
+            .to_string(),
+        );
+
+        // A patch of the spec only carries the fields which are modelled.
+        version.spec.cluster_id = None;
+        let patch = serde_json::to_value(&version.spec).expect("Serialize to JSON");
+        assert_eq!(
+            patch,
+            serde_json::json!({
+                "desiredUpdate": {
+                    "force": false,
+                    "version": "4.1.15",
+                    "image": "quay.io/openshift-release-dev/ocp-release:4.1.15",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn available_updates() {
+        let version = fixture(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let status = version.status.expect("status");
+        let versions: Vec<String> = status
+            .available_updates
+            .expect("available updates")
+            .iter()
+            .map(|update| update.version.to_string())
+            .collect();
+        assert_eq!(versions, vec!["4.1.15", "4.1.16"]);
+        assert_eq!(status.history.len(), 1);
+        assert_eq!(
+            version.spec.cluster_id.as_deref(),
+            Some("0c9f9f1b-0cf5-4ea0-8b2f-bd2cb1e0fdd5")
+        );
+    }
+
+    #[test]
+    fn parses_versions_leniently() {
+        let parse = |version| parse_version(version).map(|version| version.to_string());
+        assert_eq!(parse("4.1.14").as_deref(), Some("4.1.14"));
+        assert_eq!(parse("v4.1.14").as_deref(), Some("4.1.14"));
+        assert_eq!(parse("4.2").as_deref(), Some("4.2.0"));
+        assert_eq!(parse("4.2-rc.1").as_deref(), Some("4.2.0-rc.1"));
+        assert_eq!(parse("4.1.14.1").as_deref(), Some("4.1.14+1"));
+        assert_eq!(parse("4.1.14.1-ci+abc").as_deref(), Some("4.1.14-ci+1.abc"));
+        assert_eq!(parse("latest"), None);
+        assert_eq!(parse("4"), None);
+
+        let status: ClusterVersionStatus = serde_json::from_value(serde_json::json!({
+            "availableUpdates": [
+                { "version": "v4.1.15", "image": "quay.io/release:a" },
+                { "version": "nightly", "image": "quay.io/release@sha256:0b1c" },
+                { "version": "nightly", "image": "quay.io/release@sha256:0a2d" },
+                { "version": "4.1.16", "image": "quay.io/release:c" },
+            ],
+        }))
+        .expect("Deserialize status");
+        let mut updates = status.available_updates.expect("available updates");
+        let versions: Vec<String> = updates
+            .iter()
+            .map(|update| update.version.to_string())
+            .collect();
+        assert_eq!(
+            versions,
+            vec![
+                "4.1.15",
+                "0.0.0-unversioned.sha256-0b1c",
+                "0.0.0-unversioned.sha256-0a2d",
+                "4.1.16"
+            ]
+        );
+
+        // Releases without versions are told apart by their images, and come before the rest.
+        assert!(!updates[0].is_unversioned());
+        assert!(updates[1].is_unversioned());
+        assert_ne!(updates[1], updates[2]);
+        updates.sort();
+        let images: Vec<&str> = updates.iter().map(|update| update.image.as_str()).collect();
+        assert_eq!(
+            images,
+            vec![
+                "quay.io/release@sha256:0a2d",
+                "quay.io/release@sha256:0b1c",
+                "quay.io/release:a",
+                "quay.io/release:c"
+            ]
+        );
+        assert_eq!(updates[0].version_string(), "nightly");
+        assert_eq!(
+            unversioned("quay.io/release:ci").to_string(),
+            "0.0.0-unversioned.quay-io-release-ci"
+        );
+
+        // The cluster's own spelling is kept when the update is written back.
+        assert_eq!(
+            serde_json::to_value(&updates[2]).expect("Serialize to JSON"),
+            serde_json::json!({
+                "force": false,
+                "version": "v4.1.15",
+                "image": "quay.io/release:a",
+            })
+        );
+    }
+
+    #[test]
+    fn matches_history_leniently() {
+        let status: ClusterVersionStatus = serde_json::from_value(serde_json::json!({
+            "history": [
+                { "version": "v4.2", "state": "Partial", "image": "quay.io/release:b" },
+                { "version": "nightly", "state": "Completed", "image": "quay.io/release:ci" },
+                { "version": "4.1.14.1", "state": "Completed", "image": "quay.io/release:a" },
+            ],
+        }))
+        .expect("Deserialize status");
+        let version = |version| parse_version(version).expect("version");
+        assert_eq!(status.outcome(&version("4.2.0")), Outcome::InProgress);
+        assert_eq!(
+            status.outcome(&unversioned("quay.io/release:ci")),
+            Outcome::Completed
+        );
+        assert_eq!(status.outcome(&version("4.1.14.1")), Outcome::Completed);
+        assert_eq!(status.outcome(&version("4.1.15")), Outcome::NotStarted);
+        assert_eq!(status.history[0].parsed_version(), Some(version("4.2.0")));
+    }
+
+    #[test]
+    fn conditional_updates() {
+        let version = fixture(include_str!(
+            "../tests/fixtures/clusterversion-conditional.json"
+        ));
+        let status = version.status.expect("status");
+        let updates: Vec<(String, Option<bool>, Vec<String>)> = status
+            .conditional_updates
+            .iter()
+            .map(|update| {
+                (
+                    update.release.version.to_string(),
+                    update.recommended(),
+                    update.risks.iter().map(|risk| risk.name.clone()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            updates,
+            vec![
+                (
+                    "4.1.16".to_string(),
+                    Some(true),
+                    vec!["AWSMetadataTimeout".to_string()]
+                ),
+                (
+                    "4.1.17".to_string(),
+                    Some(false),
+                    vec!["EtcdDefragHang".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_history() {
+        let version = fixture(include_str!(
+            "../tests/fixtures/clusterversion-empty-history.json"
+        ));
+        let status = version.status.expect("status");
+        assert!(status.history.is_empty());
+        assert!(status.available_updates.is_none());
+    }
+
+    #[test]
+    fn null_available_updates() {
+        let version = fixture(include_str!(
+            "../tests/fixtures/clusterversion-null-updates.json"
+        ));
+        let status = version.status.expect("status");
+        assert!(status.available_updates.is_none());
+        assert_eq!(
+            status.retrieval_failure().as_deref(),
+            Some(
+                "RemoteFailed: Unable to retrieve available updates: currently installed version \
+                 4.1.14 not found in the \"stable-4.1\" channel"
+            )
+        );
+    }
+
+    #[test]
+    fn patch_omits_cluster_id_and_status() {
+        let version = fixture(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let patch = serde_json::to_value(&ClusterVersion {
+            types: version.types,
+            metadata: version.metadata,
+            spec: ClusterVersionSpec {
+                overrides: overrides(),
+                ..Default::default()
+            },
+            status: None,
+        })
+        .expect("Serialize to JSON");
+        assert!(patch["spec"].get("clusterID").is_none());
+        assert!(patch["spec"].get("overrides").is_none());
+        assert!(patch["status"].is_null());
+    }
+
+    fn overrides() -> Vec<ComponentOverride> {
+        serde_json::from_value(serde_json::json!([
+            {
+                "kind": "Deployment",
+                "group": "apps",
+                "namespace": "openshift-monitoring",
+                "name": "cluster-monitoring-operator",
+                "unmanaged": true
+            },
+            {
+                "kind": "ClusterOperator",
+                "group": "config.openshift.io",
+                "name": "monitoring",
+                "unmanaged": false
+            }
+        ]))
+        .expect("valid overrides")
+    }
+
+    #[test]
+    fn unmanaged_overrides() {
+        let spec = ClusterVersionSpec {
+            overrides: overrides(),
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::gates::unmanaged(&spec),
+            vec!["Deployment/openshift-monitoring/cluster-monitoring-operator"]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The subcommands, each of which does one thing and exits rather than running the operator.
+
+use crate::operate::exit_code;
+use crate::options::{command_line, Options};
+use crate::{
+    channel, checkconfig, explain, history, manifests, manual, multiarch, ownership, plan,
+    preflight, recommend, remote, simulate, snooze, watch,
+};
+use kube::client::APIClient;
+use openshift_update::kubeconfig;
+use openshift_update::Error;
+use std::process;
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub enum Command {
+    #[structopt(name = "status")]
+    /// Show the running operator's latest decision
+    Status(remote::Status),
+
+    #[structopt(name = "history")]
+    /// List the updates the cluster has started, newest first, and which the operator gave up on
+    History(history::History),
+
+    #[structopt(name = "queue")]
+    /// Show the updates the running operator intends to apply, in order, and what each waits on
+    Queue(remote::Queue),
+
+    #[structopt(name = "fleet-status")]
+    /// Show where each of the clusters of the running operator's fleet stands
+    FleetStatus(remote::FleetStatus),
+
+    #[structopt(name = "pause")]
+    /// Stop the running operator from applying updates
+    Pause(remote::Remote),
+
+    #[structopt(name = "resume")]
+    /// Allow the running operator to apply updates again
+    Resume(remote::Remote),
+
+    #[structopt(name = "approve")]
+    /// Approve the update the running operator is waiting on
+    Approve(remote::Remote),
+
+    #[structopt(name = "snooze")]
+    /// Defer every automatic update for a while (e.g. 7d), after which they resume by themselves
+    Snooze(snooze::Snooze),
+
+    #[structopt(name = "acquire")]
+    /// Take over the cluster's updates, holding back the operator's until they're released
+    Acquire(ownership::Acquire),
+
+    #[structopt(name = "release")]
+    /// Hand the cluster's updates back to the operator
+    Release(ownership::Release),
+
+    #[structopt(name = "watch")]
+    /// Follow the progress of the cluster's current update
+    Watch,
+
+    #[structopt(name = "channel")]
+    /// Manage the cluster's update channel
+    Channel(channel::Channel),
+
+    #[structopt(name = "explain")]
+    /// Explain why the operator would or wouldn't update the cluster right now
+    Explain(explain::Explain),
+
+    #[structopt(name = "simulate")]
+    /// Make a decision against a ClusterVersion and policy read from files, at a chosen time,
+    /// exiting as --once would
+    Simulate(simulate::Simulate),
+
+    #[structopt(name = "recommend")]
+    /// List every known update, including conditional ones, with its risks and eligibility
+    Recommend(recommend::Recommend),
+
+    #[structopt(name = "plan")]
+    /// Show the updates, and any channel switches, which would take the cluster to a version
+    Plan(plan::Plan),
+
+    #[structopt(name = "preflight")]
+    /// Run the checks which hold back updates against the cluster, exiting with 2 if any fails
+    Preflight(preflight::Preflight),
+
+    #[structopt(name = "migrate-to-multi-arch")]
+    /// Move the cluster to the multi-architecture payload of its version, once the checks which
+    /// hold back updates pass
+    MigrateToMultiArch(multiarch::MigrateToMultiArch),
+
+    #[structopt(name = "completions")]
+    /// Print the completion script for a shell: bash, zsh, fish, powershell or elvish
+    Completions {
+        #[structopt(possible_values = &Shell::variants())]
+        shell: Shell,
+    },
+
+    #[structopt(name = "man")]
+    /// Print the man page
+    Man,
+
+    #[structopt(name = "generate-manifests")]
+    /// Print the manifests which run the operator in the cluster (expects the API token in the
+    /// openshift-update-api-token Secret)
+    GenerateManifests(manifests::GenerateManifests),
+
+    #[structopt(name = "check-config")]
+    /// Validate the operator's options without starting it, exiting with 2 if any is invalid
+    CheckConfig(checkconfig::CheckConfig),
+}
+
+/// Run the subcommand, exiting with its outcome where it has one.
+pub fn run(command: &Command, options: &Options) -> Result<(), Error> {
+    match command {
+        Command::Watch => watch::run(
+            APIClient::new(kubeconfig::load()?),
+            &options.cluster_version_name,
+        ),
+        Command::Channel(command) => {
+            channel::run(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::Snooze(command) => {
+            snooze::run(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::Acquire(command) => {
+            ownership::acquire(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::Release(command) => {
+            ownership::release(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::MigrateToMultiArch(command) => {
+            multiarch::run(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::History(command) => {
+            history::run(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::Explain(command) => {
+            explain::run(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::Simulate(command) => {
+            let decision = simulate::run(command_line, command)?;
+            process::exit(exit_code(Some(&decision)));
+        }
+        Command::Recommend(command) => {
+            recommend::run(APIClient::new(kubeconfig::load()?), options, command)
+        }
+        Command::Plan(command) => plan::run(APIClient::new(kubeconfig::load()?), options, command),
+        Command::Completions { shell } => {
+            manual::completions(*shell);
+            Ok(())
+        }
+        Command::Man => {
+            if let Err(error) = manual::man() {
+                error!("Failed to write man page: {}", error);
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Command::CheckConfig(command) => {
+            if !checkconfig::run(options, command) {
+                process::exit(2);
+            }
+            Ok(())
+        }
+        Command::GenerateManifests(command) => {
+            manifests::run(command);
+            Ok(())
+        }
+        Command::Preflight(command) => {
+            if !preflight::run(APIClient::new(kubeconfig::load()?), options, command)? {
+                process::exit(2);
+            }
+            Ok(())
+        }
+        command => {
+            if let Err(error) = remote::run(command) {
+                error!("{}", error);
+                process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
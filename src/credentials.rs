@@ -0,0 +1,278 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The way the cluster's cloud credentials are managed, and whether they are ready for an update.
+//!
+//! The Cloud Credential Operator normally mints (or passes through) the credentials each
+//! component asks for with a CredentialsRequest. In Manual mode, which includes clusters using
+//! short-term credentials from the cloud's security token service (STS), it does neither: the
+//! administrator has to extract the CredentialsRequests of the release being updated to, provision
+//! them (with `ccoctl` for STS) and then mark the cluster as ready by annotating the
+//! CloudCredential. Updating to a new minor version without doing so breaks the cloud
+//! integrations which need new permissions, so those updates are blocked until the annotation
+//! names the version.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::kubeapi;
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use crate::retry::{self, Backoff};
+use crate::Error;
+use kube::api::{Object, RawApi, Void};
+use kube::client::APIClient;
+
+/// Annotation on the CloudCredential naming the version the credentials have been updated for.
+pub const UPGRADEABLE_TO_ANNOTATION: &str = "cloudcredential.openshift.io/upgradeable-to";
+
+/// How the cluster's cloud credentials are managed.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Credentials {
+    /// The CloudCredential's mode: "Manual", "Mint", "Passthrough" or "" (the default).
+    pub mode: String,
+    /// Whether the cluster's service accounts are federated with the cloud, meaning that its
+    /// credentials are short-lived ones from STS.
+    pub sts: bool,
+    /// The version which the credentials have been updated for, if they have been.
+    pub upgradeable_to: Option<String>,
+}
+
+impl Credentials {
+    pub fn manual(&self) -> bool {
+        self.mode == "Manual"
+    }
+
+    /// Why the update must wait for the credentials to be updated, if it must.
+    pub fn blocks(&self, current: Option<&str>, update: &semver::Version) -> Option<String> {
+        if !self.manual() {
+            return None;
+        }
+        let target = (update.major, update.minor);
+        if current
+            .and_then(minor)
+            .is_some_and(|current| current >= target)
+        {
+            return None;
+        }
+        if self
+            .upgradeable_to
+            .as_deref()
+            .and_then(minor)
+            .is_some_and(|ready| ready >= target)
+        {
+            return None;
+        }
+        Some(format!(
+            "the cloud credentials are managed manually{}; extract the CredentialsRequests with \
+             `oc adm release extract --credentials-requests` from {}, {} and then annotate \
+             cloudcredential/cluster with {}={}",
+            if self.sts { " with STS" } else { "" },
+            update,
+            if self.sts {
+                "provision them with `ccoctl`"
+            } else {
+                "provision them"
+            },
+            UPGRADEABLE_TO_ANNOTATION,
+            update
+        ))
+    }
+}
+
+/// The major and minor parts of a version (e.g. "4.11" or "4.11.3").
+fn minor(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.split('-').next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Access to the cluster's credentials configuration, which allows decisions to be exercised
+/// against fixtures.
+pub trait CredentialsClient {
+    /// How the credentials are managed, or `None` if the cluster has no Cloud Credential
+    /// Operator.
+    fn credentials(&self) -> Result<Option<Credentials>, Error>;
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct CloudCredentialSpec {
+    #[serde(rename = "credentialsMode", default)]
+    credentials_mode: String,
+}
+
+type CloudCredential = Object<CloudCredentialSpec, Void>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct AuthenticationSpec {
+    #[serde(rename = "serviceAccountIssuer", default)]
+    service_account_issuer: String,
+}
+
+type Authentication = Object<AuthenticationSpec, Void>;
+
+pub struct KubeCredentials {
+    pub client: APIClient,
+    pub backoff: Backoff,
+}
+
+impl KubeCredentials {
+    fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        resource: &str,
+        api: RawApi,
+    ) -> Result<Option<T>, Error> {
+        let result = self.backoff.retry(&format!("get {}", resource), || {
+            let request = api.get("cluster")?;
+            Ok(kubeapi::call("get", resource, || {
+                self.client.request::<T>(request)
+            })?)
+        });
+        match result {
+            Ok(object) => Ok(Some(object)),
+            Err(ref error) if retry::not_found(error) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl CredentialsClient for KubeCredentials {
+    fn credentials(&self) -> Result<Option<Credentials>, Error> {
+        let credential: CloudCredential = match self.get(
+            "cloudcredentials",
+            RawApi::customResource("cloudcredentials")
+                .group("operator.openshift.io")
+                .version("v1"),
+        )? {
+            Some(credential) => credential,
+            None => return Ok(None),
+        };
+        let authentication: Option<Authentication> = self.get(
+            "authentications",
+            RawApi::customResource("authentications")
+                .group("config.openshift.io")
+                .version("v1"),
+        )?;
+        Ok(Some(Credentials {
+            mode: credential.spec.credentials_mode,
+            sts: authentication.is_some_and(|authentication| {
+                !authentication.spec.service_account_issuer.is_empty()
+            }),
+            upgradeable_to: credential
+                .metadata
+                .annotations
+                .get(UPGRADEABLE_TO_ANNOTATION)
+                .cloned(),
+        }))
+    }
+}
+
+/// Block updates to a new minor version while the cloud credentials are managed manually and
+/// haven't been updated for it.
+pub struct ManualCredentials {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for ManualCredentials {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } => {
+                let blocked = current.credentials.as_ref().and_then(|credentials| {
+                    credentials.blocks(current.version.as_deref(), &update.version)
+                });
+                match blocked {
+                    Some(reason) => Decision::Blocked {
+                        reason,
+                        update,
+                        gate: "credentials".to_string(),
+                    },
+                    None => Decision::Apply { update },
+                }
+            }
+            decision => decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clusterversion::ClusterVersion;
+    use crate::policy::Latest;
+
+    fn version(version: &str) -> semver::Version {
+        semver::Version::parse(version).expect("valid version")
+    }
+
+    #[test]
+    fn blocks_minor_updates_until_the_credentials_are_updated() {
+        let mut credentials = Credentials {
+            mode: "Manual".to_string(),
+            sts: true,
+            upgradeable_to: None,
+        };
+        assert_eq!(credentials.blocks(Some("4.1.2"), &version("4.1.16")), None);
+        assert_eq!(
+            credentials.blocks(Some("4.1.2"), &version("4.2.0")),
+            Some(
+                "the cloud credentials are managed manually with STS; extract the \
+                 CredentialsRequests with `oc adm release extract --credentials-requests` from \
+                 4.2.0, provision them with `ccoctl` and then annotate cloudcredential/cluster \
+                 with cloudcredential.openshift.io/upgradeable-to=4.2.0"
+                    .to_string()
+            )
+        );
+
+        credentials.upgradeable_to = Some("4.2".to_string());
+        assert_eq!(credentials.blocks(Some("4.1.2"), &version("4.2.0")), None);
+        assert!(credentials
+            .blocks(Some("4.1.2"), &version("4.3.0"))
+            .is_some());
+
+        credentials.mode = "Mint".to_string();
+        assert_eq!(credentials.blocks(Some("4.1.2"), &version("4.3.0")), None);
+    }
+
+    #[test]
+    fn blocks_the_candidate() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let mut state = ClusterState::new(&version, now);
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        let policy = ManualCredentials {
+            inner: Box::new(Latest),
+        };
+
+        state.credentials = Some(Credentials {
+            mode: "Manual".to_string(),
+            sts: false,
+            upgradeable_to: None,
+        });
+        // Only updates to a new minor version need new credentials.
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+        state.version = Some("4.0.9".to_string());
+        match policy.evaluate(&state, &candidates) {
+            Decision::Blocked { gate, .. } => assert_eq!(gate, "credentials"),
+            decision => panic!("unexpected decision: {:?}", decision),
+        }
+    }
+}
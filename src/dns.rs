@@ -0,0 +1,153 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A health check of the cluster's DNS.
+//!
+//! DNS is frequently a silent casualty of the node reboots during an update: the control plane
+//! looks fine while workloads can no longer find each other. The check looks at the DNS
+//! operator's ClusterOperator and, when the operator runs inside the cluster, also resolves a
+//! well-known name through the cluster's DNS.
+
+use crate::clusterversion::ClusterStatusCondition;
+use crate::health::{Health, HealthCheck};
+use crate::kubeapi;
+use crate::retry::Backoff;
+use crate::Error;
+use kube::api::{Api, Object, Void};
+use kube::client::APIClient;
+use std::net::ToSocketAddrs;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct OperatorStatus {
+    #[serde(default)]
+    pub conditions: Vec<ClusterStatusCondition>,
+}
+
+pub type ClusterOperator = Object<Void, OperatorStatus>;
+
+/// Fails while the DNS operator is unavailable or degraded, or `name` can't be resolved.
+pub struct Dns {
+    pub client: APIClient,
+    /// A name to resolve (e.g. "kubernetes.default.svc.cluster.local"), which requires the
+    /// operator to run inside the cluster.
+    pub name: Option<String>,
+    pub backoff: Backoff,
+}
+
+impl HealthCheck for Dns {
+    fn gate(&self) -> &'static str {
+        "dns"
+    }
+
+    fn check(&self) -> Result<Health, Error> {
+        let api = Api::<ClusterOperator>::customResource(self.client.clone(), "clusteroperators")
+            .group("config.openshift.io")
+            .version("v1");
+        let operator = self.backoff.retry("get the DNS ClusterOperator", || {
+            Ok(kubeapi::call("get", "clusteroperators", || api.get("dns"))?)
+        })?;
+        let resolved = self.name.as_ref().map(|name| {
+            (name.as_str(), 0)
+                .to_socket_addrs()
+                .map(|addresses| addresses.count())
+                .map_err(|error| error.to_string())
+        });
+        Ok(health(
+            &operator.status.unwrap_or_default(),
+            self.name.as_deref().zip(resolved),
+        ))
+    }
+}
+
+/// The verdict on the DNS operator's status and, if a name was resolved, on how many addresses
+/// it resolved to.
+pub fn health(status: &OperatorStatus, resolved: Option<(&str, Result<usize, String>)>) -> Health {
+    let condition = |type_| {
+        status
+            .conditions
+            .iter()
+            .find(|condition| condition.type_ == type_)
+    };
+    let mut problems = Vec::new();
+    match condition("Available") {
+        Some(available) if available.status == "True" => {}
+        available => problems.push(format!(
+            "the DNS operator is not available ({})",
+            available
+                .and_then(|available| available.message.as_deref())
+                .unwrap_or("no reason given")
+        )),
+    }
+    if let Some(degraded) = condition("Degraded").filter(|degraded| degraded.status == "True") {
+        problems.push(format!(
+            "the DNS operator is degraded ({})",
+            degraded.message.as_deref().unwrap_or("no reason given")
+        ));
+    }
+    match &resolved {
+        Some((name, Ok(0))) => problems.push(format!("{} resolved to no addresses", name)),
+        Some((name, Err(error))) => problems.push(format!("failed to resolve {}: {}", name, error)),
+        _ => {}
+    }
+
+    Health {
+        gate: "dns",
+        passed: problems.is_empty(),
+        reason: match (problems.is_empty(), resolved) {
+            (true, Some((name, _))) => format!("the DNS operator is healthy and {} resolves", name),
+            (true, None) => "the DNS operator is healthy".to_string(),
+            (false, _) => problems.join("; "),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn judges_dns() {
+        let status: OperatorStatus = serde_json::from_value(serde_json::json!({
+            "conditions": [
+                { "type": "Available", "status": "True" },
+                { "type": "Degraded", "status": "False" },
+            ],
+        }))
+        .expect("valid status");
+        let name = "kubernetes.default.svc.cluster.local";
+        assert!(health(&status, None).passed);
+        assert_eq!(
+            health(&status, Some((name, Ok(1)))).reason,
+            "the DNS operator is healthy and kubernetes.default.svc.cluster.local resolves"
+        );
+        assert_eq!(
+            health(&status, Some((name, Err("timed out".to_string())))).reason,
+            "failed to resolve kubernetes.default.svc.cluster.local: timed out"
+        );
+
+        let status: OperatorStatus = serde_json::from_value(serde_json::json!({
+            "conditions": [
+                { "type": "Available", "status": "True" },
+                { "type": "Degraded", "status": "True", "message": "2 of 6 pods unavailable" },
+            ],
+        }))
+        .expect("valid status");
+        let unhealthy = health(&status, None);
+        assert!(!unhealthy.passed);
+        assert_eq!(
+            unhealthy.reason,
+            "the DNS operator is degraded (2 of 6 pods unavailable)"
+        );
+    }
+}
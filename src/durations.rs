@@ -0,0 +1,361 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How long each update the operator requested took, for tracking against service-level
+//! objectives and for sizing maintenance windows.
+//!
+//! An update is timed from being requested to being accepted by the cluster-version operator
+//! (which includes verifying and fetching the release), and from then until it completed. Each
+//! MachineConfigPool is timed from the update being accepted until the pool last became Updated,
+//! which is when the last of its nodes finished rebooting.
+//!
+//! While an update is in progress, how much longer it will take is estimated from how long the
+//! previous updates took and from how many of the machines have been updated so far.
+
+use crate::clusterversion::HistoricalEntry;
+use crate::kubeapi;
+use crate::metrics;
+use crate::retry::Backoff;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use kube::api::{ListParams, Object, RawApi, Void};
+use kube::client::APIClient;
+
+const ACCEPTANCE_METRIC: &str = "openshift_update_acceptance_duration_seconds";
+const ACCEPTANCE_HELP: &str = "How long requested updates took to be accepted by the cluster.";
+
+const UPDATE_METRIC: &str = "openshift_update_duration_seconds";
+const UPDATE_HELP: &str = "How long accepted updates took to complete.";
+
+const REMAINING_METRIC: &str = "openshift_update_estimated_remaining_seconds";
+const REMAINING_HELP: &str = "How much longer the update in progress is estimated to take.";
+
+const POOL_METRIC: &str = "openshift_update_pool_duration_seconds";
+const POOL_HELP: &str = "How long each MachineConfigPool took to roll out accepted updates.";
+
+/// Bounds of the buckets of the acceptance durations, between ten seconds and an hour.
+const ACCEPTANCE_BUCKETS: &[f64] = &[10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+
+/// Bounds of the buckets of the update and pool durations, between ten minutes and a day.
+const UPDATE_BUCKETS: &[f64] = &[
+    600.0, 1200.0, 1800.0, 2700.0, 3600.0, 5400.0, 7200.0, 10800.0, 14400.0, 21600.0, 43200.0,
+    86400.0,
+];
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct PoolStatus {
+    #[serde(default)]
+    pub conditions: Vec<PoolCondition>,
+    #[serde(rename = "machineCount", default)]
+    pub machine_count: u32,
+    #[serde(rename = "updatedMachineCount", default)]
+    pub updated_machine_count: u32,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct PoolCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    #[serde(rename = "lastTransitionTime")]
+    pub last_transition_time: Option<DateTime<Utc>>,
+}
+
+pub type MachineConfigPool = Object<Void, PoolStatus>;
+
+#[derive(serde::Deserialize)]
+struct PoolList {
+    items: Vec<MachineConfigPool>,
+}
+
+/// Access to the cluster's MachineConfigPools, which allows the timings to be exercised against
+/// fixtures.
+pub trait PoolClient {
+    fn pools(&self) -> Result<Vec<MachineConfigPool>, Error>;
+}
+
+pub struct KubePools {
+    pub client: APIClient,
+    pub backoff: Backoff,
+}
+
+impl PoolClient for KubePools {
+    fn pools(&self) -> Result<Vec<MachineConfigPool>, Error> {
+        self.backoff.retry("list MachineConfigPools", || {
+            let request = RawApi::customResource("machineconfigpools")
+                .group("machineconfiguration.openshift.io")
+                .version("v1")
+                .list(&ListParams::default())?;
+            let list = kubeapi::call("list", "machineconfigpools", || {
+                self.client.request::<PoolList>(request)
+            })?;
+            Ok(list.items)
+        })
+    }
+}
+
+/// How long the phases of an update took, in seconds, as far as they are known.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Durations {
+    /// From being requested to being accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acceptance: Option<i64>,
+    /// From being accepted to completing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update: Option<i64>,
+    /// From being accepted to each pool becoming Updated.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pools: Vec<PoolDuration>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct PoolDuration {
+    pub pool: String,
+    pub seconds: i64,
+}
+
+/// Time the update recorded by `entry`, which was requested at `requested`.
+pub fn measure(
+    requested: Option<DateTime<Utc>>,
+    entry: &HistoricalEntry,
+    pools: &[MachineConfigPool],
+) -> Durations {
+    let started = entry.started_time;
+    let between = |from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>| {
+        let seconds = (to? - from?).num_seconds();
+        // The clocks of the operator and the cluster may disagree slightly.
+        Some(seconds.max(0))
+    };
+    Durations {
+        acceptance: between(requested, started),
+        update: between(started, entry.completion_time),
+        pools: pools
+            .iter()
+            .filter_map(|pool| {
+                let updated = pool
+                    .status
+                    .as_ref()?
+                    .conditions
+                    .iter()
+                    .find(|condition| condition.type_ == "Updated" && condition.status == "True")?
+                    .last_transition_time;
+                // A pool which became Updated before the update started wasn't rolled by it.
+                match (started, updated) {
+                    (Some(started), Some(updated)) if updated < started => None,
+                    _ => Some(PoolDuration {
+                        pool: pool.metadata.name.clone(),
+                        seconds: between(started, updated)?,
+                    }),
+                }
+            })
+            .collect(),
+    }
+}
+
+/// How much longer an update in progress is expected to take.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Estimate {
+    /// In seconds.
+    pub remaining: i64,
+    #[serde(rename = "completesAt")]
+    pub completes_at: DateTime<Utc>,
+}
+
+/// How long the completed updates in the history took, in seconds. The oldest entry is the
+/// installation, which says nothing about how long updates take.
+pub fn past(history: &[HistoricalEntry]) -> Vec<i64> {
+    let updates = &history[..history.len().saturating_sub(1)];
+    updates
+        .iter()
+        .filter(|entry| entry.state.as_deref() == Some("Completed"))
+        .filter_map(|entry| Some((entry.completion_time? - entry.started_time?).num_seconds()))
+        .collect()
+}
+
+/// How many of the pools' machines have been updated, and how many there are.
+pub fn machines(pools: &[MachineConfigPool]) -> (u32, u32) {
+    pools
+        .iter()
+        .filter_map(|pool| pool.status.as_ref())
+        .fold((0, 0), |(updated, total), status| {
+            (
+                updated + status.updated_machine_count,
+                total + status.machine_count,
+            )
+        })
+}
+
+/// Estimate how much longer the update which started at `started` will take, from how long the
+/// `past` updates took (the median of them) and from how many of the `machines` are updated
+/// already. The machines are only counted while some, but not all, of them are: until they start
+/// rolling, they're all still updated to the previous version. The update can't complete before
+/// either estimate says so, so the later one is taken.
+pub fn estimate(
+    past: &[i64],
+    started: DateTime<Utc>,
+    (updated, total): (u32, u32),
+    now: DateTime<Utc>,
+) -> Option<Estimate> {
+    let elapsed = (now - started).num_seconds().max(0);
+    let mut past = past.to_vec();
+    past.sort();
+    let by_history = past
+        .get(past.len() / 2)
+        .map(|typical| (typical - elapsed).max(0));
+    // Assuming that the rest of the machines take as long as the ones already done.
+    let by_machines = if updated > 0 && updated < total {
+        Some(elapsed * i64::from(total - updated) / i64::from(updated))
+    } else {
+        None
+    };
+    let remaining = by_history.into_iter().chain(by_machines).max()?;
+    Some(Estimate {
+        remaining,
+        completes_at: now + chrono::Duration::seconds(remaining),
+    })
+}
+
+/// Report the estimate of the update in progress, or clear it once there is none.
+pub fn record_estimate(estimate: Option<&Estimate>) {
+    metrics::clear(REMAINING_METRIC);
+    if let Some(estimate) = estimate {
+        metrics::set(
+            REMAINING_METRIC,
+            REMAINING_HELP,
+            &[],
+            estimate.remaining as f64,
+        );
+    }
+}
+
+/// Add the durations to the histograms.
+pub fn record(durations: &Durations) {
+    if let Some(seconds) = durations.acceptance {
+        metrics::observe(
+            ACCEPTANCE_METRIC,
+            ACCEPTANCE_HELP,
+            ACCEPTANCE_BUCKETS,
+            &[],
+            seconds as f64,
+        );
+    }
+    if let Some(seconds) = durations.update {
+        metrics::observe(
+            UPDATE_METRIC,
+            UPDATE_HELP,
+            UPDATE_BUCKETS,
+            &[],
+            seconds as f64,
+        );
+    }
+    for pool in &durations.pools {
+        metrics::observe(
+            POOL_METRIC,
+            POOL_HELP,
+            UPDATE_BUCKETS,
+            &[("pool", &pool.pool)],
+            pool.seconds as f64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_each_phase() {
+        let time = |time: &str| time.parse::<DateTime<Utc>>().expect("valid time");
+        let entry = HistoricalEntry {
+            started_time: Some(time("2019-09-16T18:32:19Z")),
+            completion_time: Some(time("2019-09-16T19:32:19Z")),
+            image: None,
+            state: Some("Completed".to_string()),
+            version: Some("4.1.15".to_string()),
+        };
+        let pool = |name: &str, updated: &str| {
+            serde_json::json!({
+                "metadata": { "name": name },
+                "spec": {},
+                "status": {
+                    "conditions": [
+                        { "type": "Updating", "status": "False", "lastTransitionTime": updated },
+                        { "type": "Updated", "status": "True", "lastTransitionTime": updated },
+                    ],
+                },
+            })
+        };
+        let pools: Vec<MachineConfigPool> = serde_json::from_value(serde_json::json!([
+            pool("master", "2019-09-16T19:02:19Z"),
+            pool("worker", "2019-09-16T19:30:19Z"),
+            pool("infra", "2019-09-01T00:00:00Z"),
+        ]))
+        .expect("valid pools");
+
+        assert_eq!(
+            measure(Some(time("2019-09-16T18:31:49Z")), &entry, &pools),
+            Durations {
+                acceptance: Some(30),
+                update: Some(3600),
+                pools: vec![
+                    PoolDuration {
+                        pool: "master".to_string(),
+                        seconds: 1800,
+                    },
+                    PoolDuration {
+                        pool: "worker".to_string(),
+                        seconds: 3480,
+                    },
+                ],
+            }
+        );
+        assert_eq!(measure(None, &entry, &[]).acceptance, None);
+    }
+
+    #[test]
+    fn estimates_the_remaining_time() {
+        let time = |time: &str| time.parse::<DateTime<Utc>>().expect("valid time");
+        let started = time("2019-09-17T00:00:00Z");
+        let now = time("2019-09-17T00:30:00Z");
+        assert_eq!(estimate(&[], started, (3, 3), now), None);
+
+        let history = estimate(&[7200, 3600, 4800], started, (3, 3), now).expect("estimate");
+        assert_eq!(history.remaining, 3000);
+        assert_eq!(history.completes_at, time("2019-09-17T01:20:00Z"));
+
+        // A third of the machines took half an hour, so the rest would take another hour.
+        let machines = estimate(&[3600], started, (2, 6), now).expect("estimate");
+        assert_eq!(machines.remaining, 3600);
+        // An update which is overdue is estimated to complete any moment.
+        let later = time("2019-09-17T02:00:00Z");
+        assert_eq!(
+            estimate(&[3600], started, (0, 6), later).map(|estimate| estimate.remaining),
+            Some(0)
+        );
+
+        let entry = |state: &str, started: &str, completed: &str| HistoricalEntry {
+            started_time: Some(time(started)),
+            completion_time: Some(time(completed)),
+            image: None,
+            state: Some(state.to_string()),
+            version: None,
+        };
+        let history = vec![
+            entry("Completed", "2019-09-16T00:00:00Z", "2019-09-16T01:00:00Z"),
+            entry("Partial", "2019-09-15T00:00:00Z", "2019-09-15T03:00:00Z"),
+            entry("Completed", "2019-09-14T00:00:00Z", "2019-09-14T00:40:00Z"),
+        ];
+        assert_eq!(past(&history), vec![3600]);
+    }
+}
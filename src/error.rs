@@ -0,0 +1,65 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A request to the Kubernetes API failed or was refused.
+    Api(kube::Error),
+    /// A request to another HTTP service (e.g. OpenShift Cluster Manager) failed or was refused.
+    Http(reqwest::Error),
+    /// An object could not be serialized or deserialized.
+    Serialization(serde_json::Error),
+    /// A policy or gate could not be evaluated.
+    Policy(String),
+    /// The operator's configuration is invalid or incomplete.
+    Config(String),
+    /// A release image could not be read.
+    Release(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Api(err) => write!(f, "Kubernetes API request failed: {}", err),
+            Error::Http(err) => write!(f, "HTTP request failed: {}", err),
+            Error::Serialization(err) => write!(f, "failed to (de)serialize object: {}", err),
+            Error::Policy(message) => write!(f, "policy evaluation failed: {}", message),
+            Error::Config(message) => write!(f, "invalid configuration: {}", message),
+            Error::Release(message) => write!(f, "failed to read release: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<kube::Error> for Error {
+    fn from(err: kube::Error) -> Self {
+        Error::Api(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serialization(err)
+    }
+}
@@ -0,0 +1,735 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An explanation of what the operator would do with the cluster right now, and why.
+//!
+//! The explanation is made from the same inputs as a real decision (the ClusterVersion, the update
+//! graph and, with `--state-namespace`, the persisted state), but nothing is changed.
+
+use crate::options::Options;
+use crate::output::Output;
+use crate::reconciler::{budget_checks, channel_graph, health_checks, upgrade_policy};
+use chrono::{DateTime, Utc};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{
+    self, ClusterUpdate, ClusterVersion, ClusterVersionClient, KubeClient,
+};
+use openshift_update::credentials::{Credentials, CredentialsClient, KubeCredentials};
+use openshift_update::gates::{self, PAUSED_ANNOTATION};
+use openshift_update::graph::GraphClient;
+use openshift_update::health::{self, Health};
+use openshift_update::http::HttpClient;
+use openshift_update::policy::{self, ClusterState, Decision, DecisionReason, UpgradePolicy};
+use openshift_update::state::{ConfigMapStore, State, StateStore};
+use openshift_update::steps::{self, AckClient, KubeAcks};
+use openshift_update::storage::{KubeStorage, StorageClient};
+use openshift_update::Error;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Explain {
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the explanation: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Explanation {
+    version: Option<String>,
+    channel: Option<String>,
+    candidates: Vec<Candidate>,
+    pub checks: Vec<Check>,
+    pub decision: Decision,
+    pub reason: DecisionReason,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Candidate {
+    version: semver::Version,
+    image: String,
+    /// Whether this is the update the checks are made against.
+    selected: bool,
+    /// Why the update will never be chosen, if it won't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    excluded: Option<String>,
+}
+
+/// The verdict of one of the checks which can hold back an update.
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct Check {
+    pub gate: &'static str,
+    pub passed: bool,
+    pub reason: String,
+}
+
+/// What is read from the cluster besides the ClusterVersion, for the policy to consider.
+#[derive(Debug, Default)]
+pub(crate) struct Observed {
+    pub competing_managers: Vec<String>,
+    pub health: Vec<Health>,
+    pub storage_provisioners: Vec<String>,
+    pub error_budget: Vec<Health>,
+    pub credentials: Option<Credentials>,
+    pub admin_acks: HashSet<String>,
+}
+
+pub fn run(client: APIClient, options: &Options, command: &Explain) -> Result<(), Error> {
+    let (graph, saved, version, observed) = load(client, options)?;
+    let explanation = explain(options, &graph, &saved, &version, &observed, Utc::now())?;
+    command.output.print(&explanation, render);
+    Ok(())
+}
+
+/// Read the update graph client, the persisted state, the ClusterVersion and the rest of the
+/// cluster, which are what decisions are made from.
+pub(crate) fn load(
+    client: APIClient,
+    options: &Options,
+) -> Result<(HttpClient, State, ClusterVersion, Observed), Error> {
+    let graph = HttpClient {
+        http: options.http(&client)?,
+        backoff: options.backoff(),
+    };
+    let saved = match &options.state_namespace {
+        Some(namespace) => {
+            ConfigMapStore::new(client.clone(), namespace, options.backoff()).load()?
+        }
+        None => State::default(),
+    };
+    let versions = KubeClient::new(
+        client.clone(),
+        &options.cluster_version_name,
+        options.backoff(),
+    )?;
+    Ok((
+        graph,
+        saved,
+        versions.get()?,
+        observe(options, &client, &versions)?,
+    ))
+}
+
+/// Read the field managers other than the operator's which set the ClusterVersion's desired
+/// update, and run the health checks.
+pub(crate) fn observe(
+    options: &Options,
+    client: &APIClient,
+    versions: &dyn ClusterVersionClient,
+) -> Result<Observed, Error> {
+    Ok(Observed {
+        competing_managers: gates::competing_managers(
+            &versions.managed_fields()?,
+            &options.field_manager,
+        ),
+        health: health::run(&health_checks(
+            options,
+            client,
+            options.check_capacity && clusterversion::single_node(client),
+        )),
+        error_budget: match &options.slo_file {
+            Some(_) => health::run(&budget_checks(options, &options.http(client)?)?),
+            None => Vec::new(),
+        },
+        storage_provisioners: match &options.storage_matrix {
+            Some(_) => KubeStorage {
+                client: client.clone(),
+                backoff: options.backoff(),
+            }
+            .provisioners()?,
+            None => Vec::new(),
+        },
+        credentials: KubeCredentials {
+            client: client.clone(),
+            backoff: options.backoff(),
+        }
+        .credentials()?,
+        admin_acks: if options.manual_steps.is_empty() {
+            HashSet::new()
+        } else {
+            KubeAcks {
+                client: client.clone(),
+                backoff: options.backoff(),
+            }
+            .admin_acks()?
+        },
+    })
+}
+
+/// The state the reconcile loop would give the policy, with the offered updates first seen at
+/// `now` unless the persisted state says otherwise.
+pub(crate) fn cluster_state(
+    options: &Options,
+    version: &ClusterVersion,
+    saved: &State,
+    observed: &Observed,
+    offered: &[ClusterUpdate],
+    now: DateTime<Utc>,
+) -> ClusterState {
+    let mut state = ClusterState::new(version, now);
+    state.snoozed = crate::reconciler::current_snooze(options, version);
+    state.competing_managers = observed.competing_managers.clone();
+    state.health = observed.health.clone();
+    state.storage_provisioners = observed.storage_provisioners.clone();
+    state.error_budget = observed.error_budget.clone();
+    state.credentials = observed.credentials.clone();
+    state.admin_acks = observed.admin_acks.clone();
+    state.first_seen = offered
+        .iter()
+        .map(|update| {
+            let seen = saved
+                .first_seen
+                .get(&update.version)
+                .cloned()
+                .unwrap_or(now);
+            (update.version.clone(), seen)
+        })
+        .collect();
+    state.poisoned = saved.poisoned.keys().cloned().collect();
+    state.regression = saved.regression.clone();
+    state.approved = saved.approved.clone();
+    state
+}
+
+pub(crate) fn explain(
+    options: &Options,
+    graph: &dyn GraphClient,
+    saved: &State,
+    version: &ClusterVersion,
+    observed: &Observed,
+    now: DateTime<Utc>,
+) -> Result<Explanation, Error> {
+    let mut available = version
+        .status
+        .as_ref()
+        .and_then(|status| status.available_updates.clone())
+        .unwrap_or_default();
+    available.sort_by(|a, b| b.cmp(a));
+
+    let channel_graph = if available.is_empty() {
+        None
+    } else {
+        channel_graph(graph, version)?
+    };
+    let excluded = |update: &ClusterUpdate| {
+        if let Some(reason) = saved.poisoned.get(&update.version) {
+            return Some(format!("failed before ({})", reason));
+        }
+        if !options.allow_prerelease && policy::prerelease(&update.version) {
+            return Some("a pre-release (see --allow-prerelease)".to_string());
+        }
+        match &channel_graph {
+            Some((channel, graph)) if !graph.contains(&update.version) => {
+                Some(format!("not part of the {} channel", channel))
+            }
+            _ => None,
+        }
+    };
+    let offered: Vec<ClusterUpdate> = available
+        .iter()
+        .filter(|update| {
+            channel_graph
+                .as_ref()
+                .is_none_or(|(_, graph)| graph.contains(&update.version))
+        })
+        .filter(|update| options.allow_prerelease || !policy::prerelease(&update.version))
+        .cloned()
+        .collect();
+
+    let mut state = cluster_state(options, version, saved, observed, &offered, now);
+    if let Some((_, graph)) = &channel_graph {
+        state.security = graph.security();
+    }
+
+    // The checks are made against whatever the operator would pick if nothing held it back.
+    let selected = policy::Latest.evaluate(&state, &offered).update().cloned();
+    let decision = upgrade_policy(options).evaluate(&state, &offered);
+
+    Ok(Explanation {
+        version: state.version.clone(),
+        channel: version.spec.channel.clone(),
+        candidates: available
+            .iter()
+            .map(|update| Candidate {
+                version: update.version.clone(),
+                image: update.image.clone(),
+                selected: selected.as_ref() == Some(update),
+                excluded: excluded(update),
+            })
+            .collect(),
+        checks: checks(options, &state, selected.as_ref()),
+        reason: decision.reason(),
+        decision,
+    })
+}
+
+/// Every check of the update the policy would select, including those only its policies make.
+pub(crate) fn checks(
+    options: &Options,
+    state: &ClusterState,
+    selected: Option<&ClusterUpdate>,
+) -> Vec<Check> {
+    let mut checks = gate_checks(options, state);
+    checks.extend(storage_check(options, state, selected));
+    checks.extend(credentials_check(state, selected));
+    checks.extend(steps_check(options, state, selected));
+
+    checks.push(window_check(options, state));
+    checks.push(match (options.max_jitter, &state.cluster_id, selected) {
+        (None, _, _) => check("jitter", true, "disabled".to_string()),
+        (Some(_), None, _) => check("jitter", true, "the cluster has no ID".to_string()),
+        (Some(_), Some(_), None) => check("jitter", true, "no candidate".to_string()),
+        (Some(max), Some(cluster_id), Some(update)) => {
+            let seen = state
+                .first_seen
+                .get(&update.version)
+                .cloned()
+                .unwrap_or(state.now);
+            let not_before = policy::not_before(
+                Some(max),
+                cluster_id,
+                seen,
+                options.maintenance_window.as_ref(),
+            );
+            if state.now < not_before {
+                check(
+                    "jitter",
+                    false,
+                    format!("first seen {}, not before {}", seen, not_before),
+                )
+            } else {
+                check(
+                    "jitter",
+                    true,
+                    format!("first seen {}, allowed since {}", seen, not_before),
+                )
+            }
+        }
+    });
+    checks.push(if options.require_approval {
+        match (&state.approved, selected) {
+            (Some(approved), Some(update)) if approved == &update.version => {
+                check("approval", true, format!("{} was approved", approved))
+            }
+            _ if options.state_namespace.is_some() => {
+                check("approval", false, "required, and not approved".to_string())
+            }
+            _ => check(
+                "approval",
+                false,
+                "required, and only known to the running operator without --state-namespace"
+                    .to_string(),
+            ),
+        }
+    } else {
+        check("approval", true, "not required".to_string())
+    });
+    checks
+}
+
+/// The check of the maintenance window, which holds back updates while it's closed.
+pub(crate) fn window_check(options: &Options, state: &ClusterState) -> Check {
+    match &options.maintenance_window {
+        None => check("window", true, "no maintenance window".to_string()),
+        Some(window) if window.contains(state.now) => {
+            check("window", true, format!("within the window ({})", window))
+        }
+        Some(window) => check(
+            "window",
+            false,
+            format!(
+                "outside of the window ({}), which next opens at {}",
+                window,
+                window.next(state.now, Duration::from_secs(0))
+            ),
+        ),
+    }
+}
+
+/// The checks of the cluster itself, which hold back any update regardless of which one was
+/// selected or when.
+pub(crate) fn gate_checks(options: &Options, state: &ClusterState) -> Vec<Check> {
+    let mut checks = vec![
+        if state.update_in_progress {
+            check(
+                "update-in-progress",
+                false,
+                "the cluster is still updating".to_string(),
+            )
+        } else {
+            check(
+                "update-in-progress",
+                true,
+                "no update is in progress".to_string(),
+            )
+        },
+        if state.paused {
+            check(
+                "paused",
+                false,
+                format!("paused with the {} annotation", PAUSED_ANNOTATION),
+            )
+        } else {
+            check("paused", true, "not paused".to_string())
+        },
+        match &state.snoozed {
+            Some(snooze) if snooze.until > state.now => {
+                check("snooze", false, policy::snooze_reason(snooze))
+            }
+            _ => check("snooze", true, "not snoozed".to_string()),
+        },
+        match (state.unmanaged.is_empty(), options.allow_unmanaged) {
+            (true, _) => check("overrides", true, "every component is managed".to_string()),
+            (false, true) => check(
+                "overrides",
+                true,
+                format!(
+                    "allowed unmanaged components: {}",
+                    state.unmanaged.join(", ")
+                ),
+            ),
+            (false, false) => check(
+                "overrides",
+                false,
+                format!("unmanaged components: {}", state.unmanaged.join(", ")),
+            ),
+        },
+        if state.competing_managers.is_empty() {
+            check(
+                "field-manager",
+                true,
+                "nothing else sets the desired update".to_string(),
+            )
+        } else {
+            check(
+                "field-manager",
+                false,
+                format!(
+                    "spec.desiredUpdate is also set by {}",
+                    state.competing_managers.join(", ")
+                ),
+            )
+        },
+        match &state.owner {
+            Some(owner) if owner != &options.field_manager => {
+                check("owner", false, format!("updates are owned by {}", owner))
+            }
+            _ => check("owner", true, "nobody else owns updates".to_string()),
+        },
+        match &state.regression {
+            Some(regression) => check(
+                "regression",
+                false,
+                format!("the cluster's version {}", regression),
+            ),
+            None => check(
+                "regression",
+                true,
+                "the version only moved forwards".to_string(),
+            ),
+        },
+    ];
+    checks.extend(
+        state
+            .health
+            .iter()
+            .map(|health| check(health.gate, health.passed, health.reason.clone())),
+    );
+    // Security fixes are let through regardless, which only the reason can say.
+    checks.extend(state.error_budget.iter().map(|health| {
+        let reason = if health.passed {
+            health.reason.clone()
+        } else {
+            format!("{} (security fixes are still applied)", health.reason)
+        };
+        check(health.gate, health.passed, reason)
+    }));
+    checks
+}
+
+/// The check of the cluster's manually managed cloud credentials against the update, if they are
+/// managed manually.
+pub(crate) fn credentials_check(
+    state: &ClusterState,
+    update: Option<&ClusterUpdate>,
+) -> Option<Check> {
+    let credentials = state
+        .credentials
+        .as_ref()
+        .filter(|credentials| credentials.manual())?;
+    Some(
+        match update
+            .and_then(|update| credentials.blocks(state.version.as_deref(), &update.version))
+        {
+            Some(reason) => check("credentials", false, reason),
+            None => check(
+                "credentials",
+                true,
+                match (update, &credentials.upgradeable_to) {
+                    (None, _) => "no candidate".to_string(),
+                    (Some(_), Some(version)) => {
+                        format!("the credentials have been updated for {}", version)
+                    }
+                    (Some(update), None) => format!(
+                        "{} needs no new credentials, being within the minor version",
+                        update.version
+                    ),
+                },
+            ),
+        },
+    )
+}
+
+/// The check of the manual steps preceding the update, if any are configured.
+pub(crate) fn steps_check(
+    options: &Options,
+    state: &ClusterState,
+    update: Option<&ClusterUpdate>,
+) -> Option<Check> {
+    if options.manual_steps.is_empty() {
+        return None;
+    }
+    let update = match update {
+        Some(update) => update,
+        None => return Some(check("manual-steps", true, "no candidate".to_string())),
+    };
+    Some(
+        match steps::pending(&options.manual_steps, state, &update.version) {
+            Some(reason) => check("manual-steps", false, reason),
+            None => check(
+                "manual-steps",
+                true,
+                format!("no manual steps are pending before {}", update.version),
+            ),
+        },
+    )
+}
+
+/// The check of the cluster's storage provisioners against the update, if there's a matrix of
+/// them.
+pub(crate) fn storage_check(
+    options: &Options,
+    state: &ClusterState,
+    update: Option<&ClusterUpdate>,
+) -> Option<Check> {
+    let matrix = options.storage_matrix.as_ref()?;
+    let update = match update {
+        Some(update) => update,
+        None => return Some(check("storage", true, "no candidate".to_string())),
+    };
+    let verdict = matrix.verdict(&state.storage_provisioners, &update.version);
+    Some(if !verdict.removed.is_empty() {
+        check(
+            "storage",
+            false,
+            format!(
+                "{} no longer supports {}",
+                update.version,
+                verdict.removed.join(", ")
+            ),
+        )
+    } else if !verdict.deprecated.is_empty() {
+        check(
+            "storage",
+            true,
+            format!(
+                "{} deprecates {}",
+                update.version,
+                verdict.deprecated.join(", ")
+            ),
+        )
+    } else {
+        check(
+            "storage",
+            true,
+            format!("{} supports every provisioner in use", update.version),
+        )
+    })
+}
+
+fn check(gate: &'static str, passed: bool, reason: String) -> Check {
+    Check {
+        gate,
+        passed,
+        reason,
+    }
+}
+
+/// Write a line per check.
+pub(crate) fn render_checks(text: &mut String, checks: &[Check]) {
+    for check in checks {
+        writeln!(
+            text,
+            "  {:<20} {:<5} {}",
+            check.gate,
+            if check.passed { "pass" } else { "FAIL" },
+            check.reason
+        )
+        .expect("write to string");
+    }
+}
+
+pub(crate) fn render(explanation: &Explanation) -> String {
+    let mut text = String::new();
+
+    match (&explanation.version, &explanation.channel) {
+        (Some(version), Some(channel)) => {
+            writeln!(
+                text,
+                "Cluster version {} in the {} channel",
+                version, channel
+            )
+        }
+        (Some(version), None) => writeln!(text, "Cluster version {} without a channel", version),
+        (None, _) => writeln!(text, "Cluster version unknown"),
+    }
+    .expect("write to string");
+
+    writeln!(text, "\nCandidates").expect("write to string");
+    if explanation.candidates.is_empty() {
+        writeln!(text, "  none available").expect("write to string");
+    }
+    for candidate in &explanation.candidates {
+        let note = match (&candidate.excluded, candidate.selected) {
+            (Some(reason), _) => format!("excluded: {}", reason),
+            (None, true) => "selected".to_string(),
+            (None, false) => "superseded by a newer update".to_string(),
+        };
+        writeln!(text, "  {:<24} {}", candidate.version, note).expect("write to string");
+    }
+
+    writeln!(text, "\nChecks").expect("write to string");
+    render_checks(&mut text, &explanation.checks);
+
+    writeln!(
+        text,
+        "\n{} ({})",
+        summary(&explanation.decision),
+        explanation.reason
+    )
+    .expect("write to string");
+    text
+}
+
+/// What the decision means for the cluster, for people.
+pub(crate) fn summary(decision: &Decision) -> String {
+    match decision {
+        Decision::UpToDate => "No update: the cluster is up to date".to_string(),
+        Decision::InProgress => "No update: the cluster is still updating".to_string(),
+        Decision::Delayed {
+            update, not_before, ..
+        } => {
+            format!("Would update to {} at {}", update.version, not_before)
+        }
+        Decision::Paused { update } => {
+            format!("Would update to {}, but updates are paused", update.version)
+        }
+        Decision::AwaitingApproval { update } => {
+            format!("Would update to {} once it is approved", update.version)
+        }
+        Decision::Blocked { update, gate, .. } => format!(
+            "Would update to {}, but the {} check is holding it back",
+            update.version, gate
+        ),
+        Decision::Apply { update } => format!("Would update to {} now", update.version),
+    }
+}
+
+#[cfg(all(test, feature = "cincinnati"))]
+mod tests {
+    use super::*;
+    use openshift_update::graph::Graph;
+
+    struct Fixture(Graph);
+
+    impl GraphClient for Fixture {
+        fn fetch(&self, _upstream: &str, _channel: &str, _version: &str) -> Result<Graph, Error> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn explains_held_back_updates() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let mut graph: Graph = serde_json::from_str(include_str!("../tests/fixtures/graph.json"))
+            .expect("valid fixture");
+        graph
+            .nodes
+            .retain(|node| node.version.to_string() != "4.1.16");
+        let options = Options::from_iter(&["openshift-update", "--max-jitter", "1d"]);
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+
+        let explanation = explain(
+            &options,
+            &Fixture(graph),
+            &State::default(),
+            &version,
+            &Observed::default(),
+            now,
+        )
+        .expect("explain");
+        let candidates: Vec<(String, bool, Option<String>)> = explanation
+            .candidates
+            .iter()
+            .map(|c| (c.version.to_string(), c.selected, c.excluded.clone()))
+            .collect();
+        assert_eq!(
+            candidates,
+            vec![
+                (
+                    "4.1.16".to_string(),
+                    false,
+                    Some("not part of the stable-4.1 channel".to_string())
+                ),
+                ("4.1.15".to_string(), true, None),
+            ]
+        );
+        let failed: Vec<&str> = explanation
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.gate)
+            .collect();
+        assert_eq!(failed, vec!["jitter"]);
+        assert!(matches!(explanation.decision, Decision::Delayed { .. }));
+        assert_eq!(explanation.reason, DecisionReason::SoakPending);
+        assert!(render(&explanation).contains("Would update to 4.1.15 at "));
+        assert!(render(&explanation).contains("(SoakPending)"));
+    }
+
+    #[test]
+    fn explains_the_window() {
+        let options =
+            Options::from_iter(&["openshift-update", "--maintenance-window", "22:00-04:00"]);
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let mut state = ClusterState::new(&version, "2019-09-17T12:00:00Z".parse().unwrap());
+        let closed = window_check(&options, &state);
+        assert!(!closed.passed);
+        assert_eq!(
+            closed.reason,
+            "outside of the window (22:00-04:00), which next opens at 2019-09-17 22:00:00 UTC"
+        );
+
+        state.now = "2019-09-18T01:00:00Z".parse().unwrap();
+        assert!(window_check(&options, &state).passed);
+    }
+}
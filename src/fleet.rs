@@ -0,0 +1,208 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The decisions of the fleet modes (--acm, --hosted-clusters and --ocm).
+//!
+//! Each of a fleet's clusters is decided on by the same policy as the local cluster, evaluated
+//! against what the mode can see of it: the annotations of the object standing for it (pausing,
+//! snoozing and approving its updates, or naming who owns them) and whatever of its
+//! ClusterVersion's status is mirrored there. What the local cluster keeps in its state ConfigMap
+//! (when each candidate was first seen, and which updates failed) is kept in memory for each
+//! cluster.
+
+use crate::options::Options;
+use crate::reconciler;
+use crate::status::FleetCluster;
+use chrono::{DateTime, Utc};
+use kube::api::ObjectMeta;
+use openshift_update::clusterversion::{
+    ClusterUpdate, ClusterVersion, ClusterVersionSpec, ClusterVersionStatus, Outcome,
+};
+use openshift_update::gates;
+use openshift_update::policy::{self, ClusterState, Decision, UpgradePolicy};
+use openshift_update::state::State;
+
+/// The state of one of the fleet's clusters, read from the annotations of the object standing
+/// for it and, if the mode can see it, the status of its ClusterVersion.
+pub fn cluster_state(
+    metadata: &ObjectMeta,
+    cluster_id: &str,
+    status: Option<ClusterVersionStatus>,
+    now: DateTime<Utc>,
+) -> ClusterState {
+    let version = ClusterVersion {
+        types: Default::default(),
+        metadata: metadata.clone(),
+        spec: ClusterVersionSpec {
+            cluster_id: Some(cluster_id.to_string()),
+            ..Default::default()
+        },
+        status,
+    };
+    let mut state = ClusterState::new(&version, now);
+    state.approved = gates::approved(metadata);
+    state
+}
+
+/// The policy each of the fleet's clusters is decided on by.
+pub struct Fleet<'a> {
+    pub options: &'a Options,
+    policy: Box<dyn UpgradePolicy>,
+}
+
+impl<'a> Fleet<'a> {
+    pub fn new(options: &'a Options) -> Fleet<'a> {
+        Fleet {
+            options,
+            policy: reconciler::upgrade_policy(options),
+        }
+    }
+
+    /// Decide on the cluster's update, leaving out the pre-releases just as for the local
+    /// cluster.
+    pub fn decide(
+        &self,
+        mut state: ClusterState,
+        candidates: Vec<ClusterUpdate>,
+        saved: &mut State,
+    ) -> Decision {
+        let candidates: Vec<ClusterUpdate> = candidates
+            .into_iter()
+            .filter(|update| self.options.allow_prerelease || !policy::prerelease(&update.version))
+            .collect();
+        for update in &candidates {
+            saved
+                .first_seen
+                .entry(update.version.clone())
+                .or_insert(state.now);
+        }
+        saved
+            .first_seen
+            .retain(|version, _| candidates.iter().any(|update| &update.version == version));
+        state.first_seen = saved
+            .first_seen
+            .iter()
+            .map(|(version, seen)| (version.clone(), *seen))
+            .collect();
+        state.poisoned = saved.poisoned.keys().cloned().collect();
+        self.policy.evaluate(&state, &candidates)
+    }
+
+    /// Follow the update last requested of the cluster named `name`, forgetting it once it has
+    /// completed and poisoning it if the cluster moved on without completing it.
+    pub fn track(&self, saved: &mut State, status: &ClusterVersionStatus, name: &str) {
+        let attempted = match &saved.attempted {
+            Some(attempted) => attempted.clone(),
+            None => return,
+        };
+        match status.outcome(&attempted) {
+            Outcome::NotStarted | Outcome::InProgress => {}
+            Outcome::Completed => {
+                saved.attempted = None;
+                saved.attempted_at = None;
+                saved.applied = Some(attempted);
+            }
+            Outcome::Abandoned { superseded_by } => reconciler::poison(
+                self.options,
+                saved,
+                &attempted,
+                format!("abandoned for {} by {}", superseded_by, name),
+            ),
+        }
+    }
+}
+
+/// Record the decision in the cluster's row of the fleet's status, returning the update to
+/// request if one should be.
+pub fn record(row: &mut FleetCluster, decision: Decision) -> Option<ClusterUpdate> {
+    row.candidate = decision.update().map(|update| update.version.clone());
+    if let Some((gate, reason)) = reconciler::skip_reason(&decision) {
+        debug!("Not updating {}: {} ({})", row.name, reason, gate);
+        row.hold(&gate, reason);
+    }
+    match decision {
+        Decision::Apply { update } => Some(update),
+        Decision::InProgress => {
+            row.hold("updating", "waiting for the update to complete".to_string());
+            None
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn update(version: &str) -> ClusterUpdate {
+        ClusterUpdate {
+            force: false,
+            image: format!("quay.io/openshift-release-dev/ocp-release:{}", version),
+            version: semver::Version::parse(version).expect("version"),
+            raw_version: None,
+        }
+    }
+
+    #[test]
+    fn decides_through_the_policy() {
+        let options = Options::from_iter(&[
+            "openshift-update",
+            "--hosted-clusters",
+            "--require-approval",
+            "--listen",
+            "127.0.0.1:8080",
+            "--api-token-file",
+            "token",
+        ]);
+        let fleet = Fleet::new(&options);
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let mut metadata = ObjectMeta::default();
+        let mut saved = State::default();
+        saved
+            .poisoned
+            .insert(update("4.1.16").version, "failed".to_string());
+        let candidates = vec![update("4.1.15"), update("4.1.16"), update("4.2.0-rc.1")];
+
+        let decide = |metadata: &ObjectMeta, saved: &mut State| {
+            let state = cluster_state(metadata, "abc", None, now);
+            fleet.decide(state, candidates.clone(), saved)
+        };
+        assert_eq!(
+            decide(&metadata, &mut saved),
+            Decision::AwaitingApproval {
+                update: update("4.1.15")
+            }
+        );
+        assert_eq!(saved.first_seen.len(), 2);
+
+        metadata
+            .annotations
+            .insert(gates::APPROVED_ANNOTATION.to_string(), "4.1.15".to_string());
+        assert_eq!(
+            decide(&metadata, &mut saved),
+            Decision::Apply {
+                update: update("4.1.15")
+            }
+        );
+
+        metadata
+            .annotations
+            .insert(gates::PAUSED_ANNOTATION.to_string(), "true".to_string());
+        let mut row = FleetCluster::new("clusters/abc");
+        assert_eq!(record(&mut row, decide(&metadata, &mut saved)), None);
+        assert_eq!(row.gate.as_deref(), Some("paused"));
+        assert_eq!(row.candidate, Some(update("4.1.15").version));
+    }
+}
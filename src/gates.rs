@@ -0,0 +1,167 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks which hold back an update regardless of which one was selected.
+
+use crate::clusterversion::{ClusterVersionSpec, ClusterVersionStatus, ManagedFieldsEntry};
+use chrono::{DateTime, Utc};
+use kube::api::ObjectMeta;
+
+/// Annotation which, when set to "true", stops the operator from requesting any updates of the
+/// annotated object until it is removed.
+pub const PAUSED_ANNOTATION: &str = "upgrade.crawford.dev/paused";
+
+/// Returns whether updates of the object have been paused through its annotations.
+pub fn paused(metadata: &ObjectMeta) -> bool {
+    metadata
+        .annotations
+        .get(PAUSED_ANNOTATION)
+        .map(String::as_str)
+        == Some("true")
+}
+
+/// Annotation deferring every automatic update of the annotated object until the time it holds
+/// (in RFC 3339), after which they resume by themselves.
+pub const SNOOZED_UNTIL_ANNOTATION: &str = "upgrade.crawford.dev/snoozed-until";
+
+/// Annotation recording why updates were snoozed.
+pub const SNOOZE_REASON_ANNOTATION: &str = "upgrade.crawford.dev/snooze-reason";
+
+/// A deferral of every automatic update until a point in time.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Snooze {
+    pub until: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Returns the snooze set through the object's annotations, if there is a valid one. Whether it
+/// has already ended is up to the caller.
+pub fn snoozed(metadata: &ObjectMeta) -> Option<Snooze> {
+    let until = metadata.annotations.get(SNOOZED_UNTIL_ANNOTATION)?;
+    match until.parse() {
+        Ok(until) => Some(Snooze {
+            until,
+            reason: metadata
+                .annotations
+                .get(SNOOZE_REASON_ANNOTATION)
+                .filter(|reason| !reason.trim().is_empty())
+                .cloned(),
+        }),
+        Err(err) => {
+            warn!(
+                "Ignoring invalid {} {:?}: {}",
+                SNOOZED_UNTIL_ANNOTATION, until, err
+            );
+            None
+        }
+    }
+}
+
+/// Annotation acknowledging that the cluster's version moved in a way the operator didn't cause,
+/// which holds back its updates. It has to hold the version the cluster is at now, and is removed
+/// by the operator once it has resumed.
+pub const REGRESSION_ACKNOWLEDGED_ANNOTATION: &str = "upgrade.crawford.dev/regression-acknowledged";
+
+/// Returns the version at which a regression was acknowledged through the object's annotations.
+pub fn regression_acknowledged(metadata: &ObjectMeta) -> Option<semver::Version> {
+    metadata
+        .annotations
+        .get(REGRESSION_ACKNOWLEDGED_ANNOTATION)
+        .and_then(|version| semver::Version::parse(version.trim()).ok())
+}
+
+/// Annotation naming who currently decides which updates the annotated object gets. The operator
+/// sets it to its field manager whenever it requests an update, and removes it once that update
+/// has completed; anyone else named in it holds back the operator's updates.
+pub const MANAGED_BY_ANNOTATION: &str = "upgrade.crawford.dev/managed-by";
+
+/// Returns who owns the object's updates, if anyone has claimed them.
+pub fn owner(metadata: &ObjectMeta) -> Option<String> {
+    metadata
+        .annotations
+        .get(MANAGED_BY_ANNOTATION)
+        .map(|owner| owner.trim().to_string())
+        .filter(|owner| !owner.is_empty())
+}
+
+/// Annotation approving the update to the version it holds. It's how the updates of a fleet's
+/// clusters are approved with --require-approval, since the API only approves the local cluster's.
+pub const APPROVED_ANNOTATION: &str = "upgrade.crawford.dev/approved";
+
+/// Returns the version whose update was approved through the object's annotations.
+pub fn approved(metadata: &ObjectMeta) -> Option<semver::Version> {
+    metadata
+        .annotations
+        .get(APPROVED_ANNOTATION)
+        .and_then(|version| semver::Version::parse(version.trim()).ok())
+}
+
+/// Returns whether the cluster has yet to finish its latest update.
+pub fn update_in_progress(status: &ClusterVersionStatus) -> bool {
+    status
+        .history
+        .first()
+        .is_some_and(|latest| latest.completion_time.is_none())
+}
+
+/// Returns the components which the cluster version operator has been told to leave alone.
+/// Updating with any of them is unsupported.
+pub fn unmanaged(spec: &ClusterVersionSpec) -> Vec<String> {
+    spec.overrides
+        .iter()
+        .filter(|component| component.unmanaged)
+        .map(|component| match component.namespace.as_str() {
+            "" => format!("{}/{}", component.kind, component.name),
+            namespace => format!("{}/{}/{}", component.kind, namespace, component.name),
+        })
+        .collect()
+}
+
+/// Returns the field managers other than `manager` which have set the desired update, meaning that
+/// some other automation (e.g. GitOps, a ClusterCurator or another copy of the operator) is also
+/// updating the cluster.
+pub fn competing_managers(entries: &[ManagedFieldsEntry], manager: &str) -> Vec<String> {
+    let mut managers: Vec<String> = entries
+        .iter()
+        .filter(|entry| entry.manager != manager && entry.manages(&["spec", "desiredUpdate"]))
+        .map(|entry| entry.manager.clone())
+        .collect();
+    managers.sort();
+    managers.dedup();
+    managers
+}
+
+/// The updates a fleet may still start, given a limit on how many of its clusters update at once.
+#[derive(Clone, Debug)]
+pub struct Slots(Option<usize>);
+
+impl Slots {
+    /// No more than `max` (if there is a limit) less the clusters which are `updating` already.
+    pub fn new(max: Option<usize>, updating: usize) -> Slots {
+        Slots(max.map(|max| max.saturating_sub(updating)))
+    }
+
+    /// Takes a slot for another update, returning whether there was one free.
+    pub fn take(&mut self) -> bool {
+        match &mut self.0 {
+            None => true,
+            Some(0) => false,
+            Some(free) => {
+                *free -= 1;
+                true
+            }
+        }
+    }
+}
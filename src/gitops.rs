@@ -0,0 +1,176 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The desired version declared in a Git repository, so that versions are promoted through pull
+//! requests.
+//!
+//! The repository is fetched every `--gitops-interval` with the `git` command, and the file at
+//! `--gitops-path` read from the fetched commit:
+//!
+//! ```yaml
+//! version: 4.1.16
+//! channel: stable-4.1
+//! ```
+//!
+//! Both fields are optional. Whenever the file (or the commit it was read from) changes, it is
+//! recorded along with the commit's SHA in the ClusterVersion's `upgrade.crawford.dev/gitops`
+//! annotation. That wakes the operator up, which then moves the cluster to the declared channel
+//! and updates no further than the declared version. A webhook from the Git host to the API's
+//! `POST /trigger` doesn't fetch the repository itself, but can be paired with a short interval.
+
+use crate::options::Options;
+use kube::api::{ObjectMeta, PatchParams};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{self, parse_version};
+use openshift_update::kubeapi;
+use openshift_update::kubeconfig;
+use openshift_update::Error;
+use std::env;
+use std::path::Path;
+use std::process::{self, Command};
+use std::thread;
+
+/// Annotation of the ClusterVersion recording the declared target.
+pub const TARGET_ANNOTATION: &str = "upgrade.crawford.dev/gitops";
+
+/// What the repository declares, along with the commit it was declared in.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Target {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// SHA of the commit the target was read from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+}
+
+impl Target {
+    /// The declared version, if there is one and it can be read.
+    pub fn version(&self) -> Option<semver::Version> {
+        self.version.as_deref().and_then(parse_version)
+    }
+}
+
+/// The target recorded on the ClusterVersion, if there is one.
+pub fn target(metadata: &ObjectMeta) -> Option<Target> {
+    let target = metadata.annotations.get(TARGET_ANNOTATION)?;
+    match serde_json::from_str(target) {
+        Ok(target) => Some(target),
+        Err(error) => {
+            warn!(
+                "Ignoring invalid {} annotation: {}",
+                TARGET_ANNOTATION, error
+            );
+            None
+        }
+    }
+}
+
+/// Fetch the repository every `--gitops-interval` and record its target, forever.
+pub fn schedule(options: &Options, repo: &str) {
+    let dir = env::temp_dir().join(format!("openshift-update-gitops-{}", process::id()));
+    let mut recorded = None;
+    loop {
+        let result = fetch(&dir, repo, options).and_then(|target| {
+            if recorded.as_ref() != Some(&target) {
+                record(options, &target)?;
+                info!(
+                    "Recorded the target declared in {} at {}",
+                    repo,
+                    target.revision.as_deref().unwrap_or("an unknown revision")
+                );
+                recorded = Some(target);
+            }
+            Ok(())
+        });
+        if let Err(error) = result {
+            error!("Failed to read the target from {}: {}", repo, error);
+        }
+        thread::sleep(options.gitops_interval);
+    }
+}
+
+/// Fetch the latest commit of `--gitops-ref` into a bare repository in `dir`, and read the target
+/// from it.
+fn fetch(dir: &Path, repo: &str, options: &Options) -> Result<Target, Error> {
+    if !dir.exists() {
+        git(dir, &["init", "--bare", "--quiet"])?;
+    }
+    git(
+        dir,
+        &[
+            "fetch",
+            "--quiet",
+            "--depth",
+            "1",
+            repo,
+            &options.gitops_ref,
+        ],
+    )?;
+    let revision = git(dir, &["rev-parse", "FETCH_HEAD"])?;
+    let file = git(
+        dir,
+        &["show", &format!("FETCH_HEAD:{}", options.gitops_path)],
+    )?;
+    let mut target: Target = serde_yaml::from_str(&file)
+        .map_err(|error| Error::Config(format!("invalid {}: {}", options.gitops_path, error)))?;
+    if let Some(version) = target
+        .version
+        .as_ref()
+        .filter(|_| target.version().is_none())
+    {
+        return Err(Error::Config(format!(
+            "invalid version {} in {}",
+            version, options.gitops_path
+        )));
+    }
+    target.revision = Some(revision.trim().to_string());
+    Ok(target)
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String, Error> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|error| Error::Config(format!("failed to run git: {}", error)))?;
+    if !output.status.success() {
+        return Err(Error::Config(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Record the target in the ClusterVersion's annotation. The credentials are loaded afresh every
+/// time, since they may have been rotated since the last one.
+fn record(options: &Options, target: &Target) -> Result<(), Error> {
+    let target = serde_json::to_string(target).expect("Serialize to JSON");
+    let patch = serde_json::json!({
+        "metadata": { "annotations": { TARGET_ANNOTATION: target } }
+    });
+    let versions = clusterversion::api(APIClient::new(kubeconfig::load()?));
+    kubeapi::call("patch", "clusterversions", || {
+        versions.patch(
+            &options.cluster_version_name,
+            &PatchParams::default(),
+            serde_json::to_vec(&patch).expect("Serialize to JSON"),
+        )
+    })?;
+    Ok(())
+}
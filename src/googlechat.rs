@@ -0,0 +1,136 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Events posted to a Google Chat space as cards.
+//!
+//! Each event is posted through one of the space's incoming webhooks as a card, headed by what
+//! happened and the cluster it happened to. The events about the same version are replies in one
+//! thread, so that each update's steps can be followed together. The webhook's URL holds its key
+//! and token, so it's read from a file like the other credentials.
+
+use crate::identity::Identity;
+use crate::notify::{self, Event, Notifier};
+use crate::retry::Backoff;
+use crate::secret::SecretFile;
+use crate::Error;
+
+pub struct GoogleChat {
+    pub http: reqwest::Client,
+    /// The file holding the URL of the webhook.
+    pub webhook: SecretFile,
+    pub backoff: Backoff,
+}
+
+impl Notifier for GoogleChat {
+    fn name(&self) -> &str {
+        "Google Chat"
+    }
+
+    fn send(&self, event: &Event, identity: &Identity) -> Result<(), Error> {
+        let mut url: reqwest::Url = self.webhook.read()?.parse().map_err(|error| {
+            Error::Config(format!(
+                "{} doesn't hold a webhook URL: {}",
+                self.webhook.path().display(),
+                error
+            ))
+        })?;
+        url.query_pairs_mut()
+            .append_pair("messageReplyOption", "REPLY_MESSAGE_FALLBACK_TO_NEW_THREAD");
+        let message = message(event, identity);
+        self.backoff.retry("post to Google Chat", || {
+            self.http
+                .post(url.clone())
+                .json(&message)
+                .send()?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+/// The webhook's message for the event, which is a single card.
+fn message(event: &Event, identity: &Identity) -> serde_json::Value {
+    let mut widgets = vec![serde_json::json!({ "textParagraph": { "text": event.to_string() } })];
+    if let Some(version) = event.version() {
+        widgets.push(serde_json::json!({
+            "decoratedText": { "topLabel": "Version", "text": version },
+        }));
+    }
+    let mut header = serde_json::json!({ "title": event.title() });
+    if let Some(cluster) = notify::cluster(identity) {
+        header["subtitle"] = cluster.into();
+    }
+    let mut message = serde_json::json!({
+        "cardsV2": [{
+            "cardId": "openshift-update",
+            "card": {
+                "header": header,
+                "sections": [{ "widgets": widgets }],
+            },
+        }],
+    });
+    if let Some(version) = event.version() {
+        message["thread"] = serde_json::json!({
+            "threadKey": format!("openshift-update.{}", version),
+        });
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_cards() {
+        let identity = Identity {
+            cluster_id: None,
+            infrastructure_name: Some("prod-x7k2p".to_string()),
+        };
+        let event = Event::Completed {
+            version: "4.1.16".to_string(),
+        };
+        assert_eq!(
+            message(&event, &identity),
+            serde_json::json!({
+                "cardsV2": [{
+                    "cardId": "openshift-update",
+                    "card": {
+                        "header": { "title": "Update completed", "subtitle": "prod-x7k2p" },
+                        "sections": [{
+                            "widgets": [
+                                {
+                                    "textParagraph": {
+                                        "text": "The cluster has finished updating to 4.1.16",
+                                    },
+                                },
+                                {
+                                    "decoratedText": { "topLabel": "Version", "text": "4.1.16" },
+                                },
+                            ],
+                        }],
+                    },
+                }],
+                "thread": { "threadKey": "openshift-update.4.1.16" },
+            })
+        );
+
+        let message = message(&Event::Recovered, &Identity::default());
+        assert!(message.get("thread").is_none());
+        assert_eq!(
+            message["cardsV2"][0]["card"]["header"],
+            serde_json::json!({ "title": "Operator recovered" })
+        );
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The update graph (of the Cincinnati protocol), which is where a cluster's available updates
+//! come from. Graphs are fetched by `crate::cincinnati`, or read from recordings and files.
+
+#[cfg(not(feature = "cincinnati"))]
+use crate::http::HttpClient;
+use crate::Error;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+/// The upstream used by clusters which don't set spec.upstream.
+pub const DEFAULT_UPSTREAM: &str = "https://api.openshift.com/api/upgrades_info/v1/graph";
+
+/// The releases of a channel and the updates between them.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    /// Pairs of indices into `nodes`, from the release being updated to its update.
+    pub edges: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Node {
+    pub version: semver::Version,
+    pub payload: String,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Node {
+    /// The advisory which published the release (e.g. RHBA-2019:2594), taken from its errata URL.
+    pub fn errata(&self) -> Option<&str> {
+        self.metadata
+            .get("url")?
+            .rsplit('/')
+            .next()
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Whether the release was published by a security advisory (RHSA), i.e. it fixes CVEs.
+    pub fn security(&self) -> bool {
+        self.errata()
+            .is_some_and(|errata| errata.starts_with("RHSA-"))
+    }
+}
+
+impl Graph {
+    /// Whether the release is part of the channel.
+    pub fn contains(&self, version: &semver::Version) -> bool {
+        self.node(version).is_some()
+    }
+
+    pub fn node(&self, version: &semver::Version) -> Option<&Node> {
+        self.nodes.iter().find(|node| &node.version == version)
+    }
+
+    /// The releases which were published by security advisories.
+    pub fn security(&self) -> HashSet<semver::Version> {
+        self.nodes
+            .iter()
+            .filter(|node| node.security())
+            .map(|node| node.version.clone())
+            .collect()
+    }
+
+    /// Whether the channel offers an update from one release to the other.
+    pub fn has_edge(&self, from: &semver::Version, to: &semver::Version) -> bool {
+        self.edges.iter().any(|&(source, target)| {
+            self.nodes.get(source).map(|node| &node.version) == Some(from)
+                && self.nodes.get(target).map(|node| &node.version) == Some(to)
+        })
+    }
+}
+
+/// One update of a multi-hop plan.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Hop {
+    pub from: semver::Version,
+    pub to: semver::Version,
+    /// The channel which offers the update.
+    pub channel: String,
+}
+
+/// Find the shortest sequence of updates from one release to another using the given channels'
+/// graphs. Each update is taken from the channel of the previous one for as long as that channel
+/// offers it, and otherwise from the first channel in `channels` which does.
+pub fn plan(
+    channels: &[(String, Graph)],
+    from: &semver::Version,
+    to: &semver::Version,
+) -> Option<Vec<Hop>> {
+    let mut updates: BTreeMap<&semver::Version, Vec<&semver::Version>> = BTreeMap::new();
+    for (_, graph) in channels {
+        for &(source, target) in &graph.edges {
+            if let (Some(source), Some(target)) = (graph.nodes.get(source), graph.nodes.get(target))
+            {
+                updates
+                    .entry(&source.version)
+                    .or_default()
+                    .push(&target.version);
+            }
+        }
+    }
+
+    // A breadth-first search finds the path with the fewest updates.
+    let mut previous: BTreeMap<&semver::Version, &semver::Version> = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(from);
+    while let Some(version) = queue.pop_front() {
+        if version == to {
+            break;
+        }
+        for &next in updates.get(version).into_iter().flatten() {
+            if next != from && !previous.contains_key(next) {
+                previous.insert(next, version);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let mut path = vec![to];
+    while let Some(&version) = previous.get(path[path.len() - 1]) {
+        path.push(version);
+    }
+    if path[path.len() - 1] != from || from == to {
+        return None;
+    }
+    path.reverse();
+
+    let mut channel = channels.first().map(|(channel, _)| channel.as_str())?;
+    let mut hops = Vec::new();
+    for pair in path.windows(2) {
+        let offers = |(_, graph): &&(String, Graph)| graph.has_edge(pair[0], pair[1]);
+        if !channels
+            .iter()
+            .filter(offers)
+            .any(|(name, _)| name == channel)
+        {
+            channel = channels
+                .iter()
+                .find(offers)
+                .map(|(name, _)| name.as_str())?;
+        }
+        hops.push(Hop {
+            from: pair[0].clone(),
+            to: pair[1].clone(),
+            channel: channel.to_string(),
+        });
+    }
+    Some(hops)
+}
+
+/// Access to update graphs, which allows decisions to be exercised against fixtures.
+pub trait GraphClient {
+    /// Fetch the graph of `channel` as seen by a cluster at `version`.
+    fn fetch(&self, upstream: &str, channel: &str, version: &str) -> Result<Graph, Error>;
+}
+
+/// Builds without the cincinnati feature have no client of the update graph (see
+/// `crate::cincinnati`), so they can't fetch graphs.
+#[cfg(not(feature = "cincinnati"))]
+impl GraphClient for HttpClient {
+    fn fetch(&self, upstream: &str, _channel: &str, _version: &str) -> Result<Graph, Error> {
+        Err(Error::Config(format!(
+            "fetching the update graph from {} requires the cincinnati feature",
+            upstream
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edges() {
+        let graph: Graph = serde_json::from_str(include_str!("../tests/fixtures/graph.json"))
+            .expect("valid fixture");
+        let version = |version| semver::Version::parse(version).expect("version");
+
+        assert!(graph.contains(&version("4.1.14")));
+        assert!(!graph.contains(&version("4.2.0")));
+        assert!(graph.has_edge(&version("4.1.14"), &version("4.1.16")));
+        assert!(!graph.has_edge(&version("4.1.16"), &version("4.1.14")));
+        assert_eq!(
+            graph.node(&version("4.1.15")).and_then(Node::errata),
+            Some("RHBA-2019:2681")
+        );
+    }
+
+    #[test]
+    fn plans_across_channels() {
+        let graph = |versions: &[&str], edges: &[(usize, usize)]| -> Graph {
+            serde_json::from_value(serde_json::json!({
+                "nodes": versions
+                    .iter()
+                    .map(|version| serde_json::json!({ "version": version, "payload": "" }))
+                    .collect::<Vec<_>>(),
+                "edges": edges,
+            }))
+            .expect("valid graph")
+        };
+        let channels = vec![
+            (
+                "stable-4.1".to_string(),
+                graph(&["4.1.14", "4.1.15", "4.1.16"], &[(0, 1), (1, 2)]),
+            ),
+            (
+                "stable-4.2".to_string(),
+                graph(
+                    &["4.1.15", "4.1.16", "4.2.0", "4.2.1"],
+                    &[(0, 1), (1, 2), (2, 3), (0, 3)],
+                ),
+            ),
+        ];
+        let version = |version| semver::Version::parse(version).expect("version");
+
+        let hops: Vec<(String, String)> = plan(&channels, &version("4.1.14"), &version("4.2.1"))
+            .expect("plan")
+            .into_iter()
+            .map(|hop| (hop.to.to_string(), hop.channel))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                ("4.1.15".to_string(), "stable-4.1".to_string()),
+                ("4.2.1".to_string(), "stable-4.2".to_string()),
+            ]
+        );
+        assert_eq!(plan(&channels, &version("4.2.1"), &version("4.1.14")), None);
+        assert_eq!(
+            plan(&channels, &version("4.1.14"), &version("4.1.14")),
+            None
+        );
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The control API over gRPC, for clients which would rather have the types generated for them.
+//!
+//! The service is described by proto/openshift-update.proto, and does what the HTTP API does (see
+//! [`crate::server`]), with the same bearer token. It's served over cleartext HTTP/2 by hyper,
+//! with the messages encoded here: the requests are all empty (so their fields, if a client sends
+//! any, are ignored), and the responses are small enough that a code generator would be more
+//! than they need. A refusal is given as the gRPC status closest to the HTTP API's.
+
+use crate::server::{blocking, Context, Refusal};
+use crate::status::Status;
+use chrono::{DateTime, Utc};
+use futures::{future, Async, Poll};
+use hyper::body::Payload;
+use hyper::http::request::Parts;
+use hyper::rt::{Future, Stream};
+use hyper::service::service_fn;
+use hyper::{header, Body, Chunk, HeaderMap, Method, Request, Response, Server, StatusCode};
+use kube::client::APIClient;
+use openshift_update::policy::DecisionReason;
+use openshift_update::secret::SecretFile;
+use openshift_update::state::ConfigMapStore;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const SERVICE: &str = "/openshift_update.v1.Updates/";
+
+// The gRPC status codes a call may fail with.
+const FAILED_PRECONDITION: u8 = 9;
+const UNIMPLEMENTED: u8 = 12;
+const INTERNAL: u8 = 13;
+const UNAVAILABLE: u8 = 14;
+const UNAUTHENTICATED: u8 = 16;
+
+/// Serve the gRPC API on `addr` from a background thread.
+pub fn spawn(
+    addr: SocketAddr,
+    token: SecretFile,
+    client: APIClient,
+    name: String,
+    status: Arc<Mutex<Status>>,
+    fleet: bool,
+    store: Option<ConfigMapStore>,
+) -> Result<(), hyper::Error> {
+    let context = Arc::new(Context::new(token, client, name, status, fleet, store));
+    let server = Server::try_bind(&addr)?
+        .http2_only(true)
+        .serve(move || {
+            let context = context.clone();
+            service_fn(move |request: Request<Body>| {
+                let context = context.clone();
+                let (parts, body) = request.into_parts();
+                body.concat2().and_then(move |body| {
+                    // Only the controls make requests of the Kubernetes API.
+                    let control = matches!(
+                        parts.uri.path().strip_prefix(SERVICE),
+                        Some("Approve") | Some("Pause") | Some("Resume")
+                    );
+                    let reply: Box<dyn Future<Item = _, Error = _> + Send> = if control {
+                        Box::new(
+                            blocking(move || reply(&context, &parts, &body))
+                                .or_else(|_| Ok(failed(INTERNAL, "the call failed"))),
+                        )
+                    } else {
+                        Box::new(future::ok(reply(&context, &parts, &body)))
+                    };
+                    reply
+                })
+            })
+        })
+        .map_err(|error| error!("Failed to serve gRPC API: {}", error));
+
+    info!("Serving gRPC API on {}", addr);
+    thread::spawn(move || hyper::rt::run(server));
+    Ok(())
+}
+
+/// The body of a reply: the response message, unless the call failed, then the trailers giving
+/// its status. A call which failed has its status in the headers instead, and no body.
+struct Reply {
+    message: Option<Chunk>,
+    trailers: Option<HeaderMap>,
+}
+
+impl Payload for Reply {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        Ok(Async::Ready(self.message.take()))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, hyper::Error> {
+        Ok(Async::Ready(self.trailers.take()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.message.is_none() && self.trailers.is_none()
+    }
+}
+
+/// Make the call, and reply with its response or its failure.
+fn reply(context: &Context, parts: &Parts, body: &[u8]) -> Response<Reply> {
+    if parts.method != Method::POST {
+        return rejected(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let grpc = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"));
+    if !grpc {
+        return rejected(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    match call(context, parts, body) {
+        Ok(message) => {
+            let mut frame = vec![0];
+            frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            frame.extend(message);
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/grpc")
+                .body(Reply {
+                    message: Some(frame.into()),
+                    trailers: Some(status(0, "")),
+                })
+                .expect("valid response")
+        }
+        Err((code, message)) => failed(code, &message),
+    }
+}
+
+/// The reply to a call which failed, with its status in the headers.
+fn failed(code: u8, message: &str) -> Response<Reply> {
+    let mut response = Response::new(Reply {
+        message: None,
+        trailers: None,
+    });
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/grpc"),
+    );
+    response.headers_mut().extend(status(code, message));
+    response
+}
+
+/// A request which isn't a gRPC call at all.
+fn rejected(code: StatusCode) -> Response<Reply> {
+    let mut response = Response::new(Reply {
+        message: None,
+        trailers: None,
+    });
+    *response.status_mut() = code;
+    response
+}
+
+/// The response message of the call, or the status it failed with and why.
+fn call(context: &Context, parts: &Parts, body: &[u8]) -> Result<Vec<u8>, (u8, String)> {
+    context.authorize(&parts.headers).map_err(refused)?;
+    // Each request is one message, which isn't compressed since no encodings are accepted.
+    match body {
+        [0, len @ ..] if len.len() >= 4 => {}
+        [1, ..] => return Err((UNIMPLEMENTED, "compressed messages aren't supported".into())),
+        _ => return Err((INTERNAL, "the request isn't a gRPC message".into())),
+    }
+
+    let method = parts.uri.path().strip_prefix(SERVICE).unwrap_or_default();
+    match method {
+        "GetStatus" => Ok(get_status(&context.status.lock().expect("status lock"))),
+        "ListCandidates" => {
+            context.local().map_err(refused)?;
+            let status = context.status.lock().expect("status lock");
+            let mut message = Encoder::default();
+            for queued in &status.queue {
+                message = message.message(
+                    1,
+                    Encoder::default()
+                        .string(1, &queued.version.to_string())
+                        .time(2, queued.not_before)
+                        .string(3, queued.gate.as_deref().unwrap_or_default())
+                        .string(4, queued.reason.as_deref().unwrap_or_default()),
+                );
+            }
+            Ok(message.0)
+        }
+        "Approve" => {
+            context.local().map_err(refused)?;
+            let version = context.approve().map_err(refused)?;
+            Ok(Encoder::default().string(1, &version.to_string()).0)
+        }
+        "Pause" | "Resume" => {
+            context.local().map_err(refused)?;
+            context.pause(method == "Pause").map_err(refused)?;
+            Ok(Vec::new())
+        }
+        _ => Err((
+            UNIMPLEMENTED,
+            format!("unknown method {}", parts.uri.path()),
+        )),
+    }
+}
+
+fn get_status(status: &Status) -> Vec<u8> {
+    let version = |version: &Option<semver::Version>| {
+        version
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default()
+    };
+    let reason = status.reason.as_ref();
+    let mut message = Encoder::default()
+        .string(1, status.version.as_deref().unwrap_or_default())
+        .bool(2, status.paused)
+        .string(3, reason.map(DecisionReason::code).unwrap_or_default())
+        .string(4, &version(&status.candidate))
+        .time(5, status.not_before)
+        .string(6, &version(&status.approved))
+        .time(7, status.evaluated_at)
+        .bool(8, status.single_node)
+        .string(9, reason.and_then(DecisionReason::gate).unwrap_or_default())
+        .bool(10, status.ready)
+        .uint(11, status.consecutive_failures.into())
+        .string(12, status.retrieval_failure.as_deref().unwrap_or_default());
+    for cluster in &status.clusters {
+        message = message.message(
+            13,
+            Encoder::default()
+                .string(1, &cluster.name)
+                .string(2, cluster.version.as_deref().unwrap_or_default())
+                .string(3, &version(&cluster.candidate))
+                .string(4, cluster.gate.as_deref().unwrap_or_default())
+                .string(5, cluster.reason.as_deref().unwrap_or_default())
+                .string(6, cluster.last_result.as_deref().unwrap_or_default()),
+        );
+    }
+    message.0
+}
+
+/// The gRPC status closest to the HTTP API's refusal.
+fn refused(Refusal(code, message): Refusal) -> (u8, String) {
+    let code = match code {
+        StatusCode::UNAUTHORIZED => UNAUTHENTICATED,
+        StatusCode::NOT_FOUND => UNIMPLEMENTED,
+        StatusCode::CONFLICT => FAILED_PRECONDITION,
+        StatusCode::BAD_GATEWAY => UNAVAILABLE,
+        _ => INTERNAL,
+    };
+    (code, message)
+}
+
+/// The grpc-status and grpc-message of a call, the message percent-encoded as gRPC requires.
+fn status(code: u8, message: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("grpc-status", u16::from(code).into());
+    if !message.is_empty() {
+        let encoded: String = message
+            .bytes()
+            .map(|byte| match byte {
+                b'%' => "%25".to_string(),
+                b' '..=b'~' => char::from(byte).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect();
+        headers.insert(
+            "grpc-message",
+            header::HeaderValue::from_str(&encoded).expect("percent-encoded message"),
+        );
+    }
+    headers
+}
+
+/// A message being encoded. Fields which are empty (or zero) are left out, as they are in proto3.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(mut self, number: u32, value: u64) -> Self {
+        if value != 0 {
+            self.varint(u64::from(number) << 3);
+            self.varint(value);
+        }
+        self
+    }
+
+    fn bool(self, number: u32, value: bool) -> Self {
+        self.uint(number, value.into())
+    }
+
+    fn string(self, number: u32, value: &str) -> Self {
+        if value.is_empty() {
+            self
+        } else {
+            self.bytes(number, value.as_bytes())
+        }
+    }
+
+    /// A nested message, which is encoded even when it's empty.
+    fn message(self, number: u32, message: Encoder) -> Self {
+        self.bytes(number, &message.0)
+    }
+
+    /// A google.protobuf.Timestamp, if there's a time.
+    fn time(self, number: u32, time: Option<DateTime<Utc>>) -> Self {
+        match time {
+            Some(time) => self.message(
+                number,
+                Encoder::default()
+                    // Negative seconds are encoded in two's complement, as for int64.
+                    .uint(1, time.timestamp() as u64)
+                    .uint(2, time.timestamp_subsec_nanos().into()),
+            ),
+            None => self,
+        }
+    }
+
+    fn bytes(mut self, number: u32, value: &[u8]) -> Self {
+        self.varint((u64::from(number) << 3) | 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn context(status: Status, fleet: bool) -> Context {
+        let dir =
+            std::env::temp_dir().join(format!("openshift-update-grpc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create directory");
+        let token = dir.join("token");
+        std::fs::write(&token, "s3cret").expect("write token");
+        Context::new(
+            SecretFile::new(&token),
+            APIClient::new(kube::config::Configuration::new(
+                "http://localhost".to_string(),
+                reqwest::Client::new(),
+            )),
+            "version".to_string(),
+            Arc::new(Mutex::new(status)),
+            fleet,
+            None,
+        )
+    }
+
+    fn request(method: &str, token: &str) -> Parts {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}{}", SERVICE, method))
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(())
+            .expect("valid request")
+            .into_parts()
+            .0
+    }
+
+    const EMPTY: &[u8] = &[0, 0, 0, 0, 0];
+
+    #[test]
+    fn encodes_the_status() {
+        let status = Status {
+            version: Some("4.1.15".to_string()),
+            paused: true,
+            evaluated_at: Some(Utc.timestamp(1_568_678_400, 0)),
+            consecutive_failures: 300,
+            clusters: vec![crate::status::FleetCluster {
+                name: "spoke-1".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut reply = reply(
+            &context(status, false),
+            &request("GetStatus", "s3cret"),
+            EMPTY,
+        );
+        assert_eq!(reply.status(), StatusCode::OK);
+        let message = reply.body_mut().message.take().expect("message");
+        assert_eq!(&message[..5], &[0, 0, 0, 0, message.len() as u8 - 5]);
+        assert_eq!(
+            &message[5..],
+            &[
+                &b"\x0a\x064.1.15"[..],
+                &[0x10, 1],
+                &[0x3a, 6, 0x08, 0x80, 0xc4, 0x80, 0xec, 0x05],
+                &[0x58, 0xac, 0x02],
+                &b"\x6a\x09\x0a\x07spoke-1"[..],
+            ]
+            .concat()[..]
+        );
+        assert_eq!(
+            reply.body_mut().trailers.take().expect("trailers")["grpc-status"],
+            "0"
+        );
+    }
+
+    #[test]
+    fn refuses_calls() {
+        let status = |reply: Response<Reply>| {
+            assert!(reply.body().is_end_stream());
+            (
+                reply.headers()["grpc-status"]
+                    .to_str()
+                    .expect("status")
+                    .to_string(),
+                reply
+                    .headers()
+                    .get("grpc-message")
+                    .map(|message| message.to_str().expect("message").to_string()),
+            )
+        };
+        let local = context(Status::default(), false);
+        assert_eq!(
+            status(reply(&local, &request("GetStatus", "guess"), EMPTY)).0,
+            "16"
+        );
+        assert_eq!(
+            status(reply(&local, &request("Approve", "s3cret"), EMPTY)),
+            (
+                "9".to_string(),
+                Some("no update is awaiting approval".to_string())
+            )
+        );
+        assert_eq!(
+            status(reply(&local, &request("Delete", "s3cret"), EMPTY)).0,
+            "12"
+        );
+        assert_eq!(
+            status(reply(&local, &request("GetStatus", "s3cret"), &[0, 0])).0,
+            "13"
+        );
+        let fleet = context(Status::default(), true);
+        assert_eq!(
+            status(reply(&fleet, &request("Pause", "s3cret"), EMPTY)),
+            ("12".to_string(), Some("not served for a fleet".to_string()))
+        );
+
+        let mut parts = request("GetStatus", "s3cret");
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+        assert_eq!(
+            reply(&local, &parts, EMPTY).status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            self::status(13, "50% done\n")["grpc-message"],
+            "50%25 done%0A"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks of the workloads running on the cluster, which hold back updates while they are ailing.
+//!
+//! Disturbing a cluster in the middle of an incident only compounds it, so each enabled
+//! [`HealthCheck`] is run before every decision and [`RefuseUnhealthy`] blocks updates while any
+//! of them fails. A check which can't be run at all counts as failed.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use crate::Error;
+
+/// The verdict of a health check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Health {
+    /// Name of the gate, as reported when it blocks an update.
+    pub gate: &'static str,
+    pub passed: bool,
+    pub reason: String,
+}
+
+pub trait HealthCheck {
+    fn gate(&self) -> &'static str;
+    fn check(&self) -> Result<Health, Error>;
+}
+
+/// Run each of the checks, turning those which fail to run into failures.
+pub fn run(checks: &[Box<dyn HealthCheck>]) -> Vec<Health> {
+    checks.iter().map(|check| verdict(check.as_ref())).collect()
+}
+
+/// Run the check, turning a failure to run it into a failure of the check.
+pub fn verdict(check: &dyn HealthCheck) -> Health {
+    check.check().unwrap_or_else(|error| {
+        warn!("Failed to run the {} check: {}", check.gate(), error);
+        Health {
+            gate: check.gate(),
+            passed: false,
+            reason: format!("unable to check: {}", error),
+        }
+    })
+}
+
+/// Block updates while any health check fails.
+pub struct RefuseUnhealthy {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for RefuseUnhealthy {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } => match current.health.iter().find(|health| !health.passed)
+            {
+                Some(failed) => Decision::Blocked {
+                    update,
+                    gate: failed.gate.to_string(),
+                    reason: failed.reason.clone(),
+                },
+                None => Decision::Apply { update },
+            },
+            decision => decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clusterversion::ClusterVersion;
+    use crate::policy::Latest;
+
+    struct Failing;
+
+    impl HealthCheck for Failing {
+        fn gate(&self) -> &'static str {
+            "failing"
+        }
+
+        fn check(&self) -> Result<Health, Error> {
+            Err(Error::Policy("no such API".to_string()))
+        }
+    }
+
+    #[test]
+    fn refuses_unhealthy() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        let mut state = ClusterState::new(
+            &version,
+            "2019-09-17T00:00:00Z".parse().expect("valid time"),
+        );
+        let policy = RefuseUnhealthy {
+            inner: Box::new(Latest),
+        };
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        let checks: Vec<Box<dyn HealthCheck>> = vec![Box::new(Failing)];
+        state.health = run(&checks);
+        match policy.evaluate(&state, &candidates) {
+            Decision::Blocked { gate, reason, .. } => {
+                assert_eq!(gate, "failing");
+                assert!(reason.starts_with("unable to check"), "{}", reason);
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The cluster's update history, newest first.
+//!
+//! The history is the ClusterVersion's, which records every update the cluster started (whoever
+//! requested it) until it was completed or replaced. With `--state-namespace`, the updates the
+//! operator gave up on are marked with the reason it did.
+
+use crate::options::Options;
+use crate::output::Output;
+use chrono::{DateTime, Utc};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{ClusterVersion, ClusterVersionClient, KubeClient};
+use openshift_update::state::{ConfigMapStore, State, StateStore};
+use openshift_update::Error;
+use std::fmt::Write;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct History {
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the history: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct Entry {
+    version: Option<String>,
+    image: Option<String>,
+    /// "Completed" if the update was fully applied, otherwise "Partial".
+    state: Option<String>,
+    #[serde(rename = "startedTime")]
+    started_time: Option<DateTime<Utc>>,
+    #[serde(rename = "completionTime", skip_serializing_if = "Option::is_none")]
+    completion_time: Option<DateTime<Utc>>,
+    /// Why the operator won't try the update again, if it gave up on it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed: Option<String>,
+}
+
+pub fn run(client: APIClient, options: &Options, command: &History) -> Result<(), Error> {
+    let saved = match &options.state_namespace {
+        Some(namespace) => {
+            ConfigMapStore::new(client.clone(), namespace, options.backoff()).load()?
+        }
+        None => State::default(),
+    };
+    let version =
+        KubeClient::new(client, &options.cluster_version_name, options.backoff())?.get()?;
+    command.output.print(&history(&version, &saved), |entries| {
+        render(entries, Utc::now())
+    });
+    Ok(())
+}
+
+fn history(version: &ClusterVersion, saved: &State) -> Vec<Entry> {
+    version
+        .status
+        .iter()
+        .flat_map(|status| &status.history)
+        .map(|entry| Entry {
+            version: entry.version.clone(),
+            image: entry.image.clone(),
+            state: entry.state.clone(),
+            started_time: entry.started_time,
+            completion_time: entry.completion_time,
+            failed: entry
+                .parsed_version()
+                .and_then(|version| saved.poisoned.get(&version).cloned()),
+        })
+        .collect()
+}
+
+fn render(entries: &[Entry], now: DateTime<Utc>) -> String {
+    let mut text = String::new();
+    if entries.is_empty() {
+        writeln!(text, "No updates recorded").expect("write to string");
+        return text;
+    }
+
+    writeln!(
+        text,
+        "{:<24} {:<10} {:<22} {:<12} NOTE",
+        "VERSION", "STATE", "STARTED", "DURATION"
+    )
+    .expect("write to string");
+    for entry in entries {
+        let started = entry.started_time.map_or("-".to_string(), |time| {
+            time.format("%Y-%m-%d %H:%M").to_string()
+        });
+        // An update which isn't complete has been going on for as long as it has been started.
+        let duration = match (entry.started_time, entry.completion_time) {
+            (Some(started), Some(completed)) => Some(completed - started),
+            (Some(started), None) => Some(now - started),
+            (None, _) => None,
+        }
+        .and_then(|duration| duration.to_std().ok())
+        .map_or("-".to_string(), |duration| {
+            humantime::format_duration(Duration::from_secs(duration.as_secs() / 60 * 60))
+                .to_string()
+        });
+        writeln!(
+            text,
+            "{:<24} {:<10} {:<22} {:<12} {}",
+            entry.version.as_deref().unwrap_or("unknown"),
+            entry.state.as_deref().unwrap_or("-"),
+            started,
+            duration,
+            match &entry.failed {
+                Some(reason) => format!("failed: {}", reason),
+                None => String::new(),
+            }
+        )
+        .expect("write to string");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_history() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-rolled-back.json"
+        ))
+        .expect("valid fixture");
+        let mut saved = State::default();
+        saved.poisoned.insert(
+            semver::Version::new(4, 1, 16),
+            "rolled back to 4.1.14".to_string(),
+        );
+
+        let entries = history(&version, &saved);
+        let versions: Vec<(Option<&str>, Option<&str>)> = entries
+            .iter()
+            .map(|entry| (entry.version.as_deref(), entry.failed.as_deref()))
+            .collect();
+        assert_eq!(
+            versions,
+            vec![
+                (Some("4.1.14"), None),
+                (Some("4.1.16"), Some("rolled back to 4.1.14")),
+                (Some("4.1.14"), None),
+            ]
+        );
+
+        let text = render(
+            &entries,
+            "2019-09-18T00:00:00Z".parse().expect("valid time"),
+        );
+        let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines[2],
+            "4.1.16                   Partial    2019-09-17 00:05       1h 41m       failed: rolled back to 4.1.14"
+        );
+        assert_eq!(render(&[], Utc::now()), "No updates recorded\n");
+    }
+}
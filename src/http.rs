@@ -0,0 +1,261 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration of the operator's own outbound HTTP requests (e.g. to the update graph).
+//!
+//! In proxied clusters direct egress is usually blocked, so requests follow the cluster-wide
+//! Proxy object (proxy.config.openshift.io/cluster) and trust its additional CA bundle.
+
+use crate::retry::{self, Backoff};
+use crate::state::ConfigMap;
+use crate::Error;
+use kube::api::{self, Api, RawApi};
+use kube::client::APIClient;
+use std::net::IpAddr;
+
+/// Namespace of the ConfigMap referenced by the Proxy's trustedCA.
+const TRUSTED_CA_NAMESPACE: &str = "openshift-config";
+const TRUSTED_CA_KEY: &str = "ca-bundle.crt";
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct ProxySpec {
+    #[serde(rename = "trustedCA", default)]
+    trusted_ca: ConfigMapReference,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct ConfigMapReference {
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct ProxyStatus {
+    #[serde(rename = "httpProxy", default)]
+    http_proxy: String,
+    #[serde(rename = "httpsProxy", default)]
+    https_proxy: String,
+    #[serde(rename = "noProxy", default)]
+    no_proxy: String,
+}
+
+type Proxy = api::Object<ProxySpec, ProxyStatus>;
+
+/// A client of the JSON APIs the operator reads: the update graph and the rollout service.
+pub struct HttpClient {
+    pub http: reqwest::Client,
+    pub backoff: Backoff,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HttpConfig {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    /// Hosts, domains (matching their subdomains too) and CIDRs which are reached directly.
+    pub no_proxy: Vec<String>,
+    /// Additional PEM-encoded CA certificates to trust.
+    pub ca_bundle: Option<String>,
+    /// A DER-encoded PKCS #12 archive, and its password, to authenticate with.
+    pub identity: Option<(Vec<u8>, String)>,
+    /// Accept any certificate, which is only ever appropriate in a lab.
+    pub insecure: bool,
+}
+
+impl HttpConfig {
+    /// Read the configuration from the cluster's Proxy object. Clusters without one (i.e. not
+    /// OpenShift) get the default configuration.
+    pub fn from_cluster(client: &APIClient) -> Result<HttpConfig, Error> {
+        let proxies = Api::<Proxy>::customResource(client.clone(), "proxies")
+            .group("config.openshift.io")
+            .version("v1");
+        let proxy = match proxies.get("cluster") {
+            Ok(proxy) => proxy,
+            Err(error) => {
+                let error = Error::from(error);
+                if retry::not_found(&error) {
+                    return Ok(HttpConfig::default());
+                }
+                return Err(error);
+            }
+        };
+
+        let status = proxy.status.unwrap_or_default();
+        let non_empty = |value: String| Some(value).filter(|value| !value.is_empty());
+        let mut config = HttpConfig {
+            http_proxy: non_empty(status.http_proxy),
+            https_proxy: non_empty(status.https_proxy),
+            no_proxy: status
+                .no_proxy
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(String::from)
+                .collect(),
+            ..Default::default()
+        };
+
+        let trusted_ca = proxy.spec.trusted_ca.name;
+        if !trusted_ca.is_empty() {
+            let request = RawApi::v1ConfigMap()
+                .within(TRUSTED_CA_NAMESPACE)
+                .get(&trusted_ca)?;
+            let config_map = client.request::<ConfigMap>(request)?;
+            config.ca_bundle = config_map.data.get(TRUSTED_CA_KEY).cloned();
+            if config.ca_bundle.is_none() {
+                warn!(
+                    "ConfigMap {}/{} has no {}; not trusting any additional CAs",
+                    TRUSTED_CA_NAMESPACE, trusted_ca, TRUSTED_CA_KEY
+                );
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Build a client which follows this configuration.
+    pub fn client(&self) -> Result<reqwest::Client, Error> {
+        let mut builder = reqwest::Client::builder();
+        if self.http_proxy.is_some() || self.https_proxy.is_some() {
+            for proxy in self.http_proxy.iter().chain(&self.https_proxy) {
+                reqwest::Url::parse(proxy)
+                    .map_err(|err| Error::Config(format!("invalid proxy {}: {}", proxy, err)))?;
+            }
+            let config = self.clone();
+            builder = builder.proxy(reqwest::Proxy::custom(move |url| {
+                config
+                    .proxy_for(url)
+                    .and_then(|proxy| reqwest::Url::parse(&proxy).ok())
+            }));
+        }
+        if let Some(bundle) = &self.ca_bundle {
+            for certificate in pem_certificates(bundle) {
+                builder = builder
+                    .add_root_certificate(reqwest::Certificate::from_pem(certificate.as_bytes())?);
+            }
+        }
+        if let Some((archive, password)) = &self.identity {
+            builder = builder.identity(reqwest::Identity::from_pkcs12_der(archive, password)?);
+        }
+        if self.insecure {
+            warn!("Not verifying the certificates of external endpoints");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// The proxy through which to reach `url`, if any.
+    fn proxy_for(&self, url: &reqwest::Url) -> Option<String> {
+        if url.host_str().is_some_and(|host| self.bypassed(host)) {
+            return None;
+        }
+        match url.scheme() {
+            "http" => self.http_proxy.clone(),
+            "https" => self.https_proxy.clone(),
+            _ => None,
+        }
+    }
+
+    fn bypassed(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.no_proxy.iter().any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            if let Some((network, bits)) = entry.split_once('/') {
+                return match (host.parse(), network.parse(), bits.parse()) {
+                    (Ok(host), Ok(network), Ok(bits)) => in_network(host, network, bits),
+                    _ => false,
+                };
+            }
+            let domain = entry.trim_start_matches('.');
+            !domain.is_empty()
+                && (host == domain
+                    || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.')))
+        })
+    }
+}
+
+fn in_network(host: IpAddr, network: IpAddr, bits: u32) -> bool {
+    match (host, network) {
+        (IpAddr::V4(host), IpAddr::V4(network)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(host) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(host), IpAddr::V6(network)) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(host) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Split a bundle into its certificates, since each has to be added on its own.
+fn pem_certificates(bundle: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    bundle
+        .split_inclusive(END)
+        .filter(|block| block.contains(END))
+        .map(|block| block.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy() {
+        let config = HttpConfig {
+            http_proxy: Some("http://proxy.example.com:3128".to_string()),
+            https_proxy: Some("http://proxy.example.com:3129".to_string()),
+            no_proxy: vec![
+                ".cluster.local".to_string(),
+                "example.org".to_string(),
+                "10.0.0.0/16".to_string(),
+                "fd00::/8".to_string(),
+            ],
+            ..Default::default()
+        };
+        let proxy = |url: &str| config.proxy_for(&url.parse().expect("valid URL"));
+
+        assert_eq!(
+            proxy("https://api.openshift.com/graph").as_deref(),
+            Some("http://proxy.example.com:3129")
+        );
+        assert_eq!(
+            proxy("http://api.openshift.com/graph").as_deref(),
+            Some("http://proxy.example.com:3128")
+        );
+        assert_eq!(proxy("https://svc.ns.svc.cluster.local/"), None);
+        assert_eq!(proxy("https://example.org/"), None);
+        assert_eq!(proxy("https://graph.example.org/"), None);
+        assert!(proxy("https://notexample.org/").is_some());
+        assert_eq!(proxy("https://10.0.12.1/"), None);
+        assert!(proxy("https://10.1.0.1/").is_some());
+        assert_eq!(proxy("https://[fd00::1]/"), None);
+    }
+
+    #[test]
+    fn splits_bundles() {
+        let bundle = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n";
+        assert_eq!(
+            pem_certificates(bundle),
+            vec![
+                "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----",
+                "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----",
+            ]
+        );
+    }
+}
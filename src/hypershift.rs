@@ -0,0 +1,509 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Upgrades of HyperShift hosted control planes and their node pools.
+//!
+//! Hosted clusters don't expose a ClusterVersion to the management cluster. Their version state
+//! is mirrored into the HostedCluster's status and the update is requested by changing its
+//! release image. NodePools carry their own release image and are only moved once the control
+//! plane has finished updating, one pool at a time. Each pool can restrict when it is updated and
+//! how aggressively its nodes are replaced with these annotations:
+//!
+//! - `upgrade.crawford.dev/window`: daily UTC window, e.g. "22:00-04:00"
+//! - `upgrade.crawford.dev/max-surge` and `upgrade.crawford.dev/max-unavailable`: rolling update
+//!   settings for pools using the Replace upgrade type
+//!
+//! With --max-concurrent-upgrades, a control plane which is still updating (or whose release image
+//! hasn't been picked up yet) counts against the limit, and no other control plane is moved to a
+//! new release while it's reached. Node pools follow their control plane whatever the limit.
+//!
+//! Each control plane is decided on by the same policy as the local cluster (see `fleet`), with
+//! its HostedCluster's annotations pausing, snoozing or approving its updates. An update the
+//! control plane abandoned for another is poisoned.
+
+use crate::fleet::{self, Fleet};
+use crate::options::Options;
+use crate::reconciler;
+use crate::status::{self, FleetCluster, Status};
+use chrono::Utc;
+use kube::api::{self, Api, ListParams, PatchParams};
+use kube::client::APIClient;
+use openshift_update::clusterversion::ClusterVersionStatus;
+use openshift_update::gates::{paused, Slots};
+use openshift_update::kubeapi;
+use openshift_update::retry;
+use openshift_update::state::State;
+use openshift_update::window::Window;
+use openshift_update::Error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const FORCE_ANNOTATION: &str = "hypershift.openshift.io/force-upgrade-to";
+const PROGRESS_ANNOTATION: &str = "upgrade.crawford.dev/node-pool-progress";
+const WINDOW_ANNOTATION: &str = "upgrade.crawford.dev/window";
+const MAX_SURGE_ANNOTATION: &str = "upgrade.crawford.dev/max-surge";
+const MAX_UNAVAILABLE_ANNOTATION: &str = "upgrade.crawford.dev/max-unavailable";
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct Release {
+    image: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct HostedClusterSpec {
+    #[serde(rename = "clusterID", default, skip_serializing_if = "Option::is_none")]
+    cluster_id: Option<String>,
+    release: Release,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct HostedClusterStatus {
+    version: Option<ClusterVersionStatus>,
+}
+
+type HostedCluster = api::Object<HostedClusterSpec, HostedClusterStatus>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct NodePoolSpec {
+    #[serde(rename = "clusterName")]
+    cluster_name: String,
+    release: Release,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    management: Option<NodePoolManagement>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct NodePoolManagement {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replace: Option<ReplaceUpgrade>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct ReplaceUpgrade {
+    #[serde(
+        rename = "rollingUpdate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    rolling_update: Option<RollingUpdate>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct RollingUpdate {
+    #[serde(rename = "maxSurge", default, skip_serializing_if = "Option::is_none")]
+    max_surge: Option<IntOrString>,
+    #[serde(
+        rename = "maxUnavailable",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    max_unavailable: Option<IntOrString>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum IntOrString {
+    Int(i32),
+    String(String),
+}
+
+impl From<&String> for IntOrString {
+    fn from(value: &String) -> Self {
+        match value.parse() {
+            Ok(value) => IntOrString::Int(value),
+            Err(_) => IntOrString::String(value.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+struct NodePoolStatus {
+    version: Option<String>,
+}
+
+type NodePool = api::Object<NodePoolSpec, NodePoolStatus>;
+
+pub fn run(client: APIClient, options: &Options, status: &Arc<Mutex<Status>>) -> Result<(), Error> {
+    let clusters = hypershift_api::<HostedCluster>(&client, "hostedclusters", options);
+    let pools = hypershift_api::<NodePool>(&client, "nodepools", options);
+
+    let fleet = Fleet::new(options);
+    let mut saved: HashMap<String, State> = HashMap::new();
+    loop {
+        let backoff = options.backoff();
+        match backoff.retry("list HostedClusters", || {
+            Ok(kubeapi::call("list", "hostedclusters", || {
+                clusters.list(&ListParams::default())
+            })?)
+        }) {
+            Ok(list) => {
+                let updating = list
+                    .items
+                    .iter()
+                    .filter(|cluster| updating(cluster))
+                    .count();
+                let mut slots = Slots::new(options.max_concurrent_upgrades, updating);
+                let mut rows = Vec::new();
+                for cluster in list.items {
+                    let mut row = FleetCluster::new(&format!(
+                        "{}/{}",
+                        cluster.metadata.namespace.as_deref().unwrap_or_default(),
+                        cluster.metadata.name
+                    ));
+                    match reconcile(
+                        &clusters,
+                        &pools,
+                        &fleet,
+                        saved.entry(row.name.clone()).or_default(),
+                        &mut slots,
+                        cluster,
+                        &mut row,
+                    ) {
+                        Err(error) if retry::unauthorized(&error) => return Err(error),
+                        Err(error) => {
+                            error!("Failed to reconcile HostedCluster {}: {}", row.name, error);
+                            row.hold("error", error.to_string());
+                        }
+                        Ok(()) => {}
+                    }
+                    rows.push(row);
+                }
+                // Forget the hosted clusters which were deleted.
+                saved.retain(|name, _| rows.iter().any(|row| &row.name == name));
+                let mut status = status.lock().expect("status lock");
+                status.clusters = rows;
+                status.evaluated_at = Some(Utc::now());
+            }
+            Err(error) if retry::unauthorized(&error) => return Err(error),
+            Err(error) => error!("Failed to list HostedClusters: {}", error),
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn hypershift_api<K: Clone + serde::de::DeserializeOwned>(
+    client: &APIClient,
+    resource: &str,
+    options: &Options,
+) -> Api<K> {
+    let api = Api::customResource(client.clone(), resource)
+        .group("hypershift.openshift.io")
+        .version("v1beta1");
+    match &options.hosted_cluster_namespace {
+        Some(namespace) => api.within(namespace),
+        None => api,
+    }
+}
+
+/// Returns whether the control plane has yet to finish updating to its release image.
+fn updating(cluster: &HostedCluster) -> bool {
+    let latest = cluster
+        .status
+        .as_ref()
+        .and_then(|status| status.version.as_ref())
+        .and_then(|status| status.history.first());
+    match latest {
+        Some(latest) => {
+            latest.completion_time.is_none()
+                || latest.image.as_ref() != Some(&cluster.spec.release.image)
+        }
+        None => false,
+    }
+}
+
+fn reconcile(
+    clusters: &Api<HostedCluster>,
+    pools: &Api<NodePool>,
+    fleet: &Fleet,
+    saved: &mut State,
+    slots: &mut Slots,
+    cluster: HostedCluster,
+    row: &mut FleetCluster,
+) -> Result<(), Error> {
+    let name = cluster.metadata.name.clone();
+    let namespace = cluster.metadata.namespace.clone().unwrap_or_default();
+
+    let status = match cluster
+        .status
+        .as_ref()
+        .and_then(|status| status.version.as_ref())
+    {
+        Some(status) => status.clone(),
+        None => {
+            debug!(
+                "HostedCluster {}/{} has not reported a version",
+                namespace, name
+            );
+            row.hold("unreported", "no version has been reported".to_string());
+            return Ok(());
+        }
+    };
+    row.version = status
+        .history
+        .iter()
+        .find(|entry| entry.state.as_deref() == Some("Completed"))
+        .and_then(|entry| entry.version.clone());
+    row.last_result = status::last_result(&status.history);
+    fleet.track(saved, &status, &row.name);
+
+    match status.history.first() {
+        Some(latest) if latest.completion_time.is_none() => {
+            debug!(
+                "Waiting for {}/{} to complete its update...",
+                namespace, name
+            );
+            row.hold(
+                "updating",
+                format!(
+                    "updating to {}",
+                    latest.version.as_deref().unwrap_or("its release")
+                ),
+            );
+            return Ok(());
+        }
+        Some(latest) if latest.image.as_ref() == Some(&cluster.spec.release.image) => {
+            let version = latest.version.clone().unwrap_or_default();
+            if !update_node_pools(clusters, pools, &cluster, &version)? {
+                row.hold("node-pools", format!("updating NodePools to {}", version));
+                return Ok(());
+            }
+        }
+        _ => {}
+    }
+
+    let cluster_id = cluster
+        .spec
+        .cluster_id
+        .clone()
+        .unwrap_or_else(|| name.clone());
+    let candidates = status.available_updates.clone().unwrap_or_default();
+    let mut state = fleet::cluster_state(&cluster.metadata, &cluster_id, Some(status), Utc::now());
+    // The release image may not have been picked up yet.
+    state.update_in_progress = updating(&cluster);
+    let decision = fleet.decide(state, candidates, saved);
+    let update = match fleet::record(row, decision) {
+        Some(update) => update,
+        None => return Ok(()),
+    };
+
+    if !slots.take() {
+        info!(
+            "Too many clusters are updating; {}/{} waits to update to {}",
+            namespace, name, update.version
+        );
+        row.hold("concurrency", "too many clusters are updating".to_string());
+        return Ok(());
+    }
+
+    info!(
+        "Attempting to update {}/{} to {}",
+        namespace, name, update.version
+    );
+    let mut metadata = cluster.metadata;
+    if let Some(approver) = reconciler::force_approver(fleet.options, false, &metadata, &update) {
+        warn!(
+            "Forcing the update of {}/{} to {}, approved by {}",
+            namespace, name, update.version, approver
+        );
+        metadata
+            .annotations
+            .insert(FORCE_ANNOTATION.to_string(), update.image.clone());
+    }
+    let patch = serde_json::to_vec(&HostedCluster {
+        types: cluster.types,
+        metadata,
+        spec: HostedClusterSpec {
+            cluster_id: None,
+            release: Release {
+                image: update.image,
+            },
+        },
+        status: None,
+    })
+    .expect("Serialize to JSON");
+    kubeapi::call("patch", "hostedclusters", || {
+        clusters
+            .clone()
+            .within(&namespace)
+            .patch(&name, &PatchParams::default(), patch)
+    })?;
+    saved.attempted = Some(update.version);
+    saved.attempted_at = Some(Utc::now());
+    row.reason = Some("requested the update".to_string());
+
+    Ok(())
+}
+
+/// Move the node pools of a hosted cluster, one at a time, to the release of its control plane.
+///
+/// Returns whether all of the pools are at that release.
+fn update_node_pools(
+    clusters: &Api<HostedCluster>,
+    pools: &Api<NodePool>,
+    cluster: &HostedCluster,
+    version: &str,
+) -> Result<bool, Error> {
+    let namespace = cluster.metadata.namespace.clone().unwrap_or_default();
+    let pools = pools.clone().within(&namespace);
+    let target = &cluster.spec.release.image;
+
+    let mut members: Vec<NodePool> =
+        kubeapi::call("list", "nodepools", || pools.list(&ListParams::default()))?
+            .items
+            .into_iter()
+            .filter(|pool| pool.spec.cluster_name == cluster.metadata.name)
+            .collect();
+    members.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+
+    let updated = |pool: &NodePool| {
+        &pool.spec.release.image == target
+            && pool
+                .status
+                .as_ref()
+                .and_then(|status| status.version.as_ref())
+                == Some(&version.to_string())
+    };
+    let done = members.iter().filter(|pool| updated(pool)).count();
+    let reported = report_progress(clusters, cluster, done, members.len())?;
+
+    if let Some(pool) = members
+        .iter()
+        .find(|pool| &pool.spec.release.image == target && !updated(pool))
+    {
+        debug!(
+            "Waiting for NodePool {}/{} to update to {}...",
+            namespace, pool.metadata.name, version
+        );
+        return Ok(false);
+    }
+    if done == members.len() {
+        // A freshly recorded progress annotation makes the HostedCluster in hand stale.
+        return Ok(!reported);
+    }
+
+    let now = Utc::now();
+    for pool in members
+        .into_iter()
+        .filter(|pool| &pool.spec.release.image != target)
+    {
+        let annotations = &pool.metadata.annotations;
+        if let Some(window) = annotations.get(WINDOW_ANNOTATION) {
+            match window.parse::<Window>() {
+                Ok(window) if window.contains(now) => {}
+                Ok(window) => {
+                    debug!(
+                        "NodePool {}/{} is outside of its window ({})",
+                        namespace, pool.metadata.name, window
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    warn!(
+                        "Skipping NodePool {}/{}: {}",
+                        namespace, pool.metadata.name, err
+                    );
+                    continue;
+                }
+            }
+        }
+
+        let max_surge = annotations.get(MAX_SURGE_ANNOTATION).map(IntOrString::from);
+        let max_unavailable = annotations
+            .get(MAX_UNAVAILABLE_ANNOTATION)
+            .map(IntOrString::from);
+        let management = if max_surge.is_some() || max_unavailable.is_some() {
+            Some(NodePoolManagement {
+                replace: Some(ReplaceUpgrade {
+                    rolling_update: Some(RollingUpdate {
+                        max_surge,
+                        max_unavailable,
+                    }),
+                }),
+            })
+        } else {
+            None
+        };
+
+        if paused(&cluster.metadata) || paused(&pool.metadata) {
+            info!(
+                "Updates of NodePool {}/{} are paused; not updating to {}",
+                namespace, pool.metadata.name, version
+            );
+            continue;
+        }
+
+        info!(
+            "Attempting to update NodePool {}/{} to {}",
+            namespace, pool.metadata.name, version
+        );
+        kubeapi::call("patch", "nodepools", || {
+            pools.patch(
+                &pool.metadata.name,
+                &PatchParams::default(),
+                serde_json::to_vec(&NodePool {
+                    types: pool.types,
+                    metadata: pool.metadata.clone(),
+                    spec: NodePoolSpec {
+                        cluster_name: pool.spec.cluster_name,
+                        release: cluster.spec.release.clone(),
+                        management,
+                    },
+                    status: None,
+                })
+                .expect("Serialize to JSON"),
+            )
+        })?;
+        break;
+    }
+
+    Ok(false)
+}
+
+/// Record how many of a hosted cluster's node pools are at its release on the HostedCluster.
+///
+/// Returns whether the HostedCluster had to be patched.
+fn report_progress(
+    clusters: &Api<HostedCluster>,
+    cluster: &HostedCluster,
+    done: usize,
+    total: usize,
+) -> Result<bool, Error> {
+    let progress = format!("{}/{}", done, total);
+    if cluster.metadata.annotations.get(PROGRESS_ANNOTATION) == Some(&progress) {
+        return Ok(false);
+    }
+
+    info!(
+        "{} of the NodePools of {} are up to date",
+        progress, cluster.metadata.name
+    );
+    let namespace = cluster.metadata.namespace.clone().unwrap_or_default();
+    kubeapi::call("patch", "hostedclusters", || {
+        clusters.clone().within(&namespace).patch(
+            &cluster.metadata.name,
+            &PatchParams::default(),
+            serde_json::to_vec(&serde_json::json!({
+                "metadata": { "annotations": { PROGRESS_ANNOTATION: progress } }
+            }))
+            .expect("Serialize to JSON"),
+        )
+    })?;
+
+    Ok(true)
+}
@@ -0,0 +1,62 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Which cluster the operator's metrics and events come from.
+//!
+//! The cluster ID and infrastructure name are attached to every metric sample and event, so
+//! that they can be told apart once they are aggregated across a fleet.
+
+use std::sync::Mutex;
+
+static IDENTITY: Mutex<Identity> = Mutex::new(Identity {
+    cluster_id: None,
+    infrastructure_name: None,
+});
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Identity {
+    /// The ClusterVersion's `spec.clusterID`.
+    #[serde(rename = "clusterID", skip_serializing_if = "Option::is_none")]
+    pub cluster_id: Option<String>,
+    /// The Infrastructure's `status.infrastructureName`.
+    #[serde(rename = "infrastructureName", skip_serializing_if = "Option::is_none")]
+    pub infrastructure_name: Option<String>,
+}
+
+impl Identity {
+    /// The identity as metric labels, leaving out the parts which aren't known.
+    pub fn labels(&self) -> Vec<(&'static str, &str)> {
+        let mut labels = Vec::new();
+        if let Some(cluster_id) = &self.cluster_id {
+            labels.push(("cluster_id", cluster_id.as_str()));
+        }
+        if let Some(infrastructure_name) = &self.infrastructure_name {
+            labels.push(("infrastructure_name", infrastructure_name.as_str()));
+        }
+        labels
+    }
+}
+
+/// The identity of the cluster, as far as it is known.
+pub fn get() -> Identity {
+    IDENTITY.lock().expect("identity lock").clone()
+}
+
+pub fn set_cluster_id(cluster_id: Option<String>) {
+    IDENTITY.lock().expect("identity lock").cluster_id = cluster_id;
+}
+
+pub fn set_infrastructure_name(infrastructure_name: Option<String>) {
+    IDENTITY.lock().expect("identity lock").infrastructure_name = infrastructure_name;
+}
@@ -0,0 +1,74 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Instrumented requests to the Kubernetes API.
+//!
+//! Every request is made through `call`, which admits it through the client-side rate limit and
+//! records how long it took and whether it failed, by verb and resource. A growing latency or
+//! error rate tells apart an API server which is struggling (as it may mid-update) from an
+//! operator which is merely being throttled.
+
+use crate::metrics;
+use crate::ratelimit;
+use std::time::Instant;
+
+const DURATION_METRIC: &str = "openshift_update_api_request_duration_seconds";
+const DURATION_HELP: &str = "How long requests to the Kubernetes API took, by verb and resource.";
+
+const ERRORS_METRIC: &str = "openshift_update_api_request_errors_total";
+const ERRORS_HELP: &str =
+    "Failed requests to the Kubernetes API, by verb, resource and status code.";
+
+/// Bounds of the buckets of the request durations, in seconds.
+const BUCKETS: &[f64] = &[
+    0.005, 0.025, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Make a request to the Kubernetes API with `f`, once the rate limit allows it.
+pub fn call<T, F>(verb: &str, resource: &str, f: F) -> Result<T, kube::Error>
+where
+    F: FnOnce() -> Result<T, kube::Error>,
+{
+    ratelimit::acquire();
+    let started = Instant::now();
+    let result = f();
+    let labels = [("verb", verb), ("resource", resource)];
+    metrics::observe(
+        DURATION_METRIC,
+        DURATION_HELP,
+        BUCKETS,
+        &labels,
+        started.elapsed().as_secs_f64(),
+    );
+    if let Err(error) = &result {
+        metrics::inc(
+            ERRORS_METRIC,
+            ERRORS_HELP,
+            &[
+                ("verb", verb),
+                ("resource", resource),
+                ("code", &code(error)),
+            ],
+        );
+    }
+    result
+}
+
+/// The status code of the API server's response, or "none" if there wasn't one.
+fn code(error: &kube::Error) -> String {
+    match error.api_error() {
+        Some(error) => error.code.to_string(),
+        None => "none".to_string(),
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Loading the kubeconfig, optionally impersonating another user.
+//!
+//! The client kube builds from a kubeconfig can't be given any more headers, and impersonation is
+//! done with the Impersonate-User and Impersonate-Group headers on every request. So when a user
+//! is impersonated (with --as and --as-group), the kubeconfig's current context is loaded here
+//! instead, the same way kube does, but into a client which also sends those headers. The API
+//! server then authorizes every request as that user, once it has checked that the kubeconfig's
+//! own user may impersonate them.
+//!
+//! Without a kubeconfig, a service account in the cluster impersonates with its own token, which
+//! kube mounts into the pod; that's how the operator itself runs.
+
+use crate::Error;
+use kube::config::{self, AuthInfo, Configuration};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+static IMPERSONATION: Mutex<Option<Impersonation>> = Mutex::new(None);
+
+/// Where the pod's service account token and the cluster's certificate authority are mounted.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Who every request to the Kubernetes API is made as.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Impersonation {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+/// Make every client loaded after this act as `user`, in `groups`. No user (the initial
+/// configuration) acts as the kubeconfig's own user.
+pub fn impersonate(user: Option<String>, groups: Vec<String>) {
+    *IMPERSONATION.lock().expect("impersonation lock") =
+        user.map(|user| Impersonation { user, groups });
+}
+
+/// Load the kubeconfig's current context.
+pub fn load() -> Result<Configuration, Error> {
+    let impersonation = IMPERSONATION.lock().expect("impersonation lock").clone();
+    match impersonation {
+        Some(impersonation) => {
+            let path = env::var_os("KUBECONFIG")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    env::var_os("HOME").map(|home| PathBuf::from(home).join(".kube/config"))
+                })
+                .filter(|path| path.exists());
+            match (path, in_cluster_server()) {
+                (Some(path), _) => load_as(&path, &impersonation),
+                (None, Some(server)) => {
+                    load_in_cluster_as(server, Path::new(SERVICE_ACCOUNT_DIR), &impersonation)
+                }
+                (None, None) => Err(Error::Config("no kubeconfig was found".to_string())),
+            }
+        }
+        None => Ok(config::load_kube_config()?),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ExecCredential {
+    status: Option<ExecCredentialStatus>,
+}
+
+#[derive(serde::Deserialize)]
+struct ExecCredentialStatus {
+    token: Option<String>,
+}
+
+fn load_as(path: &Path, impersonation: &Impersonation) -> Result<Configuration, Error> {
+    let failed = |what: &str, error: &dyn std::fmt::Display| {
+        Error::Config(format!("failed to {}: {}", what, error))
+    };
+
+    let kubeconfig: config::Config = serde_yaml::from_slice(
+        &fs::read(path).map_err(|error| failed(&format!("read {}", path.display()), &error))?,
+    )
+    .map_err(|error| failed(&format!("parse {}", path.display()), &error))?;
+    let context = kubeconfig
+        .contexts
+        .iter()
+        .find(|context| context.name == kubeconfig.current_context)
+        .map(|context| &context.context)
+        .ok_or_else(|| Error::Config(format!("no context {}", kubeconfig.current_context)))?;
+    let cluster = kubeconfig
+        .clusters
+        .iter()
+        .find(|cluster| cluster.name == context.cluster)
+        .map(|cluster| &cluster.cluster)
+        .ok_or_else(|| Error::Config(format!("no cluster {}", context.cluster)))?;
+    let user = kubeconfig
+        .auth_infos
+        .iter()
+        .find(|user| user.name == context.user)
+        .map(|user| &user.auth_info)
+        .ok_or_else(|| Error::Config(format!("no user {}", context.user)))?;
+
+    let mut builder = reqwest::Client::builder();
+    if let Some(bundle) = data_or_file(
+        &cluster.certificate_authority_data,
+        &cluster.certificate_authority,
+    )? {
+        for cert in X509::stack_from_pem(&bundle)
+            .map_err(|error| failed("read the certificate authority", &error))?
+        {
+            let der = cert
+                .to_der()
+                .map_err(|error| failed("encode the certificate authority", &error))?;
+            builder = builder.add_root_certificate(
+                reqwest::Certificate::from_der(&der)
+                    .map_err(|error| failed("load the certificate authority", &error))?,
+            );
+        }
+    }
+    if let Some(identity) = client_identity(user)? {
+        builder = builder.identity(identity);
+    }
+    if cluster.insecure_skip_tls_verify == Some(true) {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    let mut headers = headers(impersonation)?;
+    if let Some(authorization) = authorization(user)? {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&authorization).map_err(|_| {
+                Error::Config(format!("the credentials of {} are invalid", context.user))
+            })?,
+        );
+    }
+    let client = builder
+        .default_headers(headers)
+        .build()
+        .map_err(|error| failed("build the client", &error))?;
+    Ok(Configuration::new(cluster.server.clone(), client))
+}
+
+/// The address of the Kubernetes API, if running in a pod of the cluster.
+fn in_cluster_server() -> Option<String> {
+    let host = env::var("KUBERNETES_SERVICE_HOST").ok()?;
+    let port = env::var("KUBERNETES_SERVICE_PORT").ok()?;
+    // IPv6 addresses are bracketed in URLs.
+    Some(if host.contains(':') {
+        format!("https://[{}]:{}", host, port)
+    } else {
+        format!("https://{}:{}", host, port)
+    })
+}
+
+/// Load the configuration of the pod's service account from the files mounted in `dir`, the same
+/// way kube does, impersonating the user.
+fn load_in_cluster_as(
+    server: String,
+    dir: &Path,
+    impersonation: &Impersonation,
+) -> Result<Configuration, Error> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        fs::read(&path)
+            .map_err(|error| Error::Config(format!("failed to read {}: {}", path.display(), error)))
+    };
+    let failed = |error: &dyn std::fmt::Display| {
+        Error::Config(format!(
+            "failed to load the certificate authority of the service account: {}",
+            error
+        ))
+    };
+
+    let ca = X509::from_pem(&read("ca.crt")?)
+        .and_then(|ca| ca.to_der())
+        .map_err(|error| failed(&error))?;
+    let token = String::from_utf8_lossy(&read("token")?).trim().to_string();
+    let mut headers = headers(impersonation)?;
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| {
+            Error::Config("the token of the service account is invalid".to_string())
+        })?,
+    );
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_der(&ca).map_err(|error| failed(&error))?)
+        .default_headers(headers)
+        .build()
+        .map_err(|error| Error::Config(format!("failed to build the client: {}", error)))?;
+    Ok(Configuration::new(server, client))
+}
+
+/// The impersonation headers, with one Impersonate-Group per group.
+fn headers(impersonation: &Impersonation) -> Result<HeaderMap, Error> {
+    let value = |value: &str| {
+        HeaderValue::from_str(value)
+            .map_err(|_| Error::Config(format!("{:?} can't be impersonated", value)))
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("Impersonate-User", value(&impersonation.user)?);
+    for group in &impersonation.groups {
+        headers.append("Impersonate-Group", value(group)?);
+    }
+    Ok(headers)
+}
+
+/// The value of the Authorization header for the user, if it authenticates with one.
+fn authorization(user: &AuthInfo) -> Result<Option<String>, Error> {
+    let token = match (
+        &user.token,
+        &user.token_file,
+        &user.exec,
+        &user.auth_provider,
+    ) {
+        (Some(token), ..) => Some(token.clone()),
+        (None, Some(file), ..) => Some(
+            fs::read_to_string(file)
+                .map_err(|error| Error::Config(format!("failed to read {}: {}", file, error)))?
+                .trim()
+                .to_string(),
+        ),
+        (None, None, Some(exec), _) => {
+            let mut command = Command::new(&exec.command);
+            command.args(exec.args.iter().flatten());
+            for variable in exec.env.iter().flatten() {
+                if let (Some(name), Some(value)) = (variable.get("name"), variable.get("value")) {
+                    command.env(name, value);
+                }
+            }
+            let output = command.output().map_err(|error| {
+                Error::Config(format!("failed to run {}: {}", exec.command, error))
+            })?;
+            if !output.status.success() {
+                return Err(Error::Config(format!(
+                    "{} failed: {}",
+                    exec.command,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            let credential: ExecCredential = serde_json::from_slice(&output.stdout)?;
+            credential.status.and_then(|status| status.token)
+        }
+        // Tokens from an auth provider are used until they expire; they aren't refreshed.
+        (None, None, None, Some(provider)) => provider.config.get("access-token").cloned(),
+        (None, None, None, None) => None,
+    };
+    Ok(match (token, &user.username, &user.password) {
+        (Some(token), ..) => Some(format!("Bearer {}", token)),
+        (None, Some(username), Some(password)) => Some(format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", username, password))
+        )),
+        _ => None,
+    })
+}
+
+/// The user's client certificate and key, if it authenticates with them.
+fn client_identity(user: &AuthInfo) -> Result<Option<reqwest::Identity>, Error> {
+    let failed = |error: &dyn std::fmt::Display| {
+        Error::Config(format!("failed to load the client certificate: {}", error))
+    };
+    let cert = data_or_file(&user.client_certificate_data, &user.client_certificate)?;
+    let key = data_or_file(&user.client_key_data, &user.client_key)?;
+    let (cert, key) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let cert = X509::from_pem(&cert).map_err(|error| failed(&error))?;
+    let key = PKey::private_key_from_pem(&key).map_err(|error| failed(&error))?;
+    let archive = Pkcs12::builder()
+        .build(" ", "kubeconfig", &key, &cert)
+        .and_then(|archive| archive.to_der())
+        .map_err(|error| failed(&error))?;
+    reqwest::Identity::from_pkcs12_der(&archive, " ")
+        .map(Some)
+        .map_err(|error| failed(&error))
+}
+
+/// The base64-encoded data, or else the contents of the file.
+fn data_or_file(data: &Option<String>, file: &Option<String>) -> Result<Option<Vec<u8>>, Error> {
+    match (data, file) {
+        (Some(data), _) => base64::decode(data)
+            .map(Some)
+            .map_err(|error| Error::Config(format!("invalid base64 in the kubeconfig: {}", error))),
+        (None, Some(file)) => fs::read(file)
+            .map(Some)
+            .map_err(|error| Error::Config(format!("failed to read {}: {}", file, error))),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn impersonates_users() {
+        let headers = headers(&Impersonation {
+            user: "system:serviceaccount:ops:updater".to_string(),
+            groups: vec!["system:authenticated".to_string(), "ops".to_string()],
+        })
+        .expect("valid headers");
+        assert_eq!(
+            headers["Impersonate-User"],
+            "system:serviceaccount:ops:updater"
+        );
+        assert_eq!(
+            headers
+                .get_all("Impersonate-Group")
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["system:authenticated", "ops"]
+        );
+        assert!(super::headers(&Impersonation {
+            user: "line\nbreak".to_string(),
+            groups: Vec::new(),
+        })
+        .is_err());
+
+        let user: AuthInfo =
+            serde_yaml::from_str("username: admin\npassword: s3cret\n").expect("valid user");
+        assert_eq!(
+            authorization(&user).expect("authorization").as_deref(),
+            Some("Basic YWRtaW46czNjcmV0")
+        );
+        let user: AuthInfo =
+            serde_yaml::from_str("token: abc\nusername: admin\npassword: x\n").expect("valid user");
+        assert_eq!(
+            authorization(&user).expect("authorization").as_deref(),
+            Some("Bearer abc")
+        );
+    }
+
+    #[test]
+    fn loads_the_current_context() {
+        let dir = std::env::temp_dir().join(format!(
+            "openshift-update-kubeconfig-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).expect("create directory");
+        let path = dir.join("kubeconfig");
+        std::fs::write(
+            &path,
+            r#"
+apiVersion: v1
+kind: Config
+current-context: admin
+clusters:
+- name: prod
+  cluster:
+    server: https://api.prod.example.com:6443
+    insecure-skip-tls-verify: true
+contexts:
+- name: admin
+  context:
+    cluster: prod
+    user: admin
+users:
+- name: admin
+  user:
+    token: s3cret
+"#,
+        )
+        .expect("write kubeconfig");
+        let impersonation = Impersonation {
+            user: "updater".to_string(),
+            groups: Vec::new(),
+        };
+        let config = load_as(&path, &impersonation).expect("valid kubeconfig");
+        assert_eq!(config.base_path, "https://api.prod.example.com:6443");
+
+        std::fs::write(
+            &path,
+            "current-context: missing\nclusters: []\ncontexts: []\nusers: []\n",
+        )
+        .expect("write kubeconfig");
+        assert!(load_as(&path, &impersonation).is_err());
+    }
+
+    #[test]
+    fn loads_the_service_account() {
+        use openssl::asn1::Asn1Time;
+        use openssl::hash::MessageDigest;
+        use openssl::rsa::Rsa;
+        use openssl::x509::X509NameBuilder;
+
+        let dir = std::env::temp_dir().join(format!(
+            "openshift-update-serviceaccount-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).expect("create directory");
+        let impersonation = Impersonation {
+            user: "updater".to_string(),
+            groups: Vec::new(),
+        };
+        let server = "https://172.30.0.1:443".to_string();
+        assert!(load_in_cluster_as(server.clone(), &dir, &impersonation).is_err());
+
+        let key = PKey::from_rsa(Rsa::generate(2048).expect("key")).expect("key");
+        let mut name = X509NameBuilder::new().expect("name");
+        name.append_entry_by_text("CN", "kube-apiserver")
+            .expect("name");
+        let name = name.build();
+        let mut ca = X509::builder().expect("certificate");
+        ca.set_subject_name(&name).expect("subject");
+        ca.set_issuer_name(&name).expect("issuer");
+        ca.set_pubkey(&key).expect("key");
+        ca.set_not_before(&Asn1Time::days_from_now(0).expect("time"))
+            .expect("not before");
+        ca.set_not_after(&Asn1Time::days_from_now(1).expect("time"))
+            .expect("not after");
+        ca.sign(&key, MessageDigest::sha256()).expect("sign");
+        std::fs::write(dir.join("ca.crt"), ca.build().to_pem().expect("PEM"))
+            .expect("write certificate");
+        std::fs::write(dir.join("token"), "s3cret\n").expect("write token");
+
+        let config = load_in_cluster_as(server, &dir, &impersonation).expect("valid configuration");
+        assert_eq!(config.base_path, "https://172.30.0.1:443");
+    }
+}
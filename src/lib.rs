@@ -0,0 +1,112 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The decision logic of openshift-update, for embedding in other tools.
+//!
+//! The binary runs the operator on top of these modules, reconciling the local ClusterVersion or
+//! (with --acm, --hosted-clusters or --ocm) those of a fleet; nothing here depends on how it's
+//! run. Another tool can make the same decisions by evaluating a cluster's available updates under
+//! a chain of policies:
+//!
+//! ```
+//! use openshift_update::clusterversion::ClusterVersion;
+//! use openshift_update::policy::{self, ClusterState, Decision, UpgradePolicy};
+//!
+//! let version: ClusterVersion = serde_json::from_str(include_str!(
+//!     "../tests/fixtures/clusterversion-available.json"
+//! ))?;
+//! let policy = policy::RequireApproval {
+//!     inner: Box::new(policy::Latest),
+//! };
+//! let candidates = version
+//!     .status
+//!     .as_ref()
+//!     .and_then(|status| status.available_updates.clone())
+//!     .unwrap_or_default();
+//! let state = ClusterState::new(&version, chrono::Utc::now());
+//! match policy.evaluate(&state, &candidates) {
+//!     Decision::AwaitingApproval { update } => println!("{} awaits approval", update.version),
+//!     decision => panic!("unexpected decision {:?}", decision),
+//! }
+//! # Ok::<(), serde_json::Error>(())
+//! ```
+//!
+//! The modules, by what they're for:
+//!
+//! - `clusterversion` models the ClusterVersion and the other objects the operator reads, and
+//!   `state` what it has to remember between decisions.
+//! - `policy` selects the update to apply and when, and `gates` holds back an update regardless of
+//!   which one was selected. `velocity`, `window`, `rollout`, `steps`, `storage`, `credentials` and
+//!   `slo` are the policies built on them.
+//! - `health` checks the workloads running on the cluster, with `workloads`, `capacity`, `dns`,
+//!   `argocd` and `baremetal` as its checks.
+//! - `graph` finds the updates available from the update service, which `cincinnati` fetches, and
+//!   `release` what changes between two releases.
+//! - `notify` reports what happened, through `matrix`, `googlechat`, `opsgenie` and `snmp`, and
+//!   `metrics` and `durations` measure it.
+//!
+//! `cincinnati` is left out of builds without the cincinnati feature, and the notifiers out of
+//! those without the notifications feature.
+//! - `kubeapi`, `kubeconfig`, `protobuf` and `ratelimit` are how the Kubernetes API is called, and
+//!   `http`, `secret` and `vault` how anything else is. `retry`, `clock` and `identity` are shared
+//!   by all of them.
+
+#[macro_use]
+extern crate log;
+
+pub mod argocd;
+pub mod baremetal;
+pub mod capacity;
+#[cfg(feature = "cincinnati")]
+pub mod cincinnati;
+pub mod clock;
+pub mod clusterversion;
+pub mod credentials;
+pub mod dns;
+pub mod durations;
+mod error;
+pub mod gates;
+#[cfg(feature = "notifications")]
+pub mod googlechat;
+pub mod graph;
+pub mod health;
+pub mod http;
+pub mod identity;
+pub mod kubeapi;
+pub mod kubeconfig;
+#[cfg(feature = "notifications")]
+pub mod matrix;
+pub mod metrics;
+pub mod notify;
+#[cfg(feature = "notifications")]
+pub mod opsgenie;
+pub mod policy;
+pub mod protobuf;
+pub mod ratelimit;
+pub mod release;
+pub mod retry;
+pub mod rollout;
+pub mod secret;
+pub mod slo;
+#[cfg(feature = "notifications")]
+pub mod snmp;
+pub mod state;
+pub mod steps;
+pub mod storage;
+pub mod vault;
+pub mod velocity;
+pub mod window;
+pub mod workloads;
+
+pub use error::Error;
@@ -15,73 +15,91 @@
 #[macro_use]
 extern crate log;
 
-use chrono::{DateTime, Utc};
-use kube::api::{self, Api, PatchParams, Reflector};
+#[cfg(feature = "fleet")]
+mod acm;
+mod admission;
+mod channel;
+mod checkconfig;
+mod command;
+mod explain;
+#[cfg(feature = "fleet")]
+mod fleet;
+mod gitops;
+#[cfg(feature = "metrics-server")]
+mod grpc;
+mod history;
+#[cfg(feature = "fleet")]
+mod hypershift;
+mod manifests;
+mod manual;
+mod multiarch;
+#[cfg(feature = "fleet")]
+mod ocm;
+mod operate;
+mod options;
+mod output;
+mod ownership;
+mod plan;
+mod policyconfig;
+mod preflight;
+mod recommend;
+mod reconciler;
+mod record;
+mod remote;
+mod selfupdate;
+#[cfg(feature = "metrics-server")]
+mod server;
+mod simulate;
+mod snooze;
+mod status;
+#[cfg(feature = "metrics-server")]
+mod tls;
+mod verify;
+mod watch;
+
 use kube::client::APIClient;
-use kube::config;
 use log::LevelFilter;
-use std::cmp::Ordering;
+use openshift_update::clusterversion;
+#[cfg(feature = "notifications")]
+use openshift_update::googlechat::GoogleChat;
+use openshift_update::identity;
+use openshift_update::kubeconfig;
+#[cfg(feature = "notifications")]
+use openshift_update::matrix;
+#[cfg(feature = "notifications")]
+use openshift_update::notify;
+#[cfg(feature = "notifications")]
+use openshift_update::opsgenie::Opsgenie;
+use openshift_update::protobuf;
+use openshift_update::ratelimit;
+use openshift_update::retry;
+#[cfg(any(feature = "metrics-server", feature = "notifications"))]
+use openshift_update::secret::SecretFile;
+#[cfg(feature = "notifications")]
+use openshift_update::snmp::Snmp;
+#[cfg(feature = "metrics-server")]
+use openshift_update::state::ConfigMapStore;
+use openshift_update::vault::{self, Vault};
+use openshift_update::Error;
+use operate::{operate, progress, EXIT_TIMEOUT};
+use options::{args, command_line, Options};
+use policyconfig::Live;
+use status::Status;
+use std::env;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
-#[derive(StructOpt)]
-struct Options {
-    #[structopt(long = "force")]
-    /// Forcefully apply available updates
-    pub force: bool,
-
-    #[structopt(short = "v", parse(from_occurrences))]
-    /// Verbosity level (can be set multiple times)
-    pub verbosity: u64,
-}
-
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
-struct ClusterVersionSpec {
-    #[serde(rename = "desiredUpdate", default)]
-    desired_update: Option<ClusterUpdate>,
-}
-
-#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
-struct ClusterVersionStatus {
-    #[serde(rename = "availableUpdates")]
-    available_updates: Option<Vec<ClusterUpdate>>,
-    history: Vec<HistoricalEntry>,
-}
-
-#[derive(Clone, Debug, Eq, serde::Deserialize, serde::Serialize)]
-struct ClusterUpdate {
-    force: bool,
-    image: String,
-    version: semver::Version,
-}
-
-impl Ord for ClusterUpdate {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.version.cmp(&other.version)
-    }
-}
-
-impl PartialOrd for ClusterUpdate {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for ClusterUpdate {
-    fn eq(&self, other: &Self) -> bool {
-        self.version == other.version
-    }
-}
-
-#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
-struct HistoricalEntry {
-    #[serde(rename = "completionTime")]
-    completion_time: Option<DateTime<Utc>>,
-}
-
-type ClusterVersion = api::Object<ClusterVersionSpec, ClusterVersionStatus>;
-
-fn main() -> Result<(), kube::Error> {
-    let options = Options::from_args();
+fn main() -> Result<(), Error> {
+    let options = match args(env::args_os().collect(), |name| env::var(name).ok()) {
+        Ok(args) => Arc::new(Options::from_iter(args)),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(1);
+        }
+    };
 
     env_logger::Builder::from_default_env()
         .filter(
@@ -94,79 +112,271 @@ fn main() -> Result<(), kube::Error> {
             },
         )
         .init();
+    ratelimit::configure(options.kube_api_qps, options.kube_api_burst);
+    protobuf::configure(options.kube_api_content_type == "protobuf");
+    kubeconfig::impersonate(
+        options.impersonate_user.clone(),
+        options.impersonate_groups.clone(),
+    );
+
+    if let Some(command) = &options.command {
+        return command::run(command, &options);
+    }
 
-    let client = Api::<ClusterVersion>::customResource(
-        APIClient::new(config::load_kube_config()?),
-        "clusterversions",
-    )
-    .group("config.openshift.io")
-    .version("v1");
+    if let Some(dir) = &options.replay {
+        return record::replay(&options, dir);
+    }
 
-    let reflector = Reflector::new(client.clone())
-        .fields("metadata.name==version")
-        .init()?;
-    loop {
-        if let Err(error) = reflector.poll() {
-            error!("Failed to poll reflector: {}", error);
+    if options.interactive && !atty::is(atty::Stream::Stdin) {
+        error!("--interactive requires a terminal");
+        process::exit(1);
+    }
+    if let Some((option, feature)) = checkconfig::unsupported(&options).first() {
+        error!(
+            "{} requires the {} feature, which this build doesn't include",
+            option, feature
+        );
+        process::exit(1);
+    }
+
+    let mut client = APIClient::new(kubeconfig::load()?);
+    if let (Some(addr), Some(role)) = (&options.vault_addr, &options.vault_role) {
+        let vault = Vault {
+            http: options.http(&client)?,
+            addr: addr.clone(),
+            role: role.clone(),
+            mount: options.vault_auth_mount.clone(),
+            backoff: options.backoff(),
+        };
+        // The files have to be written before anything reads its credentials from them.
+        let refresh = match vault.sync(&options.vault_secrets) {
+            Ok(refresh) => refresh,
+            Err(error) => {
+                error!("Failed to fetch the secrets from Vault: {}", error);
+                process::exit(1);
+            }
+        };
+        let mappings = options.vault_secrets.clone();
+        thread::spawn(move || vault::schedule(&vault, &mappings, refresh));
+    }
+    let local = !(options.acm || options.hosted_clusters || options.ocm);
+    if local {
+        clusterversion::check_api_version(&client)?;
+    }
+    let single_node = local && clusterversion::single_node(&client);
+    if single_node {
+        info!("Detected a single-node cluster");
+    }
+    if local {
+        identity::set_infrastructure_name(clusterversion::infrastructure_name(&client));
+    }
+    #[cfg(feature = "notifications")]
+    if local {
+        register_notifiers(&options, &client)?;
+    }
+
+    let status = Arc::new(Mutex::new(Status {
+        single_node,
+        ready: true,
+        ..Default::default()
+    }));
+    if let Some(timeout) = options.timeout {
+        let status = status.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            error!(
+                "Timed out after {}: {}",
+                humantime::format_duration(timeout),
+                progress(&status.lock().expect("status lock"))
+            );
+            process::exit(EXIT_TIMEOUT);
+        });
+    }
+    #[cfg(feature = "metrics-server")]
+    if options.listen.is_some() || options.grpc_listen.is_some() {
+        let token = match &options.api_token_file {
+            Some(path) => SecretFile::new(path),
+            None => {
+                return Err(Error::Config(
+                    "--listen and --grpc-listen require --api-token-file".to_string(),
+                ))
+            }
+        };
+        if let Err(error) = token.read() {
+            error!("Failed to read API token: {}", error);
+            process::exit(1);
+        }
+        // Approvals are saved along with the rest of the operator's state.
+        let store = |client: &APIClient| {
+            options
+                .state_namespace
+                .as_ref()
+                .map(|namespace| ConfigMapStore::new(client.clone(), namespace, options.backoff()))
+        };
+        if let Some(addr) = options.listen {
+            if let Err(error) = server::spawn(
+                addr,
+                token.clone(),
+                client.clone(),
+                options.cluster_version_name.clone(),
+                status.clone(),
+                !local,
+                store(&client),
+            ) {
+                error!("Failed to start API server: {}", error);
+                process::exit(1);
+            }
         }
+        if let Some(addr) = options.grpc_listen {
+            if let Err(error) = grpc::spawn(
+                addr,
+                token,
+                client.clone(),
+                options.cluster_version_name.clone(),
+                status.clone(),
+                !local,
+                store(&client),
+            ) {
+                error!("Failed to start gRPC server: {}", error);
+                process::exit(1);
+            }
+        }
+    }
+    #[cfg(feature = "metrics-server")]
+    if let Some(addr) = options.webhook_listen.filter(|_| local) {
+        let identity = match (&options.webhook_cert, &options.webhook_key) {
+            (Some(cert), Some(key)) => tls::Identity {
+                cert: cert.clone(),
+                key: key.clone(),
+            },
+            _ => {
+                return Err(Error::Config(
+                    "--webhook-listen requires --webhook-cert and --webhook-key".to_string(),
+                ))
+            }
+        };
+        if let Err(error) =
+            server::spawn_webhooks(addr, identity, options.policy_config_map.clone())
+        {
+            error!("Failed to start the admission webhooks: {}", error);
+            process::exit(1);
+        }
+    }
 
-        match reflector.read() {
-            Ok(mut versions) => {
-                let version = match versions.pop() {
-                    Some(version) => version,
-                    None => {
-                        error!("Unable to find ClusterVersion");
-                        continue;
-                    }
-                };
-
-                if let Some(status) = &version.status {
-                    if let Some(latest) = status.history.first() {
-                        if latest.completion_time.is_none() {
-                            debug!("Waiting for update to complete...");
-                            continue;
-                        }
-                    }
-                }
+    let live = Arc::new(Live::new(options.clone()));
+    if let Some(reference) = options.policy_config_map.clone().filter(|_| local) {
+        let live = live.clone();
+        thread::spawn(move || policyconfig::watch(&live, &reference, command_line));
+    }
+
+    if let Some(repo) = options.gitops_repo.clone().filter(|_| local) {
+        let options = options.clone();
+        thread::spawn(move || gitops::schedule(&options, &repo));
+    }
+
+    if let Some(image) = options.self_update_image.clone().filter(|_| local) {
+        let options = options.clone();
+        thread::spawn(move || selfupdate::schedule(&options, &image, options.self_update_interval));
+    }
+
+    if let Some(interval) = options.preflight_interval.filter(|_| local) {
+        let options = options.clone();
+        thread::spawn(move || preflight::schedule(&options, interval));
+    }
 
-                if let Err(error) = apply_available_update(&client, &options, version) {
-                    error!("Failed to apply update: {}", error)
+    // Bound service account tokens expire and client certificates are rotated, both of which are
+    // only read when the client is created. Rather than dying, the client is recreated from the
+    // current credentials whenever the API server stops accepting them.
+    let mut rejections = 0;
+    loop {
+        let started = Instant::now();
+        let result = if local {
+            operate(client, &live, &status, single_node)
+        } else {
+            fleet(client, &options, &status)
+        };
+        match result {
+            Err(ref error) if retry::unauthorized(error) => {
+                // Only back off if the last credentials were rejected soon after being loaded.
+                if started.elapsed() > options.backoff_max {
+                    rejections = 0;
                 }
+                let delay = options.backoff().delay(rejections);
+                warn!(
+                    "Credentials were rejected (reloading in {}): {}",
+                    humantime::format_duration(Duration::from_secs(delay.as_secs())),
+                    error
+                );
+                thread::sleep(delay);
+                rejections += 1;
+                client = APIClient::new(kubeconfig::load()?);
             }
-            Err(error) => error!("Failed to read ClusterVersion: {}", error),
+            result => return result,
         }
     }
 }
 
-fn apply_available_update(
-    client: &Api<ClusterVersion>,
-    options: &Options,
-    version: ClusterVersion,
-) -> Result<(), kube::Error> {
-    trace!("{:?}", version.status);
+/// Manage the updates of a fleet of clusters, in whichever of the fleet modes is enabled.
+#[cfg(feature = "fleet")]
+fn fleet(client: APIClient, options: &Options, status: &Arc<Mutex<Status>>) -> Result<(), Error> {
+    if options.acm {
+        acm::run(client, options, status)
+    } else if options.hosted_clusters {
+        hypershift::run(client, options, status)
+    } else {
+        ocm::run(client, options, status)
+    }
+}
 
-    let update = match version.status.and_then(|status| status.available_updates) {
-        Some(updates) => updates.into_iter().max(),
-        None => return Ok(()),
-    };
+#[cfg(not(feature = "fleet"))]
+fn fleet(_: APIClient, _: &Options, _: &Arc<Mutex<Status>>) -> Result<(), Error> {
+    Err(Error::Config(
+        "--acm, --hosted-clusters and --ocm require the fleet feature, which this build doesn't \
+         include"
+            .to_string(),
+    ))
+}
 
-    if let Some(mut update) = update {
-        update.force = options.force;
-        info!("Attempting to update to {}", update.version);
-        client.patch(
-            "version",
-            &PatchParams::default(),
-            serde_json::to_vec(&ClusterVersion {
-                types: version.types,
-                metadata: version.metadata,
-                spec: ClusterVersionSpec {
-                    desired_update: Some(update),
-                },
-                status: None,
-            })
-            .expect("Serialize to JSON"),
-        )?;
+/// Send events to each of the destinations which are configured.
+#[cfg(feature = "notifications")]
+fn register_notifiers(options: &Options, client: &APIClient) -> Result<(), Error> {
+    if let (Some(homeserver), Some(token), Some(room)) = (
+        &options.matrix_homeserver,
+        &options.matrix_token_file,
+        &options.matrix_room,
+    ) {
+        notify::register(Box::new(matrix::Matrix {
+            http: options.http(client)?,
+            homeserver: homeserver.clone(),
+            token: SecretFile::new(token),
+            room: room.clone(),
+            backoff: options.backoff(),
+        }));
+    }
+    if let Some(webhook) = &options.google_chat_webhook_file {
+        notify::register(Box::new(GoogleChat {
+            http: options.http(client)?,
+            webhook: SecretFile::new(webhook),
+            backoff: options.backoff(),
+        }));
+    }
+    if let Some(key) = &options.opsgenie_api_key_file {
+        notify::register(Box::new(Opsgenie {
+            http: options.http(client)?,
+            url: options.opsgenie_url.clone(),
+            key: SecretFile::new(key),
+            backoff: options.backoff(),
+        }));
+    }
+    if let Some(target) = &options.snmp_target {
+        notify::register(Box::new(Snmp {
+            target: target.clone(),
+            community: options.snmp_community.clone(),
+            enterprise: options.snmp_enterprise_oid.parse().map_err(|error| {
+                Error::Config(format!("invalid --snmp-enterprise-oid: {}", error))
+            })?,
+            started: Instant::now(),
+        }));
     }
-
     Ok(())
 }
@@ -0,0 +1,975 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Manifests for running the operator in the cluster it updates.
+//!
+//! The permissions are the least the operator needs when managing its own cluster: reading and
+//! patching the ClusterVersion, reading the cluster's topology and proxy configuration (along
+//! with the proxy's trusted CA bundle), and keeping its state in a ConfigMap of its namespace
+//! (and recording Events there). Each request the operator makes has to be allowed by one of the
+//! rules, which the tests check against the list of requests below them; a change making a new
+//! request adds both the rule and the request.
+//!
+//! With `--monitoring`, a Service, ServiceMonitor and PrometheusRule are added, so that the
+//! cluster's Prometheus scrapes the operator and alerts when updates stall or are held back, when
+//! routes are unreachable after an update, or when the operator itself keeps crashing.
+//!
+//! With `--webhooks`, the operator also serves its admission webhooks, and a Service and a
+//! ValidatingWebhookConfiguration are added for the API server to call them on edits of the
+//! ClusterVersion (and, with `--policy-config-map`, of the policy). The webhooks'
+//! certificate is issued by the cluster's service CA, which also injects its bundle into the
+//! configuration, unless `--webhook-ca-file` gives the CA of a certificate the admin provides.
+
+use crate::selfupdate;
+use openshift_update::baremetal;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Name shared by the operator's objects.
+const NAME: &str = "openshift-update";
+
+/// Secret holding the bearer token of the operator's API, which is left to the admin to create.
+const TOKEN_SECRET: &str = "openshift-update-api-token";
+
+/// ServiceAccount of the cluster's Prometheus.
+const PROMETHEUS: &str = "prometheus-k8s";
+
+/// Port on which the operator serves its API, probes and metrics.
+pub(crate) const PORT: u16 = 8080;
+
+/// Port on which the operator serves its admission webhooks.
+const WEBHOOK_PORT: u16 = 8443;
+
+/// Service through which the API server calls the webhooks.
+const WEBHOOK_SERVICE: &str = "openshift-update-webhook";
+
+/// Secret holding the webhooks' certificate and key (as tls.crt and tls.key).
+const WEBHOOK_SECRET: &str = "openshift-update-webhook-tls";
+
+#[derive(StructOpt)]
+pub struct GenerateManifests {
+    #[structopt(long = "image")]
+    /// Image of the operator to run
+    pub image: String,
+
+    #[structopt(long = "namespace", default_value = "openshift-update")]
+    /// Namespace to run the operator in
+    pub namespace: String,
+
+    #[structopt(long = "monitoring")]
+    /// Also print a ServiceMonitor for the operator's metrics and a PrometheusRule alerting on
+    /// them (requires the Prometheus Operator)
+    pub monitoring: bool,
+
+    #[structopt(
+        long = "stalled-alert-after",
+        default_value = "4h",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How long an update may run before the UpgradeStalled alert fires
+    pub stalled_alert_after: Duration,
+
+    #[structopt(
+        long = "blocked-alert-after",
+        default_value = "7d",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How long an update may be held back before the UpgradeBlockedTooLong alert fires
+    pub blocked_alert_after: Duration,
+
+    #[structopt(long = "policy-config-map")]
+    /// Name of the ConfigMap, in the operator's namespace, to follow the policy of
+    pub policy_config_map: Option<String>,
+
+    #[structopt(long = "webhooks")]
+    /// Also serve the admission webhooks, and print the ValidatingWebhookConfiguration which
+    /// calls them
+    pub webhooks: bool,
+
+    #[structopt(
+        long = "webhook-ca-file",
+        requires = "webhooks",
+        parse(try_from_str = ca_bundle)
+    )]
+    /// PEM file holding the CA which issued the certificate in the openshift-update-webhook-tls
+    /// Secret, which the admin then creates (by default, the service CA issues the certificate)
+    pub webhook_ca_bundle: Option<String>,
+}
+
+/// The base64-encoded contents of a file of PEM certificates, which is how the API server is
+/// given the CAs to trust.
+fn ca_bundle(path: &str) -> Result<String, String> {
+    let bundle =
+        std::fs::read(path).map_err(|error| format!("unable to read {}: {}", path, error))?;
+    if !String::from_utf8_lossy(&bundle).contains("-----BEGIN CERTIFICATE-----") {
+        return Err(format!("{} doesn't hold a PEM certificate", path));
+    }
+    Ok(base64::encode(&bundle))
+}
+
+pub fn run(command: &GenerateManifests) {
+    print!("{}", render(&manifests(command)));
+}
+
+/// A multi-document YAML stream.
+pub(crate) fn render(manifests: &[serde_json::Value]) -> String {
+    manifests
+        .iter()
+        .map(|manifest| serde_yaml::to_string(manifest).expect("Serialize to YAML") + "\n")
+        .collect()
+}
+
+fn manifests(command: &GenerateManifests) -> Vec<serde_json::Value> {
+    let namespace = command.namespace.as_str();
+    let labels = serde_json::json!({ "app": NAME });
+    let mut manifests = vec![
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Namespace",
+            "metadata": { "name": namespace },
+        }),
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ServiceAccount",
+            "metadata": { "name": NAME, "namespace": namespace },
+        }),
+        serde_json::json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "ClusterRole",
+            "metadata": { "name": NAME },
+            "rules": [
+                {
+                    "apiGroups": ["config.openshift.io"],
+                    "resources": ["clusterversions"],
+                    "verbs": ["get", "list", "watch", "patch"],
+                },
+                {
+                    "apiGroups": ["config.openshift.io"],
+                    "resources": ["infrastructures", "proxies", "authentications"],
+                    "verbs": ["get"],
+                },
+                {
+                    "apiGroups": ["operator.openshift.io"],
+                    "resources": ["cloudcredentials"],
+                    "verbs": ["get"],
+                },
+                // The applications checked by --argocd-project.
+                {
+                    "apiGroups": ["argoproj.io"],
+                    "resources": ["applications"],
+                    "verbs": ["list"],
+                },
+                // The pods checked by --max-stuck-pods, in any namespace.
+                {
+                    "apiGroups": [""],
+                    "resources": ["pods"],
+                    "verbs": ["list"],
+                },
+                // The provisioners checked against --storage-matrix, and (with --check-capacity)
+                // the classes whose claims wait for a pod.
+                {
+                    "apiGroups": ["storage.k8s.io"],
+                    "resources": ["storageclasses", "csidrivers"],
+                    "verbs": ["list"],
+                },
+                // What --verify-updates checks once an update completes: the ClusterOperators,
+                // the nodes, and the ingress canary's route along with those of --verify-route.
+                // --check-dns reads the DNS ClusterOperator too, the status lists the versions of
+                // them all, and `watch` follows them.
+                {
+                    "apiGroups": ["config.openshift.io"],
+                    "resources": ["clusteroperators"],
+                    "verbs": ["get", "list", "watch"],
+                },
+                {
+                    "apiGroups": [""],
+                    "resources": ["nodes"],
+                    "verbs": ["list"],
+                },
+                {
+                    "apiGroups": ["route.openshift.io"],
+                    "resources": ["routes"],
+                    "verbs": ["get"],
+                },
+                // The quotas and claims checked by --check-capacity, in any namespace.
+                {
+                    "apiGroups": [""],
+                    "resources": ["resourcequotas", "persistentvolumeclaims"],
+                    "verbs": ["list"],
+                },
+                // The MachineConfigPools, which are timed through each update and show when the
+                // nodes have finished, and which `watch` follows.
+                {
+                    "apiGroups": ["machineconfiguration.openshift.io"],
+                    "resources": ["machineconfigpools"],
+                    "verbs": ["get", "list", "watch"],
+                },
+            ],
+        }),
+        serde_json::json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "ClusterRoleBinding",
+            "metadata": { "name": NAME },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "ClusterRole",
+                "name": NAME,
+            },
+            "subjects": [{ "kind": "ServiceAccount", "name": NAME, "namespace": namespace }],
+        }),
+    ];
+    manifests.extend(role(
+        namespace,
+        namespace,
+        serde_json::json!([
+            {
+                "apiGroups": [""],
+                "resources": ["configmaps"],
+                "resourceNames": [openshift_update::state::CONFIG_MAP],
+                "verbs": ["get", "update"],
+            },
+            // The state is created on the first save, and creation can't be limited by name.
+            {
+                "apiGroups": [""],
+                "resources": ["configmaps"],
+                "verbs": ["create"],
+            },
+            // The policy of --policy-config-map, which is kept in this namespace. The admin names
+            // it, so this can't be limited by name either.
+            {
+                "apiGroups": [""],
+                "resources": ["configmaps"],
+                "verbs": ["get", "watch"],
+            },
+            // With --self-update-image, the operator's own Deployment is moved to each new
+            // image, which is staged in a Pod of its own first.
+            {
+                "apiGroups": ["apps"],
+                "resources": ["deployments"],
+                "resourceNames": [NAME],
+                "verbs": ["get", "patch"],
+            },
+            {
+                "apiGroups": [""],
+                "resources": ["pods"],
+                "resourceNames": [selfupdate::STAGING_POD],
+                "verbs": ["get", "delete"],
+            },
+            {
+                "apiGroups": [""],
+                "resources": ["pods"],
+                "verbs": ["create"],
+            },
+            // Events about the ConfigMaps in the namespace, such as a policy which was rejected.
+            {
+                "apiGroups": [""],
+                "resources": ["events"],
+                "verbs": ["create"],
+            },
+        ]),
+    ));
+    // The proxy's trusted CA bundle.
+    manifests.extend(role(
+        "openshift-config",
+        namespace,
+        serde_json::json!([{
+            "apiGroups": [""],
+            "resources": ["configmaps"],
+            "verbs": ["get"],
+        }]),
+    ));
+    // The hosts checked by --check-bare-metal-hosts.
+    manifests.extend(role(
+        baremetal::NAMESPACE,
+        namespace,
+        serde_json::json!([{
+            "apiGroups": ["metal3.io"],
+            "resources": ["baremetalhosts"],
+            "verbs": ["get", "list"],
+        }]),
+    ));
+    let mut deployment = serde_json::json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": { "name": NAME, "namespace": namespace },
+        "spec": {
+            // Two copies would race each other, so the old one has to be gone first.
+            "replicas": 1,
+            "strategy": { "type": "Recreate" },
+            "selector": { "matchLabels": labels },
+            "template": {
+                "metadata": { "labels": labels },
+                "spec": {
+                    "serviceAccountName": NAME,
+                    "containers": [{
+                        "name": NAME,
+                        "image": command.image,
+                        "args": [
+                            "--state-namespace", namespace,
+                            "--listen", format!("0.0.0.0:{}", PORT),
+                            "--api-token-file", "/etc/openshift-update/token",
+                            "-v",
+                        ],
+                        "ports": [{ "name": "http", "containerPort": PORT }],
+                        "readinessProbe": {
+                            "httpGet": { "path": "/readyz", "port": "http" },
+                        },
+                        "livenessProbe": {
+                            "tcpSocket": { "port": "http" },
+                        },
+                        "volumeMounts": [{
+                            "name": "api-token",
+                            "mountPath": "/etc/openshift-update",
+                            "readOnly": true,
+                        }],
+                    }],
+                    "volumes": [{
+                        "name": "api-token",
+                        "secret": { "secretName": TOKEN_SECRET },
+                    }],
+                },
+            },
+        },
+    });
+    let container = &mut deployment["spec"]["template"]["spec"]["containers"][0];
+    if let Some(name) = &command.policy_config_map {
+        extend(
+            &mut container["args"],
+            serde_json::json!(["--policy-config-map", format!("{}/{}", namespace, name)]),
+        );
+    }
+    if command.webhooks {
+        extend(
+            &mut container["args"],
+            serde_json::json!([
+                "--webhook-listen",
+                format!("0.0.0.0:{}", WEBHOOK_PORT),
+                "--webhook-cert",
+                "/etc/openshift-update/webhook/tls.crt",
+                "--webhook-key",
+                "/etc/openshift-update/webhook/tls.key",
+            ]),
+        );
+        extend(
+            &mut container["ports"],
+            serde_json::json!([{ "name": "https", "containerPort": WEBHOOK_PORT }]),
+        );
+        extend(
+            &mut container["volumeMounts"],
+            serde_json::json!([{
+                "name": "webhook-tls",
+                "mountPath": "/etc/openshift-update/webhook",
+                "readOnly": true,
+            }]),
+        );
+        extend(
+            &mut deployment["spec"]["template"]["spec"]["volumes"],
+            serde_json::json!([{
+                "name": "webhook-tls",
+                "secret": { "secretName": WEBHOOK_SECRET },
+            }]),
+        );
+    }
+    manifests.push(deployment);
+    if command.monitoring {
+        // The cluster's Prometheus only looks for ServiceMonitors in namespaces labelled so.
+        manifests[0]["metadata"]["labels"] =
+            serde_json::json!({ "openshift.io/cluster-monitoring": "true" });
+        manifests.extend(monitoring(command));
+    }
+    if command.webhooks {
+        manifests.extend(webhooks(command));
+    }
+    manifests
+}
+
+/// Append the items of `more` to the array `list`.
+fn extend(list: &mut serde_json::Value, more: serde_json::Value) {
+    if let (Some(list), serde_json::Value::Array(more)) = (list.as_array_mut(), more) {
+        list.extend(more);
+    }
+}
+
+/// The Service through which the API server calls the webhooks, and the configuration telling it
+/// to.
+fn webhooks(command: &GenerateManifests) -> Vec<serde_json::Value> {
+    let namespace = command.namespace.as_str();
+    let mut service = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Service",
+        "metadata": { "name": WEBHOOK_SERVICE, "namespace": namespace },
+        "spec": {
+            "selector": { "app": NAME },
+            "ports": [{ "name": "https", "port": 443, "targetPort": "https" }],
+        },
+    });
+    let mut configuration = serde_json::json!({
+        "apiVersion": "admissionregistration.k8s.io/v1",
+        "kind": "ValidatingWebhookConfiguration",
+        "metadata": { "name": NAME },
+        "webhooks": [],
+    });
+    if command.webhook_ca_bundle.is_none() {
+        service["metadata"]["annotations"] = serde_json::json!({
+            "service.beta.openshift.io/serving-cert-secret-name": WEBHOOK_SECRET,
+        });
+        configuration["metadata"]["annotations"] = serde_json::json!({
+            "service.beta.openshift.io/inject-cabundle": "true",
+        });
+    }
+    let webhook = |name: &str, path: &str, rules: serde_json::Value| {
+        let mut client = serde_json::json!({
+            "service": {
+                "namespace": namespace,
+                "name": WEBHOOK_SERVICE,
+                "path": path,
+                "port": 443,
+            },
+        });
+        if let Some(bundle) = &command.webhook_ca_bundle {
+            client["caBundle"] = bundle.as_str().into();
+        }
+        serde_json::json!({
+            "name": name,
+            "clientConfig": client,
+            "rules": rules,
+            "admissionReviewVersions": ["v1"],
+            "sideEffects": "None",
+            // The webhooks only catch mistakes, so they mustn't block changes while the operator
+            // is down (which, for the ConfigMaps in its namespace, includes its own state).
+            "failurePolicy": "Ignore",
+            "timeoutSeconds": 5,
+        })
+    };
+    // Edits redirecting an update which is underway.
+    let cluster_version = webhook(
+        "clusterversion.upgrade.crawford.dev",
+        "/validate/clusterversion",
+        serde_json::json!([{
+            "operations": ["UPDATE"],
+            "apiGroups": ["config.openshift.io"],
+            "apiVersions": ["v1"],
+            "resources": ["clusterversions"],
+            "scope": "Cluster",
+        }]),
+    );
+    extend(
+        &mut configuration["webhooks"],
+        serde_json::json!([cluster_version]),
+    );
+    if command.policy_config_map.is_some() {
+        let mut policy = webhook(
+            "policy.upgrade.crawford.dev",
+            "/validate/policy",
+            serde_json::json!([{
+                "operations": ["CREATE", "UPDATE"],
+                "apiGroups": [""],
+                "apiVersions": ["v1"],
+                "resources": ["configmaps"],
+                "scope": "Namespaced",
+            }]),
+        );
+        // ConfigMaps can't be selected by name, but the review lets the others through.
+        policy["namespaceSelector"] = serde_json::json!({
+            "matchLabels": { "kubernetes.io/metadata.name": namespace },
+        });
+        extend(&mut configuration["webhooks"], serde_json::json!([policy]));
+    }
+    vec![service, configuration]
+}
+
+/// The Service exposing the metrics, and what Prometheus needs to scrape and alert on them.
+fn monitoring(command: &GenerateManifests) -> Vec<serde_json::Value> {
+    let namespace = command.namespace.as_str();
+    let labels = serde_json::json!({ "app": NAME });
+    vec![
+        // Lets the cluster's Prometheus discover the operator's endpoints.
+        serde_json::json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "Role",
+            "metadata": { "name": PROMETHEUS, "namespace": namespace },
+            "rules": [{
+                "apiGroups": [""],
+                "resources": ["services", "endpoints", "pods"],
+                "verbs": ["get", "list", "watch"],
+            }],
+        }),
+        serde_json::json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "RoleBinding",
+            "metadata": { "name": PROMETHEUS, "namespace": namespace },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "Role",
+                "name": PROMETHEUS,
+            },
+            "subjects": [{
+                "kind": "ServiceAccount",
+                "name": PROMETHEUS,
+                "namespace": "openshift-monitoring",
+            }],
+        }),
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": { "name": NAME, "namespace": namespace, "labels": labels },
+            "spec": {
+                "selector": labels,
+                "ports": [{ "name": "http", "port": PORT, "targetPort": "http" }],
+            },
+        }),
+        serde_json::json!({
+            "apiVersion": "monitoring.coreos.com/v1",
+            "kind": "ServiceMonitor",
+            "metadata": { "name": NAME, "namespace": namespace },
+            "spec": {
+                "selector": { "matchLabels": labels },
+                "endpoints": [{
+                    "port": "http",
+                    "path": "/metrics",
+                    "bearerTokenSecret": { "name": TOKEN_SECRET, "key": "token" },
+                }],
+            },
+        }),
+        serde_json::json!({
+            "apiVersion": "monitoring.coreos.com/v1",
+            "kind": "PrometheusRule",
+            "metadata": { "name": NAME, "namespace": namespace },
+            "spec": {
+                "groups": [{
+                    "name": NAME,
+                    "rules": [
+                        {
+                            "alert": "UpgradeStalled",
+                            "expr": format!(
+                                "openshift_update_version_drift_seconds > {}",
+                                command.stalled_alert_after.as_secs()
+                            ),
+                            "labels": { "severity": "warning" },
+                            "annotations": {
+                                "summary": "The cluster hasn't reached its desired version.",
+                                "description": format!(
+                                    "The desired version has differed from the last completed \
+                                     one for more than {}.",
+                                    humantime::format_duration(command.stalled_alert_after)
+                                ),
+                            },
+                        },
+                        {
+                            "alert": "UpgradeBlockedTooLong",
+                            "expr": format!(
+                                "openshift_update_candidate_pending_seconds > {}",
+                                command.blocked_alert_after.as_secs()
+                            ),
+                            "labels": { "severity": "warning" },
+                            "annotations": {
+                                "summary": "An available update isn't being applied.",
+                                "description": format!(
+                                    "An update has been held back for more than {}; see the \
+                                     operator's status for why.",
+                                    humantime::format_duration(command.blocked_alert_after)
+                                ),
+                            },
+                        },
+                        {
+                            "alert": "OperatorCrashLooping",
+                            "expr": format!(
+                                "increase(kube_pod_container_status_restarts_total{{\
+                                 namespace=\"{}\", container=\"{}\"}}[15m]) > 2",
+                                namespace, NAME
+                            ),
+                            "for": "15m",
+                            "labels": { "severity": "critical" },
+                            "annotations": {
+                                "summary": "The update operator keeps restarting.",
+                                "description": "The update operator has restarted more than \
+                                                twice in 15 minutes, so updates aren't being \
+                                                managed.",
+                            },
+                        },
+                        {
+                            "alert": "UpgradeRoutesUnreachable",
+                            "expr": "openshift_update_verification_passed{check=\"routes\"} == 0",
+                            "labels": { "severity": "critical" },
+                            "annotations": {
+                                "summary": "Routes are unreachable after an update.",
+                                "description": "The control plane and ingress were healthy after \
+                                                the last update, but requests to routes failed; \
+                                                see the operator's verified event.",
+                            },
+                        },
+                    ],
+                }],
+            },
+        }),
+    ]
+}
+
+/// A Role in `namespace` with the given rules, bound to the operator's ServiceAccount (which is in
+/// `operator_namespace`).
+fn role(
+    namespace: &str,
+    operator_namespace: &str,
+    rules: serde_json::Value,
+) -> Vec<serde_json::Value> {
+    vec![
+        serde_json::json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "Role",
+            "metadata": { "name": NAME, "namespace": namespace },
+            "rules": rules,
+        }),
+        serde_json::json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "RoleBinding",
+            "metadata": { "name": NAME, "namespace": namespace },
+            "roleRef": {
+                "apiGroup": "rbac.authorization.k8s.io",
+                "kind": "Role",
+                "name": NAME,
+            },
+            "subjects": [{
+                "kind": "ServiceAccount",
+                "name": NAME,
+                "namespace": operator_namespace,
+            }],
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_manifests() {
+        let manifests = manifests(&GenerateManifests::from_iter(&[
+            "generate-manifests",
+            "--image",
+            "quay.io/example/openshift-update:latest",
+            "--namespace",
+            "updates",
+        ]));
+        let kinds: Vec<(&str, &str)> = manifests
+            .iter()
+            .map(|manifest| {
+                (
+                    manifest["kind"].as_str().expect("kind"),
+                    manifest["metadata"]["namespace"].as_str().unwrap_or(""),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("Namespace", ""),
+                ("ServiceAccount", "updates"),
+                ("ClusterRole", ""),
+                ("ClusterRoleBinding", ""),
+                ("Role", "updates"),
+                ("RoleBinding", "updates"),
+                ("Role", "openshift-config"),
+                ("RoleBinding", "openshift-config"),
+                ("Role", "openshift-machine-api"),
+                ("RoleBinding", "openshift-machine-api"),
+                ("Deployment", "updates"),
+            ]
+        );
+        let container = &manifests[10]["spec"]["template"]["spec"]["containers"][0];
+        assert_eq!(
+            container["image"],
+            "quay.io/example/openshift-update:latest"
+        );
+        assert_eq!(container["args"][1], "updates");
+        assert!(render(&manifests).starts_with("---\napiVersion: v1\nkind: Namespace\n"));
+    }
+
+    /// The requests the operator makes of its cluster, as (API group, resource, namespace, verb).
+    /// The namespace is empty for cluster-scoped resources and for lists across all namespaces,
+    /// and "updates" is the operator's own.
+    const REQUESTS: &[(&str, &str, &str, &str)] = &[
+        ("config.openshift.io", "clusterversions", "", "get"),
+        ("config.openshift.io", "clusterversions", "", "list"),
+        ("config.openshift.io", "clusterversions", "", "watch"),
+        ("config.openshift.io", "clusterversions", "", "patch"),
+        ("config.openshift.io", "infrastructures", "", "get"),
+        ("config.openshift.io", "proxies", "", "get"),
+        ("config.openshift.io", "authentications", "", "get"),
+        ("operator.openshift.io", "cloudcredentials", "", "get"),
+        ("", "configmaps", "updates", "get"),
+        ("", "configmaps", "updates", "update"),
+        ("", "configmaps", "updates", "create"),
+        ("", "configmaps", "updates", "watch"),
+        ("", "events", "updates", "create"),
+        ("apps", "deployments", "updates", "get"),
+        ("apps", "deployments", "updates", "patch"),
+        ("", "pods", "updates", "create"),
+        ("", "pods", "updates", "get"),
+        ("", "pods", "updates", "delete"),
+        // The proxy's trusted CA bundle and the admin acks.
+        ("", "configmaps", "openshift-config", "get"),
+        ("argoproj.io", "applications", "", "list"),
+        ("", "pods", "", "list"),
+        ("storage.k8s.io", "storageclasses", "", "list"),
+        ("storage.k8s.io", "csidrivers", "", "list"),
+        ("config.openshift.io", "clusteroperators", "", "list"),
+        ("", "nodes", "", "list"),
+        (
+            "route.openshift.io",
+            "routes",
+            "openshift-ingress-canary",
+            "get",
+        ),
+        ("config.openshift.io", "clusteroperators", "", "get"),
+        ("", "resourcequotas", "", "list"),
+        ("", "persistentvolumeclaims", "", "list"),
+        (
+            "metal3.io",
+            "baremetalhosts",
+            "openshift-machine-api",
+            "list",
+        ),
+        (
+            "machineconfiguration.openshift.io",
+            "machineconfigpools",
+            "",
+            "list",
+        ),
+        ("config.openshift.io", "clusteroperators", "", "watch"),
+        (
+            "machineconfiguration.openshift.io",
+            "machineconfigpools",
+            "",
+            "watch",
+        ),
+    ];
+
+    /// Sources whose requests go to another cluster (a fleet's hub) or to OCM, with credentials of
+    /// their own.
+    const ELSEWHERE: &[&str] = &["acm.rs", "hypershift.rs", "ocm.rs"];
+
+    /// Paths outside of the resources, which every authenticated user may get.
+    const NON_RESOURCES: &[&str] = &["apigroups", "readyz"];
+
+    /// The string literal at the start of `source`, if there's one.
+    fn literal(source: &str) -> Option<&str> {
+        let source = source.trim_start().strip_prefix('"')?;
+        source.find('"').map(|end| &source[..end])
+    }
+
+    /// The (verb, resource) of each `kubeapi::call` in the source which names its resource, and
+    /// the resource of each `customResource` which does. Those given a resource to request (e.g.
+    /// the capacity and storage gates) have to be listed by hand.
+    fn requests(source: &str) -> Vec<(Option<&str>, &str)> {
+        let mut requests = Vec::new();
+        for (start, _) in source.match_indices("kubeapi::call(") {
+            let args = &source[start + "kubeapi::call(".len()..];
+            let verb = literal(args).expect("kubeapi::call with a literal verb");
+            let rest = args.split_once(',').map_or("", |(_, rest)| rest);
+            if let Some(resource) = literal(rest) {
+                requests.push((Some(verb), resource));
+            }
+        }
+        for (start, _) in source.match_indices("customResource(") {
+            let args = &source[start + "customResource(".len()..];
+            let args = &args[..args.find(')').unwrap_or(args.len())];
+            if let Some(resource) = args.rsplit(',').next().and_then(literal) {
+                requests.push((None, resource));
+            }
+        }
+        requests
+    }
+
+    #[test]
+    fn lists_the_requests_it_makes() {
+        let sources = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for entry in std::fs::read_dir(sources).expect("read src") {
+            let path = entry.expect("read src").path();
+            let file = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if !file.ends_with(".rs") || ELSEWHERE.contains(&file) || file == "manifests.rs" {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).expect("read source");
+            for (verb, resource) in requests(&source) {
+                if NON_RESOURCES.contains(&resource) {
+                    continue;
+                }
+                assert!(
+                    REQUESTS.iter().any(|request| request.1 == resource
+                        && verb.is_none_or(|verb| request.3 == verb)),
+                    "{} makes a request ({} {}) which isn't in REQUESTS",
+                    file,
+                    verb.unwrap_or("-"),
+                    resource
+                );
+            }
+        }
+    }
+
+    /// Whether one of the operator's ClusterRole or Roles allows the request.
+    fn allowed(manifests: &[serde_json::Value], request: &(&str, &str, &str, &str)) -> bool {
+        let (group, resource, namespace, verb) = *request;
+        let contains = |list: &serde_json::Value, item: &str| {
+            list.as_array()
+                .is_some_and(|list| list.iter().any(|entry| entry == item))
+        };
+        manifests
+            .iter()
+            .filter(|manifest| {
+                manifest["metadata"]["name"] == NAME
+                    && (manifest["kind"] == "ClusterRole"
+                        || (manifest["kind"] == "Role"
+                            && manifest["metadata"]["namespace"] == namespace))
+            })
+            .flat_map(|role| role["rules"].as_array().cloned().unwrap_or_default())
+            .any(|rule| {
+                contains(&rule["apiGroups"], group)
+                    && contains(&rule["resources"], resource)
+                    && contains(&rule["verbs"], verb)
+            })
+    }
+
+    #[test]
+    fn grants_the_requests_it_makes() {
+        let manifests = manifests(&GenerateManifests::from_iter(&[
+            "generate-manifests",
+            "--image",
+            "quay.io/example/openshift-update:latest",
+            "--namespace",
+            "updates",
+        ]));
+        for request in REQUESTS {
+            assert!(allowed(&manifests, request), "{:?} isn't allowed", request);
+        }
+        assert!(!allowed(
+            &manifests,
+            &("", "configmaps", "kube-system", "get")
+        ));
+    }
+
+    #[test]
+    fn generates_webhooks() {
+        let manifests = manifests(&GenerateManifests::from_iter(&[
+            "generate-manifests",
+            "--image",
+            "quay.io/example/openshift-update:latest",
+            "--policy-config-map",
+            "update-policy",
+            "--webhooks",
+        ]));
+        let find = |kind: &str| {
+            manifests
+                .iter()
+                .find(|manifest| manifest["kind"] == kind)
+                .unwrap_or_else(|| panic!("no {}", kind))
+        };
+        let container = &find("Deployment")["spec"]["template"]["spec"]["containers"][0];
+        let args: Vec<&str> = container["args"]
+            .as_array()
+            .expect("args")
+            .iter()
+            .filter_map(|arg| arg.as_str())
+            .collect();
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--policy-config-map", "openshift-update/update-policy"]));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--webhook-listen", "0.0.0.0:8443"]));
+        assert_eq!(
+            find("Service")["metadata"]["annotations"]
+                ["service.beta.openshift.io/serving-cert-secret-name"],
+            WEBHOOK_SECRET
+        );
+        let configuration = find("ValidatingWebhookConfiguration");
+        assert_eq!(
+            configuration["metadata"]["annotations"]["service.beta.openshift.io/inject-cabundle"],
+            "true"
+        );
+        let paths: Vec<&serde_json::Value> = configuration["webhooks"]
+            .as_array()
+            .expect("webhooks")
+            .iter()
+            .map(|webhook| &webhook["clientConfig"]["service"]["path"])
+            .collect();
+        assert_eq!(paths, vec!["/validate/clusterversion", "/validate/policy"]);
+        assert!(configuration["webhooks"][0]["clientConfig"]
+            .get("caBundle")
+            .is_none());
+
+        let ca =
+            std::env::temp_dir().join(format!("openshift-update-ca-{}.pem", std::process::id()));
+        std::fs::write(
+            &ca,
+            "-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----\n",
+        )
+        .expect("write CA");
+        let ca = ca.to_str().expect("path");
+        let manifests = super::manifests(&GenerateManifests::from_iter(&[
+            "generate-manifests",
+            "--image",
+            "quay.io/example/openshift-update:latest",
+            "--policy-config-map",
+            "update-policy",
+            "--webhooks",
+            "--webhook-ca-file",
+            ca,
+        ]));
+        let configuration = manifests
+            .iter()
+            .find(|manifest| manifest["kind"] == "ValidatingWebhookConfiguration")
+            .expect("ValidatingWebhookConfiguration");
+        assert!(configuration["metadata"].get("annotations").is_none());
+        assert_eq!(
+            configuration["webhooks"][1]["clientConfig"]["caBundle"],
+            ca_bundle(ca).expect("CA bundle")
+        );
+        assert!(ca_bundle("/nonexistent").is_err());
+    }
+
+    #[test]
+    fn generates_monitoring() {
+        let command = GenerateManifests::from_iter(&[
+            "generate-manifests",
+            "--image",
+            "quay.io/example/openshift-update:latest",
+            "--monitoring",
+            "--blocked-alert-after",
+            "2d",
+        ]);
+        let manifests = manifests(&command);
+        let rule = manifests
+            .iter()
+            .find(|manifest| manifest["kind"] == "PrometheusRule")
+            .expect("PrometheusRule");
+        assert_eq!(
+            manifests[0]["metadata"]["labels"]["openshift.io/cluster-monitoring"],
+            "true"
+        );
+        let rules = &rule["spec"]["groups"][0]["rules"];
+        assert_eq!(
+            rules[1]["expr"],
+            "openshift_update_candidate_pending_seconds > 172800"
+        );
+        assert_eq!(
+            rules[2]["expr"],
+            "increase(kube_pod_container_status_restarts_total{namespace=\"openshift-update\", \
+             container=\"openshift-update\"}[15m]) > 2"
+        );
+        assert_eq!(rules[3]["alert"], "UpgradeRoutesUnreachable");
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shell completions and a man page, generated from the command-line definitions at runtime so
+//! that they can't fall out of date and packages don't need a build step to produce them.
+
+use crate::options::Options;
+use std::io::{self, Write};
+use structopt::clap::{self, Shell};
+use structopt::StructOpt;
+
+const NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Write the completion script for `shell` to stdout.
+pub fn completions(shell: Shell) {
+    Options::clap().gen_completions_to(NAME, shell, &mut io::stdout());
+}
+
+/// Write the man page to stdout.
+pub fn man() -> clap::Result<()> {
+    let mut help = Vec::new();
+    Options::clap().write_long_help(&mut help)?;
+    io::stdout().write_all(page(&String::from_utf8_lossy(&help)).as_bytes())?;
+    Ok(())
+}
+
+/// A man page in roff, with the help text as its description.
+fn page(help: &str) -> String {
+    let mut page = format!(
+        ".TH {} 1 \"\" \"{} {}\"\n.SH NAME\n{} \\- update OpenShift clusters automatically\n\
+         .SH DESCRIPTION\n.nf\n",
+        NAME.to_uppercase(),
+        NAME,
+        env!("CARGO_PKG_VERSION"),
+        NAME
+    );
+    for line in help.lines() {
+        let line = line.replace('\\', "\\e").replace('-', "\\-");
+        // Lines starting with a control character would be taken as requests.
+        if line.starts_with('.') || line.starts_with('\'') {
+            page.push_str("\\&");
+        }
+        page.push_str(&line);
+        page.push('\n');
+    }
+    page.push_str(".fi\n.SH SEE ALSO\nRun \\fB");
+    page.push_str(NAME);
+    page.push_str(" help\\fR \\fISUBCOMMAND\\fR for the options of each subcommand.\n");
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_help() {
+        let page = page("USAGE:\n    openshift-update [FLAGS]\n.hidden \\n\n");
+        assert!(page.starts_with(".TH OPENSHIFT-UPDATE 1 "));
+        assert!(page.contains("\n    openshift\\-update [FLAGS]\n"));
+        assert!(page.contains("\n\\&.hidden \\en\n"));
+    }
+}
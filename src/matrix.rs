@@ -0,0 +1,111 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Events posted to a Matrix room.
+//!
+//! Each event is sent to the room as a notice (which bots are expected to send, and clients
+//! don't notify about as loudly as messages) through the homeserver's client-server API, as the
+//! user whose access token is given. That user has to have joined the room already.
+
+use crate::identity::Identity;
+use crate::notify::{self, Event, Notifier};
+use crate::retry::Backoff;
+use crate::secret::SecretFile;
+use crate::Error;
+use chrono::Utc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Matrix {
+    pub http: reqwest::Client,
+    /// Base URL of the homeserver, e.g. "https://matrix.example.com".
+    pub homeserver: reqwest::Url,
+    pub token: SecretFile,
+    /// The ID of the room (e.g. "!ops:example.com"), rather than one of its aliases.
+    pub room: String,
+    pub backoff: Backoff,
+}
+
+/// Distinguishes the transactions started within the same millisecond.
+static TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+
+impl Matrix {
+    /// The URL to send a message to the room with. The homeserver ignores a transaction it has
+    /// already seen from the same token, so retries never post a message twice.
+    fn url(&self, transaction: &str) -> Result<reqwest::Url, Error> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| Error::Config(format!("{} is not a homeserver URL", self.homeserver)))?
+            .pop_if_empty()
+            .extend(&[
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &self.room,
+                "send",
+                "m.room.message",
+                transaction,
+            ]);
+        Ok(url)
+    }
+}
+
+impl Notifier for Matrix {
+    fn name(&self) -> &str {
+        "Matrix"
+    }
+
+    fn send(&self, event: &Event, identity: &Identity) -> Result<(), Error> {
+        let transaction = format!(
+            "openshift-update.{}.{}",
+            Utc::now().timestamp_millis(),
+            TRANSACTIONS.fetch_add(1, Ordering::Relaxed)
+        );
+        let url = self.url(&transaction)?;
+        let body = serde_json::json!({
+            "msgtype": "m.notice",
+            "body": notify::message(event, identity),
+        });
+        self.backoff.retry("send to the Matrix room", || {
+            self.http
+                .put(url.clone())
+                .bearer_auth(self.token.read()?)
+                .json(&body)
+                .send()?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_to_the_room() {
+        let matrix = Matrix {
+            http: reqwest::Client::new(),
+            homeserver: "https://matrix.example.com/".parse().expect("valid URL"),
+            token: SecretFile::new("/nonexistent".as_ref()),
+            room: "!ops:example.com".to_string(),
+            backoff: Backoff::default(),
+        };
+        assert_eq!(
+            matrix.url("openshift-update.1.0").expect("URL").as_str(),
+            "https://matrix.example.com/_matrix/client/v3/rooms/!ops:example.com/send/\
+             m.room.message/openshift-update.1.0"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics in the Prometheus text exposition format.
+//!
+//! The operator only exposes a handful of metrics, so they are kept in a single process-wide
+//! registry rather than pulling in a client library. Every sample is labelled with the cluster's
+//! identity (see `identity`).
+
+use crate::identity;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+static REGISTRY: Mutex<BTreeMap<&str, Family>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    /// With the upper bounds of its buckets.
+    Histogram(&'static [f64]),
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    /// Samples keyed by their rendered labels.
+    samples: BTreeMap<String, f64>,
+    /// The observations of histograms, keyed by their rendered labels.
+    histograms: BTreeMap<String, Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket, not counting those in the buckets below it.
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+/// Set the value of a gauge.
+pub fn set(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+    update(name, help, Kind::Gauge, labels, |sample| *sample = value);
+}
+
+/// Increment a counter.
+pub fn inc(name: &'static str, help: &'static str, labels: &[(&str, &str)]) {
+    update(name, help, Kind::Counter, labels, |sample| *sample += 1.0);
+}
+
+/// Add an observation to a histogram with the given (ascending) bucket bounds.
+pub fn observe(
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    labels: &[(&str, &str)],
+    value: f64,
+) {
+    let mut registry = REGISTRY.lock().expect("metrics lock");
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind: Kind::Histogram(buckets),
+        samples: BTreeMap::new(),
+        histograms: BTreeMap::new(),
+    });
+    debug_assert_eq!(
+        family.kind,
+        Kind::Histogram(buckets),
+        "{} registered with two types",
+        name
+    );
+    let histogram = family
+        .histograms
+        .entry(render_labels(labels))
+        .or_insert_with(|| Histogram {
+            buckets: vec![0; buckets.len()],
+            ..Default::default()
+        });
+    if let Some(bucket) = buckets.iter().position(|bound| value <= *bound) {
+        histogram.buckets[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += value;
+}
+
+/// Drop every sample of a metric, for when its labels no longer apply.
+pub fn clear(name: &'static str) {
+    if let Some(family) = REGISTRY.lock().expect("metrics lock").get_mut(name) {
+        family.samples.clear();
+        family.histograms.clear();
+    }
+}
+
+fn update<F: FnOnce(&mut f64)>(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &[(&str, &str)],
+    f: F,
+) {
+    let mut registry = REGISTRY.lock().expect("metrics lock");
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        samples: BTreeMap::new(),
+        histograms: BTreeMap::new(),
+    });
+    debug_assert_eq!(family.kind, kind, "{} registered with two types", name);
+    f(family.samples.entry(render_labels(labels)).or_insert(0.0));
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', r#"\""#)
+                .replace('\n', r"\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Two sets of rendered labels as one.
+fn join_labels(first: &str, second: &str) -> String {
+    match (first, second) {
+        ("", labels) | (labels, "") => labels.to_string(),
+        (first, second) => format!("{},{}", first.trim_end_matches('}'), &second[1..]),
+    }
+}
+
+/// Render every metric in the text exposition format.
+pub fn render() -> String {
+    let identity = identity::get();
+    let identity = render_labels(&identity.labels());
+    let registry = REGISTRY.lock().expect("metrics lock");
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        };
+        writeln!(out, "# HELP {} {}", name, family.help).expect("write to string");
+        writeln!(out, "# TYPE {} {}", name, kind).expect("write to string");
+        for (labels, value) in &family.samples {
+            writeln!(out, "{}{} {}", name, join_labels(&identity, labels), value)
+                .expect("write to string");
+        }
+        let bounds = match family.kind {
+            Kind::Histogram(bounds) => bounds,
+            _ => continue,
+        };
+        for (labels, histogram) in &family.histograms {
+            let labels = join_labels(&identity, labels);
+            // The buckets are cumulative, ending with one which holds every observation.
+            let mut cumulative = 0;
+            let mut buckets: Vec<(String, u64)> = bounds
+                .iter()
+                .zip(&histogram.buckets)
+                .map(|(bound, count)| {
+                    cumulative += count;
+                    (bound.to_string(), cumulative)
+                })
+                .collect();
+            buckets.push(("+Inf".to_string(), histogram.count));
+            for (bound, count) in buckets {
+                let le = render_labels(&[("le", &bound)]);
+                writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    join_labels(&labels, &le),
+                    count
+                )
+                .expect("write to string");
+            }
+            writeln!(out, "{}_sum{} {}", name, labels, histogram.sum).expect("write to string");
+            writeln!(out, "{}_count{} {}", name, labels, histogram.count).expect("write to string");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_families() {
+        set(
+            "test_gauge",
+            "A gauge.",
+            &[("version", "4.1.16"), ("reason", "say \"hi\"")],
+            2.5,
+        );
+        inc("test_counter", "A counter.", &[]);
+        inc("test_counter", "A counter.", &[]);
+
+        let rendered = render();
+        assert!(rendered.contains(
+            "# HELP test_gauge A gauge.\n# TYPE test_gauge gauge\n\
+             test_gauge{version=\"4.1.16\",reason=\"say \\\"hi\\\"\"} 2.5\n"
+        ));
+        assert!(rendered.contains("# TYPE test_counter counter\ntest_counter 2\n"));
+
+        clear("test_gauge");
+        assert!(!render().contains("test_gauge{"));
+    }
+
+    #[test]
+    fn renders_histograms() {
+        const BUCKETS: &[f64] = &[60.0, 600.0];
+        observe(
+            "test_histogram",
+            "A histogram.",
+            BUCKETS,
+            &[("pool", "worker")],
+            30.0,
+        );
+        observe(
+            "test_histogram",
+            "A histogram.",
+            BUCKETS,
+            &[("pool", "worker")],
+            90.0,
+        );
+        observe(
+            "test_histogram",
+            "A histogram.",
+            BUCKETS,
+            &[("pool", "worker")],
+            900.0,
+        );
+
+        assert!(render().contains(
+            "# TYPE test_histogram histogram\n\
+             test_histogram_bucket{pool=\"worker\",le=\"60\"} 1\n\
+             test_histogram_bucket{pool=\"worker\",le=\"600\"} 2\n\
+             test_histogram_bucket{pool=\"worker\",le=\"+Inf\"} 3\n\
+             test_histogram_sum{pool=\"worker\"} 1020\n\
+             test_histogram_count{pool=\"worker\"} 3\n"
+        ));
+    }
+}
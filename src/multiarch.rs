@@ -0,0 +1,205 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Migration of the cluster to the multi-architecture release payload.
+//!
+//! This is the operator's take on `oc adm upgrade --to-multi-arch`: the desired update is set to
+//! the cluster's current version with the "Multi" architecture, which has the cluster-version
+//! operator fetch and roll out the multi-architecture payload of that version. Until then, a
+//! cluster can only run compute machines of its installed architecture. The migration is judged
+//! by the same checks which hold back updates, and has to be confirmed before it is started.
+
+use crate::explain::{self, gate_checks, observe, render_checks};
+use crate::options::Options;
+use chrono::Utc;
+use kube::api::{PatchParams, RawApi};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{ClusterVersionClient, KubeClient};
+use openshift_update::kubeapi;
+use openshift_update::state::State;
+use openshift_update::Error;
+use std::io::{self, BufRead, Write};
+use structopt::StructOpt;
+
+/// The architecture of the multi-architecture payloads.
+const MULTI: &str = "Multi";
+
+#[derive(StructOpt)]
+pub struct MigrateToMultiArch {
+    #[structopt(long = "yes")]
+    /// Start the migration without asking for confirmation
+    pub yes: bool,
+}
+
+pub fn run(
+    client: APIClient,
+    options: &Options,
+    command: &MigrateToMultiArch,
+) -> Result<(), Error> {
+    let versions = KubeClient::new(
+        client.clone(),
+        &options.cluster_version_name,
+        options.backoff(),
+    )?
+    .field_manager(&options.field_manager);
+    let version = versions.get()?;
+
+    // The architecture isn't modelled, since updates never change it, so it's read separately.
+    let request = RawApi::customResource("clusterversions")
+        .group("config.openshift.io")
+        .version("v1")
+        .get(versions.name())?;
+    let raw: serde_json::Value =
+        kubeapi::call("get", "clusterversions", || client.request(request))?;
+    let current = migration(&raw)?;
+
+    let observed = observe(options, &client, &versions)?;
+    let state = explain::cluster_state(
+        options,
+        &version,
+        &State::default(),
+        &observed,
+        &[],
+        Utc::now(),
+    );
+    let checks = gate_checks(options, &state);
+    let mut text = format!("Migrate {} to the multi-architecture payload\n", current);
+    render_checks(&mut text, &checks);
+    print!("{}", text);
+
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|check| !check.passed)
+        .map(|check| check.gate)
+        .collect();
+    if !failed.is_empty() {
+        return Err(Error::Config(format!(
+            "the migration is held back by {}",
+            failed.join(", ")
+        )));
+    }
+    if !command.yes && !atty::is(atty::Stream::Stdin) {
+        return Err(Error::Config(
+            "confirming the migration requires a terminal (or --yes)".to_string(),
+        ));
+    }
+    if !command.yes && !confirm() {
+        println!("Not migrating the cluster");
+        return Ok(());
+    }
+
+    let patch = serde_json::json!({
+        "spec": {
+            "desiredUpdate": {
+                "architecture": MULTI,
+                "version": current,
+                // The payload is resolved by the cluster-version operator, from the version.
+                "image": null,
+                "force": false,
+            },
+        },
+    });
+    let patch = serde_json::to_vec(&patch)?;
+    kubeapi::call("patch", "clusterversions", || {
+        versions.api().patch(
+            versions.name(),
+            &PatchParams {
+                field_manager: Some(options.field_manager.clone()),
+                ..Default::default()
+            },
+            patch,
+        )
+    })?;
+    println!(
+        "Requested the migration of {} to the multi-architecture payload",
+        current
+    );
+    Ok(())
+}
+
+/// The version to migrate at, as long as the cluster can be migrated.
+fn migration(version: &serde_json::Value) -> Result<String, Error> {
+    let status = &version["status"];
+    if status["desired"]["architecture"] == MULTI {
+        return Err(Error::Config(
+            "the cluster already runs the multi-architecture payload".to_string(),
+        ));
+    }
+    if version["spec"]["desiredUpdate"]["architecture"] == MULTI {
+        return Err(Error::Config(
+            "the migration to the multi-architecture payload was already requested".to_string(),
+        ));
+    }
+    let latest = &status["history"][0];
+    match (latest["state"].as_str(), latest["version"].as_str()) {
+        (Some("Completed"), Some(version)) => Ok(version.to_string()),
+        (_, Some(version)) => Err(Error::Config(format!(
+            "the cluster hasn't finished updating to {}",
+            version
+        ))),
+        (_, None) => Err(Error::Config(
+            "the cluster doesn't report its version".to_string(),
+        )),
+    }
+}
+
+fn confirm() -> bool {
+    print!("Migrate the cluster? [y/N] ");
+    io::stdout().flush().expect("flush stdout");
+
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => {
+            let answer = answer.trim().to_lowercase();
+            answer == "y" || answer == "yes"
+        }
+        Err(error) => {
+            error!("Failed to read answer: {}", error);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_at_the_current_version() {
+        let mut version: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        assert_eq!(migration(&version).expect("migration"), "4.1.14");
+
+        version["spec"]["desiredUpdate"]["architecture"] = MULTI.into();
+        assert_eq!(
+            migration(&version).expect_err("requested").to_string(),
+            "invalid configuration: the migration to the multi-architecture payload was already \
+             requested"
+        );
+
+        version["status"]["desired"]["architecture"] = MULTI.into();
+        assert_eq!(
+            migration(&version).expect_err("migrated").to_string(),
+            "invalid configuration: the cluster already runs the multi-architecture payload"
+        );
+
+        let updating: serde_json::Value = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-in-progress.json"
+        ))
+        .expect("valid fixture");
+        assert!(migration(&updating).is_err());
+    }
+}
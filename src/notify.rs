@@ -0,0 +1,456 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Machine-readable events marking the steps of an update.
+//!
+//! Besides being written to stdout, events are sent to whichever notifiers have been registered
+//! (e.g. a chat room), as a line of text describing them.
+
+use crate::durations::Durations;
+use crate::identity::{self, Identity};
+use crate::policy::DecisionReason;
+use crate::release::Diff;
+use crate::Error;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static NOTIFIERS: Mutex<Vec<Box<dyn Notifier>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    /// A new update has become the candidate.
+    #[serde(rename = "candidate-found")]
+    CandidateFound {
+        version: semver::Version,
+        image: String,
+        /// How the candidate's release differs from the cluster's, if it was compared.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        changes: Option<Diff>,
+    },
+
+    /// The candidate is being held back by one of the operator's checks.
+    #[serde(rename = "gate-blocked")]
+    GateBlocked {
+        version: semver::Version,
+        gate: String,
+        /// The stable code of why it's held back (e.g. "GateBlocked" or "SoakPending").
+        code: DecisionReason,
+        reason: String,
+    },
+
+    /// The candidate is still being held back by the same check, as a reminder.
+    #[serde(rename = "still-blocked")]
+    StillBlocked {
+        version: semver::Version,
+        gate: String,
+        code: DecisionReason,
+        reason: String,
+        since: DateTime<Utc>,
+    },
+
+    /// The check which was holding back the candidate no longer is, either because it passed or
+    /// because there's another candidate.
+    #[serde(rename = "unblocked")]
+    Unblocked {
+        version: semver::Version,
+        gate: String,
+        since: DateTime<Utc>,
+    },
+
+    /// The candidate has been requested from the cluster.
+    #[serde(rename = "patch-applied")]
+    PatchApplied {
+        version: semver::Version,
+        /// The commit of the GitOps repository which declared the target, if there is one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        revision: Option<String>,
+    },
+
+    /// The update about to be requested will take down the Kubernetes API, since the cluster
+    /// has a single node.
+    #[serde(rename = "downtime")]
+    Downtime { version: semver::Version },
+
+    /// An update was forced, skipping the verification of its signature.
+    #[serde(rename = "forced")]
+    Forced {
+        version: semver::Version,
+        /// Who approved forcing it.
+        approver: String,
+    },
+
+    /// An update failed and was abandoned, and will not be attempted again.
+    #[serde(rename = "poisoned")]
+    Poisoned {
+        version: semver::Version,
+        reason: String,
+    },
+
+    /// Every automatic update has been deferred until a point in time.
+    #[serde(rename = "snoozed")]
+    Snoozed {
+        until: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+
+    /// A reminder that updates are about to resume, sent a while before a snooze ends.
+    #[serde(rename = "snooze-ending")]
+    SnoozeEnding {
+        until: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+
+    /// A snooze has ended, or was cancelled, and automatic updates have resumed.
+    #[serde(rename = "snooze-ended")]
+    SnoozeEnded { until: DateTime<Utc> },
+
+    /// The desired version still hasn't been reached after the drift threshold.
+    #[serde(rename = "drift")]
+    Drift {
+        desired: semver::Version,
+        completed: Option<String>,
+        since: DateTime<Utc>,
+    },
+
+    /// The cluster's version moved without the operator causing it, which holds back updates
+    /// until it's acknowledged.
+    #[serde(rename = "regressed")]
+    Regressed {
+        version: semver::Version,
+        reason: String,
+    },
+
+    /// The cluster has finished updating.
+    #[serde(rename = "completed")]
+    Completed { version: String },
+
+    /// The cluster was checked once it had completed an update the operator requested.
+    #[serde(rename = "verified")]
+    Verified {
+        version: semver::Version,
+        passed: bool,
+        /// The checks which failed, with their reasons.
+        failures: Vec<String>,
+    },
+
+    /// An update the operator requested has completed, and has been timed.
+    #[serde(rename = "timed")]
+    Timed {
+        version: semver::Version,
+        durations: Durations,
+    },
+
+    /// The operator has failed too many times in a row and is backing off.
+    #[serde(rename = "unhealthy")]
+    Unhealthy { failures: u32, error: String },
+
+    /// The operator has succeeded again after being unhealthy.
+    #[serde(rename = "recovered")]
+    Recovered,
+}
+
+impl Event {
+    /// A few words for people on what happened (e.g. "Update held back").
+    pub fn title(&self) -> &'static str {
+        match self {
+            Event::CandidateFound { .. } => "Update available",
+            Event::GateBlocked { .. } => "Update held back",
+            Event::StillBlocked { .. } => "Update still held back",
+            Event::Unblocked { .. } => "Update no longer held back",
+            Event::PatchApplied { .. } => "Update started",
+            Event::Downtime { .. } => "API downtime ahead",
+            Event::Forced { .. } => "Update forced",
+            Event::Poisoned { .. } => "Update abandoned",
+            Event::Snoozed { .. } => "Updates snoozed",
+            Event::SnoozeEnding { .. } => "Snooze ending",
+            Event::SnoozeEnded { .. } => "Updates resumed",
+            Event::Drift { .. } => "Update stalled",
+            Event::Regressed { .. } => "Version regressed",
+            Event::Completed { .. } => "Update completed",
+            Event::Verified { passed: true, .. } => "Update verified",
+            Event::Verified { .. } => "Update failed verification",
+            Event::Timed { .. } => "Update timed",
+            Event::Unhealthy { .. } => "Operator unhealthy",
+            Event::Recovered => "Operator recovered",
+        }
+    }
+
+    /// The version the event is about, if it's about one.
+    pub fn version(&self) -> Option<String> {
+        match self {
+            Event::CandidateFound { version, .. }
+            | Event::GateBlocked { version, .. }
+            | Event::StillBlocked { version, .. }
+            | Event::Unblocked { version, .. }
+            | Event::PatchApplied { version, .. }
+            | Event::Downtime { version }
+            | Event::Forced { version, .. }
+            | Event::Poisoned { version, .. }
+            | Event::Regressed { version, .. }
+            | Event::Verified { version, .. }
+            | Event::Timed { version, .. } => Some(version.to_string()),
+            Event::Drift { desired, .. } => Some(desired.to_string()),
+            Event::Completed { version } => Some(version.clone()),
+            Event::Snoozed { .. }
+            | Event::SnoozeEnding { .. }
+            | Event::SnoozeEnded { .. }
+            | Event::Unhealthy { .. }
+            | Event::Recovered => None,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::CandidateFound { version, image, .. } => {
+                write!(f, "{} is available ({})", version, image)
+            }
+            Event::GateBlocked {
+                version,
+                gate,
+                reason,
+                ..
+            } => write!(
+                f,
+                "{} is held back by the {} gate: {}",
+                version, gate, reason
+            ),
+            Event::StillBlocked {
+                version,
+                gate,
+                reason,
+                since,
+                ..
+            } => write!(
+                f,
+                "{} is still held back by the {} gate, as it has been since {}: {}",
+                version, gate, since, reason
+            ),
+            Event::Unblocked { version, gate, .. } => {
+                write!(f, "{} is no longer held back by the {} gate", version, gate)
+            }
+            Event::PatchApplied { version, revision } => {
+                write!(f, "Updating to {}", version)?;
+                match revision {
+                    Some(revision) => write!(f, ", as declared by {}", revision),
+                    None => Ok(()),
+                }
+            }
+            Event::Downtime { version } => write!(
+                f,
+                "The Kubernetes API of this single-node cluster will be unavailable while it \
+                 updates to {}",
+                version
+            ),
+            Event::Forced { version, approver } => write!(
+                f,
+                "Forcing the update to {}, as approved by {}",
+                version, approver
+            ),
+            Event::Poisoned { version, reason } => write!(
+                f,
+                "The update to {} was {}; it will not be attempted again",
+                version, reason
+            ),
+            Event::Snoozed { until, reason } => {
+                write!(f, "Updates are snoozed until {}", until)?;
+                match reason {
+                    Some(reason) => write!(f, ": {}", reason),
+                    None => Ok(()),
+                }
+            }
+            Event::SnoozeEnding { until, .. } => write!(f, "Updates will resume at {}", until),
+            Event::SnoozeEnded { until } => write!(
+                f,
+                "Updates have resumed, having been snoozed until {}",
+                until
+            ),
+            Event::Drift {
+                desired,
+                completed,
+                since,
+            } => write!(
+                f,
+                "The update to {} hasn't completed since {} (the last completed version is {})",
+                desired,
+                since,
+                completed.as_deref().unwrap_or("unknown")
+            ),
+            Event::Regressed { reason, .. } => write!(
+                f,
+                "The cluster's version {}; updates are held back until that's acknowledged",
+                reason
+            ),
+            Event::Completed { version } => {
+                write!(f, "The cluster has finished updating to {}", version)
+            }
+            Event::Verified {
+                version,
+                passed: true,
+                ..
+            } => write!(
+                f,
+                "The cluster passed its checks after updating to {}",
+                version
+            ),
+            Event::Verified {
+                version, failures, ..
+            } => write!(
+                f,
+                "The cluster failed its checks after updating to {}: {}",
+                version,
+                failures.join("; ")
+            ),
+            Event::Timed { version, durations } => match durations.update {
+                Some(seconds) if seconds >= 0 => write!(
+                    f,
+                    "The update to {} took {}",
+                    version,
+                    humantime::format_duration(Duration::from_secs(seconds as u64))
+                ),
+                _ => write!(f, "The update to {} has been timed", version),
+            },
+            Event::Unhealthy { failures, error } => write!(
+                f,
+                "The operator has failed {} times in a row: {}",
+                failures, error
+            ),
+            Event::Recovered => write!(f, "The operator has recovered"),
+        }
+    }
+}
+
+/// A destination for events besides stdout.
+pub trait Notifier: Send {
+    /// What the destination is called in the logs (e.g. "Matrix").
+    fn name(&self) -> &str;
+
+    fn send(&self, event: &Event, identity: &Identity) -> Result<(), Error>;
+}
+
+/// Send every event from now on to `notifier`.
+pub fn register(notifier: Box<dyn Notifier>) {
+    NOTIFIERS.lock().expect("notifiers lock").push(notifier);
+}
+
+/// Send the event to each of the registered notifiers. Failures are only logged, since a
+/// notification mustn't keep the operator from acting.
+pub fn send(event: &Event) {
+    let identity = identity::get();
+    for notifier in NOTIFIERS.lock().expect("notifiers lock").iter() {
+        if let Err(error) = notifier.send(event, &identity) {
+            error!("Failed to send event to {}: {}", notifier.name(), error);
+        }
+    }
+}
+
+/// What people know the cluster as, if that's known: its infrastructure name, or else its ID.
+pub fn cluster(identity: &Identity) -> Option<&str> {
+    identity
+        .infrastructure_name
+        .as_deref()
+        .or(identity.cluster_id.as_deref())
+}
+
+/// The event as a line of text for people, naming the cluster it's from if that's known.
+pub fn message(event: &Event, identity: &Identity) -> String {
+    match cluster(identity) {
+        Some(cluster) => format!("{}: {}", cluster, event),
+        None => event.to_string(),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    identity: Identity,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Write the event to stdout as a single line of JSON, along with the time and the cluster's
+/// identity.
+pub fn stdout(event: &Event) {
+    let record = Record {
+        time: Utc::now(),
+        identity: identity::get(),
+        event,
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Err(error) = writeln!(
+        stdout,
+        "{}",
+        serde_json::to_string(&record).expect("Serialize to JSON")
+    )
+    .and_then(|_| stdout.flush())
+    {
+        error!("Failed to write event: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_events() {
+        let version = semver::Version::parse("4.1.16").expect("valid version");
+        let event = Event::GateBlocked {
+            version: version.clone(),
+            gate: "alerts".to_string(),
+            code: DecisionReason::GateBlocked {
+                gate: "alerts".to_string(),
+            },
+            reason: "1 alert is firing".to_string(),
+        };
+        assert_eq!(
+            event.to_string(),
+            "4.1.16 is held back by the alerts gate: 1 alert is firing"
+        );
+
+        let identity = Identity {
+            cluster_id: Some("e9c2a1d4".to_string()),
+            infrastructure_name: Some("prod-x7k2p".to_string()),
+        };
+        assert_eq!(
+            message(
+                &Event::Completed {
+                    version: "4.1.16".to_string()
+                },
+                &identity
+            ),
+            "prod-x7k2p: The cluster has finished updating to 4.1.16"
+        );
+        assert_eq!(
+            message(&Event::Recovered, &Identity::default()),
+            "The operator has recovered"
+        );
+
+        let downtime = Event::Downtime { version };
+        assert_eq!(downtime.version().as_deref(), Some("4.1.16"));
+        assert_eq!(
+            serde_json::to_value(&downtime).expect("Serialize to JSON"),
+            serde_json::json!({ "event": "downtime", "version": "4.1.16" })
+        );
+    }
+}
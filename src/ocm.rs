@@ -0,0 +1,310 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Upgrades of managed (OSD and ROSA) clusters through OpenShift Cluster Manager.
+//!
+//! Managed clusters must not have their ClusterVersion patched directly. Instead, an upgrade policy
+//! is created for the cluster in OCM, which then performs the upgrade on its own schedule.
+//!
+//! The cluster is decided on by the same policy as in the local mode (see `fleet`), against its
+//! own ClusterVersion, whose annotations pause, snooze or approve its updates, and the upgrades
+//! OCM offers.
+
+use crate::fleet::{self, Fleet};
+use crate::options::Options;
+use crate::status::{self, FleetCluster, Status};
+use chrono::{DateTime, Utc};
+use kube::api::Api;
+use kube::client::APIClient;
+use openshift_update::clusterversion::{self, ClusterUpdate, ClusterVersion};
+use openshift_update::gates;
+use openshift_update::kubeapi;
+use openshift_update::policy::ClusterState;
+use openshift_update::retry::{self, Backoff};
+use openshift_update::secret::SecretFile;
+use openshift_update::state::State;
+use openshift_update::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// OCM refuses policies that are scheduled to run less than five minutes from now.
+const SCHEDULE_DELAY_MINUTES: i64 = 6;
+
+#[derive(Debug, serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct List<T> {
+    #[serde(default = "Vec::new")]
+    items: Vec<T>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Cluster {
+    id: String,
+    version: ClusterVersionInfo,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ClusterVersionInfo {
+    raw_id: String,
+    #[serde(default)]
+    available_upgrades: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct UpgradePolicy {
+    schedule_type: String,
+    upgrade_type: String,
+    version: String,
+    next_run: DateTime<Utc>,
+}
+
+struct Client {
+    http: reqwest::Client,
+    url: String,
+    token_url: String,
+    offline_token: SecretFile,
+    access_token: Option<(String, Instant)>,
+    backoff: Backoff,
+}
+
+impl Client {
+    /// Exchange the offline token for a short-lived access token, reusing it until it is about to
+    /// expire.
+    fn access_token(&mut self) -> Result<String, Error> {
+        if let Some((token, expiry)) = &self.access_token {
+            if Instant::now() < *expiry {
+                return Ok(token.clone());
+            }
+        }
+
+        let response: TokenResponse = self
+            .http
+            .post(&self.token_url)
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", "cloud-services"),
+                ("refresh_token", &self.offline_token.read()?),
+            ])
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        let lifetime = Duration::from_secs(response.expires_in.saturating_sub(60));
+        self.access_token = Some((response.access_token.clone(), Instant::now() + lifetime));
+        Ok(response.access_token)
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(
+        &mut self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T, Error> {
+        let backoff = self.backoff;
+        backoff.retry(&format!("get {}", path), || {
+            let token = self.access_token()?;
+            Ok(self
+                .http
+                .get(&format!("{}{}", self.url, path))
+                .query(query)
+                .bearer_auth(token)
+                .send()?
+                .error_for_status()?
+                .json()?)
+        })
+    }
+
+    fn find_cluster(&mut self, external_id: &str) -> Result<String, Error> {
+        let search = format!("external_id = '{}'", external_id);
+        let clusters: List<Cluster> =
+            self.get("/api/clusters_mgmt/v1/clusters", &[("search", &search)])?;
+        match clusters.items.into_iter().next() {
+            Some(cluster) => Ok(cluster.id),
+            None => Err(Error::Config(format!(
+                "no cluster with external ID {} in OCM; set --ocm-cluster",
+                external_id
+            ))),
+        }
+    }
+
+    fn cluster(&mut self, id: &str) -> Result<Cluster, Error> {
+        self.get(&format!("/api/clusters_mgmt/v1/clusters/{}", id), &[])
+    }
+
+    fn upgrade_policies(&mut self, id: &str) -> Result<Vec<UpgradePolicy>, Error> {
+        let policies: List<UpgradePolicy> = self.get(
+            &format!("/api/clusters_mgmt/v1/clusters/{}/upgrade_policies", id),
+            &[],
+        )?;
+        Ok(policies.items)
+    }
+
+    fn create_upgrade_policy(&mut self, id: &str, policy: &UpgradePolicy) -> Result<(), Error> {
+        let backoff = self.backoff;
+        backoff.retry("create upgrade policy", || {
+            let token = self.access_token()?;
+            self.http
+                .post(&format!(
+                    "{}/api/clusters_mgmt/v1/clusters/{}/upgrade_policies",
+                    self.url, id
+                ))
+                .bearer_auth(token)
+                .json(policy)
+                .send()?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+pub fn run(client: APIClient, options: &Options, status: &Arc<Mutex<Status>>) -> Result<(), Error> {
+    if options.force {
+        warn!("OCM does not allow forced updates; ignoring --force");
+    }
+
+    let mut ocm = Client {
+        http: options.http(&client)?,
+        url: options.ocm_url.trim_end_matches('/').to_string(),
+        token_url: options.ocm_token_url.clone(),
+        offline_token: match &options.ocm_token_file {
+            Some(path) => SecretFile::new(path),
+            None => return Err(Error::Config("--ocm requires --ocm-token-file".to_string())),
+        },
+        access_token: None,
+        backoff: options.backoff(),
+    };
+
+    let versions = clusterversion::api(client);
+    let external_id = versions
+        .get(&options.cluster_version_name)?
+        .spec
+        .cluster_id
+        .unwrap_or_default();
+    let id = match &options.ocm_cluster {
+        Some(id) => id.clone(),
+        None => ocm.find_cluster(&external_id)?,
+    };
+    info!("Managing upgrades of OCM cluster {}", id);
+
+    let fleet = Fleet::new(options);
+    let mut saved = State::default();
+    loop {
+        let mut row = FleetCluster::new(&id);
+        match reconcile(
+            &mut ocm,
+            &versions,
+            &fleet,
+            &mut saved,
+            &id,
+            &external_id,
+            &mut row,
+        ) {
+            Err(error) if retry::unauthorized(&error) => return Err(error),
+            Err(error) => {
+                error!("Failed to reconcile OCM cluster {}: {}", id, error);
+                row.hold("error", error.to_string());
+            }
+            Ok(()) => {}
+        }
+        {
+            let mut status = status.lock().expect("status lock");
+            status.clusters = vec![row];
+            status.evaluated_at = Some(Utc::now());
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn reconcile(
+    ocm: &mut Client,
+    versions: &Api<ClusterVersion>,
+    fleet: &Fleet,
+    saved: &mut State,
+    id: &str,
+    external_id: &str,
+    row: &mut FleetCluster,
+) -> Result<(), Error> {
+    let cluster = ocm.cluster(id)?;
+    trace!("{:?}", cluster);
+    row.version = Some(cluster.version.raw_id.clone());
+
+    // Pausing, snoozing and approving are done through the local ClusterVersion, just like for
+    // unmanaged clusters.
+    let version = kubeapi::call("get", "clusterversions", || {
+        versions.get(&fleet.options.cluster_version_name)
+    })?;
+    if let Some(status) = &version.status {
+        row.last_result = status::last_result(&status.history);
+        fleet.track(saved, status, id);
+    }
+
+    if let Some(policy) = ocm.upgrade_policies(id)?.first() {
+        debug!(
+            "Upgrade to {} is already scheduled for {}",
+            policy.version, policy.next_run
+        );
+        row.candidate = semver::Version::parse(&policy.version).ok();
+        row.hold("scheduled", format!("scheduled for {}", policy.next_run));
+        return Ok(());
+    }
+
+    let candidates = cluster
+        .version
+        .available_upgrades
+        .iter()
+        .filter_map(|version| semver::Version::parse(version).ok())
+        .map(|version| ClusterUpdate {
+            force: false,
+            // OCM picks the release image itself.
+            image: String::new(),
+            version,
+            raw_version: None,
+        })
+        .collect();
+    let mut state = ClusterState::new(&version, Utc::now());
+    state.cluster_id = Some(external_id.to_string());
+    state.version = row.version.clone();
+    state.approved = gates::approved(&version.metadata);
+    let decision = fleet.decide(state, candidates, saved);
+    let update = match fleet::record(row, decision) {
+        Some(update) => update.version,
+        None => return Ok(()),
+    };
+
+    info!(
+        "Scheduling update from {} to {}",
+        cluster.version.raw_id, update
+    );
+    ocm.create_upgrade_policy(
+        id,
+        &UpgradePolicy {
+            schedule_type: "manual".to_string(),
+            upgrade_type: "OSD".to_string(),
+            version: update.to_string(),
+            next_run: Utc::now() + chrono::Duration::minutes(SCHEDULE_DELAY_MINUTES),
+        },
+    )?;
+    saved.attempted = Some(update);
+    saved.attempted_at = Some(Utc::now());
+    row.reason = Some("scheduled the update".to_string());
+    Ok(())
+}
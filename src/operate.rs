@@ -0,0 +1,458 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running the operator against the local cluster: reconciling its ClusterVersion whenever it
+//! changes, until the API server rejects the credentials, and exiting once --once or --wait is
+//! done.
+
+use crate::policyconfig::Live;
+use crate::reconciler::{
+    budget_checks, dns_check, emit, health_checks, skip_reason, upgrade_policy, Reconciler,
+};
+use crate::record;
+use crate::status::{Operator, Status};
+use crate::verify::KubeVerifier;
+use chrono::Utc;
+use kube::client::APIClient;
+use openshift_update::clock::SystemClock;
+use openshift_update::clusterversion::{
+    ClusterVersion, ClusterVersionClient, HistoricalEntry, KubeClient, Outcome,
+};
+use openshift_update::credentials::KubeCredentials;
+use openshift_update::durations::KubePools;
+use openshift_update::http::HttpClient;
+use openshift_update::notify::Event;
+use openshift_update::policy::Decision;
+use openshift_update::release::RegistryClient;
+use openshift_update::retry::{self, CircuitBreaker, Transition};
+use openshift_update::secret::SecretFile;
+use openshift_update::state::{ConfigMapStore, MemoryStore, StateStore};
+use openshift_update::steps::KubeAcks;
+use openshift_update::storage::KubeStorage;
+use openshift_update::Error;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Manage updates of the local cluster until the API server rejects the client's credentials.
+/// Only the policy follows `live`; everything else is fixed by the options at the start.
+pub fn operate(
+    client: APIClient,
+    live: &Live,
+    status: &Arc<Mutex<Status>>,
+    single_node: bool,
+) -> Result<(), Error> {
+    let options = &live.get();
+    let store: Box<dyn StateStore> = match &options.state_namespace {
+        Some(namespace) => Box::new(ConfigMapStore::new(
+            client.clone(),
+            namespace,
+            options.backoff(),
+        )),
+        None => Box::new(MemoryStore::default()),
+    };
+    let http = options.http(&client)?;
+    let versions = KubeClient::new(
+        client.clone(),
+        &options.cluster_version_name,
+        options.backoff(),
+    )?
+    .field_manager(&options.field_manager);
+
+    let graph = HttpClient {
+        http,
+        backoff: options.backoff(),
+    };
+    let registry = RegistryClient {
+        http: graph.http.clone(),
+        backoff: options.backoff(),
+        auth: options.registry_auth_file.as_deref().map(SecretFile::new),
+    };
+    let storage = KubeStorage {
+        client: client.clone(),
+        backoff: options.backoff(),
+    };
+    let credentials = KubeCredentials {
+        client: client.clone(),
+        backoff: options.backoff(),
+    };
+    let acks = KubeAcks {
+        client: client.clone(),
+        backoff: options.backoff(),
+    };
+    let pools = KubePools {
+        client: client.clone(),
+        backoff: options.backoff(),
+    };
+    let budgets = budget_checks(options, &graph.http)?;
+    let verifier = KubeVerifier {
+        client: client.clone(),
+        http: graph.http.clone(),
+        urls: options.verify_urls.clone(),
+        routes: options.verify_routes.clone(),
+        dns: dns_check(options, &client),
+    };
+    let mut breaker = CircuitBreaker::new(options.failure_threshold);
+    // With --wait, the update the cluster is being watched for after the first evaluation.
+    let mut waiting: Option<semver::Version> = None;
+    let mut desired = None;
+    loop {
+        // A single evaluation shouldn't wait for the ClusterVersion to change first.
+        let latest = if options.once || (options.wait && waiting.is_none()) {
+            versions.get().map(Some)
+        } else {
+            versions.watch()
+        };
+        let result = match latest {
+            Ok(Some(version)) => {
+                // The wait is over before anything else is made of the cluster's new state (such
+                // as the next update).
+                if let Some(code) = waiting
+                    .as_ref()
+                    .and_then(|requested| waited(&version, requested))
+                {
+                    info!("{}", progress(&status.lock().expect("status lock")));
+                    process::exit(code);
+                }
+                desired = updating_to(&version);
+                // The policy may have changed since the last reconcile, in which case it applies
+                // from this one on.
+                let options = live.get();
+                let policy = upgrade_policy(&options);
+                let checks = health_checks(&options, &client, single_node);
+                if let Some(dir) = &options.record {
+                    record::save(
+                        dir,
+                        &options,
+                        &client,
+                        &versions,
+                        store.as_ref(),
+                        &graph,
+                        &version,
+                    );
+                }
+                let reconciler = Reconciler {
+                    client: &versions,
+                    store: store.as_ref(),
+                    graph: &graph,
+                    releases: if options.release_diff {
+                        Some(&registry)
+                    } else {
+                        None
+                    },
+                    rollout: &graph,
+                    health: &checks,
+                    storage: &storage,
+                    credentials: &credentials,
+                    acks: &acks,
+                    pools: &pools,
+                    budgets: &budgets,
+                    verifier: &verifier,
+                    options: &options,
+                    policy: policy.as_ref(),
+                    status,
+                    single_node,
+                    clock: &SystemClock,
+                };
+                let result = reconciler.reconcile(version);
+                record_operators(&verifier, status);
+                match result {
+                    Err(error) if retry::unauthorized(&error) => return Err(error),
+                    result => result.map_err(|error| format!("Failed to apply update: {}", error)),
+                }
+            }
+            Ok(None) => Err(format!(
+                "Unable to find ClusterVersion {} (see --cluster-version-name)",
+                options.cluster_version_name
+            )),
+            Err(error) if retry::unauthorized(&error) => return Err(error),
+            Err(error) => Err(format!("Failed to read ClusterVersion: {}", error)),
+        };
+        match (&result, &options.heartbeat_file) {
+            (Err(error), _) => error!("{}", error),
+            (Ok(()), Some(path)) => heartbeat(path),
+            (Ok(()), None) => {}
+        }
+        if options.once || (options.wait && waiting.is_none()) {
+            let decision = status.lock().expect("status lock").decision.clone();
+            waiting = match (&result, &decision) {
+                (Ok(()), Some(Decision::Apply { update })) if options.wait => {
+                    Some(update.version.clone())
+                }
+                (Ok(()), Some(Decision::InProgress)) if options.wait => desired.clone(),
+                _ => None,
+            };
+            match &waiting {
+                Some(version) => info!("Waiting for the cluster to finish updating to {}", version),
+                None => process::exit(match result {
+                    Ok(()) => exit_code(decision.as_ref()),
+                    Err(_) => EXIT_ERROR,
+                }),
+            }
+        }
+
+        match (breaker.record(result.is_ok()), result) {
+            (Some(Transition::Opened), Err(error)) => {
+                error!(
+                    "Failed {} times in a row; retrying every {}",
+                    breaker.failures(),
+                    humantime::format_duration(options.failure_interval)
+                );
+                emit(
+                    options,
+                    Event::Unhealthy {
+                        failures: breaker.failures(),
+                        error,
+                    },
+                );
+            }
+            (Some(Transition::Closed), _) => {
+                info!("Recovered after repeated failures");
+                emit(options, Event::Recovered);
+            }
+            _ => {}
+        }
+        {
+            let mut status = status.lock().expect("status lock");
+            status.ready = !breaker.is_open();
+            status.consecutive_failures = breaker.failures();
+        }
+        if breaker.is_open() {
+            thread::sleep(options.failure_interval);
+        }
+    }
+}
+
+/// Refresh the ClusterOperators in the status, since whether every one of them is at the new
+/// version is the first thing asked once an update completes.
+fn record_operators(verifier: &KubeVerifier, status: &Mutex<Status>) {
+    match verifier.operators() {
+        Ok(operators) => {
+            let mut status = status.lock().expect("status lock");
+            let target = status.version.clone();
+            status.operators = operators
+                .iter()
+                .map(|operator| Operator::new(operator, target.as_deref()))
+                .collect();
+        }
+        Err(error) => warn!("Failed to list the ClusterOperators: {}", error),
+    }
+}
+
+/// Exit codes of `--once`, so that wrappers can act on the outcome without parsing the output.
+const EXIT_APPLIED: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_NOTHING_TO_DO: i32 = 10;
+const EXIT_BLOCKED: i32 = 20;
+const EXIT_WINDOW: i32 = 30;
+const EXIT_PENDING: i32 = 35;
+pub const EXIT_TIMEOUT: i32 = 40;
+const EXIT_FAILED: i32 = 50;
+
+/// The exit code of `--wait` once the cluster is done with the update to `requested`, or None
+/// while it's still to be applied.
+fn waited(version: &ClusterVersion, requested: &semver::Version) -> Option<i32> {
+    let outcome = version
+        .status
+        .as_ref()
+        .map_or(Outcome::NotStarted, |status| status.outcome(requested));
+    let desired = version
+        .spec
+        .desired_update
+        .as_ref()
+        .map(|update| &update.version);
+    match outcome {
+        Outcome::Completed => Some(EXIT_APPLIED),
+        Outcome::Abandoned { .. } => Some(EXIT_FAILED),
+        // Withdrawn (e.g. by --abort-after) or replaced before the cluster took it up.
+        Outcome::NotStarted if desired != Some(requested) => Some(EXIT_FAILED),
+        Outcome::NotStarted | Outcome::InProgress => None,
+    }
+}
+
+/// The version the cluster is updating to: the desired update, or failing that, the version its
+/// history has yet to complete.
+fn updating_to(version: &ClusterVersion) -> Option<semver::Version> {
+    if let Some(update) = &version.spec.desired_update {
+        return Some(update.version.clone());
+    }
+    version
+        .status
+        .as_ref()
+        .and_then(|status| status.history.first())
+        .filter(|entry| entry.state.as_deref() != Some("Completed"))
+        .and_then(HistoricalEntry::parsed_version)
+}
+
+/// The exit code of `--once` for the decision it made.
+pub fn exit_code(decision: Option<&Decision>) -> i32 {
+    match decision {
+        Some(Decision::Apply { .. }) => EXIT_APPLIED,
+        None | Some(Decision::UpToDate) | Some(Decision::InProgress) => EXIT_NOTHING_TO_DO,
+        Some(Decision::Paused { .. }) | Some(Decision::Blocked { .. }) => EXIT_BLOCKED,
+        Some(Decision::Delayed { window: true, .. }) => EXIT_WINDOW,
+        Some(Decision::AwaitingApproval { .. }) | Some(Decision::Delayed { .. }) => EXIT_PENDING,
+    }
+}
+
+/// How far the operator got, for when `--timeout` cuts it short.
+pub fn progress(status: &Status) -> String {
+    let version = status.version.as_deref().unwrap_or("an unknown version");
+    match &status.decision {
+        None => format!(
+            "the cluster was not evaluated ({} consecutive failures)",
+            status.consecutive_failures
+        ),
+        Some(Decision::UpToDate) => format!("the cluster is up to date at {}", version),
+        Some(Decision::InProgress) => format!("the cluster is still updating to {}", version),
+        Some(Decision::Apply { update }) => format!(
+            "the cluster at {} was being updated to {}",
+            version, update.version
+        ),
+        Some(decision) => match (decision.update(), skip_reason(decision)) {
+            (Some(update), Some((gate, reason))) => format!(
+                "the update of the cluster at {} to {} is held back by the {} check: {}",
+                version, update.version, gate, reason
+            ),
+            _ => format!("the cluster is at {}", version),
+        },
+    }
+}
+
+/// Record that the operator is alive by writing the time to the file.
+fn heartbeat(path: &Path) {
+    if let Err(error) = fs::write(path, format!("{}\n", Utc::now().to_rfc3339())) {
+        warn!(
+            "Failed to write heartbeat file {}: {}",
+            path.display(),
+            error
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+    use openshift_update::clusterversion::ClusterUpdate;
+    use structopt::StructOpt;
+
+    #[test]
+    fn exits_with_the_outcome() {
+        let update = ClusterUpdate {
+            version: semver::Version::new(4, 1, 16),
+            image: "quay.io/openshift-release-dev/ocp-release:4.1.16".to_string(),
+            force: false,
+            raw_version: None,
+        };
+        assert_eq!(exit_code(None), EXIT_NOTHING_TO_DO);
+        assert_eq!(exit_code(Some(&Decision::UpToDate)), EXIT_NOTHING_TO_DO);
+        assert_eq!(
+            exit_code(Some(&Decision::Apply {
+                update: update.clone()
+            })),
+            EXIT_APPLIED
+        );
+        assert_eq!(
+            exit_code(Some(&Decision::Paused {
+                update: update.clone()
+            })),
+            EXIT_BLOCKED
+        );
+        assert_eq!(
+            exit_code(Some(&Decision::Delayed {
+                update: update.clone(),
+                not_before: Utc::now(),
+                window: true,
+            })),
+            EXIT_WINDOW
+        );
+        assert_eq!(
+            exit_code(Some(&Decision::Delayed {
+                update: update.clone(),
+                not_before: Utc::now(),
+                window: false,
+            })),
+            EXIT_PENDING
+        );
+        assert_eq!(
+            exit_code(Some(&Decision::AwaitingApproval { update })),
+            EXIT_PENDING
+        );
+    }
+
+    #[test]
+    fn waits_for_updates() {
+        let mut version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-in-progress.json"
+        ))
+        .expect("valid fixture");
+        let requested = semver::Version::new(4, 1, 16);
+        assert_eq!(updating_to(&version), Some(requested.clone()));
+        assert_eq!(waited(&version, &requested), None);
+
+        // Once it completes.
+        version.status.as_mut().expect("status").history[0].state = Some("Completed".to_string());
+        assert_eq!(updating_to(&version), None);
+        assert_eq!(waited(&version, &requested), Some(EXIT_APPLIED));
+        // Or once the cluster moves on without it.
+        version.status.as_mut().expect("status").history[1].state = Some("Partial".to_string());
+        assert_eq!(
+            waited(&version, &semver::Version::new(4, 1, 14)),
+            Some(EXIT_FAILED)
+        );
+        // Or once it's withdrawn before the cluster took it up.
+        assert_eq!(
+            waited(&version, &semver::Version::new(4, 1, 17)),
+            Some(EXIT_FAILED)
+        );
+
+        // --timeout only bounds the one-shot runs, which are either --once or --wait.
+        let parse = |args: &[&str]| {
+            Options::from_iter_safe(["openshift-update"].iter().chain(args)).map(|_| ())
+        };
+        assert!(parse(&["--wait", "--timeout", "1h"]).is_ok());
+        assert!(parse(&["--once", "--timeout", "1h"]).is_ok());
+        assert!(parse(&["--timeout", "1h"]).is_err());
+        assert!(parse(&["--once", "--wait"]).is_err());
+    }
+
+    #[test]
+    fn reports_progress_on_timeout() {
+        let mut status = Status::default();
+        assert_eq!(
+            progress(&status),
+            "the cluster was not evaluated (0 consecutive failures)"
+        );
+
+        status.version = Some("4.1.0".to_string());
+        status.decision = Some(Decision::Blocked {
+            update: ClusterUpdate {
+                version: semver::Version::new(4, 1, 16),
+                image: "quay.io/openshift-release-dev/ocp-release:4.1.16".to_string(),
+                force: false,
+                raw_version: None,
+            },
+            gate: "dns".to_string(),
+            reason: "the DNS operator is degraded".to_string(),
+        });
+        assert_eq!(
+            progress(&status),
+            "the update of the cluster at 4.1.0 to 4.1.16 is held back by the dns check: the DNS \
+             operator is degraded"
+        );
+    }
+}
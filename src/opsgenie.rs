@@ -0,0 +1,210 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opsgenie alerts for updates which fail or stall.
+//!
+//! Only the events someone has to act on open an alert: an update which was abandoned, failed its
+//! checks or hasn't completed after the drift threshold, and the operator itself failing
+//! repeatedly. Each alert is closed again by the event marking its recovery, such as the cluster
+//! completing an update. Alerts are identified by an alias naming the cluster and the problem, so
+//! Opsgenie folds repeats of a problem into the alert which is already open, and the alerts of
+//! clusters sharing a team stay apart. The cluster's ID and the version are given as tags.
+
+use crate::identity::Identity;
+use crate::notify::{self, Event, Notifier};
+use crate::retry::Backoff;
+use crate::secret::SecretFile;
+use crate::Error;
+use reqwest::header::AUTHORIZATION;
+
+/// The longest message Opsgenie accepts.
+const MAX_MESSAGE: usize = 130;
+
+pub struct Opsgenie {
+    pub http: reqwest::Client,
+    /// Base URL of the Alert API, e.g. "https://api.opsgenie.com" or, for accounts in the EU,
+    /// "https://api.eu.opsgenie.com".
+    pub url: String,
+    /// The key of an API integration.
+    pub key: SecretFile,
+    pub backoff: Backoff,
+}
+
+/// The problems an alert is opened for, which each have one alert per cluster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Problem {
+    Abandoned,
+    Unverified,
+    Stalled,
+    Unhealthy,
+}
+
+impl Problem {
+    fn name(self) -> &'static str {
+        match self {
+            Problem::Abandoned => "abandoned",
+            Problem::Unverified => "unverified",
+            Problem::Stalled => "stalled",
+            Problem::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Open(Problem),
+    Close(&'static [Problem]),
+}
+
+/// What the event does to the alerts, if anything.
+fn action(event: &Event) -> Option<Action> {
+    match event {
+        Event::Poisoned { .. } => Some(Action::Open(Problem::Abandoned)),
+        Event::Verified { passed: false, .. } => Some(Action::Open(Problem::Unverified)),
+        Event::Verified { passed: true, .. } => Some(Action::Close(&[Problem::Unverified])),
+        Event::Drift { .. } => Some(Action::Open(Problem::Stalled)),
+        Event::Completed { .. } => Some(Action::Close(&[Problem::Abandoned, Problem::Stalled])),
+        Event::Unhealthy { .. } => Some(Action::Open(Problem::Unhealthy)),
+        Event::Recovered => Some(Action::Close(&[Problem::Unhealthy])),
+        _ => None,
+    }
+}
+
+/// The alias of the cluster's alert for the problem.
+fn alias(problem: Problem, identity: &Identity) -> String {
+    match notify::cluster(identity) {
+        Some(cluster) => format!("openshift-update.{}.{}", cluster, problem.name()),
+        None => format!("openshift-update.{}", problem.name()),
+    }
+}
+
+/// The request opening the alert for the event.
+fn alert(problem: Problem, event: &Event, identity: &Identity) -> serde_json::Value {
+    let mut message = notify::message(event, identity);
+    if message.chars().count() > MAX_MESSAGE {
+        message = message.chars().take(MAX_MESSAGE - 3).collect::<String>() + "...";
+    }
+    let mut tags = vec!["openshift-update".to_string(), problem.name().to_string()];
+    if let Some(cluster_id) = &identity.cluster_id {
+        tags.push(format!("cluster:{}", cluster_id));
+    }
+    if let Some(version) = event.version() {
+        tags.push(format!("version:{}", version));
+    }
+    serde_json::json!({
+        "message": message,
+        "alias": alias(problem, identity),
+        "description": event.to_string(),
+        "tags": tags,
+        "details": serde_json::to_value(event).expect("Serialize to JSON"),
+        "source": "openshift-update",
+        "priority": match problem {
+            Problem::Abandoned | Problem::Unhealthy => "P2",
+            Problem::Unverified | Problem::Stalled => "P3",
+        },
+    })
+}
+
+impl Opsgenie {
+    fn post(&self, what: &str, path: &str, body: &serde_json::Value) -> Result<(), Error> {
+        let url = format!("{}/v2/alerts{}", self.url.trim_end_matches('/'), path);
+        self.backoff.retry(what, || {
+            self.http
+                .post(&url)
+                .header(AUTHORIZATION, format!("GenieKey {}", self.key.read()?))
+                .json(body)
+                .send()?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+impl Notifier for Opsgenie {
+    fn name(&self) -> &str {
+        "Opsgenie"
+    }
+
+    fn send(&self, event: &Event, identity: &Identity) -> Result<(), Error> {
+        match action(event) {
+            Some(Action::Open(problem)) => self.post(
+                "open the Opsgenie alert",
+                "",
+                &alert(problem, event, identity),
+            ),
+            Some(Action::Close(problems)) => {
+                // Closing an alert which isn't open is accepted, and leaves nothing behind.
+                for problem in problems {
+                    self.post(
+                        "close the Opsgenie alert",
+                        &format!("/{}/close?identifierType=alias", alias(*problem, identity)),
+                        &serde_json::json!({
+                            "source": "openshift-update",
+                            "note": event.to_string(),
+                        }),
+                    )?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_and_closes_alerts() {
+        let version = semver::Version::parse("4.1.16").expect("valid version");
+        let identity = Identity {
+            cluster_id: Some("e9c2a1d4".to_string()),
+            infrastructure_name: Some("prod-x7k2p".to_string()),
+        };
+        let drift = Event::Drift {
+            desired: version.clone(),
+            completed: Some("4.1.15".to_string()),
+            since: "2019-09-17T00:00:00Z".parse().expect("valid time"),
+        };
+        assert_eq!(action(&drift), Some(Action::Open(Problem::Stalled)));
+        let alert = alert(Problem::Stalled, &drift, &identity);
+        assert_eq!(alert["alias"], "openshift-update.prod-x7k2p.stalled");
+        assert_eq!(
+            alert["tags"],
+            serde_json::json!([
+                "openshift-update",
+                "stalled",
+                "cluster:e9c2a1d4",
+                "version:4.1.16"
+            ])
+        );
+        assert!(alert["message"].as_str().expect("message").len() <= MAX_MESSAGE);
+
+        assert_eq!(
+            action(&Event::Completed {
+                version: version.to_string()
+            }),
+            Some(Action::Close(&[Problem::Abandoned, Problem::Stalled]))
+        );
+        assert_eq!(
+            action(&Event::CandidateFound {
+                version,
+                image: "quay.io/openshift-release-dev/ocp-release:4.1.16".to_string(),
+                changes: None,
+            }),
+            None
+        );
+    }
+}
@@ -0,0 +1,973 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The operator's options, as they're given on the command line and in the environment.
+
+use crate::command::Command;
+use crate::{admission, policyconfig};
+use chrono::{DateTime, Utc};
+use kube::client::APIClient;
+use openshift_update::http::HttpConfig;
+use openshift_update::retry::Backoff;
+use openshift_update::slo::Objectives;
+use openshift_update::steps::ManualStep;
+use openshift_update::storage::Matrix;
+use openshift_update::vault;
+use openshift_update::velocity::Days;
+use openshift_update::window::Window;
+use openshift_update::Error;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    after_help = "Every option can also be set through an UPGRADE_* environment variable \
+                          named after it (e.g. UPGRADE_MAX_JITTER=2h or UPGRADE_FORCE=true), \
+                          which the command line takes precedence over.",
+    group = structopt::clap::ArgGroup::with_name("one-shot"),
+    group = structopt::clap::ArgGroup::with_name("api").multiple(true)
+)]
+// The options of the subsystems a build leaves out are still accepted (and refused by
+// `checkconfig::unsupported`), but never read.
+#[cfg_attr(
+    not(all(feature = "metrics-server", feature = "notifications")),
+    allow(dead_code)
+)]
+pub struct Options {
+    #[structopt(long = "force")]
+    /// Forcefully apply available updates, which skips the verification of their signatures.
+    /// Unless --interactive, an update is only forced while the ClusterVersion's (or with
+    /// --hosted-clusters, the HostedCluster's) upgrade.crawford.dev/force-approved-by annotation
+    /// names one of --force-approved-by
+    pub force: bool,
+
+    #[structopt(
+        long = "force-approved-by",
+        env = "UPGRADE_FORCE_APPROVED_BY",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    /// Who may approve forcing an update, by the name the upgrade.crawford.dev/force-approved-by
+    /// annotation gives. Unless --interactive, --force does nothing without it (may be repeated)
+    pub force_approved_by: Vec<String>,
+
+    #[structopt(long = "ca-bundle", env = "UPGRADE_CA_BUNDLE", parse(from_os_str))]
+    /// File of PEM-encoded CA certificates to trust for external endpoints such as the update
+    /// graph, in addition to the system's and the cluster proxy's
+    pub ca_bundle: Option<PathBuf>,
+
+    #[structopt(
+        long = "client-identity",
+        env = "UPGRADE_CLIENT_IDENTITY",
+        parse(from_os_str)
+    )]
+    /// PKCS #12 archive of the client certificate and key to present to external endpoints
+    pub client_identity: Option<PathBuf>,
+
+    #[structopt(
+        long = "client-identity-password-file",
+        env = "UPGRADE_CLIENT_IDENTITY_PASSWORD_FILE",
+        parse(from_os_str)
+    )]
+    /// File containing the password of the client identity archive
+    pub client_identity_password_file: Option<PathBuf>,
+
+    #[structopt(long = "insecure-skip-tls-verify")]
+    /// Don't verify the certificates of external endpoints (for lab use only)
+    pub insecure_skip_tls_verify: bool,
+
+    #[structopt(
+        long = "kube-api-qps",
+        env = "UPGRADE_KUBE_API_QPS",
+        default_value = "5"
+    )]
+    /// Most requests per second to make to the Kubernetes API, or 0 for no limit
+    pub kube_api_qps: f64,
+
+    #[structopt(
+        long = "kube-api-burst",
+        env = "UPGRADE_KUBE_API_BURST",
+        default_value = "10"
+    )]
+    /// Most requests to make to the Kubernetes API at once, ahead of the QPS limit
+    pub kube_api_burst: u32,
+
+    #[structopt(long = "as", env = "UPGRADE_AS")]
+    /// Make every request to the Kubernetes API as this user (or service account, as
+    /// "system:serviceaccount:<namespace>:<name>"), which the kubeconfig's user (or, without a
+    /// kubeconfig, the pod's service account) must be allowed to impersonate
+    pub impersonate_user: Option<String>,
+
+    #[structopt(
+        long = "as-group",
+        env = "UPGRADE_AS_GROUP",
+        requires = "impersonate-user",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    /// A group to impersonate along with --as; may be given more than once
+    pub impersonate_groups: Vec<String>,
+
+    #[structopt(
+        long = "kube-api-content-type",
+        env = "UPGRADE_KUBE_API_CONTENT_TYPE",
+        default_value = "protobuf",
+        possible_values = &["protobuf", "json"]
+    )]
+    /// How to request the lists of pods and nodes the gates read, "protobuf" (which is smaller
+    /// and cheaper to decode) or "json"
+    pub kube_api_content_type: String,
+
+    #[structopt(
+        long = "cluster-version-name",
+        env = "UPGRADE_CLUSTER_VERSION_NAME",
+        default_value = "version"
+    )]
+    /// Name of the ClusterVersion to manage
+    pub cluster_version_name: String,
+
+    #[structopt(
+        long = "field-manager",
+        env = "UPGRADE_FIELD_MANAGER",
+        default_value = "openshift-update"
+    )]
+    /// Field manager to record changes to the ClusterVersion under; updates are refused while any
+    /// other manager sets the desired update, so copies of the operator need different ones
+    pub field_manager: String,
+
+    #[structopt(
+        long = "max-jitter",
+        env = "UPGRADE_MAX_JITTER",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Delay applying a newly available update by up to this long (e.g. "2h"), derived from the
+    /// cluster ID so that clusters sharing mirrors don't all start upgrading at the same moment
+    pub max_jitter: Option<Duration>,
+
+    #[structopt(long = "maintenance-window", env = "UPGRADE_MAINTENANCE_WINDOW")]
+    /// Only start updates within this daily window in UTC (e.g. "22:00-04:00"). With
+    /// --max-jitter, each cluster starts at its own point of the window rather than as it opens
+    pub maintenance_window: Option<Window>,
+
+    #[structopt(long = "rollout-url", env = "UPGRADE_ROLLOUT_URL")]
+    /// Ask this rollout service what share of the fleet should have a version before updating to
+    /// it, and wait until the share reaches the cluster's position (derived from its ID)
+    pub rollout_url: Option<String>,
+
+    #[structopt(long = "policy-config-map", env = "UPGRADE_POLICY_CONFIG_MAP")]
+    /// ConfigMap ("<namespace>/<name>") whose keys override the policy options above and below
+    /// (e.g. "max-jitter: 2h"), watched and applied whenever it changes
+    pub policy_config_map: Option<policyconfig::Reference>,
+
+    #[structopt(long = "gitops-repo", env = "UPGRADE_GITOPS_REPO")]
+    /// Git repository declaring the cluster's target version and channel, which updates go no
+    /// further than
+    pub gitops_repo: Option<String>,
+
+    #[structopt(
+        long = "gitops-ref",
+        env = "UPGRADE_GITOPS_REF",
+        default_value = "HEAD"
+    )]
+    /// Branch (or other ref) of the GitOps repository to follow
+    pub gitops_ref: String,
+
+    #[structopt(
+        long = "gitops-path",
+        env = "UPGRADE_GITOPS_PATH",
+        default_value = "cluster-version.yaml"
+    )]
+    /// Path of the YAML file declaring the target within the GitOps repository
+    pub gitops_path: String,
+
+    #[structopt(
+        long = "gitops-interval",
+        env = "UPGRADE_GITOPS_INTERVAL",
+        default_value = "5m",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How often to fetch the GitOps repository
+    pub gitops_interval: Duration,
+
+    #[structopt(long = "acm")]
+    /// Drive the upgrades of the spoke clusters of an ACM hub through ClusterCurators instead of
+    /// updating the local ClusterVersion
+    pub acm: bool,
+
+    #[structopt(long = "acm-selector", env = "UPGRADE_ACM_SELECTOR")]
+    /// Label selector restricting which ManagedClusters are upgraded
+    pub acm_selector: Option<String>,
+
+    #[structopt(long = "hosted-clusters")]
+    /// Upgrade HyperShift HostedClusters and their NodePools instead of the local ClusterVersion
+    pub hosted_clusters: bool,
+
+    #[structopt(
+        long = "hosted-cluster-namespace",
+        env = "UPGRADE_HOSTED_CLUSTER_NAMESPACE"
+    )]
+    /// Only upgrade the HostedClusters in this namespace
+    pub hosted_cluster_namespace: Option<String>,
+
+    #[structopt(
+        long = "max-concurrent-upgrades",
+        env = "UPGRADE_MAX_CONCURRENT_UPGRADES"
+    )]
+    /// With --acm or --hosted-clusters, the most clusters to update at once; the others wait until
+    /// one of them has finished. Unlimited if unset
+    pub max_concurrent_upgrades: Option<usize>,
+
+    #[structopt(long = "ocm", requires = "ocm-token-file")]
+    /// Schedule upgrades through OpenShift Cluster Manager upgrade policies, as required for
+    /// managed (OSD and ROSA) clusters, instead of updating the ClusterVersion directly
+    pub ocm: bool,
+
+    #[structopt(
+        long = "ocm-url",
+        env = "UPGRADE_OCM_URL",
+        default_value = "https://api.openshift.com"
+    )]
+    /// Base URL of the OpenShift Cluster Manager API
+    pub ocm_url: String,
+
+    #[structopt(
+        long = "ocm-token-url",
+        env = "UPGRADE_OCM_TOKEN_URL",
+        default_value = "https://sso.redhat.com/auth/realms/redhat-external/protocol/openid-connect/token"
+    )]
+    /// URL at which the OCM offline token is exchanged for access tokens
+    pub ocm_token_url: String,
+
+    #[structopt(
+        long = "ocm-token-file",
+        env = "UPGRADE_OCM_TOKEN_FILE",
+        parse(from_os_str)
+    )]
+    /// File containing the OCM offline token
+    pub ocm_token_file: Option<PathBuf>,
+
+    #[structopt(long = "ocm-cluster", env = "UPGRADE_OCM_CLUSTER")]
+    /// OCM ID of the cluster (looked up from the ClusterVersion's cluster ID by default)
+    pub ocm_cluster: Option<String>,
+
+    #[structopt(
+        long = "listen",
+        env = "UPGRADE_LISTEN",
+        group = "api",
+        requires = "api-token-file"
+    )]
+    /// Address on which to serve the control and status API (e.g. "0.0.0.0:8080"). With --acm,
+    /// --hosted-clusters or --ocm, only the status of the fleet and the metrics are served
+    pub listen: Option<SocketAddr>,
+
+    #[structopt(
+        long = "grpc-listen",
+        env = "UPGRADE_GRPC_LISTEN",
+        group = "api",
+        requires = "api-token-file"
+    )]
+    /// Address on which to serve the control and status API over gRPC, in cleartext HTTP/2 (e.g.
+    /// "0.0.0.0:8081"), as described by proto/openshift-update.proto
+    pub grpc_listen: Option<SocketAddr>,
+
+    #[structopt(
+        long = "api-token-file",
+        env = "UPGRADE_API_TOKEN_FILE",
+        parse(from_os_str)
+    )]
+    /// File containing the bearer token that clients of the API (over HTTP or gRPC) must present
+    pub api_token_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "webhook-listen",
+        env = "UPGRADE_WEBHOOK_LISTEN",
+        requires_all = &["webhook-cert", "webhook-key"]
+    )]
+    /// Address on which to serve the admission webhooks over HTTPS (e.g. "0.0.0.0:8443")
+    pub webhook_listen: Option<SocketAddr>,
+
+    #[structopt(
+        long = "webhook-cert",
+        env = "UPGRADE_WEBHOOK_CERT",
+        parse(from_os_str)
+    )]
+    /// PEM file holding the webhooks' certificate, followed by any intermediates
+    pub webhook_cert: Option<PathBuf>,
+
+    #[structopt(long = "webhook-key", env = "UPGRADE_WEBHOOK_KEY", parse(from_os_str))]
+    /// PEM file holding the key of the webhooks' certificate
+    pub webhook_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "heartbeat-file",
+        env = "UPGRADE_HEARTBEAT_FILE",
+        parse(from_os_str)
+    )]
+    /// File to which the time is written after every successful reconcile, for an exec liveness
+    /// probe to check the age of (e.g. with `find FILE -mmin -10`)
+    pub heartbeat_file: Option<PathBuf>,
+
+    #[structopt(long = "record", env = "UPGRADE_RECORD", parse(from_os_str))]
+    /// Directory to which the inputs of every decision (the ClusterVersion, the update graph, the
+    /// persisted state and the checks of the cluster) are written, for --replay
+    pub record: Option<PathBuf>,
+
+    #[structopt(
+        long = "replay",
+        env = "UPGRADE_REPLAY",
+        parse(from_os_str),
+        conflicts_with = "record"
+    )]
+    /// Make the decisions recorded with --record again, in order, under the policy set by the
+    /// other options and without contacting the cluster
+    pub replay: Option<PathBuf>,
+
+    #[structopt(long = "require-approval", requires = "api")]
+    /// Only apply an update once it has been approved through the API, which saves the approval
+    /// with --state-namespace (or, for the clusters of a fleet, through their
+    /// upgrade.crawford.dev/approved annotation)
+    pub require_approval: bool,
+
+    #[structopt(
+        long = "z-stream-delay",
+        env = "UPGRADE_Z_STREAM_DELAY",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Only apply an update within the cluster's minor version once it has been available for
+    /// this long (e.g. "7d")
+    pub z_stream_delay: Option<Duration>,
+
+    #[structopt(long = "minor-days", env = "UPGRADE_MINOR_DAYS")]
+    /// Only start updates to a new minor version on these days of the month (e.g. "1-7")
+    pub minor_days: Option<Days>,
+
+    #[structopt(
+        long = "manual-step",
+        env = "UPGRADE_MANUAL_STEPS",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    /// Hold back updates to a new minor version until this step has been acknowledged, given as
+    /// "<name>=<from>-><to>" or "<name>=<to>" (e.g. "firewall-change=4.12->4.13"). It's
+    /// acknowledged by annotating the ClusterVersion with
+    /// manual-steps.upgrade.crawford.dev/<name>=<to>, or by setting <name> to "true" in the
+    /// openshift-config/admin-acks ConfigMap (may be repeated)
+    pub manual_steps: Vec<ManualStep>,
+
+    #[structopt(long = "require-eus-approval", requires = "api")]
+    /// Only apply an update away from an even (EUS) minor version once it has been approved
+    /// through the API
+    pub require_eus_approval: bool,
+
+    #[structopt(long = "allow-unmanaged")]
+    /// Update even though components have been overridden to be unmanaged (unsupported)
+    pub allow_unmanaged: bool,
+
+    #[structopt(long = "allow-prerelease")]
+    /// Also update to pre-release versions (nightlies, engineering and release candidates),
+    /// which are skipped by default
+    pub allow_prerelease: bool,
+
+    #[structopt(
+        long = "argocd-project",
+        env = "UPGRADE_ARGOCD_PROJECTS",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    /// Block updates while any Argo CD application in this project is Degraded or OutOfSync
+    /// ("*" for every project; may be repeated)
+    pub argocd_projects: Vec<String>,
+
+    #[structopt(long = "max-stuck-pods", env = "UPGRADE_MAX_STUCK_PODS")]
+    /// Block updates while more than this many pods are crash-looping or failing to pull their
+    /// images
+    pub max_stuck_pods: Option<usize>,
+
+    #[structopt(
+        long = "stuck-pods-namespace",
+        env = "UPGRADE_STUCK_PODS_NAMESPACES",
+        use_delimiter = true,
+        number_of_values = 1,
+        requires = "max-stuck-pods"
+    )]
+    /// Only count the stuck pods in this namespace, rather than in every one (may be repeated)
+    pub stuck_pods_namespaces: Vec<String>,
+
+    #[structopt(long = "check-bare-metal-hosts")]
+    /// Block updates while any of a bare-metal cluster's BareMetalHosts is in error (usually
+    /// because of its BMC) or is being provisioned
+    pub check_bare_metal_hosts: bool,
+
+    #[structopt(long = "check-capacity")]
+    /// Block updates while any namespace has used up its quota for pods or any
+    /// PersistentVolumeClaim is stuck Pending, either of which keeps drained pods from being
+    /// rescheduled
+    pub check_capacity: bool,
+
+    #[structopt(
+        long = "storage-matrix",
+        env = "UPGRADE_STORAGE_MATRIX",
+        parse(try_from_str = Matrix::read)
+    )]
+    /// YAML file listing the minor versions which deprecate and remove support for storage
+    /// provisioners; updates which remove one that the cluster uses are blocked
+    pub storage_matrix: Option<Matrix>,
+
+    #[structopt(long = "check-dns")]
+    /// Block updates while the cluster's DNS is unhealthy, and check it again once each update
+    /// completes (with --verify-updates)
+    pub check_dns: bool,
+
+    #[structopt(
+        long = "dns-check-name",
+        env = "UPGRADE_DNS_CHECK_NAME",
+        requires = "check-dns"
+    )]
+    /// Also resolve this name through the cluster's DNS (e.g.
+    /// "kubernetes.default.svc.cluster.local"), when running inside the cluster
+    pub dns_check_name: Option<String>,
+
+    #[structopt(
+        long = "slo-file",
+        env = "UPGRADE_SLO_FILE",
+        parse(try_from_str = Objectives::read)
+    )]
+    /// YAML file of service-level objectives; updates other than security fixes are blocked
+    /// while any of their error budgets burns too fast
+    pub slo_file: Option<Objectives>,
+
+    #[structopt(
+        long = "prometheus-url",
+        env = "UPGRADE_PROMETHEUS_URL",
+        default_value = "https://thanos-querier.openshift-monitoring.svc:9091"
+    )]
+    /// Prometheus-compatible API which the service-level objectives are queried from
+    pub prometheus_url: String,
+
+    #[structopt(
+        long = "prometheus-token-file",
+        env = "UPGRADE_PROMETHEUS_TOKEN_FILE",
+        parse(from_os_str)
+    )]
+    /// File containing the bearer token to query Prometheus with
+    pub prometheus_token_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "registry-auth-file",
+        env = "UPGRADE_REGISTRY_AUTH_FILE",
+        parse(from_os_str)
+    )]
+    /// File of registry credentials in the format of a pull secret's .dockerconfigjson, with
+    /// which release images are read (anonymously for registries it doesn't list)
+    pub registry_auth_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "vault-addr",
+        env = "UPGRADE_VAULT_ADDR",
+        requires = "vault-role"
+    )]
+    /// Base URL of a HashiCorp Vault from which to fetch the secrets given by --vault-secret
+    pub vault_addr: Option<String>,
+
+    #[structopt(long = "vault-role", env = "UPGRADE_VAULT_ROLE")]
+    /// Role to log into Vault as with the service account token
+    pub vault_role: Option<String>,
+
+    #[structopt(
+        long = "vault-auth-mount",
+        env = "UPGRADE_VAULT_AUTH_MOUNT",
+        default_value = "kubernetes"
+    )]
+    /// Path at which Vault's Kubernetes auth method is mounted
+    pub vault_auth_mount: String,
+
+    #[structopt(
+        long = "vault-secret",
+        env = "UPGRADE_VAULT_SECRETS",
+        use_delimiter = true,
+        number_of_values = 1,
+        requires = "vault-addr"
+    )]
+    /// Fetch a key of a secret from Vault and keep it written to a file, given as PATH#KEY=FILE
+    /// (e.g. "secret/data/openshift-update#ocm-token=/run/secrets/ocm-token", may be repeated)
+    pub vault_secrets: Vec<vault::Mapping>,
+
+    #[structopt(long = "release-diff")]
+    /// Compare each new candidate's release with the cluster's and list the components which
+    /// change in its candidate-found event (reads the release images from their registries)
+    pub release_diff: bool,
+
+    #[structopt(long = "once", group = "one-shot", conflicts_with = "interactive")]
+    /// Evaluate the local ClusterVersion a single time, apply the chosen update and exit with 0
+    /// if an update was applied, 10 if there was nothing to do, 20 if the update was held back by
+    /// a check (or is paused), 30 if it is outside of the maintenance window, 35 if it is still
+    /// soaking, jittered, paced by the cadence or awaiting approval and 1 on error
+    pub once: bool,
+
+    #[structopt(long = "wait", group = "one-shot", conflicts_with = "interactive")]
+    /// Like --once, but once an update has been applied (or if one already is in progress), wait
+    /// for the cluster to finish it, exiting with 0 once it has and 50 if it was abandoned or
+    /// withdrawn instead
+    pub wait: bool,
+
+    #[structopt(
+        long = "timeout",
+        env = "UPGRADE_TIMEOUT",
+        requires = "one-shot",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Give up on --once or --wait after this long, reporting how far it got and exiting with 40
+    pub timeout: Option<Duration>,
+
+    #[structopt(long = "interactive")]
+    /// Describe each update of the local ClusterVersion and ask for confirmation on the terminal
+    /// before applying it
+    pub interactive: bool,
+
+    #[structopt(
+        long = "manual-edits",
+        env = "UPGRADE_MANUAL_EDITS",
+        default_value = "warn"
+    )]
+    /// What the ClusterVersion admission webhook does about others changing the desired update
+    /// while an update the operator requested is in progress: "warn" or "block"
+    pub manual_edits: admission::Edits,
+
+    #[structopt(long = "events-stdout", conflicts_with = "interactive")]
+    /// Write a JSON object to stdout for each step of an update of the local ClusterVersion (one
+    /// per line)
+    pub events_stdout: bool,
+
+    #[structopt(
+        long = "matrix-homeserver",
+        env = "UPGRADE_MATRIX_HOMESERVER",
+        requires_all = &["matrix-token-file", "matrix-room"]
+    )]
+    /// Post each step of an update of the local ClusterVersion to a Matrix room, through this
+    /// homeserver (e.g. https://matrix.example.com)
+    pub matrix_homeserver: Option<reqwest::Url>,
+
+    #[structopt(
+        long = "matrix-token-file",
+        env = "UPGRADE_MATRIX_TOKEN_FILE",
+        parse(from_os_str),
+        requires = "matrix-homeserver"
+    )]
+    /// File containing the access token of the Matrix user to post as, which has to have joined
+    /// the room
+    pub matrix_token_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "matrix-room",
+        env = "UPGRADE_MATRIX_ROOM",
+        requires = "matrix-homeserver"
+    )]
+    /// ID of the Matrix room to post to (e.g. !ops:example.com), rather than one of its aliases
+    pub matrix_room: Option<String>,
+
+    #[structopt(
+        long = "google-chat-webhook-file",
+        env = "UPGRADE_GOOGLE_CHAT_WEBHOOK_FILE",
+        parse(from_os_str)
+    )]
+    /// File containing the URL of a Google Chat space's incoming webhook, which each step of an
+    /// update of the local ClusterVersion is posted to as a card
+    pub google_chat_webhook_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "opsgenie-api-key-file",
+        env = "UPGRADE_OPSGENIE_API_KEY_FILE",
+        parse(from_os_str)
+    )]
+    /// File containing the key of an Opsgenie API integration, which is alerted when an update
+    /// of the local ClusterVersion fails or stalls (and when the operator keeps failing), and
+    /// whose alerts are closed once that recovers
+    pub opsgenie_api_key_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "opsgenie-url",
+        env = "UPGRADE_OPSGENIE_URL",
+        default_value = "https://api.opsgenie.com"
+    )]
+    /// Opsgenie's API, which is https://api.eu.opsgenie.com for accounts in the EU
+    pub opsgenie_url: String,
+
+    #[structopt(long = "snmp-target", env = "UPGRADE_SNMP_TARGET")]
+    /// Send an SNMPv2c trap to this receiver, given as "<host>:<port>" (e.g. nms.example.com:162),
+    /// when an update of the local ClusterVersion starts, completes or fails. The traps are
+    /// described by mibs/OPENSHIFT-UPDATE-MIB.txt
+    pub snmp_target: Option<String>,
+
+    #[structopt(
+        long = "snmp-community",
+        env = "UPGRADE_SNMP_COMMUNITY",
+        default_value = "public"
+    )]
+    /// Community to send the traps with
+    pub snmp_community: String,
+
+    #[structopt(
+        long = "snmp-enterprise-oid",
+        env = "UPGRADE_SNMP_ENTERPRISE_OID",
+        default_value = "1.3.6.1.4.1.8072.9999.1"
+    )]
+    /// Root of the MIB of the traps, which has to match the MIB loaded by the receiver. The
+    /// default is in the Net-SNMP playpen, where the MIB is rooted as it's shipped
+    pub snmp_enterprise_oid: String,
+
+    #[structopt(long = "verify-updates")]
+    /// Once each update completes, check that the API server, ClusterOperators and nodes are
+    /// healthy at the new version, and report the results as a verified event
+    pub verify_updates: bool,
+
+    #[structopt(
+        long = "verify-url",
+        env = "UPGRADE_VERIFY_URLS",
+        use_delimiter = true,
+        number_of_values = 1,
+        requires = "verify-updates"
+    )]
+    /// Also check that this URL (e.g. an application's route) responds successfully (may be
+    /// repeated)
+    pub verify_urls: Vec<String>,
+
+    #[structopt(
+        long = "verify-route",
+        env = "UPGRADE_VERIFY_ROUTES",
+        use_delimiter = true,
+        number_of_values = 1,
+        requires = "verify-updates"
+    )]
+    /// Also request this Route ("<namespace>/<name>") over HTTPS, along with the ingress canary,
+    /// once the API server and ingress are healthy (may be repeated)
+    pub verify_routes: Vec<policyconfig::Reference>,
+
+    #[structopt(
+        long = "backoff-initial",
+        env = "UPGRADE_BACKOFF_INITIAL",
+        default_value = "1s",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Delay before retrying a failed API request, doubled (with jitter) on each further failure
+    pub backoff_initial: Duration,
+
+    #[structopt(
+        long = "backoff-max",
+        env = "UPGRADE_BACKOFF_MAX",
+        default_value = "5m",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Longest delay between retries of a failed API request; also used when throttled
+    pub backoff_max: Duration,
+
+    #[structopt(long = "retries", env = "UPGRADE_RETRIES", default_value = "5")]
+    /// Number of times a failed API request is retried before giving up
+    pub retries: u32,
+
+    #[structopt(long = "state-namespace", env = "UPGRADE_STATE_NAMESPACE")]
+    /// Namespace of the ConfigMap in which state is kept across restarts (kept in memory if unset)
+    pub state_namespace: Option<String>,
+
+    #[structopt(
+        long = "abort-after",
+        env = "UPGRADE_ABORT_AFTER",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Withdraw and poison a requested update which the cluster hasn't accepted within this long
+    /// (three times as long on a single node)
+    pub abort_after: Option<Duration>,
+
+    #[structopt(long = "snooze-until", env = "UPGRADE_SNOOZE_UNTIL")]
+    /// Defer every automatic update until this time (in RFC 3339, e.g. 2019-10-01T00:00:00Z),
+    /// after which they resume by themselves
+    pub snooze_until: Option<DateTime<Utc>>,
+
+    #[structopt(
+        long = "snooze-reason",
+        env = "UPGRADE_SNOOZE_REASON",
+        requires = "snooze-until"
+    )]
+    /// Why updates are snoozed, which is reported along with the snooze
+    pub snooze_reason: Option<String>,
+
+    #[structopt(
+        long = "snooze-reminder",
+        env = "UPGRADE_SNOOZE_REMINDER",
+        default_value = "1d",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How long before a snooze ends to remind that updates are about to resume
+    pub snooze_reminder: Duration,
+
+    #[structopt(
+        long = "blocked-reminder",
+        env = "UPGRADE_BLOCKED_REMINDER",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How often to remind that the candidate is still held back by the same gate. Without it,
+    /// that's only reported when it starts and once it stops
+    pub blocked_reminder: Option<Duration>,
+
+    #[structopt(
+        long = "min-patch-interval",
+        env = "UPGRADE_MIN_PATCH_INTERVAL",
+        default_value = "30s",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Wait at least this long between patches of the desired update, however often the
+    /// ClusterVersion changes
+    pub min_patch_interval: Duration,
+
+    #[structopt(
+        long = "drift-threshold",
+        env = "UPGRADE_DRIFT_THRESHOLD",
+        default_value = "4h",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How long the desired version may differ from the last completed one before it is reported
+    /// (three times as long on a single node)
+    pub drift_threshold: Duration,
+
+    #[structopt(
+        long = "failure-threshold",
+        env = "UPGRADE_FAILURE_THRESHOLD",
+        default_value = "5"
+    )]
+    /// Number of consecutive failures after which the operator reports itself unready
+    pub failure_threshold: u32,
+
+    #[structopt(
+        long = "failure-interval",
+        env = "UPGRADE_FAILURE_INTERVAL",
+        default_value = "10m",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Delay between attempts once the failure threshold has been reached
+    pub failure_interval: Duration,
+
+    #[structopt(
+        long = "preflight-interval",
+        env = "UPGRADE_PREFLIGHT_INTERVAL",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// Also run the preflight checks this often (e.g. "24h"), publishing the results on the
+    /// ClusterVersion and as metrics
+    pub preflight_interval: Option<Duration>,
+
+    #[structopt(long = "self-update-image", env = "UPGRADE_SELF_UPDATE_IMAGE")]
+    /// Tag of the operator's own image to follow (e.g. quay.io/crawford/openshift-update:stable),
+    /// moving its Deployment to whichever digest the tag points at
+    pub self_update_image: Option<String>,
+
+    #[structopt(
+        long = "self-update-deployment",
+        env = "UPGRADE_SELF_UPDATE_DEPLOYMENT",
+        default_value = "openshift-update/openshift-update"
+    )]
+    /// The operator's own Deployment, as <namespace>/<name>
+    pub self_update_deployment: policyconfig::Reference,
+
+    #[structopt(
+        long = "self-update-container",
+        env = "UPGRADE_SELF_UPDATE_CONTAINER",
+        default_value = "openshift-update"
+    )]
+    /// Name of the operator's container in its Deployment
+    pub self_update_container: String,
+
+    #[structopt(
+        long = "self-update-interval",
+        env = "UPGRADE_SELF_UPDATE_INTERVAL",
+        default_value = "1h",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How often to check the tag of --self-update-image
+    pub self_update_interval: Duration,
+
+    #[structopt(long = "self-update-days", env = "UPGRADE_SELF_UPDATE_DAYS")]
+    /// Only move the operator to a new image on these days of the month (e.g. "1-7")
+    pub self_update_days: Option<Days>,
+
+    #[structopt(
+        long = "self-update-timeout",
+        env = "UPGRADE_SELF_UPDATE_TIMEOUT",
+        default_value = "5m",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// How long the new image may take to pass its staged check before it's given up on
+    pub self_update_timeout: Duration,
+
+    #[structopt(
+        long = "refresh-updates-after",
+        env = "UPGRADE_REFRESH_UPDATES_AFTER",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    /// While the cluster fails to retrieve its available updates, ask it to try again this often
+    /// (e.g. "15m")
+    pub refresh_updates_after: Option<Duration>,
+
+    #[structopt(short = "v", parse(from_occurrences))]
+    /// Verbosity level (can be set multiple times)
+    pub verbosity: u64,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Flags which can also be set through the environment. clap only reads the environment for
+/// options which take a value, so these are added to the command line before it is parsed.
+const ENV_FLAGS: &[&str] = &[
+    "force",
+    "insecure-skip-tls-verify",
+    "acm",
+    "hosted-clusters",
+    "ocm",
+    "require-approval",
+    "require-eus-approval",
+    "allow-unmanaged",
+    "allow-prerelease",
+    "release-diff",
+    "once",
+    "wait",
+    "interactive",
+    "events-stdout",
+    "verify-updates",
+    "check-dns",
+    "check-capacity",
+    "check-bare-metal-hosts",
+];
+
+/// The command line, with the flags (and verbosity) set through the environment added to it.
+pub fn args<F>(mut args: Vec<OsString>, var: F) -> Result<Vec<OsString>, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let truthy = |name: &str| match var(name).as_deref().map(str::trim) {
+        None | Some("") | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") => Ok(true),
+        Some(value) => Err(format!("{} must be true or false, not {:?}", name, value)),
+    };
+
+    for flag in ENV_FLAGS.iter().rev() {
+        let name = format!("UPGRADE_{}", flag.to_uppercase().replace('-', "_"));
+        let arg = OsString::from(format!("--{}", flag));
+        if truthy(&name)? && !args.contains(&arg) {
+            args.insert(1, arg);
+        }
+    }
+    let verbose = args
+        .iter()
+        .any(|arg| arg.to_str().is_some_and(|arg| arg.starts_with("-v")));
+    if let (Some(verbosity), false) = (var("UPGRADE_VERBOSITY"), verbose) {
+        let verbosity: usize = verbosity
+            .trim()
+            .parse()
+            .map_err(|_| format!("UPGRADE_VERBOSITY must be a number, not {:?}", verbosity))?;
+        for _ in 0..verbosity {
+            args.insert(1, OsString::from("-v"));
+        }
+    }
+    Ok(args)
+}
+
+/// The options as they were given on the command line and in the environment, before the policy
+/// ConfigMap overrides them.
+pub fn command_line() -> Options {
+    Options::from_iter(
+        args(env::args_os().collect(), |name| env::var(name).ok())
+            .expect("arguments were valid at startup"),
+    )
+}
+
+impl Options {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            initial: self.backoff_initial,
+            max: self.backoff_max,
+            retries: self.retries,
+        }
+    }
+
+    /// Build the client for requests to external endpoints, combining the cluster's proxy
+    /// configuration with the TLS options.
+    pub fn http(&self, client: &APIClient) -> Result<reqwest::Client, Error> {
+        let read = |path: &PathBuf| {
+            fs::read(path)
+                .map_err(|err| Error::Config(format!("failed to read {}: {}", path.display(), err)))
+        };
+
+        let mut config = HttpConfig::from_cluster(client)?;
+        if let Some(path) = &self.ca_bundle {
+            let bundle = String::from_utf8(read(path)?)
+                .map_err(|_| Error::Config(format!("{} is not a PEM file", path.display())))?;
+            config.ca_bundle = Some(match config.ca_bundle {
+                Some(trusted) => format!("{}\n{}", trusted, bundle),
+                None => bundle,
+            });
+        }
+        if let Some(path) = &self.client_identity {
+            let password = match &self.client_identity_password_file {
+                Some(path) => String::from_utf8_lossy(&read(path)?).trim().to_string(),
+                None => String::new(),
+            };
+            config.identity = Some((read(path)?, password));
+        }
+        config.insecure = self.insecure_skip_tls_verify;
+        config.client()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_flags_from_the_environment() {
+        let env = |name: &str| match name {
+            "UPGRADE_FORCE" => Some("true".to_string()),
+            "UPGRADE_ACM" => Some("false".to_string()),
+            "UPGRADE_ALLOW_UNMANAGED" => Some("1".to_string()),
+            "UPGRADE_VERBOSITY" => Some("2".to_string()),
+            _ => None,
+        };
+        let command_line = |line: &[&str]| line.iter().map(OsString::from).collect();
+
+        let options = Options::from_iter(
+            args(
+                command_line(&["openshift-update", "--force", "explain"]),
+                env,
+            )
+            .expect("args"),
+        );
+        assert!(options.force);
+        assert!(options.allow_unmanaged);
+        assert!(!options.acm);
+        assert_eq!(options.verbosity, 2);
+        assert!(matches!(options.command, Some(Command::Explain(_))));
+
+        let options =
+            Options::from_iter(args(command_line(&["openshift-update", "-v"]), env).expect("args"));
+        assert_eq!(options.verbosity, 1);
+
+        assert!(args(command_line(&["openshift-update"]), |_| Some(
+            "yes".to_string()
+        ))
+        .is_err());
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The formats in which the read-only subcommands print their reports.
+//!
+//! Every report can be printed as a table for people, or as JSON or YAML for scripts. The
+//! structured formats are serialized from the same value the table is rendered from, so they
+//! never carry less.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Table,
+    Json,
+    Yaml,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // "text" was the name of the table before the structured formats were unified.
+            "table" | "text" => Ok(Output::Table),
+            "json" => Ok(Output::Json),
+            "yaml" => Ok(Output::Yaml),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+impl Output {
+    /// Format `value`, using `table` to render it as a table.
+    pub fn format<T, F>(self, value: &T, table: F) -> String
+    where
+        T: serde::Serialize,
+        F: FnOnce(&T) -> String,
+    {
+        match self {
+            Output::Table => table(value),
+            Output::Json => serde_json::to_string_pretty(value).expect("Serialize to JSON") + "\n",
+            Output::Yaml => serde_yaml::to_string(value).expect("Serialize to YAML") + "\n",
+        }
+    }
+
+    /// Print `value`, using `table` to render it as a table.
+    pub fn print<T, F>(self, value: &T, table: F)
+    where
+        T: serde::Serialize,
+        F: FnOnce(&T) -> String,
+    {
+        print!("{}", self.format(value, table));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_values() {
+        let value = serde_json::json!({ "passed": true });
+        let table = |_: &serde_json::Value| "passed\n".to_string();
+        assert_eq!(Output::Table.format(&value, table), "passed\n");
+        assert_eq!(
+            Output::Json.format(&value, table),
+            "{\n  \"passed\": true\n}\n"
+        );
+        assert_eq!(Output::Yaml.format(&value, table), "---\npassed: true\n");
+        assert_eq!("text".parse(), Ok(Output::Table));
+        assert!("xml".parse::<Output>().is_err());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Claiming and releasing the cluster's updates through the managed-by annotation of the
+//! ClusterVersion.
+//!
+//! While anyone other than the operator is named in the annotation, the operator holds back its
+//! updates, so that a person can take over the cluster (e.g. to step it through an update by hand)
+//! without stopping the operator. The operator's own claim only lasts for the update it requested,
+//! so it can be taken over without `--force`; somebody else's can't.
+
+use crate::options::Options;
+use kube::api::PatchParams;
+use kube::client::APIClient;
+use openshift_update::clusterversion;
+use openshift_update::gates::{self, MANAGED_BY_ANNOTATION};
+use openshift_update::Error;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Acquire {
+    #[structopt(long = "owner")]
+    /// Who is taking over the cluster's updates (e.g. an email address)
+    pub owner: String,
+
+    #[structopt(long = "force")]
+    /// Take over the cluster's updates even if somebody else owns them
+    pub force: bool,
+}
+
+#[derive(StructOpt)]
+pub struct Release {
+    #[structopt(long = "owner")]
+    /// Who is expected to own the cluster's updates; nothing is released if somebody else does
+    pub owner: Option<String>,
+
+    #[structopt(long = "force")]
+    /// Release the cluster's updates even if somebody else owns them
+    pub force: bool,
+}
+
+pub fn acquire(client: APIClient, options: &Options, command: &Acquire) -> Result<(), Error> {
+    let owner = command.owner.trim();
+    if owner.is_empty() {
+        return Err(Error::Config("the owner can't be empty".to_string()));
+    }
+
+    match current(&client, options)? {
+        Some(ref current) if current == owner => {
+            println!("The cluster's updates are already owned by {}", owner);
+            return Ok(());
+        }
+        Some(ref current) if current != &options.field_manager && !command.force => {
+            return Err(Error::Config(format!(
+                "the cluster's updates are owned by {} (use --force to take them over)",
+                current
+            )));
+        }
+        _ => {}
+    }
+
+    annotate(&client, options, Some(owner))?;
+    println!("The cluster's updates are now owned by {}", owner);
+    Ok(())
+}
+
+pub fn release(client: APIClient, options: &Options, command: &Release) -> Result<(), Error> {
+    let current = match current(&client, options)? {
+        Some(current) => current,
+        None => {
+            println!("Nobody owns the cluster's updates");
+            return Ok(());
+        }
+    };
+    if let Some(owner) = &command.owner {
+        if owner.trim() != current && !command.force {
+            return Err(Error::Config(format!(
+                "the cluster's updates are owned by {}, not {} (use --force to release them)",
+                current,
+                owner.trim()
+            )));
+        }
+    }
+
+    annotate(&client, options, None)?;
+    println!("Released the cluster's updates from {}", current);
+    Ok(())
+}
+
+/// Who currently owns the cluster's updates.
+fn current(client: &APIClient, options: &Options) -> Result<Option<String>, Error> {
+    let version = clusterversion::api(client.clone()).get(&options.cluster_version_name)?;
+    Ok(gates::owner(&version.metadata))
+}
+
+/// Set (or with `None`, remove) the managed-by annotation.
+fn annotate(client: &APIClient, options: &Options, owner: Option<&str>) -> Result<(), Error> {
+    let patch = serde_json::json!({
+        "metadata": { "annotations": { MANAGED_BY_ANNOTATION: owner } }
+    });
+    clusterversion::api(client.clone()).patch(
+        &options.cluster_version_name,
+        &PatchParams::default(),
+        serde_json::to_vec(&patch)?,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,225 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sequence of updates which would take the cluster to a given version, for review before
+//! the operator is let loose on it.
+
+use crate::options::Options;
+use crate::output::Output;
+use kube::client::APIClient;
+use openshift_update::clusterversion;
+use openshift_update::graph::{self, GraphClient, Hop};
+use openshift_update::http::HttpClient;
+use openshift_update::Error;
+use std::fmt::Write;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Plan {
+    #[structopt(long = "to")]
+    /// Version to plan the updates to (e.g. 4.2.1)
+    pub to: semver::Version,
+
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the plan: "table", "json", "yaml" or "dot" (Graphviz)
+    pub output: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Report(Output),
+    Dot,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            _ => s.parse().map(Format::Report),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Report<'a> {
+    from: semver::Version,
+    to: &'a semver::Version,
+    channel: &'a str,
+    hops: Vec<Hop>,
+}
+
+pub fn run(client: APIClient, options: &Options, command: &Plan) -> Result<(), Error> {
+    let version = clusterversion::api(client.clone()).get(&options.cluster_version_name)?;
+    let current = version
+        .status
+        .as_ref()
+        .and_then(|status| status.history.first())
+        .and_then(|latest| latest.version.as_deref())
+        .and_then(|version| semver::Version::parse(version).ok())
+        .ok_or_else(|| Error::Config("the cluster doesn't report its version".to_string()))?;
+    let channel =
+        version.spec.channel.as_deref().ok_or_else(|| {
+            Error::Config("the cluster isn't subscribed to a channel".to_string())
+        })?;
+    let upstream = version
+        .spec
+        .upstream
+        .as_deref()
+        .unwrap_or(graph::DEFAULT_UPSTREAM);
+
+    let client = HttpClient {
+        http: options.http(&client)?,
+        backoff: options.backoff(),
+    };
+    let mut graphs = Vec::new();
+    for name in channels(channel, &current, &command.to) {
+        match client.fetch(upstream, &name, &current.to_string()) {
+            Ok(graph) => graphs.push((name, graph)),
+            // Only the cluster's own channel is known to exist.
+            Err(error) if name != channel => {
+                warn!("Failed to fetch the {} channel: {}", name, error)
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    let hops = graph::plan(&graphs, &current, &command.to).ok_or_else(|| {
+        let names: Vec<&str> = graphs.iter().map(|(name, _)| name.as_str()).collect();
+        Error::Config(format!(
+            "no updates lead from {} to {} in {}",
+            current,
+            command.to,
+            names.join(", ")
+        ))
+    })?;
+    let report = Report {
+        from: current,
+        to: &command.to,
+        channel,
+        hops,
+    };
+    match command.output {
+        Format::Report(output) => output.print(&report, |report| render(channel, &report.hops)),
+        Format::Dot => print!("{}", dot(channel, &report.hops)),
+    }
+    Ok(())
+}
+
+/// The channels to search, starting with the cluster's own. Channels are named after the minor
+/// release they lead to (e.g. stable-4.2), so getting to a later minor release means passing
+/// through the channels of the same kind for each minor release on the way.
+fn channels(channel: &str, current: &semver::Version, to: &semver::Version) -> Vec<String> {
+    let mut channels = vec![channel.to_string()];
+    let prefix = match channel.rsplit_once('-') {
+        Some((prefix, version)) if version.split('.').count() == 2 => prefix,
+        _ => return channels,
+    };
+    if current.major != to.major {
+        return channels;
+    }
+    for minor in current.minor..=to.minor {
+        let name = format!("{}-{}.{}", prefix, to.major, minor);
+        if !channels.contains(&name) {
+            channels.push(name);
+        }
+    }
+    channels
+}
+
+fn render(channel: &str, hops: &[Hop]) -> String {
+    let mut text = String::new();
+    let mut channel = channel;
+    for (i, hop) in hops.iter().enumerate() {
+        if hop.channel != channel {
+            writeln!(text, "   switch to the {} channel", hop.channel).expect("write to string");
+            channel = &hop.channel;
+        }
+        writeln!(text, "{:>2}. update from {} to {}", i + 1, hop.from, hop.to)
+            .expect("write to string");
+    }
+    text
+}
+
+/// Render the plan as a Graphviz digraph, drawing the updates which need a channel switch in
+/// bold.
+fn dot(channel: &str, hops: &[Hop]) -> String {
+    let mut text = String::from("digraph plan {\n");
+    let mut channel = channel;
+    for hop in hops {
+        let style = if hop.channel != channel {
+            ", style=bold"
+        } else {
+            ""
+        };
+        writeln!(
+            text,
+            "  \"{}\" -> \"{}\" [label=\"{}\"{}];",
+            hop.from, hop.to, hop.channel, style
+        )
+        .expect("write to string");
+        channel = &hop.channel;
+    }
+    text.push_str("}\n");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str) -> semver::Version {
+        semver::Version::parse(version).expect("version")
+    }
+
+    #[test]
+    fn searches_later_channels() {
+        assert_eq!(
+            channels("stable-4.1", &version("4.1.14"), &version("4.3.2")),
+            vec!["stable-4.1", "stable-4.2", "stable-4.3"]
+        );
+        assert_eq!(
+            channels("candidate", &version("4.1.14"), &version("4.3.2")),
+            vec!["candidate"]
+        );
+    }
+
+    #[test]
+    fn marks_channel_switches() {
+        let hops = vec![
+            Hop {
+                from: version("4.1.14"),
+                to: version("4.1.15"),
+                channel: "stable-4.1".to_string(),
+            },
+            Hop {
+                from: version("4.1.15"),
+                to: version("4.2.1"),
+                channel: "stable-4.2".to_string(),
+            },
+        ];
+        assert_eq!(
+            render("stable-4.1", &hops),
+            " 1. update from 4.1.14 to 4.1.15\n   switch to the stable-4.2 channel\n 2. update \
+             from 4.1.15 to 4.2.1\n"
+        );
+        assert_eq!(
+            dot("stable-4.1", &hops),
+            "digraph plan {\n  \"4.1.14\" -> \"4.1.15\" [label=\"stable-4.1\"];\n  \"4.1.15\" -> \
+             \"4.2.1\" [label=\"stable-4.2\", style=bold];\n}\n"
+        );
+    }
+}
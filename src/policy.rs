@@ -0,0 +1,886 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selection of the update to apply and of when to apply it.
+//!
+//! Decisions are made by an [`UpgradePolicy`]. The built-in behaviors are each a policy of their
+//! own: [`Latest`] picks the newest candidate which hasn't failed before, and [`Jitter`],
+//! [`Pausable`], [`RequireApproval`] and [`RefuseUnmanaged`] wrap another policy and hold back
+//! whatever it picked. Embedders can supply their own policies, or wrap the built-in ones.
+
+use crate::clusterversion::{ClusterUpdate, ClusterVersion};
+use crate::credentials::Credentials;
+use crate::gates::{self, Snooze};
+use crate::health::Health;
+use crate::rollout::Percentages;
+use crate::steps;
+use crate::window::Window;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hasher;
+use std::time::Duration;
+
+/// What a policy decided about a cluster.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type")]
+pub enum Decision {
+    /// No updates are available.
+    UpToDate,
+    /// The cluster is still applying an update.
+    InProgress,
+    /// The update is held back by its jitter or cadence.
+    Delayed {
+        update: ClusterUpdate,
+        #[serde(rename = "notBefore")]
+        not_before: DateTime<Utc>,
+        /// Whether it's waiting for the days it may start on, rather than for time to pass
+        /// since it was first seen.
+        #[serde(default)]
+        window: bool,
+    },
+    /// Updates are paused through an annotation.
+    Paused { update: ClusterUpdate },
+    /// The update is waiting to be approved.
+    AwaitingApproval { update: ClusterUpdate },
+    /// The update is held back by a check of the cluster's health or configuration.
+    Blocked {
+        update: ClusterUpdate,
+        gate: String,
+        reason: String,
+    },
+    /// The update should be applied now.
+    Apply { update: ClusterUpdate },
+}
+
+impl Decision {
+    /// The update this decision concerns, if any.
+    pub fn update(&self) -> Option<&ClusterUpdate> {
+        match self {
+            Decision::UpToDate | Decision::InProgress => None,
+            Decision::Delayed { update, .. }
+            | Decision::Paused { update }
+            | Decision::AwaitingApproval { update }
+            | Decision::Blocked { update, .. }
+            | Decision::Apply { update } => Some(update),
+        }
+    }
+
+    /// Why this decision was made, as a stable code.
+    pub fn reason(&self) -> DecisionReason {
+        match self {
+            Decision::UpToDate => DecisionReason::AlreadyCurrent,
+            Decision::InProgress => DecisionReason::UpdateInProgress,
+            Decision::Delayed { window: true, .. } => DecisionReason::WindowClosed,
+            Decision::Delayed { .. } => DecisionReason::SoakPending,
+            Decision::Paused { .. } => DecisionReason::Paused,
+            Decision::AwaitingApproval { .. } => DecisionReason::AwaitingApproval,
+            Decision::Blocked { gate, .. } => DecisionReason::GateBlocked { gate: gate.clone() },
+            Decision::Apply { .. } => DecisionReason::Ready,
+        }
+    }
+}
+
+/// Why a decision was made, for automation to branch on. Unlike the messages logged alongside
+/// them, which are written for people and may be reworded, the codes never change; they're
+/// serialized as just the code (e.g. "GateBlocked"), with the gate given separately.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecisionReason {
+    /// No update is available, so the cluster is as current as it can be.
+    AlreadyCurrent,
+    /// The cluster is still applying an update.
+    UpdateInProgress,
+    /// The candidate is waiting for its jitter or z-stream delay to pass.
+    SoakPending,
+    /// The candidate may only start on certain days, and this isn't one of them.
+    WindowClosed,
+    /// Updates are paused through an annotation.
+    Paused,
+    /// The candidate is waiting to be approved.
+    AwaitingApproval,
+    /// A check of the cluster's health or configuration is holding the candidate back.
+    GateBlocked { gate: String },
+    /// The candidate should be applied now.
+    Ready,
+}
+
+impl DecisionReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DecisionReason::AlreadyCurrent => "AlreadyCurrent",
+            DecisionReason::UpdateInProgress => "UpdateInProgress",
+            DecisionReason::SoakPending => "SoakPending",
+            DecisionReason::WindowClosed => "WindowClosed",
+            DecisionReason::Paused => "Paused",
+            DecisionReason::AwaitingApproval => "AwaitingApproval",
+            DecisionReason::GateBlocked { .. } => "GateBlocked",
+            DecisionReason::Ready => "Ready",
+        }
+    }
+
+    /// The gate holding back the candidate, if something is. Delays of either kind are the
+    /// "delay" gate.
+    pub fn gate(&self) -> Option<&str> {
+        match self {
+            DecisionReason::AlreadyCurrent
+            | DecisionReason::UpdateInProgress
+            | DecisionReason::Ready => None,
+            DecisionReason::SoakPending | DecisionReason::WindowClosed => Some("delay"),
+            DecisionReason::Paused => Some("paused"),
+            DecisionReason::AwaitingApproval => Some("approval"),
+            DecisionReason::GateBlocked { gate } => Some(gate),
+        }
+    }
+}
+
+impl fmt::Display for DecisionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl serde::Serialize for DecisionReason {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+/// Everything a policy may consider about a cluster, besides its candidate updates.
+#[derive(Clone, Debug)]
+pub struct ClusterState {
+    /// The version the cluster is at or is updating to.
+    pub version: Option<String>,
+    pub cluster_id: Option<String>,
+    pub update_in_progress: bool,
+    pub paused: bool,
+    /// Until when every automatic update is deferred, if it is.
+    pub snoozed: Option<Snooze>,
+    /// Who owns the cluster's updates, if anyone has claimed them.
+    pub owner: Option<String>,
+    pub approved: Option<semver::Version>,
+    /// Components which have been overridden to be unmanaged.
+    pub unmanaged: Vec<String>,
+    /// Other field managers which have set the desired update.
+    pub competing_managers: Vec<String>,
+    /// The versions the manual steps were acknowledged for through annotations, by step.
+    pub step_acks: HashMap<String, String>,
+    /// The cluster administrators' acknowledgements which are set to "true".
+    pub admin_acks: HashSet<String>,
+    /// How far each version has been rolled out, if that is coordinated by a rollout service.
+    pub rollout: Option<Percentages>,
+    /// The verdicts of the enabled health checks.
+    pub health: Vec<Health>,
+    /// The provisioners of the StorageClasses and the CSIDrivers, if they were read.
+    pub storage_provisioners: Vec<String>,
+    /// The verdicts on the error budgets of the service-level objectives.
+    pub error_budget: Vec<Health>,
+    /// How the cloud credentials are managed, if the cluster has a Cloud Credential Operator.
+    pub credentials: Option<Credentials>,
+    /// Versions which were published by security advisories.
+    pub security: HashSet<semver::Version>,
+    /// How the cluster's version moved without the operator causing it, until that's
+    /// acknowledged.
+    pub regression: Option<String>,
+    /// Versions which previously failed to apply, and must not be selected.
+    pub poisoned: HashSet<semver::Version>,
+    /// When each of the candidates was first seen.
+    pub first_seen: HashMap<semver::Version, DateTime<Utc>>,
+    pub now: DateTime<Utc>,
+}
+
+impl ClusterState {
+    pub fn new(version: &ClusterVersion, now: DateTime<Utc>) -> ClusterState {
+        let status = version.status.clone().unwrap_or_default();
+        ClusterState {
+            version: status
+                .history
+                .first()
+                .and_then(|latest| latest.version.clone()),
+            cluster_id: version.spec.cluster_id.clone(),
+            update_in_progress: gates::update_in_progress(&status),
+            paused: gates::paused(&version.metadata),
+            snoozed: gates::snoozed(&version.metadata),
+            owner: gates::owner(&version.metadata),
+            approved: None,
+            unmanaged: gates::unmanaged(&version.spec),
+            competing_managers: Vec::new(),
+            step_acks: steps::acknowledgements(&version.metadata),
+            admin_acks: HashSet::new(),
+            rollout: None,
+            health: Vec::new(),
+            storage_provisioners: Vec::new(),
+            error_budget: Vec::new(),
+            credentials: None,
+            security: HashSet::new(),
+            regression: None,
+            poisoned: HashSet::new(),
+            first_seen: HashMap::new(),
+            now,
+        }
+    }
+}
+
+pub trait UpgradePolicy {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision;
+}
+
+/// Apply the newest candidate which hasn't been poisoned, once the cluster has finished its
+/// previous update.
+pub struct Latest;
+
+impl UpgradePolicy for Latest {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        if current.update_in_progress {
+            return Decision::InProgress;
+        }
+
+        match candidates
+            .iter()
+            .filter(|update| !current.poisoned.contains(&update.version))
+            .max()
+        {
+            Some(update) => Decision::Apply {
+                update: update.clone(),
+            },
+            None => Decision::UpToDate,
+        }
+    }
+}
+
+/// Delay applying each update by an amount derived from the cluster ID. With a maintenance
+/// window, the delay is counted from the window's opening instead, so that the clusters sharing
+/// the window are spread across it rather than all starting as it opens.
+///
+/// Clusters without an ID are not delayed.
+pub struct Jitter {
+    pub inner: Box<dyn UpgradePolicy>,
+    pub max: Duration,
+    pub window: Option<Window>,
+}
+
+impl UpgradePolicy for Jitter {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match (
+            self.inner.evaluate(current, candidates),
+            &current.cluster_id,
+        ) {
+            (Decision::Apply { update }, Some(cluster_id)) => {
+                let seen = current
+                    .first_seen
+                    .get(&update.version)
+                    .cloned()
+                    .unwrap_or(current.now);
+                let window = self.window.as_ref();
+                let closed = window.is_some_and(|window| !window.contains(current.now));
+                let not_before = not_before(Some(self.max), cluster_id, seen, window);
+                if current.now < not_before {
+                    Decision::Delayed {
+                        update,
+                        not_before,
+                        window: closed,
+                    }
+                } else if closed {
+                    // The window closed again before the update was applied, so it waits for
+                    // the same point of the next one.
+                    Decision::Delayed {
+                        update,
+                        not_before: self::not_before(
+                            Some(self.max),
+                            cluster_id,
+                            current.now,
+                            window,
+                        ),
+                        window: true,
+                    }
+                } else {
+                    Decision::Apply { update }
+                }
+            }
+            (decision, _) => decision,
+        }
+    }
+}
+
+/// Hold back updates while the cluster is paused.
+pub struct Pausable {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for Pausable {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } if current.paused => Decision::Paused { update },
+            decision => decision,
+        }
+    }
+}
+
+/// Hold back updates while they're snoozed. They resume once the snooze has ended.
+pub struct Snoozable {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for Snoozable {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match (self.inner.evaluate(current, candidates), &current.snoozed) {
+            (Decision::Apply { update }, Some(snooze)) if snooze.until > current.now => {
+                Decision::Blocked {
+                    update,
+                    gate: "snooze".to_string(),
+                    reason: snooze_reason(snooze),
+                }
+            }
+            (decision, _) => decision,
+        }
+    }
+}
+
+/// Describe a snooze, along with why it was set if that was given.
+pub fn snooze_reason(snooze: &Snooze) -> String {
+    match &snooze.reason {
+        Some(reason) => format!("snoozed until {} ({})", snooze.until, reason),
+        None => format!("snoozed until {}", snooze.until),
+    }
+}
+
+/// Hold back updates until they have been approved.
+pub struct RequireApproval {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for RequireApproval {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } if current.approved.as_ref() != Some(&update.version) => {
+                Decision::AwaitingApproval { update }
+            }
+            decision => decision,
+        }
+    }
+}
+
+/// Hold back updates while any component is unmanaged, since updating such a cluster is
+/// unsupported and frequently breaks it.
+pub struct RefuseUnmanaged {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for RefuseUnmanaged {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } if !current.unmanaged.is_empty() => Decision::Blocked {
+                update,
+                gate: "overrides".to_string(),
+                reason: format!("unmanaged components: {}", current.unmanaged.join(", ")),
+            },
+            decision => decision,
+        }
+    }
+}
+
+/// Block updates while something else is also setting the desired update, so that the two don't
+/// keep overwriting each other's choices.
+pub struct RefuseCompeting {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for RefuseCompeting {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } if !current.competing_managers.is_empty() => {
+                Decision::Blocked {
+                    update,
+                    gate: "field-manager".to_string(),
+                    reason: format!(
+                        "spec.desiredUpdate is also set by {}",
+                        current.competing_managers.join(", ")
+                    ),
+                }
+            }
+            decision => decision,
+        }
+    }
+}
+
+/// Hold back updates after the cluster's version moved in a way the operator didn't cause (see
+/// [`gates::REGRESSION_ACKNOWLEDGED_ANNOTATION`]), since whatever moved it may still be at work.
+pub struct RefuseRegressed {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for RefuseRegressed {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match (
+            self.inner.evaluate(current, candidates),
+            &current.regression,
+        ) {
+            (Decision::Apply { update }, Some(regression)) => Decision::Blocked {
+                update,
+                gate: "regression".to_string(),
+                reason: format!(
+                    "the cluster's version {} (see the {} annotation)",
+                    regression,
+                    gates::REGRESSION_ACKNOWLEDGED_ANNOTATION
+                ),
+            },
+            (decision, _) => decision,
+        }
+    }
+}
+
+/// Hold back updates while somebody other than the operator (`manager`) owns the cluster's
+/// updates.
+pub struct RespectOwner {
+    pub inner: Box<dyn UpgradePolicy>,
+    pub manager: String,
+}
+
+impl UpgradePolicy for RespectOwner {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match (self.inner.evaluate(current, candidates), &current.owner) {
+            (Decision::Apply { update }, Some(owner)) if owner != &self.manager => {
+                Decision::Blocked {
+                    update,
+                    gate: "owner".to_string(),
+                    reason: format!(
+                        "updates are owned by {} (see the {} annotation)",
+                        owner,
+                        gates::MANAGED_BY_ANNOTATION
+                    ),
+                }
+            }
+            (decision, _) => decision,
+        }
+    }
+}
+
+/// One of the candidates, with when it would be applied or what holds it back.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Queued {
+    pub version: semver::Version,
+    /// The earliest it would be applied, if only a delay holds it back.
+    #[serde(rename = "notBefore", skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<DateTime<Utc>>,
+    /// The gate holding it back, unless it's ready or only delayed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The candidates in the order the policy would apply them: those which are ready now, then the
+/// delayed ones by when their delay ends, then those held back by a gate. Each is evaluated on
+/// its own, and newer versions come first within each group since the newest eligible candidate
+/// is the one applied. While an update is in progress, the candidates which would otherwise be
+/// ready wait for it to complete.
+pub fn queue(
+    policy: &dyn UpgradePolicy,
+    current: &ClusterState,
+    candidates: &[ClusterUpdate],
+) -> Vec<Queued> {
+    let mut state = current.clone();
+    state.update_in_progress = false;
+    let held = |gate: &str, reason: String| (None, Some(gate.to_string()), Some(reason));
+
+    let mut queue: Vec<Queued> = candidates
+        .iter()
+        .filter_map(|candidate| {
+            let (not_before, gate, reason) =
+                match policy.evaluate(&state, std::slice::from_ref(candidate)) {
+                    Decision::UpToDate | Decision::InProgress => return None,
+                    Decision::Apply { .. } if current.update_in_progress => held(
+                        "in-progress",
+                        "waiting for the current update to complete".to_string(),
+                    ),
+                    Decision::Apply { .. } => (None, None, None),
+                    Decision::Delayed { not_before, .. } => (Some(not_before), None, None),
+                    Decision::Paused { .. } => held("paused", "updates are paused".to_string()),
+                    Decision::AwaitingApproval { .. } => {
+                        held("approval", "awaiting approval".to_string())
+                    }
+                    Decision::Blocked { gate, reason, .. } => held(&gate, reason),
+                };
+            Some(Queued {
+                version: candidate.version.clone(),
+                not_before,
+                gate,
+                reason,
+            })
+        })
+        .collect();
+    queue.sort_by(|a, b| {
+        let rank = |queued: &Queued| (queued.gate.is_some(), queued.not_before);
+        rank(a)
+            .cmp(&rank(b))
+            .then_with(|| b.version.cmp(&a.version))
+    });
+    queue
+}
+
+/// Whether `version` is a pre-release (e.g. a nightly, engineering candidate or release
+/// candidate) or a build of one, neither of which are updated to unless explicitly allowed.
+pub fn prerelease(version: &semver::Version) -> bool {
+    !version.pre.is_empty() || !version.build.is_empty()
+}
+
+/// Returns the earliest time at which an update first seen at `seen` may be applied. With a
+/// maintenance window, that's the jitter into the window's next opening (no further in than the
+/// window is long).
+pub fn not_before(
+    max_jitter: Option<Duration>,
+    cluster_id: &str,
+    seen: DateTime<Utc>,
+    window: Option<&Window>,
+) -> DateTime<Utc> {
+    let max = max_jitter.unwrap_or_default();
+    match window {
+        Some(window) => window.next(seen, jitter(cluster_id, max.min(window.length()))),
+        None => seen + chrono::Duration::from_std(jitter(cluster_id, max)).expect("jitter"),
+    }
+}
+
+/// Derive a stable delay within `max` from the cluster ID.
+///
+/// FNV is used rather than the standard library's hasher since the latter makes no promise of
+/// producing the same output across releases, and the jitter must not move between restarts.
+pub fn jitter(cluster_id: &str, max: Duration) -> Duration {
+    if max.as_secs() == 0 {
+        return Duration::from_secs(0);
+    }
+
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(cluster_id.as_bytes());
+    Duration::from_secs(hasher.finish() % max.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(json: &str) -> (ClusterState, Vec<ClusterUpdate>) {
+        let version: ClusterVersion = serde_json::from_str(json).expect("valid fixture");
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        (ClusterState::new(&version, now), candidates)
+    }
+
+    fn available() -> (ClusterState, Vec<ClusterUpdate>) {
+        fixture(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+    }
+
+    fn version(decision: &Decision) -> Option<String> {
+        decision.update().map(|update| update.version.to_string())
+    }
+
+    #[test]
+    fn latest_picks_newest() {
+        let (state, candidates) = available();
+        let decision = Latest.evaluate(&state, &candidates);
+        assert!(matches!(decision, Decision::Apply { .. }));
+        assert_eq!(version(&decision).as_deref(), Some("4.1.16"));
+    }
+
+    #[test]
+    fn queues_candidates() {
+        let (mut state, candidates) = available();
+        state.paused = true;
+        let policy = Pausable {
+            inner: Box::new(Latest),
+        };
+        let paused = queue(&policy, &state, &candidates);
+        assert_eq!(paused.len(), candidates.len());
+        assert_eq!(paused[0].version.to_string(), "4.1.16");
+        assert!(paused
+            .iter()
+            .all(|queued| queued.gate.as_deref() == Some("paused")));
+
+        // Poisoned versions are never applied, so they aren't queued.
+        state.paused = false;
+        state.poisoned.insert(paused[0].version.clone());
+        let ready = queue(&policy, &state, &candidates);
+        assert_eq!(ready.len(), candidates.len() - 1);
+        assert!(ready.iter().all(|queued| queued.gate.is_none()));
+    }
+
+    #[test]
+    fn latest_waits_for_update_in_progress() {
+        let (state, candidates) = fixture(include_str!(
+            "../tests/fixtures/clusterversion-in-progress.json"
+        ));
+        assert!(state.update_in_progress);
+        assert_eq!(state.version.as_deref(), Some("4.1.16"));
+        assert_eq!(Latest.evaluate(&state, &candidates), Decision::InProgress);
+    }
+
+    #[test]
+    fn latest_without_candidates() {
+        for json in &[
+            include_str!("../tests/fixtures/clusterversion-empty-history.json"),
+            include_str!("../tests/fixtures/clusterversion-null-updates.json"),
+        ] {
+            let (state, candidates) = fixture(json);
+            assert!(!state.update_in_progress);
+            assert_eq!(Latest.evaluate(&state, &candidates), Decision::UpToDate);
+        }
+    }
+
+    #[test]
+    fn jitter_delays_new_updates() {
+        let (mut state, candidates) = available();
+        let max = Duration::from_secs(24 * 60 * 60);
+        let policy = Jitter {
+            inner: Box::new(Latest),
+            max,
+            window: None,
+        };
+        let delay = jitter(state.cluster_id.as_deref().expect("cluster ID"), max);
+        assert!(delay.as_secs() > 0);
+
+        match policy.evaluate(&state, &candidates) {
+            Decision::Delayed { not_before, .. } => assert_eq!(
+                not_before,
+                state.now + chrono::Duration::from_std(delay).expect("delay")
+            ),
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+
+        let seen = state.now - chrono::Duration::from_std(max).expect("max");
+        for update in &candidates {
+            state.first_seen.insert(update.version.clone(), seen);
+        }
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+
+    #[test]
+    fn jitter_lands_inside_the_window() {
+        let (mut state, candidates) = available();
+        let max = Duration::from_secs(24 * 60 * 60);
+        let window: Window = "01:00-05:00".parse().expect("valid window");
+        let policy = Jitter {
+            inner: Box::new(Latest),
+            max,
+            window: Some(window),
+        };
+        let delay = jitter(
+            state.cluster_id.as_deref().expect("cluster ID"),
+            window.length(),
+        );
+        // The updates are first seen now, at midnight, so they wait for the window to open.
+        let opening: DateTime<Utc> = "2019-09-17T01:00:00Z".parse().expect("valid time");
+        let slot = opening + chrono::Duration::from_std(delay).expect("delay");
+
+        match policy.evaluate(&state, &candidates) {
+            Decision::Delayed {
+                not_before, window, ..
+            } => {
+                assert_eq!(not_before, slot);
+                assert!(window);
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+
+        state.now = slot;
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        // Past the window, the update waits for the same point of the next one.
+        state.now = "2019-09-17T06:00:00Z".parse().expect("valid time");
+        for update in &candidates {
+            state.first_seen.insert(update.version.clone(), opening);
+        }
+        match policy.evaluate(&state, &candidates) {
+            Decision::Delayed { not_before, .. } => {
+                assert_eq!(not_before, slot + chrono::Duration::days(1))
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+    }
+
+    #[test]
+    fn jitter_is_stable() {
+        let max = Duration::from_secs(3600);
+        assert_eq!(jitter("cluster", max), jitter("cluster", max));
+        assert_eq!(
+            jitter("cluster", Duration::from_secs(0)),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn gives_stable_reasons() {
+        let (mut state, candidates) = available();
+        let policy = Pausable {
+            inner: Box::new(Latest),
+        };
+        assert_eq!(
+            policy.evaluate(&state, &[]).reason(),
+            DecisionReason::AlreadyCurrent
+        );
+        assert_eq!(
+            policy.evaluate(&state, &candidates).reason(),
+            DecisionReason::Ready
+        );
+
+        state.paused = true;
+        let reason = policy.evaluate(&state, &candidates).reason();
+        assert_eq!(reason.code(), "Paused");
+        assert_eq!(reason.gate(), Some("paused"));
+
+        let reason = DecisionReason::GateBlocked {
+            gate: "dns".to_string(),
+        };
+        assert_eq!(reason.gate(), Some("dns"));
+        assert_eq!(
+            serde_json::to_string(&reason).expect("serialize"),
+            "\"GateBlocked\""
+        );
+    }
+
+    #[test]
+    fn paused() {
+        let (mut state, candidates) = available();
+        let policy = Pausable {
+            inner: Box::new(Latest),
+        };
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        state.paused = true;
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Paused { .. }
+        ));
+    }
+
+    #[test]
+    fn snoozed() {
+        let (mut state, candidates) = available();
+        let policy = Snoozable {
+            inner: Box::new(Latest),
+        };
+        state.snoozed = Some(Snooze {
+            until: state.now + chrono::Duration::days(7),
+            reason: Some("quarter-end freeze".to_string()),
+        });
+        match policy.evaluate(&state, &candidates) {
+            Decision::Blocked { gate, reason, .. } => {
+                assert_eq!(gate, "snooze");
+                assert!(reason.ends_with("(quarter-end freeze)"));
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+
+        // Updates resume by themselves once the snooze has ended.
+        state.now = state.now + chrono::Duration::days(8);
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+
+    #[test]
+    fn respects_the_owner() {
+        let (mut state, candidates) = available();
+        let policy = RespectOwner {
+            inner: Box::new(Latest),
+            manager: "openshift-update".to_string(),
+        };
+        state.owner = Some("openshift-update".to_string());
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        state.owner = Some("jane@example.com".to_string());
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked { ref gate, .. } if gate == "owner"
+        ));
+    }
+
+    #[test]
+    fn refuses_competing_managers() {
+        let (mut state, candidates) = available();
+        let policy = RefuseCompeting {
+            inner: Box::new(Latest),
+        };
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        state.competing_managers = vec!["argocd-controller".into()];
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked { gate, .. } if gate == "field-manager"
+        ));
+    }
+
+    #[test]
+    fn refuses_unmanaged() {
+        let (mut state, candidates) = available();
+        let policy = RefuseUnmanaged {
+            inner: Box::new(Latest),
+        };
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        state.unmanaged =
+            vec!["Deployment/openshift-monitoring/cluster-monitoring-operator".into()];
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked { .. }
+        ));
+    }
+
+    #[test]
+    fn approval() {
+        let (mut state, candidates) = available();
+        let policy = RequireApproval {
+            inner: Box::new(Latest),
+        };
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::AwaitingApproval { .. }
+        ));
+
+        // Approving an older version doesn't approve the newest.
+        state.approved = Some(semver::Version::parse("4.1.15").expect("version"));
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::AwaitingApproval { .. }
+        ));
+
+        state.approved = Some(semver::Version::parse("4.1.16").expect("version"));
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+}
@@ -0,0 +1,577 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The update policy read from a ConfigMap, for those who'd rather not use the command line.
+//!
+//! Each key of the ConfigMap named by `--policy-config-map` is one of the policy options, named
+//! as on the command line without the leading dashes (e.g. `max-jitter: 2h` or
+//! `allow-prerelease: "true"`), and overrides it. The ConfigMap is watched, and each version of
+//! it is applied as a whole, or not at all: if any key is invalid, the previous policy stays in
+//! effect and the problems are reported as a Warning Event on the ConfigMap. Whenever a new
+//! policy takes effect, its ConfigMap's resourceVersion is recorded in the ClusterVersion's
+//! `upgrade.crawford.dev/policy` annotation, which also makes the operator re-evaluate.
+
+use crate::options::Options;
+use chrono::Utc;
+use kube::api::{ListParams, PatchParams, PostParams, RawApi};
+use kube::client::APIClient;
+use openshift_update::clusterversion;
+use openshift_update::kubeapi;
+use openshift_update::kubeconfig;
+use openshift_update::retry;
+use openshift_update::Error;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Annotation of the ClusterVersion recording the resourceVersion of the policy in effect.
+pub const POLICY_ANNOTATION: &str = "upgrade.crawford.dev/policy";
+
+/// How long to wait before watching again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The keys which may be set in the ConfigMap.
+const KEYS: &[&str] = &[
+    "force",
+    "force-approved-by",
+    "max-jitter",
+    "maintenance-window",
+    "z-stream-delay",
+    "minor-days",
+    "manual-steps",
+    "require-approval",
+    "require-eus-approval",
+    "allow-unmanaged",
+    "allow-prerelease",
+    "min-patch-interval",
+    "snooze-until",
+    "snooze-reason",
+    "rollout-url",
+];
+
+/// The namespace and name of the ConfigMap, given as "<namespace>/<name>".
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    fn from_str(reference: &str) -> Result<Self, Self::Err> {
+        match reference.split_once('/') {
+            Some((namespace, name))
+                if !namespace.is_empty() && !name.is_empty() && !name.contains('/') =>
+            {
+                Ok(Reference {
+                    namespace: namespace.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "{:?} is not of the form <namespace>/<name>",
+                reference
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    uid: String,
+    #[serde(rename = "resourceVersion", default)]
+    resource_version: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct ConfigMap {
+    #[serde(default)]
+    metadata: Metadata,
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+#[derive(serde::Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    type_: String,
+    object: serde_json::Value,
+}
+
+/// The options currently in effect, which change along with the ConfigMap.
+pub struct Live {
+    current: Mutex<Arc<Options>>,
+}
+
+impl Live {
+    pub fn new(options: Arc<Options>) -> Live {
+        Live {
+            current: Mutex::new(options),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Options> {
+        self.current.lock().expect("policy lock").clone()
+    }
+
+    fn set(&self, options: Options) {
+        *self.current.lock().expect("policy lock") = Arc::new(options);
+    }
+}
+
+/// Override the options with the ConfigMap's data, or return every problem with it.
+pub fn apply(
+    mut options: Options,
+    data: &BTreeMap<String, String>,
+) -> Result<Options, Vec<String>> {
+    let mut problems = Vec::new();
+    for (key, value) in data {
+        let value = value.trim();
+        let flag = || match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(format!(
+                "{}: {:?} is neither \"true\" nor \"false\"",
+                key, value
+            )),
+        };
+        let duration = || match value {
+            "" => Ok(None),
+            value => humantime::parse_duration(value)
+                .map(Some)
+                .map_err(|error| format!("{}: {}", key, error)),
+        };
+        let optional = || Some(value.to_string()).filter(|value| !value.is_empty());
+        let result = match key.as_str() {
+            "force" => flag().map(|flag| options.force = flag),
+            "force-approved-by" => {
+                options.force_approved_by = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|approver| !approver.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                Ok(())
+            }
+            "max-jitter" => duration().map(|max| options.max_jitter = max),
+            "maintenance-window" => optional()
+                .map(|window| window.parse().map(Some))
+                .unwrap_or(Ok(None))
+                .map(|window| options.maintenance_window = window)
+                .map_err(|error| format!("{}: {}", key, error)),
+            "z-stream-delay" => duration().map(|delay| options.z_stream_delay = delay),
+            "minor-days" => optional()
+                .map(|days| days.parse().map(Some))
+                .unwrap_or(Ok(None))
+                .map(|days| options.minor_days = days)
+                .map_err(|error| format!("{}: {}", key, error)),
+            "manual-steps" => value
+                .split(',')
+                .map(str::trim)
+                .filter(|step| !step.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map(|steps| options.manual_steps = steps)
+                .map_err(|error| format!("{}: {}", key, error)),
+            "require-approval" => flag().map(|flag| options.require_approval = flag),
+            "require-eus-approval" => flag().map(|flag| options.require_eus_approval = flag),
+            "allow-unmanaged" => flag().map(|flag| options.allow_unmanaged = flag),
+            "allow-prerelease" => flag().map(|flag| options.allow_prerelease = flag),
+            "min-patch-interval" => duration()
+                .and_then(|interval| interval.ok_or_else(|| format!("{}: must be set", key)))
+                .map(|interval| options.min_patch_interval = interval),
+            "snooze-until" => optional()
+                .map(|until| until.parse().map(Some))
+                .unwrap_or(Ok(None))
+                .map(|until| options.snooze_until = until)
+                .map_err(|error| format!("{}: {}", key, error)),
+            "snooze-reason" => {
+                options.snooze_reason = optional();
+                Ok(())
+            }
+            "rollout-url" => optional()
+                .map(|url| reqwest::Url::parse(&url).map(|_| Some(url)))
+                .unwrap_or(Ok(None))
+                .map(|url| options.rollout_url = url)
+                .map_err(|error| format!("{}: {}", key, error)),
+            _ => Err(format!(
+                "{}: unknown key (expected one of {})",
+                key,
+                KEYS.join(", ")
+            )),
+        };
+        if let Err(problem) = result {
+            problems.push(problem);
+        }
+    }
+    if options.listen.is_none() {
+        for (key, set) in &[
+            ("require-approval", options.require_approval),
+            ("require-eus-approval", options.require_eus_approval),
+        ] {
+            if *set {
+                problems.push(format!("{}: requires --listen", key));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(options)
+    } else {
+        Err(problems)
+    }
+}
+
+/// Follow the ConfigMap, forever. `command_line` returns the options as they were given, which
+/// each version of the ConfigMap is applied to.
+pub fn watch<F>(live: &Live, reference: &Reference, command_line: F)
+where
+    F: Fn() -> Options,
+{
+    let (namespace, name) = (reference.namespace.as_str(), reference.name.as_str());
+    let mut applied = None;
+    loop {
+        // The credentials are loaded afresh after every failure, since they may have been
+        // rotated.
+        let result = kubeconfig::load().and_then(|config| {
+            follow(
+                &APIClient::new(config),
+                live,
+                namespace,
+                name,
+                &command_line,
+                &mut applied,
+            )
+        });
+        if let Err(error) = result {
+            error!(
+                "Failed to watch the policy in ConfigMap {}/{}: {}",
+                namespace, name, error
+            );
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Apply the current version of the ConfigMap, then each new one until the watch fails.
+fn follow(
+    client: &APIClient,
+    live: &Live,
+    namespace: &str,
+    name: &str,
+    command_line: &dyn Fn() -> Options,
+    applied: &mut Option<String>,
+) -> Result<(), Error> {
+    let api = RawApi::v1ConfigMap().within(namespace);
+    let request = api.get(name)?;
+    let config_map =
+        match kubeapi::call("get", "configmaps", || client.request::<ConfigMap>(request)) {
+            Ok(config_map) => config_map,
+            Err(error) => {
+                let error = Error::from(error);
+                if !retry::not_found(&error) {
+                    return Err(error);
+                }
+                // Without the ConfigMap, the command line applies as it is.
+                ConfigMap::default()
+            }
+        };
+    let mut version = config_map.metadata.resource_version.clone();
+    update(
+        client,
+        live,
+        namespace,
+        name,
+        command_line,
+        config_map,
+        applied,
+    )?;
+
+    let params = ListParams {
+        field_selector: Some(format!("metadata.name={}", name)),
+        timeout: Some(290),
+        ..Default::default()
+    };
+    loop {
+        let request = api.watch(&params, &version)?;
+        let events = kubeapi::call("watch", "configmaps", || {
+            client.request_events::<WatchEvent>(request)
+        })?;
+        for event in events {
+            let config_map = match event.type_.as_str() {
+                "ADDED" | "MODIFIED" => serde_json::from_value(event.object)?,
+                "DELETED" => ConfigMap::default(),
+                _ => {
+                    return Err(Error::Config(format!(
+                        "watch of the policy failed: {}",
+                        event.object
+                    )))
+                }
+            };
+            if !config_map.metadata.resource_version.is_empty() {
+                version = config_map.metadata.resource_version.clone();
+            }
+            update(
+                client,
+                live,
+                namespace,
+                name,
+                command_line,
+                config_map,
+                applied,
+            )?;
+        }
+    }
+}
+
+/// Apply a version of the ConfigMap, unless it already has been.
+fn update(
+    client: &APIClient,
+    live: &Live,
+    namespace: &str,
+    name: &str,
+    command_line: &dyn Fn() -> Options,
+    config_map: ConfigMap,
+    applied: &mut Option<String>,
+) -> Result<(), Error> {
+    let version = config_map.metadata.resource_version.clone();
+    if applied.as_ref() == Some(&version) {
+        return Ok(());
+    }
+    *applied = Some(version.clone());
+
+    match apply(command_line(), &config_map.data) {
+        Ok(options) => {
+            info!("Applying the policy in ConfigMap {}/{}", namespace, name);
+            let cluster_version_name = options.cluster_version_name.clone();
+            live.set(options);
+            let patch = serde_json::json!({
+                "metadata": { "annotations": { POLICY_ANNOTATION: version } }
+            });
+            kubeapi::call("patch", "clusterversions", || {
+                clusterversion::api(client.clone()).patch(
+                    &cluster_version_name,
+                    &PatchParams::default(),
+                    serde_json::to_vec(&patch).expect("Serialize to JSON"),
+                )
+            })?;
+        }
+        Err(problems) => {
+            warn!(
+                "Ignoring the invalid policy in ConfigMap {}/{}: {}",
+                namespace,
+                name,
+                problems.join("; ")
+            );
+            let now = Utc::now();
+            let body = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Event",
+                "metadata": { "generateName": format!("{}.", name), "namespace": namespace },
+                "involvedObject": {
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "namespace": namespace,
+                    "name": name,
+                    "uid": config_map.metadata.uid,
+                    "resourceVersion": version,
+                },
+                "reason": "InvalidPolicy",
+                "message": problems.join("; "),
+                "type": "Warning",
+                "source": { "component": "openshift-update" },
+                "firstTimestamp": now,
+                "lastTimestamp": now,
+                "count": 1,
+            });
+            let request = RawApi::v1Event().within(namespace).create(
+                &PostParams::default(),
+                serde_json::to_vec(&body).expect("Serialize to JSON"),
+            )?;
+            kubeapi::call("create", "events", || {
+                client.request::<serde_json::Value>(request)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// The response to an AdmissionReview of a change to a ConfigMap, which rejects an invalid
+/// policy instead of letting it be ignored once it's stored. Other ConfigMaps are let through.
+#[cfg(feature = "metrics-server")]
+pub fn review(
+    review: &serde_json::Value,
+    reference: &Reference,
+    options: Options,
+) -> serde_json::Value {
+    let request = &review["request"];
+    let policy = request["namespace"] == reference.namespace.as_str()
+        && request["name"] == reference.name.as_str();
+    let writes = matches!(
+        request["operation"].as_str(),
+        Some("CREATE") | Some("UPDATE")
+    );
+    let problems = if policy && writes {
+        match serde_json::from_value::<ConfigMap>(request["object"].clone()) {
+            Ok(config_map) => apply(options, &config_map.data).err().unwrap_or_default(),
+            Err(error) => vec![format!("invalid ConfigMap: {}", error)],
+        }
+    } else {
+        Vec::new()
+    };
+
+    let mut response = serde_json::json!({
+        "uid": request["uid"],
+        "allowed": problems.is_empty(),
+    });
+    if !problems.is_empty() {
+        response["status"] = serde_json::json!({
+            "code": 422,
+            "message": format!("invalid update policy: {}", problems.join("; ")),
+        });
+    }
+    serde_json::json!({
+        "apiVersion": review["apiVersion"].as_str().unwrap_or("admission.k8s.io/v1"),
+        "kind": "AdmissionReview",
+        "response": response,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn applies_every_key_or_none() {
+        let data = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let command_line = || Options::from_iter(&["openshift-update", "--max-jitter", "1h"]);
+
+        let options = apply(
+            command_line(),
+            &data(&[
+                ("max-jitter", "2h"),
+                ("maintenance-window", "22:00-04:00"),
+                ("allow-prerelease", "true"),
+                ("minor-days", "1-7"),
+                ("manual-steps", "firewall=4.1->4.2, vendor=4.2"),
+                ("snooze-until", "2019-10-01T00:00:00Z"),
+                ("force-approved-by", "jane@example.com, john@example.com"),
+            ]),
+        )
+        .expect("valid policy");
+        assert_eq!(
+            options.force_approved_by,
+            vec!["jane@example.com", "john@example.com"]
+        );
+        assert_eq!(options.max_jitter, Some(Duration::from_secs(7200)));
+        assert_eq!(
+            options.maintenance_window.map(|window| window.to_string()),
+            Some("22:00-04:00".to_string())
+        );
+        assert!(options.allow_prerelease);
+        assert!(options.minor_days.is_some());
+        assert_eq!(options.manual_steps.len(), 2);
+        assert!(options.snooze_until.is_some());
+
+        let options = apply(command_line(), &data(&[("max-jitter", "")])).expect("valid policy");
+        assert_eq!(options.max_jitter, None);
+
+        assert_eq!(
+            apply(
+                command_line(),
+                &data(&[
+                    ("allow-prerelease", "yes"),
+                    ("max-jitter", "2h"),
+                    ("require-approval", "true"),
+                    ("max-surge", "1"),
+                ]),
+            )
+            .err(),
+            Some(vec![
+                "allow-prerelease: \"yes\" is neither \"true\" nor \"false\"".to_string(),
+                "max-surge: unknown key (expected one of force, force-approved-by, max-jitter, \
+                 maintenance-window, z-stream-delay, minor-days, manual-steps, require-approval, \
+                 require-eus-approval, allow-unmanaged, allow-prerelease, min-patch-interval, \
+                 snooze-until, snooze-reason, rollout-url)"
+                    .to_string(),
+                "require-approval: requires --listen".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "metrics-server")]
+    fn rejects_invalid_policies() {
+        let reference: Reference = "openshift-update/policy".parse().expect("reference");
+        let request = |name: &str, data: serde_json::Value| {
+            serde_json::json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "request": {
+                    "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                    "namespace": "openshift-update",
+                    "name": name,
+                    "operation": "UPDATE",
+                    "object": { "metadata": { "name": name }, "data": data },
+                },
+            })
+        };
+        let command_line = || Options::from_iter(&["openshift-update"]);
+
+        let response = review(
+            &request("policy", serde_json::json!({ "max-jitter": "2h" })),
+            &reference,
+            command_line(),
+        );
+        assert_eq!(
+            response["response"],
+            serde_json::json!({ "uid": "705ab4f5-6393-11e8-b7cc-42010a800002", "allowed": true })
+        );
+
+        let response = review(
+            &request("policy", serde_json::json!({ "max-jitter": "soon" })),
+            &reference,
+            command_line(),
+        );
+        assert_eq!(response["response"]["allowed"], false);
+        assert_eq!(
+            response["response"]["status"]["message"],
+            "invalid update policy: max-jitter: expected number at 0"
+        );
+
+        let response = review(
+            &request("unrelated", serde_json::json!({ "max-jitter": "soon" })),
+            &reference,
+            command_line(),
+        );
+        assert_eq!(response["response"]["allowed"], true);
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs the checks which hold back updates against the cluster, without updating it.
+//!
+//! The checks are those `explain` makes against the update the operator would pick, followed by
+//! the verdict of the policy itself (the same chain the operator decides with), so that whatever
+//! holds the update back, such as its jitter or a missing approval, fails the run even where no
+//! single check covers it. The report is meant for CI: `run` returns whether every check passed,
+//! which the caller turns into the exit code. The operator can also run the checks on a schedule, independent of any
+//! update, so that whatever would hold back the next update is noticed well ahead of it. Those
+//! results are published as conditions in an annotation of the ClusterVersion (its status
+//! conditions belong to the cluster-version operator) and as metrics.
+
+use crate::explain::{explain, load, render_checks, summary, Check, Observed};
+use crate::options::Options;
+use crate::output::Output;
+use chrono::{DateTime, Utc};
+use kube::api::PatchParams;
+use kube::client::APIClient;
+use openshift_update::clusterversion::{ClusterVersion, KubeClient};
+use openshift_update::graph::GraphClient;
+use openshift_update::kubeapi;
+use openshift_update::kubeconfig;
+use openshift_update::metrics;
+use openshift_update::policy::Decision;
+use openshift_update::state::State;
+use openshift_update::Error;
+use std::fmt::Write;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Annotation of the ClusterVersion holding the results of the last scheduled run.
+const PREFLIGHT_ANNOTATION: &str = "upgrade.crawford.dev/preflight";
+
+#[derive(StructOpt)]
+pub struct Preflight {
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the report: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Report {
+    passed: bool,
+    checks: Vec<Check>,
+}
+
+/// Print the report, returning whether every check passed.
+pub fn run(client: APIClient, options: &Options, command: &Preflight) -> Result<bool, Error> {
+    let (graph, saved, version, observed) = load(client, options)?;
+    let report = preflight(options, &graph, &saved, &version, &observed, Utc::now())?;
+    command.output.print(&report, render);
+    Ok(report.passed)
+}
+
+/// Run the checks every `interval`, forever.
+pub fn schedule(options: &Options, interval: Duration) {
+    loop {
+        match publish(options) {
+            Ok(true) => info!("Preflight checks passed"),
+            Ok(false) => warn!("Preflight checks failed"),
+            Err(error) => error!("Failed to run preflight checks: {}", error),
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Run the checks once and publish the results, returning whether every check passed.
+fn publish(options: &Options) -> Result<bool, Error> {
+    // The credentials are loaded afresh on every run, since they may have been rotated since the
+    // last one.
+    let client = APIClient::new(kubeconfig::load()?);
+    let versions = KubeClient::new(
+        client.clone(),
+        &options.cluster_version_name,
+        options.backoff(),
+    )?;
+    let now = Utc::now();
+    let (graph, saved, version, observed) = load(client, options)?;
+    let report = preflight(options, &graph, &saved, &version, &observed, now)?;
+
+    metrics::clear("openshift_update_preflight_passed");
+    for check in &report.checks {
+        metrics::set(
+            "openshift_update_preflight_passed",
+            "Whether each of the checks passed in the last preflight run",
+            &[("gate", check.gate)],
+            if check.passed { 1.0 } else { 0.0 },
+        );
+    }
+    metrics::set(
+        "openshift_update_preflight_timestamp_seconds",
+        "When the last preflight run finished",
+        &[],
+        now.timestamp() as f64,
+    );
+
+    let patch = serde_json::to_vec(&annotation(&report, now)).expect("Serialize to JSON");
+    kubeapi::call("patch", "clusterversions", || {
+        versions
+            .api()
+            .patch(versions.name(), &PatchParams::default(), patch)
+    })?;
+    Ok(report.passed)
+}
+
+/// The patch recording the report in the ClusterVersion's annotation.
+fn annotation(report: &Report, now: DateTime<Utc>) -> serde_json::Value {
+    let conditions: Vec<serde_json::Value> = report
+        .checks
+        .iter()
+        .map(|check| {
+            serde_json::json!({
+                "type": check.gate,
+                "status": if check.passed { "True" } else { "False" },
+                "message": check.reason,
+                "lastProbeTime": now,
+            })
+        })
+        .collect();
+    let value = serde_json::json!({ "passed": report.passed, "conditions": conditions });
+    serde_json::json!({
+        "metadata": { "annotations": { PREFLIGHT_ANNOTATION: value.to_string() } }
+    })
+}
+
+fn preflight(
+    options: &Options,
+    graph: &dyn GraphClient,
+    saved: &State,
+    version: &ClusterVersion,
+    observed: &Observed,
+    now: DateTime<Utc>,
+) -> Result<Report, Error> {
+    let explanation = explain(options, graph, saved, version, observed, now)?;
+    let mut checks = explanation.checks;
+    checks.push(Check {
+        gate: "policy",
+        passed: matches!(
+            explanation.decision,
+            Decision::UpToDate | Decision::Apply { .. }
+        ),
+        reason: format!(
+            "{} ({})",
+            summary(&explanation.decision),
+            explanation.reason
+        ),
+    });
+    Ok(Report {
+        passed: checks.iter().all(|check| check.passed),
+        checks,
+    })
+}
+
+fn render(report: &Report) -> String {
+    let mut text = String::new();
+    render_checks(&mut text, &report.checks);
+    let failed = report.checks.iter().filter(|check| !check.passed).count();
+    if failed == 0 {
+        writeln!(text, "\nAll {} checks passed", report.checks.len())
+    } else {
+        writeln!(
+            text,
+            "\n{} of {} checks failed",
+            failed,
+            report.checks.len()
+        )
+    }
+    .expect("write to string");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openshift_update::graph::Graph;
+
+    struct Fixture;
+
+    impl GraphClient for Fixture {
+        fn fetch(&self, _upstream: &str, _channel: &str, _version: &str) -> Result<Graph, Error> {
+            Ok(
+                serde_json::from_str(include_str!("../tests/fixtures/graph.json"))
+                    .expect("valid fixture"),
+            )
+        }
+    }
+
+    fn failed(report: &Report) -> Vec<&str> {
+        report
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.gate)
+            .collect()
+    }
+
+    #[test]
+    fn fails_while_updating() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-in-progress.json"
+        ))
+        .expect("valid fixture");
+        let options = Options::from_iter(&["openshift-update"]);
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+
+        let report = preflight(
+            &options,
+            &Fixture,
+            &State::default(),
+            &version,
+            &Observed::default(),
+            now,
+        )
+        .expect("preflight");
+        assert!(!report.passed);
+        assert_eq!(failed(&report), vec!["update-in-progress", "policy"]);
+        assert!(render(&report).ends_with("\n2 of 11 checks failed\n"));
+
+        let patch = annotation(&report, now);
+        let value: serde_json::Value = serde_json::from_str(
+            patch["metadata"]["annotations"][PREFLIGHT_ANNOTATION]
+                .as_str()
+                .expect("annotation"),
+        )
+        .expect("valid JSON");
+        assert_eq!(value["passed"], false);
+        assert_eq!(value["conditions"][0]["type"], "update-in-progress");
+        assert_eq!(value["conditions"][0]["status"], "False");
+        assert_eq!(value["conditions"][1]["status"], "True");
+    }
+
+    #[test]
+    fn evaluates_the_policy() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let report = |args: &[&str]| {
+            preflight(
+                &Options::from_iter(args),
+                &Fixture,
+                &State::default(),
+                &version,
+                &Observed::default(),
+                now,
+            )
+            .expect("preflight")
+        };
+
+        let passed = report(&["openshift-update"]);
+        assert!(passed.passed);
+        assert_eq!(
+            passed.checks.last().map(|check| check.reason.as_str()),
+            Some("Would update to 4.1.16 now (Ready)")
+        );
+
+        // The jitter holds back an update which was only just seen.
+        let jittered = report(&["openshift-update", "--max-jitter", "1d"]);
+        assert_eq!(failed(&jittered), vec!["jitter", "policy"]);
+        assert!(jittered
+            .checks
+            .last()
+            .expect("policy")
+            .reason
+            .ends_with("(SoakPending)"));
+    }
+}
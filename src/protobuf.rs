@@ -0,0 +1,629 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lists of pods and nodes in the API server's protobuf encoding.
+//!
+//! The gates list every pod and node on each pass, which on a large cluster is megabytes of JSON
+//! for the API server to encode and the operator to parse. The API server also serves its built-in
+//! types as protobuf, which is several times smaller and cheaper on both ends. kube only speaks
+//! JSON, so these lists are requested here instead, accepting either encoding. A protobuf
+//! response is decoded into the JSON the API server would have sent, reduced to the fields
+//! described below (which are those the gates read), and deserialized as before. Anything else
+//! (e.g. an API server which only offers JSON) is parsed as JSON.
+//!
+//! Protobuf is used once it has been enabled with `configure`, which the operator does unless
+//! --kube-api-content-type is "json".
+
+use crate::kubeconfig;
+use chrono::{TimeZone, Utc};
+use kube::client::APIClient;
+use kube::config::Configuration;
+use kube::{ApiError, ErrorKind};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use std::sync::Mutex;
+
+const PROTOBUF: &str = "application/vnd.kubernetes.protobuf";
+
+/// Every protobuf response starts with this, followed by a runtime.Unknown holding the encoded
+/// object in its field 2.
+const MAGIC: &[u8] = b"k8s\x00";
+
+static ENABLED: Mutex<bool> = Mutex::new(false);
+
+/// The configuration the lists are requested with, which is loaded again once its credentials
+/// are rejected.
+static CONFIGURATION: Mutex<Option<Configuration>> = Mutex::new(None);
+
+/// Request the lists in protobuf, or with `false` (the initial configuration) in JSON.
+pub fn configure(enabled: bool) {
+    *ENABLED.lock().expect("protobuf lock") = enabled;
+}
+
+/// What a field holds, and how it's given in JSON.
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Int,
+    Bool,
+    /// A metav1.Time, given as an RFC 3339 timestamp.
+    Time,
+    /// A resource.Quantity, given as its string.
+    Quantity,
+    Message(&'static Message),
+    /// A message which is given as `{}` when it's absent, as the spec of an object must be.
+    Always(&'static Message),
+    /// A map from strings, encoded as repeated entries.
+    Map(&'static Kind),
+}
+
+struct Field {
+    number: u32,
+    name: &'static str,
+    kind: Kind,
+    repeated: bool,
+}
+
+/// The fields of a message which are decoded; any others are skipped.
+pub struct Message(&'static [Field]);
+
+const fn field(number: u32, name: &'static str, kind: Kind) -> Field {
+    Field {
+        number,
+        name,
+        kind,
+        repeated: false,
+    }
+}
+
+const fn repeated(number: u32, name: &'static str, kind: Kind) -> Field {
+    Field {
+        number,
+        name,
+        kind,
+        repeated: true,
+    }
+}
+
+static EMPTY: Message = Message(&[]);
+
+static OBJECT_META: Message = Message(&[
+    field(1, "name", Kind::String),
+    field(3, "namespace", Kind::String),
+    field(5, "uid", Kind::String),
+    field(6, "resourceVersion", Kind::String),
+    field(7, "generation", Kind::Int),
+    field(8, "creationTimestamp", Kind::Time),
+    field(11, "labels", Kind::Map(&Kind::String)),
+    field(12, "annotations", Kind::Map(&Kind::String)),
+    repeated(13, "ownerReferences", Kind::Message(&OWNER_REFERENCE)),
+    repeated(14, "finalizers", Kind::String),
+]);
+
+static OWNER_REFERENCE: Message = Message(&[
+    field(1, "kind", Kind::String),
+    field(3, "name", Kind::String),
+    field(4, "uid", Kind::String),
+    field(5, "apiVersion", Kind::String),
+    field(6, "controller", Kind::Bool),
+    field(7, "blockOwnerDeletion", Kind::Bool),
+]);
+
+static LIST_META: Message = Message(&[
+    field(2, "resourceVersion", Kind::String),
+    field(3, "continue", Kind::String),
+]);
+
+/// A metav1.Status, which is what requests which fail respond with.
+static STATUS: Message = Message(&[
+    field(2, "status", Kind::String),
+    field(3, "message", Kind::String),
+    field(4, "reason", Kind::String),
+    field(6, "code", Kind::Int),
+]);
+
+static POD: Message = Message(&[
+    field(1, "metadata", Kind::Message(&OBJECT_META)),
+    field(2, "spec", Kind::Always(&EMPTY)),
+    field(3, "status", Kind::Message(&POD_STATUS)),
+]);
+
+static POD_STATUS: Message = Message(&[
+    field(1, "phase", Kind::String),
+    repeated(2, "conditions", Kind::Message(&POD_CONDITION)),
+    field(3, "message", Kind::String),
+    field(4, "reason", Kind::String),
+    repeated(8, "containerStatuses", Kind::Message(&CONTAINER_STATUS)),
+    repeated(
+        10,
+        "initContainerStatuses",
+        Kind::Message(&CONTAINER_STATUS),
+    ),
+]);
+
+static POD_CONDITION: Message = Message(&[
+    field(1, "type", Kind::String),
+    field(2, "status", Kind::String),
+    field(4, "lastTransitionTime", Kind::Time),
+    field(5, "reason", Kind::String),
+    field(6, "message", Kind::String),
+]);
+
+static CONTAINER_STATUS: Message = Message(&[
+    field(1, "name", Kind::String),
+    field(2, "state", Kind::Message(&CONTAINER_STATE)),
+    field(3, "lastState", Kind::Message(&CONTAINER_STATE)),
+    field(4, "ready", Kind::Bool),
+    field(5, "restartCount", Kind::Int),
+    field(6, "image", Kind::String),
+    field(9, "started", Kind::Bool),
+]);
+
+static CONTAINER_STATE: Message = Message(&[
+    field(1, "waiting", Kind::Message(&CONTAINER_STATE_WAITING)),
+    field(2, "running", Kind::Message(&CONTAINER_STATE_RUNNING)),
+    field(3, "terminated", Kind::Message(&CONTAINER_STATE_TERMINATED)),
+]);
+
+static CONTAINER_STATE_WAITING: Message = Message(&[
+    field(1, "reason", Kind::String),
+    field(2, "message", Kind::String),
+]);
+
+static CONTAINER_STATE_RUNNING: Message = Message(&[field(1, "startedAt", Kind::Time)]);
+
+static CONTAINER_STATE_TERMINATED: Message = Message(&[
+    field(1, "exitCode", Kind::Int),
+    field(2, "signal", Kind::Int),
+    field(3, "reason", Kind::String),
+    field(4, "message", Kind::String),
+    field(5, "startedAt", Kind::Time),
+    field(6, "finishedAt", Kind::Time),
+]);
+
+static NODE: Message = Message(&[
+    field(1, "metadata", Kind::Message(&OBJECT_META)),
+    field(2, "spec", Kind::Always(&NODE_SPEC)),
+    field(3, "status", Kind::Message(&NODE_STATUS)),
+]);
+
+static NODE_SPEC: Message = Message(&[
+    field(1, "podCIDR", Kind::String),
+    field(3, "providerID", Kind::String),
+    field(4, "unschedulable", Kind::Bool),
+    repeated(5, "taints", Kind::Message(&TAINT)),
+]);
+
+static TAINT: Message = Message(&[
+    field(1, "key", Kind::String),
+    field(2, "value", Kind::String),
+    field(3, "effect", Kind::String),
+    field(4, "timeAdded", Kind::Time),
+]);
+
+static NODE_STATUS: Message = Message(&[
+    field(1, "capacity", Kind::Map(&Kind::Quantity)),
+    field(2, "allocatable", Kind::Map(&Kind::Quantity)),
+    repeated(4, "conditions", Kind::Message(&NODE_CONDITION)),
+    field(7, "nodeInfo", Kind::Message(&NODE_SYSTEM_INFO)),
+]);
+
+static NODE_CONDITION: Message = Message(&[
+    field(1, "type", Kind::String),
+    field(2, "status", Kind::String),
+    field(3, "lastHeartbeatTime", Kind::Time),
+    field(4, "lastTransitionTime", Kind::Time),
+    field(5, "reason", Kind::String),
+    field(6, "message", Kind::String),
+]);
+
+static NODE_SYSTEM_INFO: Message = Message(&[
+    field(1, "machineID", Kind::String),
+    field(2, "systemUUID", Kind::String),
+    field(3, "bootID", Kind::String),
+    field(4, "kernelVersion", Kind::String),
+    field(5, "osImage", Kind::String),
+    field(6, "containerRuntimeVersion", Kind::String),
+    field(7, "kubeletVersion", Kind::String),
+    field(8, "kubeProxyVersion", Kind::String),
+    field(9, "operatingSystem", Kind::String),
+    field(10, "architecture", Kind::String),
+]);
+
+/// A v1.PodList.
+pub static POD_LIST: Message = Message(&[
+    field(1, "metadata", Kind::Message(&LIST_META)),
+    repeated(2, "items", Kind::Message(&POD)),
+]);
+
+/// A v1.NodeList.
+pub static NODE_LIST: Message = Message(&[
+    field(1, "metadata", Kind::Message(&LIST_META)),
+    repeated(2, "items", Kind::Message(&NODE)),
+]);
+
+/// A field as it's found on the wire.
+enum Wire<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+fn varint(buf: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first().ok_or("truncated varint")?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint is too long".to_string())
+}
+
+fn skip(buf: &mut &[u8], len: usize) -> Result<&'static [u8], String> {
+    if buf.len() < len {
+        return Err("truncated field".to_string());
+    }
+    *buf = &buf[len..];
+    Ok(&[])
+}
+
+/// The fields of a message, in the order they were encoded.
+fn fields(mut buf: &[u8]) -> Result<Vec<(u32, Wire<'_>)>, String> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let number = (key >> 3) as u32;
+        let wire = match key & 7 {
+            0 => Wire::Varint(varint(&mut buf)?),
+            1 => Wire::Bytes(skip(&mut buf, 8)?),
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                if buf.len() < len {
+                    return Err("truncated field".to_string());
+                }
+                let (bytes, rest) = buf.split_at(len);
+                buf = rest;
+                Wire::Bytes(bytes)
+            }
+            5 => Wire::Bytes(skip(&mut buf, 4)?),
+            wire => return Err(format!("field {} has unknown wire type {}", number, wire)),
+        };
+        fields.push((number, wire));
+    }
+    Ok(fields)
+}
+
+/// Decode the message into the JSON the API server would have given.
+fn decode(message: &Message, buf: &[u8]) -> Result<serde_json::Value, String> {
+    let mut object = serde_json::Map::new();
+    for field in message.0 {
+        if let Kind::Always(_) = field.kind {
+            object.insert(field.name.to_string(), serde_json::json!({}));
+        }
+    }
+    for (number, wire) in fields(buf)? {
+        let field = match message.0.iter().find(|field| field.number == number) {
+            Some(field) => field,
+            None => continue,
+        };
+        if let Kind::Map(kind) = field.kind {
+            let entry = bytes(field, wire)?;
+            let (mut key, mut value) = (String::new(), convert(field, *kind, Wire::Bytes(&[]))?);
+            for (number, wire) in fields(entry)? {
+                match number {
+                    1 => key = string(field, wire)?,
+                    2 => value = convert(field, *kind, wire)?,
+                    _ => {}
+                }
+            }
+            object
+                .entry(field.name)
+                .or_insert_with(|| serde_json::json!({}))[key] = value;
+        } else if field.repeated {
+            let value = convert(field, field.kind, wire)?;
+            match object
+                .entry(field.name)
+                .or_insert_with(|| serde_json::json!([]))
+            {
+                serde_json::Value::Array(values) => values.push(value),
+                _ => unreachable!("repeated fields are arrays"),
+            }
+        } else {
+            object.insert(field.name.to_string(), convert(field, field.kind, wire)?);
+        }
+    }
+    Ok(serde_json::Value::Object(object))
+}
+
+fn bytes<'a>(field: &Field, wire: Wire<'a>) -> Result<&'a [u8], String> {
+    match wire {
+        Wire::Bytes(bytes) => Ok(bytes),
+        Wire::Varint(_) => Err(format!("{} isn't length-delimited", field.name)),
+    }
+}
+
+fn string(field: &Field, wire: Wire) -> Result<String, String> {
+    String::from_utf8(bytes(field, wire)?.to_vec())
+        .map_err(|_| format!("{} isn't UTF-8", field.name))
+}
+
+fn convert(field: &Field, kind: Kind, wire: Wire) -> Result<serde_json::Value, String> {
+    let varint = |wire| match wire {
+        Wire::Varint(value) => Ok(value),
+        Wire::Bytes(_) => Err(format!("{} isn't a varint", field.name)),
+    };
+    Ok(match kind {
+        Kind::String => string(field, wire)?.into(),
+        // Negative numbers are encoded in two's complement, as for int64.
+        Kind::Int => (varint(wire)? as i64).into(),
+        Kind::Bool => (varint(wire)? != 0).into(),
+        Kind::Time => {
+            let mut seconds = 0;
+            for (number, wire) in fields(bytes(field, wire)?)? {
+                if number == 1 {
+                    seconds = varint(wire)? as i64;
+                }
+            }
+            Utc.timestamp(seconds, 0)
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                .into()
+        }
+        Kind::Quantity => {
+            let mut quantity = String::new();
+            for (number, wire) in fields(bytes(field, wire)?)? {
+                if number == 1 {
+                    quantity = string(field, wire)?;
+                }
+            }
+            quantity.into()
+        }
+        Kind::Message(message) | Kind::Always(message) => decode(message, bytes(field, wire)?)?,
+        Kind::Map(_) => unreachable!("maps aren't nested"),
+    })
+}
+
+/// Unwrap the object from the envelope of a protobuf response, and decode it as the message.
+fn unwrap(body: &[u8], message: &Message) -> Result<serde_json::Value, String> {
+    if !body.starts_with(MAGIC) {
+        return Err("the response has no protobuf envelope".to_string());
+    }
+    let raw = fields(&body[MAGIC.len()..])?
+        .into_iter()
+        .find(|(number, _)| *number == 2)
+        .and_then(|(_, wire)| match wire {
+            Wire::Bytes(raw) => Some(raw),
+            Wire::Varint(_) => None,
+        })
+        .ok_or("the envelope holds no object")?;
+    decode(message, raw)
+}
+
+/// The configuration to make the requests with, if protobuf is to be used.
+fn configuration() -> Option<Configuration> {
+    if !*ENABLED.lock().expect("protobuf lock") {
+        return None;
+    }
+    let mut configuration = CONFIGURATION.lock().expect("configuration lock");
+    if configuration.is_none() {
+        match kubeconfig::load() {
+            Ok(loaded) => *configuration = Some(loaded),
+            Err(error) => debug!(
+                "Listing in JSON, since the kubeconfig failed to load: {}",
+                error
+            ),
+        }
+    }
+    configuration.clone()
+}
+
+/// Make the list request, decoding its response into a `T` whichever way it's encoded. Without
+/// protobuf, this is the same as `client.request(request)`.
+pub fn list<T: serde::de::DeserializeOwned>(
+    client: &APIClient,
+    request: http::Request<Vec<u8>>,
+    message: &'static Message,
+) -> Result<T, kube::Error> {
+    let configuration = match configuration() {
+        Some(configuration) => configuration,
+        None => return client.request(request),
+    };
+
+    let (parts, body) = request.into_parts();
+    let url = format!("{}{}", configuration.base_path, parts.uri);
+    trace!("{} {}", parts.method, url);
+    let mut response = configuration
+        .client
+        .request(parts.method, &url)
+        .headers(parts.headers)
+        .header(ACCEPT, format!("{}, application/json", PROTOBUF))
+        .body(body)
+        .send()
+        .map_err(|error| {
+            debug!("Failed to list {}: {}", url, error);
+            kube::Error::from(ErrorKind::RequestSend)
+        })?;
+    let mut body = Vec::new();
+    response.copy_to(&mut body).map_err(|error| {
+        debug!("Failed to read the list of {}: {}", url, error);
+        kube::Error::from(ErrorKind::RequestParse)
+    })?;
+    let protobuf = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(PROTOBUF));
+
+    let status = response.status();
+    if !status.is_success() {
+        let error = if protobuf {
+            unwrap(&body, &STATUS)
+                .ok()
+                .and_then(|status| serde_json::from_value::<ApiError>(status).ok())
+        } else {
+            serde_json::from_slice::<ApiError>(&body).ok()
+        };
+        let error = error.unwrap_or_else(|| ApiError {
+            status: status.to_string(),
+            message: String::from_utf8_lossy(&body).into_owned(),
+            reason: status.canonical_reason().unwrap_or_default().to_string(),
+            code: status.as_u16(),
+        });
+        if error.code == 401 {
+            // The credentials have probably been rotated, so they're loaded again next time.
+            *CONFIGURATION.lock().expect("configuration lock") = None;
+        }
+        return Err(ErrorKind::Api(error).into());
+    }
+
+    let parsed = if protobuf {
+        unwrap(&body, message)
+            .and_then(|value| serde_json::from_value(value).map_err(|error| error.to_string()))
+    } else {
+        serde_json::from_slice(&body).map_err(|error| error.to_string())
+    };
+    parsed.map_err(|error| {
+        warn!("Failed to decode the list of {}: {}", url, error);
+        ErrorKind::SerdeParse.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workloads::Pod;
+
+    fn key(number: u32, wire: u64) -> Vec<u8> {
+        encode_varint((u64::from(number) << 3) | wire)
+    }
+
+    fn encode_varint(mut value: u64) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        while value >= 0x80 {
+            encoded.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        encoded.push(value as u8);
+        encoded
+    }
+
+    fn int(number: u32, value: u64) -> Vec<u8> {
+        [key(number, 0), encode_varint(value)].concat()
+    }
+
+    fn bytes(number: u32, value: &[u8]) -> Vec<u8> {
+        [
+            key(number, 2),
+            encode_varint(value.len() as u64),
+            value.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn envelope(raw: &[u8]) -> Vec<u8> {
+        [
+            MAGIC.to_vec(),
+            bytes(1, &[bytes(1, b"v1"), bytes(2, b"PodList")].concat()),
+            bytes(2, raw),
+            bytes(4, PROTOBUF.as_bytes()),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn decodes_pods() {
+        let waiting = bytes(1, &bytes(1, b"CrashLoopBackOff"));
+        let container = [bytes(1, b"app"), bytes(2, &waiting), int(5, 7)].concat();
+        let metadata = [
+            bytes(1, b"api-7d4f"),
+            bytes(3, b"prod"),
+            bytes(8, &int(1, 1_568_678_400)),
+            bytes(11, &[bytes(1, b"app"), bytes(2, b"api")].concat()),
+            // A field the decoder doesn't know, which is skipped.
+            int(99, 1),
+        ]
+        .concat();
+        let pod = [
+            bytes(1, &metadata),
+            bytes(3, &[bytes(1, b"Running"), bytes(8, &container)].concat()),
+        ]
+        .concat();
+        let list = [bytes(1, &bytes(2, b"42")), bytes(2, &pod), bytes(2, &pod)].concat();
+
+        let value = unwrap(&envelope(&list), &POD_LIST).expect("valid list");
+        assert_eq!(value["metadata"]["resourceVersion"], "42");
+        let pods: Vec<Pod> = serde_json::from_value(value["items"].clone()).expect("valid pods");
+        assert_eq!(pods.len(), 2);
+        assert_eq!(pods[0].metadata.name, "api-7d4f");
+        assert_eq!(pods[0].metadata.labels["app"], "api");
+        let status = pods[0].status.as_ref().expect("status");
+        assert_eq!(status.container_statuses[0].restart_count, 7);
+        assert_eq!(
+            status.container_statuses[0]
+                .state
+                .waiting
+                .as_ref()
+                .map(|waiting| waiting.reason.as_str()),
+            Some("CrashLoopBackOff")
+        );
+        assert_eq!(
+            value["items"][0]["metadata"]["creationTimestamp"],
+            "2019-09-17T00:00:00Z"
+        );
+        assert_eq!(value["items"][0]["spec"], serde_json::json!({}));
+
+        assert!(unwrap(&list, &POD_LIST).is_err());
+        assert!(unwrap(&envelope(&list[..list.len() - 1]), &POD_LIST).is_err());
+    }
+
+    #[test]
+    fn decodes_nodes_and_errors() {
+        let info = bytes(7, b"v1.14.6+73b5d76");
+        let capacity = [bytes(1, b"cpu"), bytes(2, &bytes(1, b"4"))].concat();
+        let condition = [bytes(1, b"Ready"), bytes(2, b"True")].concat();
+        let status = [bytes(1, &capacity), bytes(4, &condition), bytes(7, &info)].concat();
+        let node = [
+            bytes(1, &bytes(1, b"worker-0")),
+            bytes(2, &int(4, 1)),
+            bytes(3, &status),
+        ]
+        .concat();
+        let value = unwrap(&envelope(&bytes(2, &node)), &NODE_LIST).expect("valid list");
+        assert_eq!(
+            value["items"][0],
+            serde_json::json!({
+                "metadata": { "name": "worker-0" },
+                "spec": { "unschedulable": true },
+                "status": {
+                    "capacity": { "cpu": "4" },
+                    "conditions": [{ "type": "Ready", "status": "True" }],
+                    "nodeInfo": { "kubeletVersion": "v1.14.6+73b5d76" },
+                },
+            })
+        );
+
+        let status = [
+            bytes(2, b"Failure"),
+            bytes(3, b"Unauthorized"),
+            bytes(4, b"Unauthorized"),
+            int(6, 401),
+        ]
+        .concat();
+        let error: ApiError =
+            serde_json::from_value(unwrap(&envelope(&status), &STATUS).expect("valid status"))
+                .expect("valid error");
+        assert_eq!(error.code, 401);
+        assert_eq!(error.reason, "Unauthorized");
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client-side rate limiting of requests to the Kubernetes API.
+//!
+//! Requests are admitted by a token bucket which refills at a steady rate (the QPS) up to a
+//! maximum (the burst), the same scheme used by client-go. Every client in the process shares the
+//! one API server, so they also share a single process-wide bucket.
+
+use crate::metrics;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static LIMITER: Mutex<Option<Bucket>> = Mutex::new(None);
+
+const THROTTLED_METRIC: &str = "openshift_update_throttled_requests_total";
+const THROTTLED_HELP: &str = "Kubernetes API requests delayed by the client-side rate limit.";
+
+#[derive(Debug)]
+pub struct Bucket {
+    qps: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// A full bucket, admitting `burst` requests at once and `qps` per second after that.
+    pub fn new(qps: f64, burst: u32, now: Instant) -> Bucket {
+        let burst = f64::from(burst.max(1));
+        Bucket {
+            qps,
+            burst,
+            tokens: burst,
+            refilled: now,
+        }
+    }
+
+    /// Take a token, returning how long the request has to wait for it. Tokens are reserved
+    /// ahead of time, so concurrent callers are admitted in the order they asked.
+    pub fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.qps).min(self.burst) - 1.0;
+        self.refilled = now;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.qps)
+        }
+    }
+}
+
+/// Limit requests to `qps` per second with bursts of up to `burst`. A QPS of zero (the initial
+/// configuration) disables the limit.
+pub fn configure(qps: f64, burst: u32) {
+    *LIMITER.lock().expect("limiter lock") = if qps > 0.0 {
+        Some(Bucket::new(qps, burst, Instant::now()))
+    } else {
+        None
+    };
+}
+
+/// Block until a request to the Kubernetes API may be made.
+pub fn acquire() {
+    let delay = match LIMITER.lock().expect("limiter lock").as_mut() {
+        Some(bucket) => bucket.take(Instant::now()),
+        None => return,
+    };
+    if delay > Duration::from_secs(0) {
+        debug!("Throttling Kubernetes API request for {:?}", delay);
+        metrics::inc(THROTTLED_METRIC, THROTTLED_HELP, &[]);
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_bursts_then_throttles() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2.0, 3, start);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), Duration::from_secs(0));
+        }
+        assert_eq!(bucket.take(start), Duration::from_millis(500));
+        assert_eq!(bucket.take(start), Duration::from_secs(1));
+
+        // After the reservations are caught up on, the bucket refills up to the burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(later), Duration::from_secs(0));
+        }
+        assert_eq!(bucket.take(later), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A list of every update the cluster knows of, with what is known about its risks and whether
+//! the operator's policy would allow it.
+//!
+//! This is the operator's take on `oc adm upgrade --include-not-recommended`: conditional updates
+//! are listed alongside the recommended ones, and each is judged as the operator would judge it if
+//! it were the only candidate.
+
+use crate::explain::{self, Observed};
+use crate::options::Options;
+use crate::output::Output;
+use crate::reconciler::{channel_graph, upgrade_policy};
+use chrono::{DateTime, Utc};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{ClusterUpdate, ClusterVersion, ConditionalUpdateRisk};
+use openshift_update::graph::{GraphClient, Node};
+use openshift_update::policy::{self, Decision};
+use openshift_update::release::{self, Diff, RegistryClient, ReleaseClient};
+use openshift_update::secret::SecretFile;
+use openshift_update::state::State;
+use openshift_update::Error;
+use std::fmt::Write;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Recommend {
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the list: "table", "json" or "yaml"
+    pub output: Output,
+
+    #[structopt(long = "diff")]
+    /// Compare each update's release with the cluster's (reads the release images from their
+    /// registries)
+    pub diff: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Recommendation {
+    version: semver::Version,
+    image: String,
+    /// Whether the cluster recommends the update, or `None` if it has yet to evaluate the risks.
+    recommended: Option<bool>,
+    risks: Vec<ConditionalUpdateRisk>,
+    /// When the operator first saw the update offered, if it has.
+    #[serde(rename = "offeredSince", skip_serializing_if = "Option::is_none")]
+    offered_since: Option<DateTime<Utc>>,
+    /// The advisory which published the release.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errata: Option<String>,
+    /// Whether the advisory is a security advisory (RHSA), i.e. the release fixes CVEs.
+    security: bool,
+    eligible: bool,
+    eligibility: String,
+    /// How the release differs from the cluster's, if it was compared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Diff>,
+}
+
+pub fn run(client: APIClient, options: &Options, command: &Recommend) -> Result<(), Error> {
+    let (graph, saved, version, observed) = explain::load(client, options)?;
+    let registry = RegistryClient {
+        http: graph.http.clone(),
+        backoff: options.backoff(),
+        auth: options.registry_auth_file.as_deref().map(SecretFile::new),
+    };
+    let releases: Option<&dyn ReleaseClient> = if command.diff { Some(&registry) } else { None };
+    let recommendations = recommend(
+        options,
+        &graph,
+        releases,
+        &saved,
+        &version,
+        &observed,
+        Utc::now(),
+    )?;
+    command.output.print(&recommendations, |recommendations| {
+        render(recommendations, Utc::now())
+    });
+    Ok(())
+}
+
+fn recommend(
+    options: &Options,
+    graph: &dyn GraphClient,
+    releases: Option<&dyn ReleaseClient>,
+    saved: &State,
+    version: &ClusterVersion,
+    observed: &Observed,
+    now: DateTime<Utc>,
+) -> Result<Vec<Recommendation>, Error> {
+    let status = version.status.clone().unwrap_or_default();
+    let available = status.available_updates.unwrap_or_default();
+
+    // Recommended conditional updates are also listed as available, so the two are merged.
+    let mut updates: Vec<(ClusterUpdate, Option<bool>, Vec<ConditionalUpdateRisk>)> = available
+        .iter()
+        .map(|update| (update.clone(), Some(true), Vec::new()))
+        .collect();
+    for conditional in status.conditional_updates {
+        match updates
+            .iter_mut()
+            .find(|(update, _, _)| update == &conditional.release)
+        {
+            Some((_, _, risks)) => *risks = conditional.risks,
+            None => updates.push((
+                conditional.release.clone(),
+                conditional.recommended(),
+                conditional.risks,
+            )),
+        }
+    }
+    updates.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+
+    let channel_graph = if updates.is_empty() {
+        None
+    } else {
+        channel_graph(graph, version)?
+    };
+    let on_channel = |update: &ClusterUpdate| {
+        channel_graph
+            .as_ref()
+            .is_none_or(|(_, graph)| graph.contains(&update.version))
+    };
+    let offered: Vec<ClusterUpdate> = available
+        .iter()
+        .filter(|update| on_channel(update))
+        .cloned()
+        .collect();
+    let mut state = explain::cluster_state(options, version, saved, observed, &offered, now);
+    if let Some((_, graph)) = &channel_graph {
+        state.security = graph.security();
+    }
+    let policy = upgrade_policy(options);
+
+    // The cluster's release is only read once, however many updates it's compared with.
+    let image = status
+        .history
+        .first()
+        .and_then(|latest| latest.image.as_ref());
+    let current = match (releases, image) {
+        (Some(releases), Some(image)) => Some(releases.fetch(image)?),
+        _ => None,
+    };
+    let changes = |update: &ClusterUpdate| {
+        let (releases, current) = (releases?, current.as_ref()?);
+        match releases.fetch(&update.image) {
+            Ok(release) => Some(release::diff(current, &release)),
+            Err(error) => {
+                warn!(
+                    "Failed to read the release of {}: {}",
+                    update.version, error
+                );
+                None
+            }
+        }
+    };
+
+    Ok(updates
+        .into_iter()
+        .map(|(update, recommended, risks)| {
+            let (eligible, eligibility) = if !available.contains(&update) {
+                match recommended {
+                    Some(_) => (false, "not recommended".to_string()),
+                    None => (false, "risks not yet evaluated".to_string()),
+                }
+            } else if let Some(reason) = saved.poisoned.get(&update.version) {
+                (false, format!("failed before ({})", reason))
+            } else if !options.allow_prerelease && policy::prerelease(&update.version) {
+                (false, "a pre-release (see --allow-prerelease)".to_string())
+            } else if !on_channel(&update) {
+                let (channel, _) = channel_graph.as_ref().expect("channel graph");
+                (false, format!("not part of the {} channel", channel))
+            } else {
+                match policy.evaluate(&state, std::slice::from_ref(&update)) {
+                    Decision::Apply { .. } => (true, "eligible now".to_string()),
+                    Decision::Delayed { not_before, .. } => {
+                        (false, format!("delayed until {}", not_before))
+                    }
+                    Decision::Paused { .. } => (false, "updates are paused".to_string()),
+                    Decision::AwaitingApproval { .. } => (false, "awaiting approval".to_string()),
+                    Decision::Blocked { gate, reason, .. } => {
+                        (false, format!("blocked by the {} check: {}", gate, reason))
+                    }
+                    Decision::InProgress => (false, "waiting for the current update".to_string()),
+                    Decision::UpToDate => (false, "not offered".to_string()),
+                }
+            };
+            let errata = channel_graph
+                .as_ref()
+                .and_then(|(_, graph)| graph.node(&update.version))
+                .and_then(Node::errata)
+                .map(String::from);
+            Recommendation {
+                changes: changes(&update),
+                offered_since: saved
+                    .first_seen
+                    .get(&update.version)
+                    .cloned()
+                    .filter(|_| available.contains(&update)),
+                security: channel_graph
+                    .as_ref()
+                    .and_then(|(_, graph)| graph.node(&update.version))
+                    .is_some_and(Node::security),
+                errata,
+                version: update.version,
+                image: update.image,
+                recommended,
+                risks,
+                eligible,
+                eligibility,
+            }
+        })
+        .collect())
+}
+
+fn render(recommendations: &[Recommendation], now: DateTime<Utc>) -> String {
+    let mut text = String::new();
+    if recommendations.is_empty() {
+        writeln!(text, "No updates available").expect("write to string");
+        return text;
+    }
+
+    writeln!(
+        text,
+        "{:<24} {:<12} {:<12} {:<24} ELIGIBILITY",
+        "VERSION", "RECOMMENDED", "OFFERED", "ERRATA"
+    )
+    .expect("write to string");
+    for recommendation in recommendations {
+        let offered = match recommendation.offered_since {
+            Some(since) => {
+                let age = (now - since).to_std().unwrap_or_default();
+                // Only the largest unit is shown; the exact time is in the JSON output.
+                humantime::format_duration(Duration::from_secs(age.as_secs()))
+                    .to_string()
+                    .split(' ')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            }
+            None => "-".to_string(),
+        };
+        let errata = match &recommendation.errata {
+            Some(errata) if recommendation.security => format!("{} (security)", errata),
+            Some(errata) => errata.clone(),
+            None => "-".to_string(),
+        };
+        writeln!(
+            text,
+            "{:<24} {:<12} {:<12} {:<24} {}",
+            recommendation.version,
+            match recommendation.recommended {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "unknown",
+            },
+            offered,
+            errata,
+            recommendation.eligibility
+        )
+        .expect("write to string");
+        for risk in &recommendation.risks {
+            writeln!(text, "    {}: {} {}", risk.name, risk.message, risk.url)
+                .expect("write to string");
+        }
+        if let Some(changes) = &recommendation.changes {
+            write!(
+                text,
+                "    changes: {} changed, {} added, {} removed",
+                changes.changed.len(),
+                changes.added.len(),
+                changes.removed.len()
+            )
+            .expect("write to string");
+            if let Some(bump) = &changes.machine_os {
+                write!(
+                    text,
+                    "; RHCOS {} to {}",
+                    bump.from.as_deref().unwrap_or("unknown"),
+                    bump.to.as_deref().unwrap_or("unknown")
+                )
+                .expect("write to string");
+            }
+            writeln!(text).expect("write to string");
+        }
+    }
+    text
+}
+
+#[cfg(all(test, feature = "cincinnati"))]
+mod tests {
+    use super::*;
+    use openshift_update::graph::Graph;
+    use openshift_update::release::Release;
+
+    struct Fixture(Graph);
+
+    impl GraphClient for Fixture {
+        fn fetch(&self, _upstream: &str, _channel: &str, _version: &str) -> Result<Graph, Error> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// The cluster's release has the older contents, and every other release the newer.
+    struct Releases;
+
+    impl ReleaseClient for Releases {
+        fn fetch(&self, image: &str) -> Result<Release, Error> {
+            let json: &[u8] = if image.ends_with(CURRENT) {
+                include_bytes!("../tests/fixtures/image-references-4.1.14.json")
+            } else {
+                include_bytes!("../tests/fixtures/image-references-4.1.15.json")
+            };
+            Release::from_image_references(json)
+        }
+    }
+
+    const CURRENT: &str = "sha256:fd3b5a6e8b0f2b1a8cd3ddd2d6d85de8f2a3b9c7f0c1a0e4a1e9d2d5a6f3bc21";
+
+    #[test]
+    fn lists_conditional_updates() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-conditional.json"
+        ))
+        .expect("valid fixture");
+        let graph = serde_json::from_str(include_str!("../tests/fixtures/graph.json"))
+            .expect("valid fixture");
+        let options = Options::from_iter(&["openshift-update", "--max-jitter", "1d"]);
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+
+        let recommendations = recommend(
+            &options,
+            &Fixture(graph),
+            Some(&Releases),
+            &State::default(),
+            &version,
+            &Observed::default(),
+            now,
+        )
+        .expect("recommend");
+        let versions: Vec<String> = recommendations
+            .iter()
+            .map(|r| r.version.to_string())
+            .collect();
+        assert_eq!(versions, vec!["4.1.17", "4.1.16", "4.1.15"]);
+
+        let (rejected, risky, safe) = (
+            &recommendations[0],
+            &recommendations[1],
+            &recommendations[2],
+        );
+        assert_eq!(rejected.recommended, Some(false));
+        assert_eq!(rejected.eligibility, "not recommended");
+        assert_eq!(risky.recommended, Some(true));
+        assert_eq!(risky.risks[0].name, "AWSMetadataTimeout");
+        assert_eq!(risky.errata.as_deref(), Some("RHBA-2019:2766"));
+        assert!(!risky.security);
+        assert!(safe.risks.is_empty());
+        for update in &[risky, safe] {
+            assert!(!update.eligible);
+            assert!(update.eligibility.starts_with("delayed until "));
+        }
+        assert_eq!(
+            safe.changes.as_ref().map(|changes| changes.changed.len()),
+            Some(2)
+        );
+
+        let text = render(&recommendations, now);
+        assert!(text.contains("    EtcdDefragHang: etcd may stop"));
+        assert!(text.contains(
+            "    changes: 2 changed, 1 added, 1 removed; RHCOS 410.8.20190830.0 to \
+             410.8.20190904.0\n"
+        ));
+    }
+}
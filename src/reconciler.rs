@@ -0,0 +1,2477 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reconciling the local cluster's ClusterVersion: deciding, under the policy the options build,
+//! whether to apply one of its updates, applying it, and recording why.
+
+use crate::options::Options;
+use crate::status::Status;
+use crate::verify::Verifier;
+use crate::{explain, gitops};
+use chrono::{DateTime, Utc};
+use kube::api::ObjectMeta;
+use kube::client::APIClient;
+use openshift_update::argocd::ArgoCd;
+use openshift_update::baremetal::BareMetalHosts;
+use openshift_update::capacity::Capacity;
+use openshift_update::clock::Clock;
+use openshift_update::clusterversion::{
+    ClusterUpdate, ClusterVersion, ClusterVersionClient, ClusterVersionSpec, ClusterVersionStatus,
+    HistoricalEntry, Outcome,
+};
+use openshift_update::credentials::{self, CredentialsClient};
+use openshift_update::dns::Dns;
+use openshift_update::durations::{self, PoolClient};
+use openshift_update::gates::{self, Snooze, MANAGED_BY_ANNOTATION, PAUSED_ANNOTATION};
+#[cfg(feature = "cincinnati")]
+use openshift_update::graph;
+use openshift_update::graph::{Graph, GraphClient};
+use openshift_update::health::{self, HealthCheck};
+use openshift_update::identity;
+use openshift_update::metrics;
+#[cfg(feature = "notifications")]
+use openshift_update::notify;
+use openshift_update::notify::Event;
+use openshift_update::policy::{self, ClusterState, Decision, UpgradePolicy};
+use openshift_update::release::{self, Diff, ReleaseClient};
+use openshift_update::retry;
+use openshift_update::rollout::{self, Percentages, RolloutClient};
+use openshift_update::secret::SecretFile;
+use openshift_update::slo::{self, Budget, Prometheus};
+use openshift_update::state::{self, Adoption, Blocked, State, StateStore};
+use openshift_update::steps::{self, AckClient};
+use openshift_update::storage::{self, StorageClient};
+use openshift_update::velocity::Velocity;
+use openshift_update::window::MaintenanceWindow;
+use openshift_update::workloads::StuckPods;
+use openshift_update::Error;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many times a patch rejected with a conflict is rebuilt from a fresh ClusterVersion.
+const CONFLICT_RETRIES: u32 = 3;
+
+/// The chain of built-in policies enabled by the options.
+pub fn upgrade_policy(options: &Options) -> Box<dyn UpgradePolicy> {
+    let mut policy: Box<dyn UpgradePolicy> = Box::new(policy::Latest);
+    if let Some(max) = options.max_jitter {
+        policy = Box::new(policy::Jitter {
+            inner: policy,
+            max,
+            window: options.maintenance_window,
+        });
+    }
+    if options.z_stream_delay.is_some()
+        || options.minor_days.is_some()
+        || options.require_eus_approval
+    {
+        policy = Box::new(Velocity {
+            inner: policy,
+            z_stream_delay: options.z_stream_delay,
+            minor_days: options.minor_days,
+            eus_approval: options.require_eus_approval,
+        });
+    }
+    if let Some(window) = options.maintenance_window {
+        policy = Box::new(MaintenanceWindow {
+            inner: policy,
+            window,
+        });
+    }
+    policy = Box::new(policy::Pausable { inner: policy });
+    policy = Box::new(policy::Snoozable { inner: policy });
+    if options.rollout_url.is_some() {
+        policy = Box::new(rollout::Rollout { inner: policy });
+    }
+    if let Some(matrix) = &options.storage_matrix {
+        policy = Box::new(storage::Storage {
+            inner: policy,
+            matrix: matrix.clone(),
+        });
+    }
+    policy = Box::new(credentials::ManualCredentials { inner: policy });
+    if !options.manual_steps.is_empty() {
+        policy = Box::new(steps::ManualSteps {
+            inner: policy,
+            steps: options.manual_steps.clone(),
+        });
+    }
+    if options.slo_file.is_some() {
+        policy = Box::new(slo::ProtectErrorBudget { inner: policy });
+    }
+    if !options.allow_unmanaged {
+        policy = Box::new(policy::RefuseUnmanaged { inner: policy });
+    }
+    policy = Box::new(policy::RefuseCompeting { inner: policy });
+    policy = Box::new(policy::RefuseRegressed { inner: policy });
+    policy = Box::new(policy::RespectOwner {
+        inner: policy,
+        manager: options.field_manager.clone(),
+    });
+    policy = Box::new(health::RefuseUnhealthy { inner: policy });
+    if options.require_approval {
+        policy = Box::new(policy::RequireApproval { inner: policy });
+    }
+    policy
+}
+
+/// The health checks enabled by the options. The capacity check is left out on a single node,
+/// which reboots in place rather than having its pods rescheduled elsewhere.
+pub fn health_checks(
+    options: &Options,
+    client: &APIClient,
+    single_node: bool,
+) -> Vec<Box<dyn HealthCheck>> {
+    let mut checks: Vec<Box<dyn HealthCheck>> = Vec::new();
+    if !options.argocd_projects.is_empty() {
+        checks.push(Box::new(ArgoCd {
+            client: client.clone(),
+            projects: options.argocd_projects.clone(),
+            backoff: options.backoff(),
+        }));
+    }
+    if let Some(dns) = dns_check(options, client) {
+        checks.push(Box::new(dns));
+    }
+    if let Some(max) = options.max_stuck_pods {
+        checks.push(Box::new(StuckPods {
+            client: client.clone(),
+            namespaces: options.stuck_pods_namespaces.clone(),
+            max,
+            backoff: options.backoff(),
+        }));
+    }
+    if options.check_bare_metal_hosts {
+        checks.push(Box::new(BareMetalHosts {
+            client: client.clone(),
+            backoff: options.backoff(),
+        }));
+    }
+    if options.check_capacity && !single_node {
+        checks.push(Box::new(Capacity {
+            client: client.clone(),
+            backoff: options.backoff(),
+        }));
+    }
+    checks
+}
+
+/// A check of each service-level objective's error budget.
+pub fn budget_checks(
+    options: &Options,
+    http: &reqwest::Client,
+) -> Result<Vec<Box<dyn HealthCheck>>, Error> {
+    let objectives = match &options.slo_file {
+        Some(objectives) => objectives,
+        None => return Ok(Vec::new()),
+    };
+    let prometheus = Prometheus {
+        http: http.clone(),
+        url: options.prometheus_url.clone(),
+        token: options
+            .prometheus_token_file
+            .as_deref()
+            .map(SecretFile::new),
+        backoff: options.backoff(),
+    };
+    Ok(objectives
+        .objectives
+        .iter()
+        .map(|objective| -> Box<dyn HealthCheck> {
+            Box::new(Budget {
+                prometheus: prometheus.clone(),
+                objective: objective.clone(),
+            })
+        })
+        .collect())
+}
+
+/// The check of the cluster's DNS, if enabled.
+pub fn dns_check(options: &Options, client: &APIClient) -> Option<Dns> {
+    if !options.check_dns {
+        return None;
+    }
+    Some(Dns {
+        client: client.clone(),
+        name: options.dns_check_name.clone(),
+        backoff: options.backoff(),
+    })
+}
+
+/// Everything needed to act on the local cluster's ClusterVersion.
+pub struct Reconciler<'a> {
+    pub client: &'a dyn ClusterVersionClient,
+    pub store: &'a dyn StateStore,
+    pub graph: &'a dyn GraphClient,
+    /// Where releases are read from to compare them, if enabled.
+    pub releases: Option<&'a dyn ReleaseClient>,
+    pub rollout: &'a dyn RolloutClient,
+    pub health: &'a [Box<dyn HealthCheck>],
+    pub storage: &'a dyn StorageClient,
+    pub credentials: &'a dyn CredentialsClient,
+    pub acks: &'a dyn AckClient,
+    pub pools: &'a dyn PoolClient,
+    /// Checks of the service-level objectives' error budgets.
+    pub budgets: &'a [Box<dyn HealthCheck>],
+    pub verifier: &'a dyn Verifier,
+    pub options: &'a Options,
+    pub policy: &'a dyn UpgradePolicy,
+    pub status: &'a Mutex<Status>,
+    pub single_node: bool,
+    /// What every time the decisions depend on is measured against.
+    pub clock: &'a dyn Clock,
+}
+
+impl<'a> Reconciler<'a> {
+    /// How long the cluster is given for what takes `timeout` elsewhere. A single node can't
+    /// update one machine while the others carry on, so it's drained and rebooted with its
+    /// control plane, which takes a good deal longer.
+    fn timeout(&self, timeout: Duration) -> Duration {
+        if self.single_node {
+            timeout * SINGLE_NODE_TIMEOUT_FACTOR
+        } else {
+            timeout
+        }
+    }
+
+    /// Evaluate the ClusterVersion and apply the chosen update. If the ClusterVersion changes
+    /// before the patch lands, the decision is made again against a fresh copy, up to
+    /// `CONFLICT_RETRIES` times.
+    pub fn reconcile(&self, mut version: ClusterVersion) -> Result<(), Error> {
+        let mut conflicts = 0;
+        loop {
+            match self.apply_available_update(version, conflicts > 0) {
+                Err(ref error) if retry::conflict(error) && conflicts < CONFLICT_RETRIES => {
+                    conflicts += 1;
+                    warn!(
+                        "ClusterVersion changed before the update could be applied; re-evaluating \
+                         ({}/{})",
+                        conflicts, CONFLICT_RETRIES
+                    );
+                    version = self.client.get()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Report the cluster failing to retrieve its updates, which otherwise looks just like there
+    /// being none. With `--refresh-updates-after`, the ClusterVersion is also annotated
+    /// periodically, since any change to it makes the cluster-version operator sync again.
+    fn check_retrieval(
+        &self,
+        version: &ClusterVersion,
+        now: DateTime<Utc>,
+    ) -> Result<Option<String>, Error> {
+        let failure = version
+            .status
+            .as_ref()
+            .and_then(ClusterVersionStatus::retrieval_failure);
+        metrics::set(
+            RETRIEVAL_METRIC,
+            RETRIEVAL_HELP,
+            &[],
+            if failure.is_some() { 1.0 } else { 0.0 },
+        );
+
+        let interval = match (&failure, self.options.refresh_updates_after) {
+            (Some(_), Some(interval)) => chrono::Duration::from_std(interval)
+                .map_err(|error| Error::Config(error.to_string()))?,
+            _ => return Ok(failure),
+        };
+        let requested = version
+            .metadata
+            .annotations
+            .get(REFRESH_ANNOTATION)
+            .and_then(|requested| requested.parse::<DateTime<Utc>>().ok());
+        if requested.is_none_or(|requested| now - requested >= interval) {
+            info!("Asking the cluster to retry retrieving its updates");
+            self.client
+                .annotate(REFRESH_ANNOTATION, Some(&now.to_rfc3339()))?;
+        }
+        Ok(failure)
+    }
+
+    /// The other field managers which set the desired update, each of which is also reported
+    /// through a metric.
+    fn competing_managers(&self) -> Result<Vec<String>, Error> {
+        let managers =
+            gates::competing_managers(&self.client.managed_fields()?, &self.options.field_manager);
+        metrics::clear(COMPETING_METRIC);
+        for manager in &managers {
+            metrics::set(
+                COMPETING_METRIC,
+                COMPETING_HELP,
+                &[("manager", manager)],
+                1.0,
+            );
+        }
+        Ok(managers)
+    }
+
+    /// Record how long the update the operator requested took, now that it has completed.
+    fn time(
+        &self,
+        saved: &mut State,
+        version: &semver::Version,
+        requested: Option<DateTime<Utc>>,
+        status: &ClusterVersionStatus,
+    ) {
+        let entry = match status.history.iter().find(|entry| entry.is_for(version)) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let pools = self.pools.pools().unwrap_or_else(|error| {
+            warn!(
+                "Failed to list MachineConfigPools to time the update: {}",
+                error
+            );
+            Vec::new()
+        });
+        let durations = durations::measure(requested, entry, &pools);
+        durations::record(&durations);
+        info!("Timed the update to {}: {:?}", version, durations);
+        if let Some(seconds) = durations.update {
+            saved.durations.insert(version.clone(), seconds);
+            while saved.durations.len() > state::DURATIONS_KEPT {
+                let oldest = saved.durations.keys().next().cloned().expect("a duration");
+                saved.durations.remove(&oldest);
+            }
+        }
+        emit(
+            self.options,
+            Event::Timed {
+                version: version.clone(),
+                durations: durations.clone(),
+            },
+        );
+        self.status.lock().expect("status lock").durations = Some(durations);
+    }
+
+    /// Estimate how much longer the update in progress will take, if one is. The durations of the
+    /// updates the operator timed are preferred to the cluster's history, which also counts the
+    /// time the cluster took to be accepted and any updates that were interrupted.
+    fn estimate(&self, saved: &State, version: &ClusterVersion, now: DateTime<Utc>) {
+        let history = version
+            .status
+            .as_ref()
+            .map(|status| status.history.as_slice())
+            .unwrap_or_default();
+        let started = history
+            .first()
+            .filter(|entry| entry.completion_time.is_none())
+            .and_then(|entry| entry.started_time);
+        let estimate = started.and_then(|started| {
+            let past = if saved.durations.is_empty() {
+                durations::past(history)
+            } else {
+                saved.durations.values().cloned().collect()
+            };
+            let pools = self.pools.pools().unwrap_or_else(|error| {
+                warn!(
+                    "Failed to list MachineConfigPools to estimate the update: {}",
+                    error
+                );
+                Vec::new()
+            });
+            durations::estimate(&past, started, durations::machines(&pools), now)
+        });
+        durations::record_estimate(estimate.as_ref());
+        self.status.lock().expect("status lock").estimate = estimate;
+    }
+
+    /// Give up the operator's claim on the cluster's updates once the update it requested is no
+    /// longer underway. Claims made by anyone else are left alone.
+    fn release(&self, version: &ClusterVersion, attempted: &semver::Version) -> Result<(), Error> {
+        if gates::owner(&version.metadata).as_ref() != Some(&self.options.field_manager) {
+            return Ok(());
+        }
+        info!(
+            "Releasing the cluster's updates now that {} is done",
+            attempted
+        );
+        self.client.annotate(MANAGED_BY_ANNOTATION, None)
+    }
+
+    /// Check the cluster at the version it has just finished updating to, and report the results.
+    fn verify(&self, version: &semver::Version) {
+        let checks = self.verifier.verify(version);
+        metrics::clear(VERIFIED_METRIC);
+        for check in &checks {
+            // Checks of the same kind (e.g. of several URLs) share a series, which only passes
+            // if all of them did.
+            let passed = checks
+                .iter()
+                .filter(|other| other.gate == check.gate)
+                .all(|other| other.passed);
+            metrics::set(
+                VERIFIED_METRIC,
+                VERIFIED_HELP,
+                &[("check", check.gate)],
+                if passed { 1.0 } else { 0.0 },
+            );
+        }
+
+        let failures: Vec<String> = checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| format!("{}: {}", check.gate, check.reason))
+            .collect();
+        if failures.is_empty() {
+            info!("Verified the cluster at {}", version);
+        } else {
+            error!(
+                "The cluster failed verification at {}: {}",
+                version,
+                failures.join("; ")
+            );
+        }
+        emit(
+            self.options,
+            Event::Verified {
+                version: version.clone(),
+                passed: failures.is_empty(),
+                failures,
+            },
+        );
+        self.status.lock().expect("status lock").verification = Some(checks);
+    }
+
+    /// Ask the rollout service how far each version has been rolled out, if there is one and
+    /// there are candidates for it to decide on.
+    fn rollout(
+        &self,
+        version: &ClusterVersion,
+        candidates: &[ClusterUpdate],
+    ) -> Result<Option<Percentages>, Error> {
+        let url = match &self.options.rollout_url {
+            Some(url) if !candidates.is_empty() => url,
+            _ => return Ok(None),
+        };
+        let current = version
+            .status
+            .as_ref()
+            .and_then(|status| status.history.first())
+            .and_then(|latest| latest.version.as_deref())
+            .unwrap_or_default();
+        let channel = version.spec.channel.as_deref().unwrap_or_default();
+        self.rollout.percentages(url, channel, current).map(Some)
+    }
+
+    /// Make a single decision and act on it. `retrying` is set when a previous attempt to patch
+    /// was rejected, so that the patch is reported even though the decision didn't change.
+    fn apply_available_update(&self, version: ClusterVersion, retrying: bool) -> Result<(), Error> {
+        trace!("{:?}", version.status);
+        identity::set_cluster_id(version.spec.cluster_id.clone());
+
+        let now = self.clock.now();
+        let mut saved = self.store.load()?;
+        let loaded = saved.clone();
+        if let (Some(attempted), Some(current)) = (&loaded.attempted, &version.status) {
+            match current.outcome(attempted) {
+                Outcome::NotStarted => {
+                    let expired = self
+                        .options
+                        .abort_after
+                        .map(|timeout| self.timeout(timeout))
+                        .and_then(|timeout| chrono::Duration::from_std(timeout).ok())
+                        .zip(loaded.attempted_at)
+                        .is_some_and(|(timeout, attempted_at)| now - attempted_at > timeout);
+                    if expired {
+                        let reason = match current.condition("ReleaseAccepted") {
+                            Some(condition) if condition.status == "False" => format!(
+                                "not accepted: {}",
+                                condition.message.as_deref().unwrap_or("no reason given")
+                            ),
+                            _ => "not accepted in time".to_string(),
+                        };
+                        warn!("Withdrawing the update to {}", attempted);
+                        self.client.patch(&ClusterVersion {
+                            types: version.types.clone(),
+                            metadata: version.metadata.clone(),
+                            spec: ClusterVersionSpec {
+                                desired_update: None,
+                                ..Default::default()
+                            },
+                            status: None,
+                        })?;
+                        poison(self.options, &mut saved, attempted, reason);
+                        // The withdrawal changes the ClusterVersion, which will then be
+                        // evaluated afresh.
+                        self.store.save(&saved)?;
+                        return Ok(());
+                    }
+                }
+                Outcome::InProgress => {}
+                Outcome::Completed => {
+                    saved.attempted = None;
+                    saved.applied = Some(attempted.clone());
+                    self.release(&version, attempted)?;
+                    self.time(&mut saved, attempted, loaded.attempted_at, current);
+                    if self.options.verify_updates {
+                        self.verify(attempted);
+                    }
+                }
+                Outcome::Abandoned { superseded_by } => {
+                    self.release(&version, attempted)?;
+                    poison(
+                        self.options,
+                        &mut saved,
+                        attempted,
+                        format!("abandoned for {}", superseded_by),
+                    )
+                }
+            }
+        }
+
+        let target = match &self.options.gitops_repo {
+            Some(_) => gitops::target(&version.metadata),
+            None => None,
+        };
+        if let Some(channel) = target.as_ref().and_then(|target| target.channel.as_ref()) {
+            if version.spec.channel.as_ref() != Some(channel) {
+                info!("Moving to the {} channel declared through GitOps", channel);
+                // The change of channel makes the cluster offer different updates, which are
+                // evaluated once it has.
+                return self.client.patch(&ClusterVersion {
+                    types: version.types.clone(),
+                    metadata: version.metadata.clone(),
+                    spec: ClusterVersionSpec {
+                        channel: Some(channel.clone()),
+                        desired_update: version.spec.desired_update.clone(),
+                        ..Default::default()
+                    },
+                    status: None,
+                });
+            }
+        }
+
+        check_drift(
+            self.options,
+            self.timeout(self.options.drift_threshold),
+            &mut saved,
+            &version,
+            now,
+        );
+        if check_regression(self.options, &mut saved, &version) {
+            self.client
+                .annotate(gates::REGRESSION_ACKNOWLEDGED_ANNOTATION, None)?;
+        }
+        self.estimate(&saved, &version, now);
+        let snoozed = current_snooze(self.options, &version);
+        check_snooze(self.options, &mut saved, snoozed.as_ref(), now);
+        let failure = self.check_retrieval(&version, now)?;
+
+        let (candidates, security) = self.on_channel(
+            &version,
+            version
+                .status
+                .as_ref()
+                .and_then(|status| status.available_updates.clone())
+                .unwrap_or_default(),
+        )?;
+        let candidates: Vec<ClusterUpdate> = candidates
+            .into_iter()
+            .filter(|update| {
+                let skipped = !self.options.allow_prerelease && policy::prerelease(&update.version);
+                if skipped {
+                    debug!(
+                        "Skipping {}, which is a pre-release (see --allow-prerelease)",
+                        update.version
+                    );
+                }
+                !skipped
+            })
+            .filter(
+                |update| match target.as_ref().and_then(gitops::Target::version) {
+                    Some(ref target) if &update.version > target => {
+                        debug!(
+                            "Skipping {}, which is beyond the target {} declared through GitOps",
+                            update.version, target
+                        );
+                        false
+                    }
+                    _ => true,
+                },
+            )
+            .collect();
+        record_adoption(&mut saved, &version);
+        for update in &candidates {
+            saved
+                .first_seen
+                .entry(update.version.clone())
+                .or_insert(now);
+        }
+        // Forget the updates which are no longer offered, so that the state doesn't grow forever.
+        // The desired one is kept until it's adopted, since the cluster stops offering it as soon
+        // as it starts updating to it.
+        let desired = version
+            .spec
+            .desired_update
+            .as_ref()
+            .map(|update| &update.version);
+        saved.first_seen.retain(|version, _| {
+            Some(version) == desired || candidates.iter().any(|update| &update.version == version)
+        });
+        export_adoption(&saved);
+
+        let competing = self.competing_managers()?;
+        let rollout = self.rollout(&version, &candidates)?;
+        // The workloads, storage and credentials are only looked at when there's something they
+        // could hold back.
+        let (health, error_budget) = if candidates.is_empty() {
+            (Vec::new(), Vec::new())
+        } else {
+            (health::run(self.health), health::run(self.budgets))
+        };
+        let storage_provisioners = if candidates.is_empty() || self.options.storage_matrix.is_none()
+        {
+            Vec::new()
+        } else {
+            self.storage.provisioners()?
+        };
+        let credentials = if candidates.is_empty() {
+            None
+        } else {
+            self.credentials.credentials()?
+        };
+        let admin_acks = if candidates.is_empty() || self.options.manual_steps.is_empty() {
+            HashSet::new()
+        } else {
+            self.acks.admin_acks()?
+        };
+
+        // The status is shared with the API, so it's only locked to copy what's needed out of it
+        // and the outcome back in, never while making requests or sending events.
+        let approved = {
+            let mut status = self.status.lock().expect("status lock");
+            if failure != status.retrieval_failure {
+                match &failure {
+                    Some(failure) => {
+                        warn!("The cluster failed to retrieve its updates: {}", failure)
+                    }
+                    None => info!("The cluster retrieved its updates again"),
+                }
+                status.retrieval_failure = failure;
+            }
+            // The API saves its approvals too, but a save of the state made since it loaded
+            // the state may have overwritten them, so the status has the latest.
+            if status.approved.is_none() {
+                status.approved = saved.approved.clone();
+            }
+            status.approved.clone()
+        };
+        saved.approved = approved.clone();
+        let mut state = ClusterState::new(&version, now);
+        state.approved = approved;
+        state.first_seen = saved
+            .first_seen
+            .iter()
+            .map(|(version, seen)| (version.clone(), *seen))
+            .collect();
+        state.poisoned = saved.poisoned.keys().cloned().collect();
+        state.regression = saved.regression.clone();
+        state.competing_managers = competing;
+        state.rollout = rollout;
+        state.health = health;
+        state.storage_provisioners = storage_provisioners;
+        state.credentials = credentials;
+        state.admin_acks = admin_acks;
+        state.error_budget = error_budget;
+        state.security = security;
+        state.snoozed = snoozed;
+        let decision = self.policy.evaluate(&state, &candidates);
+        let queue = policy::queue(self.policy, &state, &candidates);
+
+        // The previous decision is persisted so that a restart doesn't repeat the events.
+        let previous = saved.decision.replace(decision.clone());
+        {
+            let mut status = self.status.lock().expect("status lock");
+            status.queue = queue;
+            status.decision = Some(decision.clone());
+            status.reason = Some(decision.reason());
+            status.version = state.version.clone();
+            status.paused = state.paused;
+            status.snoozed = state.snoozed.clone().filter(|snooze| snooze.until > now);
+            status.evaluated_at = Some(now);
+            status.candidate = decision.update().map(|update| update.version.clone());
+            status.not_before = match &decision {
+                Decision::Delayed { not_before, .. } => Some(*not_before),
+                _ => None,
+            };
+        }
+        let pending = match &decision {
+            Decision::Apply { .. } => None,
+            decision => decision
+                .update()
+                .and_then(|update| saved.first_seen.get(&update.version))
+                .and_then(|seen| (now - *seen).to_std().ok()),
+        };
+        metrics::set(
+            PENDING_METRIC,
+            PENDING_HELP,
+            &[],
+            pending.unwrap_or_default().as_secs() as f64,
+        );
+        record_gates(self.options, &state, &decision);
+
+        // Events are only emitted when the decision changes, not on every evaluation.
+        let changed = previous.as_ref() != Some(&decision);
+        if changed {
+            emit_transition(
+                self.options,
+                previous.as_ref(),
+                &decision,
+                &state,
+                &|update| self.release_diff(&version, update),
+            );
+        }
+        check_blocked(self.options, &mut saved, &decision, now);
+
+        let code = decision.reason();
+        let result = match decision {
+            Decision::UpToDate => Ok(()),
+            Decision::InProgress => {
+                debug!("Waiting for update to complete... ({})", code);
+                Ok(())
+            }
+            Decision::Delayed {
+                update, not_before, ..
+            } => {
+                debug!(
+                    "Delaying update to {} until {} ({})",
+                    update.version, not_before, code
+                );
+                Ok(())
+            }
+            Decision::Paused { update } => {
+                info!(
+                    "Updates are paused; not updating to {} ({})",
+                    update.version, code
+                );
+                Ok(())
+            }
+            Decision::AwaitingApproval { update } => {
+                info!(
+                    "Waiting for the update to {} to be approved ({})",
+                    update.version, code
+                );
+                Ok(())
+            }
+            Decision::Blocked {
+                update,
+                gate,
+                reason,
+            } => {
+                warn!(
+                    "Not updating to {} because of the {} check: {} ({})",
+                    update.version, gate, reason, code
+                );
+                Ok(())
+            }
+            Decision::Apply { .. } if self.too_soon(&saved, now) => {
+                debug!(
+                    "Patched the ClusterVersion less than {} ago; not patching again yet",
+                    humantime::format_duration(self.options.min_patch_interval)
+                );
+                Ok(())
+            }
+            Decision::Apply { mut update } => {
+                let approver = force_approver(
+                    self.options,
+                    self.options.interactive,
+                    &version.metadata,
+                    &update,
+                );
+                update.force = approver.is_some();
+                if self.options.interactive {
+                    // The checks are made against the same state the policy decided with.
+                    let checks = explain::checks(self.options, &state, Some(&update));
+                    let status = self.status.lock().expect("status lock").clone();
+                    if !confirm(&status, &update, &checks, self.single_node) {
+                        info!("Declined the update to {}; exiting", update.version);
+                        process::exit(0);
+                    }
+                }
+
+                // The announcement of the downtime comes before it, and only once per update.
+                if self.single_node && saved.attempted.as_ref() != Some(&update.version) {
+                    warn!(
+                        "The Kubernetes API of this single-node cluster will be unavailable while \
+                         it updates to {}",
+                        update.version
+                    );
+                    emit(
+                        self.options,
+                        Event::Downtime {
+                            version: update.version.clone(),
+                        },
+                    );
+                }
+                info!("Attempting to update to {}", update.version);
+                let requested = update.version.clone();
+                // Claim the cluster's updates until this one is done.
+                let mut metadata = version.metadata;
+                metadata.annotations.insert(
+                    MANAGED_BY_ANNOTATION.to_string(),
+                    self.options.field_manager.clone(),
+                );
+                self.client
+                    .patch(&ClusterVersion {
+                        types: version.types,
+                        metadata,
+                        spec: ClusterVersionSpec {
+                            desired_update: Some(update),
+                            ..Default::default()
+                        },
+                        status: None,
+                    })
+                    .map(|()| {
+                        // The patch is repeated until the cluster picks it up; only the first one
+                        // counts as an attempt.
+                        let first = saved.attempted.as_ref() != Some(&requested);
+                        if first {
+                            saved.attempted = Some(requested.clone());
+                            saved.attempted_at = Some(now);
+                            if let Some(approver) = approver {
+                                warn!(
+                                    "Forced the update to {}, approved by {}",
+                                    requested, approver
+                                );
+                                metrics::inc(
+                                    FORCED_METRIC,
+                                    FORCED_HELP,
+                                    &[("version", &requested.to_string())],
+                                );
+                                emit(
+                                    self.options,
+                                    Event::Forced {
+                                        version: requested.clone(),
+                                        approver,
+                                    },
+                                );
+                            }
+                        }
+                        saved.patched_at = Some(now);
+                        if changed || retrying || first {
+                            emit(
+                                self.options,
+                                Event::PatchApplied {
+                                    version: requested,
+                                    revision: target.and_then(|target| target.revision),
+                                },
+                            );
+                        }
+                    })
+            }
+        };
+
+        // The state is saved even if the patch failed, since the decision (and the events which
+        // announced it) stand.
+        if saved != loaded {
+            self.store.save(&saved)?;
+        }
+        result
+    }
+
+    /// Whether the desired update was patched less than `--min-patch-interval` ago, so that a
+    /// flapping status doesn't cause a burst of patches (and the events announcing them).
+    fn too_soon(&self, saved: &State, now: DateTime<Utc>) -> bool {
+        match (
+            saved.patched_at,
+            chrono::Duration::from_std(self.options.min_patch_interval),
+        ) {
+            (Some(patched_at), Ok(interval)) => now - patched_at < interval,
+            _ => false,
+        }
+    }
+
+    /// Compare the candidate's release with the cluster's, if enabled. Failures are only logged,
+    /// since the comparison is purely informational.
+    fn release_diff(&self, version: &ClusterVersion, update: &ClusterUpdate) -> Option<Diff> {
+        let releases = self.releases?;
+        let current = version.status.as_ref()?.history.first()?.image.clone()?;
+        let result = releases.fetch(&current).and_then(|from| {
+            let to = releases.fetch(&update.image)?;
+            Ok(release::diff(&from, &to))
+        });
+        match result {
+            Ok(diff) => Some(diff),
+            Err(error) => {
+                warn!(
+                    "Failed to compare the release of {} with the cluster's: {}",
+                    update.version, error
+                );
+                None
+            }
+        }
+    }
+
+    /// Drop the candidates which aren't part of the cluster's channel. The available updates are
+    /// only refreshed periodically, so they can still include updates from a channel which the
+    /// cluster has since left.
+    fn on_channel(
+        &self,
+        version: &ClusterVersion,
+        candidates: Vec<ClusterUpdate>,
+    ) -> Result<(Vec<ClusterUpdate>, HashSet<semver::Version>), Error> {
+        if candidates.is_empty() {
+            return Ok((candidates, HashSet::new()));
+        }
+        let (channel, graph) = match channel_graph(self.graph, version)? {
+            Some(channel_graph) => channel_graph,
+            None => return Ok((candidates, HashSet::new())),
+        };
+        let candidates = candidates
+            .into_iter()
+            .filter(|update| {
+                let found = graph.contains(&update.version);
+                if !found {
+                    warn!(
+                        "Skipping {}, which is available but not part of the {} channel",
+                        update.version, channel
+                    );
+                }
+                found
+            })
+            .collect();
+        Ok((candidates, graph.security()))
+    }
+}
+
+/// Who approved forcing the update, if it's to be forced. Forcing skips the verification of
+/// the release's signature, so `--force` alone isn't enough unless someone confirms the
+/// update on the terminal: the policy has to say who may approve it (--force-approved-by),
+/// and the ClusterVersion (or the HostedCluster) has to say which of them did. `terminal` is
+/// whether someone confirms the update there, which only happens for the local cluster.
+pub fn force_approver(
+    options: &Options,
+    terminal: bool,
+    metadata: &ObjectMeta,
+    update: &ClusterUpdate,
+) -> Option<String> {
+    if !options.force {
+        return None;
+    }
+    if terminal {
+        return Some("the terminal".to_string());
+    }
+    if options.force_approved_by.is_empty() {
+        warn!(
+            "Not forcing the update to {}, since the policy doesn't say who may approve it \
+             (see --force-approved-by)",
+            update.version
+        );
+        return None;
+    }
+    match metadata
+        .annotations
+        .get(FORCE_APPROVER_ANNOTATION)
+        .map(|approver| approver.trim())
+        .filter(|approver| !approver.is_empty())
+    {
+        Some(approver)
+            if options
+                .force_approved_by
+                .iter()
+                .any(|allowed| allowed.trim() == approver) =>
+        {
+            Some(approver.to_string())
+        }
+        Some(approver) => {
+            warn!(
+                "Not forcing the update to {}, since {} (who {} names) may not approve it",
+                update.version, approver, FORCE_APPROVER_ANNOTATION
+            );
+            None
+        }
+        None => {
+            warn!(
+                "Not forcing the update to {}, since {} doesn't name who approved it",
+                update.version, FORCE_APPROVER_ANNOTATION
+            );
+            None
+        }
+    }
+}
+
+/// Fetch the update graph of the cluster's channel, unless the cluster isn't subscribed to one
+/// or doesn't know its own version.
+#[cfg(feature = "cincinnati")]
+pub fn channel_graph<'a>(
+    client: &dyn GraphClient,
+    version: &'a ClusterVersion,
+) -> Result<Option<(&'a str, Graph)>, Error> {
+    let current = version
+        .status
+        .as_ref()
+        .and_then(|status| status.history.first())
+        .and_then(|latest| latest.version.as_ref());
+    let (channel, current) = match (&version.spec.channel, current) {
+        (Some(channel), Some(current)) => (channel, current),
+        _ => return Ok(None),
+    };
+
+    let upstream = version
+        .spec
+        .upstream
+        .as_deref()
+        .unwrap_or(graph::DEFAULT_UPSTREAM);
+    Ok(Some((channel, client.fetch(upstream, channel, current)?)))
+}
+
+/// Builds without the cincinnati feature never fetch the update graph, and take the cluster's
+/// available updates as they are.
+#[cfg(not(feature = "cincinnati"))]
+pub fn channel_graph<'a>(
+    _: &dyn GraphClient,
+    _: &'a ClusterVersion,
+) -> Result<Option<(&'a str, Graph)>, Error> {
+    Ok(None)
+}
+
+/// Track how long the desired version has differed from the last one the cluster completed,
+/// and report it once that has gone on for longer than `threshold`.
+fn check_drift(
+    options: &Options,
+    threshold: Duration,
+    state: &mut State,
+    version: &ClusterVersion,
+    now: DateTime<Utc>,
+) {
+    let desired = version
+        .spec
+        .desired_update
+        .as_ref()
+        .map(|update| update.version.clone());
+    let completed = version.status.as_ref().and_then(|status| {
+        status
+            .history
+            .iter()
+            .find(|entry| entry.state.as_deref() == Some("Completed"))
+    });
+
+    let desired = match desired {
+        Some(desired) if !completed.is_some_and(|entry| entry.is_for(&desired)) => desired,
+        _ => {
+            state.drift_since = None;
+            state.drift_alerted = false;
+            metrics::set(DRIFT_METRIC, DRIFT_HELP, &[], 0.0);
+            return;
+        }
+    };
+    let completed = completed.and_then(|entry| entry.version.clone());
+
+    let since = *state.drift_since.get_or_insert(now);
+    let drift = (now - since).to_std().unwrap_or_default();
+    metrics::set(DRIFT_METRIC, DRIFT_HELP, &[], drift.as_secs() as f64);
+    if drift > threshold && !state.drift_alerted {
+        warn!(
+            "The cluster has been asked for {} since {} but is still at {}",
+            desired,
+            since,
+            completed.as_deref().unwrap_or("no completed version")
+        );
+        emit(
+            options,
+            Event::Drift {
+                desired,
+                completed,
+                since,
+            },
+        );
+        state.drift_alerted = true;
+    }
+}
+
+/// Notice the cluster's version moving in a way the operator didn't cause: backwards from the
+/// version it was last seen at, or to a history without the update the operator last saw
+/// completed (as after a restore from a backup). Either is reported once, and holds back updates
+/// until the annotation acknowledging it names the version the cluster is at. Returns whether
+/// the acknowledgement was taken, after which the annotation is to be removed.
+fn check_regression(options: &Options, state: &mut State, version: &ClusterVersion) -> bool {
+    let history = version
+        .status
+        .as_ref()
+        .map(|status| status.history.as_slice())
+        .unwrap_or_default();
+    let current = match history.first().and_then(HistoricalEntry::parsed_version) {
+        Some(current) => current,
+        None => return false,
+    };
+
+    if state.regression.is_some() {
+        if gates::regression_acknowledged(&version.metadata).as_ref() != Some(&current) {
+            return false;
+        }
+        info!(
+            "The regression to {} was acknowledged; resuming updates",
+            current
+        );
+        state.regression = None;
+        state.observed = Some(current);
+        state.applied = None;
+        metrics::set(REGRESSION_METRIC, REGRESSION_HELP, &[], 0.0);
+        return true;
+    }
+
+    let in_history = |version: &semver::Version| history.iter().any(|entry| entry.is_for(version));
+    let regression = match (&state.observed, &state.applied) {
+        (Some(observed), _) if &current < observed => {
+            Some(format!("moved backwards from {} to {}", observed, current))
+        }
+        (_, Some(applied)) if !in_history(applied) => Some(format!(
+            "is {}, and {} is no longer in its history",
+            current, applied
+        )),
+        _ => None,
+    };
+    match regression {
+        Some(regression) => {
+            warn!(
+                "The cluster's version {}; holding back updates until {} is set to {}",
+                regression,
+                gates::REGRESSION_ACKNOWLEDGED_ANNOTATION,
+                current
+            );
+            emit(
+                options,
+                Event::Regressed {
+                    version: current,
+                    reason: regression.clone(),
+                },
+            );
+            state.regression = Some(regression);
+            metrics::set(REGRESSION_METRIC, REGRESSION_HELP, &[], 1.0);
+        }
+        None => {
+            state.observed = Some(current);
+            metrics::set(REGRESSION_METRIC, REGRESSION_HELP, &[], 0.0);
+        }
+    }
+    false
+}
+
+/// The snooze in effect, set either on the ClusterVersion or by the options, whichever lasts
+/// longer.
+pub fn current_snooze(options: &Options, version: &ClusterVersion) -> Option<Snooze> {
+    let configured = options.snooze_until.map(|until| Snooze {
+        until,
+        reason: options.snooze_reason.clone(),
+    });
+    match (gates::snoozed(&version.metadata), configured) {
+        (Some(annotated), Some(configured)) if configured.until > annotated.until => {
+            Some(configured)
+        }
+        (annotated, configured) => annotated.or(configured),
+    }
+}
+
+/// Report when a snooze starts and ends, and remind that updates are about to resume a while
+/// before it does.
+fn check_snooze(options: &Options, state: &mut State, snooze: Option<&Snooze>, now: DateTime<Utc>) {
+    let snooze = snooze.filter(|snooze| snooze.until > now);
+    metrics::set(
+        SNOOZE_METRIC,
+        SNOOZE_HELP,
+        &[],
+        snooze.map_or(0.0, |snooze| snooze.until.timestamp() as f64),
+    );
+
+    let snooze = match (snooze, state.snoozed_until) {
+        (Some(snooze), _) => snooze,
+        (None, Some(until)) => {
+            info!("Resuming updates, which were snoozed until {}", until);
+            emit(options, Event::SnoozeEnded { until });
+            state.snoozed_until = None;
+            state.snooze_reminded = false;
+            return;
+        }
+        (None, None) => return,
+    };
+
+    if state.snoozed_until != Some(snooze.until) {
+        warn!("Updates are {}", policy::snooze_reason(snooze));
+        emit(
+            options,
+            Event::Snoozed {
+                until: snooze.until,
+                reason: snooze.reason.clone(),
+            },
+        );
+        state.snoozed_until = Some(snooze.until);
+        state.snooze_reminded = false;
+    }
+    let ending = chrono::Duration::from_std(options.snooze_reminder)
+        .map(|reminder| now >= snooze.until - reminder)
+        .unwrap_or(false);
+    if ending && !state.snooze_reminded {
+        info!("Updates will resume at {}", snooze.until);
+        emit(
+            options,
+            Event::SnoozeEnding {
+                until: snooze.until,
+                reason: snooze.reason.clone(),
+            },
+        );
+        state.snooze_reminded = true;
+    }
+}
+
+/// Record when the cluster adopted its latest completed version, if it had been seen to be
+/// available before. Only the most recent adoptions are kept.
+fn record_adoption(state: &mut State, version: &ClusterVersion) {
+    let completed = version.status.as_ref().and_then(|status| {
+        status
+            .history
+            .iter()
+            .find(|entry| entry.state.as_deref() == Some("Completed"))
+    });
+    let (adopted, completed_at) =
+        match completed.and_then(|entry| entry.parsed_version().zip(entry.completion_time)) {
+            Some(completed) => completed,
+            None => return,
+        };
+    if let Some(first_seen) = state.first_seen.remove(&adopted) {
+        let adoption = Adoption {
+            first_seen,
+            adopted: completed_at,
+        };
+        info!(
+            "Adopted {} {} after it became available",
+            adopted,
+            humantime::format_duration(adoption.lag().to_std().unwrap_or_default())
+        );
+        state.adopted.insert(adopted, adoption);
+    }
+    while state.adopted.len() > state::ADOPTIONS_KEPT {
+        let oldest = state
+            .adopted
+            .iter()
+            .min_by_key(|(_, adoption)| adoption.adopted)
+            .map(|(version, _)| version.clone())
+            .expect("an adoption");
+        state.adopted.remove(&oldest);
+    }
+}
+
+/// Export when each offered version was first seen, and how long the recently adopted ones took.
+fn export_adoption(state: &State) {
+    metrics::clear(FIRST_SEEN_METRIC);
+    for (version, seen) in &state.first_seen {
+        metrics::set(
+            FIRST_SEEN_METRIC,
+            FIRST_SEEN_HELP,
+            &[("version", &version.to_string())],
+            seen.timestamp() as f64,
+        );
+    }
+    metrics::clear(ADOPTION_LAG_METRIC);
+    for (version, adoption) in &state.adopted {
+        metrics::set(
+            ADOPTION_LAG_METRIC,
+            ADOPTION_LAG_HELP,
+            &[("version", &version.to_string())],
+            adoption.lag().num_seconds() as f64,
+        );
+    }
+}
+
+const FIRST_SEEN_METRIC: &str = "openshift_update_release_first_seen_timestamp_seconds";
+const FIRST_SEEN_HELP: &str =
+    "When each version the cluster is offered was first seen to be available.";
+
+const ADOPTION_LAG_METRIC: &str = "openshift_update_adoption_lag_seconds";
+const ADOPTION_LAG_HELP: &str =
+    "How long each recently adopted version was available before the cluster had updated to it.";
+
+const SNOOZE_METRIC: &str = "openshift_update_snoozed_until_timestamp_seconds";
+const SNOOZE_HELP: &str =
+    "When the current snooze of automatic updates ends, or 0 if there is none.";
+
+const DRIFT_METRIC: &str = "openshift_update_version_drift_seconds";
+const DRIFT_HELP: &str =
+    "How long the desired version has differed from the last completed one, or 0.";
+
+const REGRESSION_METRIC: &str = "openshift_update_version_regressed";
+const REGRESSION_HELP: &str =
+    "Whether the cluster's version moved without the operator causing it, until acknowledged.";
+
+const PENDING_METRIC: &str = "openshift_update_candidate_pending_seconds";
+const PENDING_HELP: &str =
+    "How long the candidate has been offered without being applied, or 0 if there is none.";
+
+const RETRIEVAL_METRIC: &str = "openshift_update_retrieval_failing";
+const RETRIEVAL_HELP: &str = "Whether the cluster is failing to retrieve its available updates.";
+
+const COMPETING_METRIC: &str = "openshift_update_competing_field_manager";
+const COMPETING_HELP: &str =
+    "Set for each other field manager which sets the ClusterVersion's desired update.";
+
+const GATE_METRIC: &str = "openshift_update_gate_status";
+const GATE_HELP: &str =
+    "Set for the status (passed or blocked) of each gate in the last evaluation of the updates.";
+
+const SKIP_METRIC: &str = "openshift_update_last_skip_reason";
+const SKIP_HELP: &str = "Set for the gate and reason which last held back a candidate update.";
+
+const DECISION_METRIC: &str = "openshift_update_decision_reason";
+const DECISION_HELP: &str = "Set for the code of the reason of the most recent decision.";
+
+const VERIFIED_METRIC: &str = "openshift_update_verification_passed";
+const VERIFIED_HELP: &str =
+    "Whether each kind of check passed when verifying the cluster after its last update.";
+
+/// Annotation of the ClusterVersion set when asking the cluster to retry retrieving its updates.
+const REFRESH_ANNOTATION: &str = "upgrade.crawford.dev/refresh-requested";
+
+/// How many times longer a single-node cluster is given to accept and complete an update (see
+/// `--abort-after` and `--drift-threshold`).
+const SINGLE_NODE_TIMEOUT_FACTOR: u32 = 3;
+
+/// Annotation of the ClusterVersion (or of a HostedCluster) naming who approved forcing updates,
+/// which `--force` requires when nobody is at the terminal to confirm them.
+const FORCE_APPROVER_ANNOTATION: &str = "upgrade.crawford.dev/force-approved-by";
+
+const FORCED_METRIC: &str = "openshift_update_forced_updates_total";
+const FORCED_HELP: &str = "Updates requested with --force, which skip signature verification.";
+
+/// Whether each gate passed in the evaluation which led to the decision: the checks of the cluster
+/// and of the candidate, and whichever held back the candidate.
+fn gate_statuses(
+    options: &Options,
+    state: &ClusterState,
+    decision: &Decision,
+) -> BTreeMap<String, bool> {
+    let selected = decision.update();
+    let mut checks = explain::gate_checks(options, state);
+    checks.push(explain::window_check(options, state));
+    checks.extend(explain::storage_check(options, state, selected));
+    checks.extend(explain::credentials_check(state, selected));
+    checks.extend(explain::steps_check(options, state, selected));
+    let mut gates: BTreeMap<String, bool> = checks
+        .into_iter()
+        .map(|check| (check.gate.to_string(), check.passed))
+        .collect();
+    if let Some((gate, _)) = skip_reason(decision) {
+        // The gates of the policies are otherwise only known when they block.
+        gates.insert(gate, false);
+    }
+    gates
+}
+
+/// The gate which held back the decision's update, and why, if one did.
+pub fn skip_reason(decision: &Decision) -> Option<(String, String)> {
+    let reason = match decision {
+        Decision::UpToDate | Decision::InProgress | Decision::Apply { .. } => return None,
+        Decision::Delayed { not_before, .. } => format!("delayed until {}", not_before),
+        Decision::Paused { .. } => format!("{} is set", PAUSED_ANNOTATION),
+        Decision::AwaitingApproval { .. } => "awaiting approval".to_string(),
+        Decision::Blocked { reason, .. } => reason.clone(),
+    };
+    let gate = decision.reason().gate().map(String::from);
+    gate.map(|gate| (gate, reason))
+}
+
+fn record_gates(options: &Options, state: &ClusterState, decision: &Decision) {
+    metrics::clear(DECISION_METRIC);
+    metrics::set(
+        DECISION_METRIC,
+        DECISION_HELP,
+        &[("code", decision.reason().code())],
+        1.0,
+    );
+    metrics::clear(GATE_METRIC);
+    for (gate, passed) in gate_statuses(options, state, decision) {
+        for (status, set) in &[("passed", passed), ("blocked", !passed)] {
+            metrics::set(
+                GATE_METRIC,
+                GATE_HELP,
+                &[("gate", &gate), ("status", status)],
+                if *set { 1.0 } else { 0.0 },
+            );
+        }
+    }
+    // The last reason is kept until another replaces it, so that it outlives the skip.
+    if let (Some((gate, reason)), Some(update)) = (skip_reason(decision), decision.update()) {
+        metrics::clear(SKIP_METRIC);
+        metrics::set(
+            SKIP_METRIC,
+            SKIP_HELP,
+            &[
+                ("gate", &gate),
+                ("code", decision.reason().code()),
+                ("version", &update.version.to_string()),
+                ("reason", &reason),
+            ],
+            1.0,
+        );
+    }
+}
+
+/// Remember never to select `version` again.
+pub fn poison(options: &Options, state: &mut State, version: &semver::Version, reason: String) {
+    warn!(
+        "The update to {} was {}; it will not be attempted again",
+        version, reason
+    );
+    emit(
+        options,
+        Event::Poisoned {
+            version: version.clone(),
+            reason: reason.clone(),
+        },
+    );
+    state.poisoned.insert(version.clone(), reason);
+    state.attempted = None;
+    state.attempted_at = None;
+}
+
+/// Emit the events marking the change from the previous decision to the current one.
+fn emit_transition(
+    options: &Options,
+    previous: Option<&Decision>,
+    decision: &Decision,
+    state: &ClusterState,
+    changes: &dyn Fn(&ClusterUpdate) -> Option<Diff>,
+) {
+    if previous == Some(&Decision::InProgress) && decision != &Decision::InProgress {
+        emit(
+            options,
+            Event::Completed {
+                version: state.version.clone().unwrap_or_default(),
+            },
+        );
+    }
+
+    let update = match decision.update() {
+        Some(update) => update,
+        None => return,
+    };
+    if previous.and_then(Decision::update) != Some(update) {
+        emit(
+            options,
+            Event::CandidateFound {
+                version: update.version.clone(),
+                image: update.image.clone(),
+                changes: changes(update),
+            },
+        );
+    }
+}
+
+/// Report when a gate starts holding back the candidate, remind of it every --blocked-reminder
+/// while it does, and report once it stops. Changes of the reason alone, like another alert
+/// firing, aren't reported.
+fn check_blocked(options: &Options, state: &mut State, decision: &Decision, now: DateTime<Utc>) {
+    let current = skip_reason(decision).zip(decision.update());
+    if let Some(blocked) = state.blocked.take() {
+        match &current {
+            Some(((gate, _), update))
+                if gate == &blocked.gate && update.version == blocked.version =>
+            {
+                state.blocked = Some(blocked);
+            }
+            _ => {
+                info!(
+                    "{} is no longer held back by the {} gate",
+                    blocked.version, blocked.gate
+                );
+                emit(
+                    options,
+                    Event::Unblocked {
+                        version: blocked.version,
+                        gate: blocked.gate,
+                        since: blocked.since,
+                    },
+                );
+            }
+        }
+    }
+
+    let ((gate, reason), update) = match current {
+        Some(current) => current,
+        None => return,
+    };
+    match &mut state.blocked {
+        None => {
+            emit(
+                options,
+                Event::GateBlocked {
+                    version: update.version.clone(),
+                    gate: gate.clone(),
+                    code: decision.reason(),
+                    reason,
+                },
+            );
+            state.blocked = Some(Blocked {
+                version: update.version.clone(),
+                gate,
+                since: now,
+                reported_at: now,
+            });
+        }
+        Some(blocked) => {
+            let due = options
+                .blocked_reminder
+                .and_then(|reminder| chrono::Duration::from_std(reminder).ok())
+                .is_some_and(|reminder| now >= blocked.reported_at + reminder);
+            if due {
+                emit(
+                    options,
+                    Event::StillBlocked {
+                        version: blocked.version.clone(),
+                        gate,
+                        code: decision.reason(),
+                        reason,
+                        since: blocked.since,
+                    },
+                );
+                blocked.reported_at = now;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "notifications")]
+pub fn emit(options: &Options, event: Event) {
+    if options.events_stdout {
+        notify::stdout(&event);
+    }
+    notify::send(&event);
+}
+
+/// Builds without the notifications feature send no events.
+#[cfg(not(feature = "notifications"))]
+pub fn emit(_: &Options, _: Event) {}
+
+/// Describe the update and the checks it passed, then ask whether to apply it.
+fn confirm(
+    status: &Status,
+    update: &ClusterUpdate,
+    checks: &[explain::Check],
+    single_node: bool,
+) -> bool {
+    print!("{}", describe(status, update, checks, single_node));
+    print!("Apply this update? [y/N] ");
+    io::stdout().flush().expect("flush stdout");
+
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(_) => {
+            let answer = answer.trim().to_lowercase();
+            answer == "y" || answer == "yes"
+        }
+        Err(error) => {
+            error!("Failed to read answer: {}", error);
+            false
+        }
+    }
+}
+
+/// The update, the decision and the checks the policy evaluated, as `confirm` shows them.
+fn describe(
+    status: &Status,
+    update: &ClusterUpdate,
+    checks: &[explain::Check],
+    single_node: bool,
+) -> String {
+    let mut text = format!(
+        "Current version:  {}\nCandidate update: {} ({})\n",
+        status.version.as_deref().unwrap_or("unknown"),
+        update.version,
+        update.image
+    );
+    if let Some(reason) = &status.reason {
+        text.push_str(&format!("Decision:         {}\n", reason));
+    }
+    explain::render_checks(&mut text, checks);
+    text.push_str(&format!(
+        "  {:<20} {}\n",
+        "forced",
+        if update.force { "yes" } else { "no" }
+    ));
+    if single_node {
+        text.push_str(&format!(
+            "  {:<20} the API will be unavailable during the update\n",
+            "single-node"
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explain::Check;
+    use openshift_update::clock::FixedClock;
+    use openshift_update::clusterversion::{self, ManagedFieldsEntry};
+    use openshift_update::credentials::Credentials;
+    use openshift_update::durations::MachineConfigPool;
+    use openshift_update::state::MemoryStore;
+    use std::cell::{Cell, RefCell};
+    use structopt::StructOpt;
+
+    struct Fixture {
+        version: ClusterVersion,
+        patches: RefCell<Vec<ClusterVersion>>,
+        annotations: RefCell<Vec<(String, Option<String>)>>,
+        managed_fields: Vec<ManagedFieldsEntry>,
+        rollout: Percentages,
+        /// The number of patches which will be rejected with a conflict.
+        conflicts: Cell<u32>,
+        store: MemoryStore,
+        graph: Graph,
+        clock: FixedClock,
+        /// Shared with the API, so it mustn't be locked while the cluster is patched.
+        status: Mutex<Status>,
+        single_node: Cell<bool>,
+    }
+
+    impl Fixture {
+        fn new(json: &str) -> Fixture {
+            Fixture {
+                version: serde_json::from_str(json).expect("valid fixture"),
+                patches: RefCell::new(Vec::new()),
+                annotations: RefCell::new(Vec::new()),
+                managed_fields: Vec::new(),
+                rollout: Percentages::new(),
+                conflicts: Cell::new(0),
+                store: MemoryStore::default(),
+                graph: serde_json::from_str(include_str!("../tests/fixtures/graph.json"))
+                    .expect("valid fixture"),
+                clock: FixedClock::new("2019-09-17T00:00:00Z".parse().expect("valid time")),
+                status: Mutex::new(Status::default()),
+                single_node: Cell::new(false),
+            }
+        }
+
+        fn run(&self, args: &[&str]) -> Vec<ClusterVersion> {
+            self.try_run(args).expect("apply update");
+            self.patches.replace(Vec::new())
+        }
+
+        fn try_run(&self, args: &[&str]) -> Result<(), Error> {
+            let options = Options::from_iter(["openshift-update"].iter().chain(args));
+            *self.status.lock().expect("status lock") = Status::default();
+            Reconciler {
+                client: self,
+                store: &self.store,
+                graph: self,
+                releases: None,
+                rollout: self,
+                health: &[],
+                storage: self,
+                credentials: self,
+                acks: self,
+                pools: self,
+                budgets: &[],
+                verifier: self,
+                options: &options,
+                policy: upgrade_policy(&options).as_ref(),
+                status: &self.status,
+                single_node: self.single_node.get(),
+                clock: &self.clock,
+            }
+            .reconcile(self.version.clone())
+        }
+    }
+
+    impl ClusterVersionClient for Fixture {
+        fn get(&self) -> Result<ClusterVersion, Error> {
+            Ok(self.version.clone())
+        }
+
+        fn managed_fields(&self) -> Result<Vec<ManagedFieldsEntry>, Error> {
+            Ok(self.managed_fields.clone())
+        }
+
+        fn patch(&self, version: &ClusterVersion) -> Result<(), Error> {
+            assert!(
+                self.status.try_lock().is_ok(),
+                "the status is locked while patching"
+            );
+            self.patches.borrow_mut().push(version.clone());
+            if self.conflicts.get() > 0 {
+                self.conflicts.set(self.conflicts.get() - 1);
+                return Err(Error::Api(
+                    kube::ErrorKind::Api(kube::ApiError {
+                        status: "Failure".to_string(),
+                        message: "the object has been modified".to_string(),
+                        reason: "Conflict".to_string(),
+                        code: 409,
+                    })
+                    .into(),
+                ));
+            }
+            Ok(())
+        }
+
+        fn annotate(&self, annotation: &str, value: Option<&str>) -> Result<(), Error> {
+            self.annotations
+                .borrow_mut()
+                .push((annotation.to_string(), value.map(str::to_string)));
+            Ok(())
+        }
+
+        fn watch(&self) -> Result<Option<ClusterVersion>, Error> {
+            Ok(Some(self.version.clone()))
+        }
+    }
+
+    impl Verifier for Fixture {
+        fn verify(&self, version: &semver::Version) -> Vec<Check> {
+            vec![Check {
+                gate: "nodes",
+                passed: false,
+                reason: format!("not at {}", version),
+            }]
+        }
+    }
+
+    impl StorageClient for Fixture {
+        fn provisioners(&self) -> Result<Vec<String>, Error> {
+            Ok(Vec::new())
+        }
+    }
+
+    impl PoolClient for Fixture {
+        fn pools(&self) -> Result<Vec<MachineConfigPool>, Error> {
+            Ok(Vec::new())
+        }
+    }
+
+    impl CredentialsClient for Fixture {
+        fn credentials(&self) -> Result<Option<Credentials>, Error> {
+            Ok(None)
+        }
+    }
+
+    impl AckClient for Fixture {
+        fn admin_acks(&self) -> Result<HashSet<String>, Error> {
+            Ok(HashSet::new())
+        }
+    }
+
+    impl RolloutClient for Fixture {
+        fn percentages(
+            &self,
+            _url: &str,
+            _channel: &str,
+            _version: &str,
+        ) -> Result<Percentages, Error> {
+            Ok(self.rollout.clone())
+        }
+    }
+
+    impl GraphClient for Fixture {
+        fn fetch(&self, _upstream: &str, _channel: &str, _version: &str) -> Result<Graph, Error> {
+            Ok(self.graph.clone())
+        }
+    }
+
+    #[test]
+    fn applies_newest_update() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let patches = fixture.run(&[]);
+        assert_eq!(patches.len(), 1);
+
+        let update = patches[0].spec.desired_update.as_ref().expect("update");
+        assert_eq!(update.version.to_string(), "4.1.16");
+        assert!(!update.force);
+        assert_eq!(
+            patches[0].metadata.resourceVersion.as_deref(),
+            Some("1047213")
+        );
+    }
+
+    #[test]
+    fn claims_updates_unless_owned() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let patches = fixture.run(&[]);
+        assert_eq!(
+            gates::owner(&patches[0].metadata).as_deref(),
+            Some(clusterversion::FIELD_MANAGER)
+        );
+
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        fixture.version.metadata.annotations.insert(
+            MANAGED_BY_ANNOTATION.to_string(),
+            "jane@example.com".to_string(),
+        );
+        assert!(fixture.run(&[]).is_empty());
+    }
+
+    #[test]
+    fn holds_back_updates_after_a_regression() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let state = State {
+            observed: Some(semver::Version::parse("4.1.15").expect("version")),
+            ..Default::default()
+        };
+        fixture.store.save(&state).expect("save state");
+        assert!(fixture.run(&[]).is_empty());
+        let state = fixture.store.load().expect("load state");
+        assert_eq!(
+            state.regression.as_deref(),
+            Some("moved backwards from 4.1.15 to 4.1.14")
+        );
+
+        // Acknowledging some other version doesn't count.
+        fixture.version.metadata.annotations.insert(
+            gates::REGRESSION_ACKNOWLEDGED_ANNOTATION.to_string(),
+            "4.1.15".to_string(),
+        );
+        assert!(fixture.run(&[]).is_empty());
+
+        fixture.version.metadata.annotations.insert(
+            gates::REGRESSION_ACKNOWLEDGED_ANNOTATION.to_string(),
+            "4.1.14".to_string(),
+        );
+        assert_eq!(fixture.run(&[]).len(), 1);
+        assert_eq!(
+            fixture.annotations.borrow().as_slice(),
+            &[(gates::REGRESSION_ACKNOWLEDGED_ANNOTATION.to_string(), None)]
+        );
+        let state = fixture.store.load().expect("load state");
+        assert_eq!(state.regression, None);
+        assert_eq!(
+            state.observed.map(|version| version.to_string()),
+            Some("4.1.14".to_string())
+        );
+
+        // Neither is the update the operator applied allowed to vanish from the history.
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let state = State {
+            applied: Some(semver::Version::parse("4.1.15").expect("version")),
+            ..Default::default()
+        };
+        fixture.store.save(&state).expect("save state");
+        assert!(fixture.run(&[]).is_empty());
+    }
+
+    #[test]
+    fn forces_update() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let patches = fixture.run(&["--force"]);
+        assert!(
+            !patches[0]
+                .spec
+                .desired_update
+                .as_ref()
+                .expect("update")
+                .force
+        );
+
+        // Only once someone the policy allows to has approved it.
+        let forced = |args: &[&str]| {
+            let mut fixture = Fixture::new(include_str!(
+                "../tests/fixtures/clusterversion-available.json"
+            ));
+            fixture.version.metadata.annotations.insert(
+                FORCE_APPROVER_ANNOTATION.to_string(),
+                "jane@example.com".to_string(),
+            );
+            fixture.run(args)[0]
+                .spec
+                .desired_update
+                .as_ref()
+                .expect("update")
+                .force
+        };
+        assert!(!forced(&["--force"]));
+        assert!(!forced(&[
+            "--force",
+            "--force-approved-by",
+            "john@example.com"
+        ]));
+        assert!(forced(&[
+            "--force",
+            "--force-approved-by",
+            "john@example.com,jane@example.com"
+        ]));
+    }
+
+    #[test]
+    fn skips_without_updates() {
+        for json in &[
+            include_str!("../tests/fixtures/clusterversion-empty-history.json"),
+            include_str!("../tests/fixtures/clusterversion-null-updates.json"),
+            include_str!("../tests/fixtures/clusterversion-in-progress.json"),
+        ] {
+            assert!(Fixture::new(json).run(&[]).is_empty());
+        }
+    }
+
+    #[test]
+    fn asks_to_retry_retrieving_updates() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-null-updates.json"
+        ));
+        assert!(fixture.run(&[]).is_empty());
+        assert!(fixture.annotations.borrow().is_empty());
+
+        assert!(fixture.run(&["--refresh-updates-after", "15m"]).is_empty());
+        let (annotation, requested) = fixture.annotations.replace(Vec::new()).remove(0);
+        assert_eq!(annotation, REFRESH_ANNOTATION);
+
+        // Not again until the interval has passed.
+        fixture
+            .version
+            .metadata
+            .annotations
+            .insert(annotation, requested.expect("annotated"));
+        assert!(fixture.run(&["--refresh-updates-after", "15m"]).is_empty());
+        assert!(fixture.annotations.borrow().is_empty());
+
+        fixture.clock.advance(chrono::Duration::minutes(15));
+        assert!(fixture.run(&["--refresh-updates-after", "15m"]).is_empty());
+        assert_eq!(fixture.annotations.borrow().len(), 1);
+    }
+
+    #[test]
+    fn refuses_to_compete_for_the_desired_update() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let entry = |manager: &str| {
+            serde_json::from_value(serde_json::json!({
+                "manager": manager,
+                "operation": "Update",
+                "fieldsV1": { "f:spec": { "f:desiredUpdate": { "f:version": {} } } },
+            }))
+            .expect("valid entry")
+        };
+        fixture.managed_fields = vec![entry("openshift-update")];
+        assert_eq!(fixture.run(&[]).len(), 1);
+
+        fixture.managed_fields.push(entry("argocd-controller"));
+        assert!(fixture.run(&[]).is_empty());
+    }
+
+    #[test]
+    fn waits_between_patches() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        assert_eq!(fixture.run(&[]).len(), 1);
+        assert!(fixture.run(&[]).is_empty());
+        assert_eq!(fixture.run(&["--min-patch-interval", "0s"]).len(), 1);
+
+        fixture.clock.advance(chrono::Duration::hours(1));
+        assert_eq!(fixture.run(&["--min-patch-interval", "1h"]).len(), 1);
+    }
+
+    #[test]
+    fn waits_for_the_rollout() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let args = ["--rollout-url", "https://rollout.example.com"];
+        assert!(fixture.run(&args).is_empty());
+
+        let newest = semver::Version::parse("4.1.16").expect("version");
+        fixture.rollout.insert(newest, 100.0);
+        assert_eq!(fixture.run(&args).len(), 1);
+    }
+
+    #[test]
+    fn follows_the_gitops_target() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let args = ["--gitops-repo", "https://git.example.com/clusters.git"];
+        let annotate = |fixture: &mut Fixture, target: serde_json::Value| {
+            fixture
+                .version
+                .metadata
+                .annotations
+                .insert(gitops::TARGET_ANNOTATION.to_string(), target.to_string());
+        };
+
+        annotate(
+            &mut fixture,
+            serde_json::json!({ "version": "4.1.15", "revision": "abc123" }),
+        );
+        let patches = fixture.run(&args);
+        let update = patches[0].spec.desired_update.as_ref().expect("update");
+        assert_eq!(update.version.to_string(), "4.1.15");
+
+        annotate(&mut fixture, serde_json::json!({ "channel": "fast-4.1" }));
+        let patches = fixture.run(&args);
+        assert_eq!(patches[0].spec.channel.as_deref(), Some("fast-4.1"));
+        assert!(patches[0].spec.desired_update.is_none());
+    }
+
+    #[test]
+    fn skips_while_paused() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        fixture
+            .version
+            .metadata
+            .annotations
+            .insert(PAUSED_ANNOTATION.to_string(), "true".to_string());
+        assert!(fixture.run(&[]).is_empty());
+    }
+
+    #[test]
+    fn retries_conflicts() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        fixture.conflicts.set(2);
+        assert_eq!(fixture.run(&[]).len(), 3);
+    }
+
+    #[test]
+    fn gives_up_on_conflicts() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        fixture.conflicts.set(CONFLICT_RETRIES + 1);
+        assert!(fixture.try_run(&[]).is_err());
+        assert_eq!(fixture.patches.borrow().len() as u32, CONFLICT_RETRIES + 1);
+    }
+
+    #[test]
+    fn poisons_abandoned_updates() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-rolled-back.json"
+        ));
+        let state = State {
+            attempted: Some(semver::Version::parse("4.1.16").expect("version")),
+            ..Default::default()
+        };
+        fixture.store.save(&state).expect("save state");
+
+        let patches = fixture.run(&[]);
+        let update = patches[0].spec.desired_update.as_ref().expect("update");
+        assert_eq!(update.version.to_string(), "4.1.15");
+
+        let state = fixture.store.load().expect("load state");
+        assert_eq!(
+            state
+                .poisoned
+                .keys()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["4.1.16"]
+        );
+        assert_eq!(state.attempted, Some(update.version.clone()));
+    }
+
+    #[test]
+    fn verifies_completed_updates() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let state = State {
+            attempted: Some(semver::Version::parse("4.1.14").expect("version")),
+            ..Default::default()
+        };
+        fixture.store.save(&state).expect("save state");
+
+        // Once verified, the next update is applied as usual.
+        let patches = fixture.run(&["--verify-updates"]);
+        assert_eq!(patches.len(), 1);
+        // The series also carry the cluster's identity.
+        assert!(metrics::render().lines().any(|line| line
+            .starts_with("openshift_update_verification_passed{")
+            && line.ends_with(",check=\"nodes\"} 0")));
+    }
+
+    #[test]
+    fn reports_the_status_of_each_gate() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let options = Options::from_iter(&["openshift-update"]);
+        let state = ClusterState::new(&version, Utc::now());
+        let update = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default()
+            .pop()
+            .expect("update");
+
+        let gates = gate_statuses(
+            &options,
+            &state,
+            &Decision::Apply {
+                update: update.clone(),
+            },
+        );
+        assert_eq!(gates.get("update-in-progress"), Some(&true));
+        assert!(gates.values().all(|passed| *passed));
+
+        let blocked = Decision::Blocked {
+            update,
+            gate: "rollout".to_string(),
+            reason: "4.1.16 has only reached 5% of clusters".to_string(),
+        };
+        let gates = gate_statuses(&options, &state, &blocked);
+        assert_eq!(gates.get("rollout"), Some(&false));
+        assert_eq!(gates.get("paused"), Some(&true));
+        assert_eq!(
+            skip_reason(&blocked),
+            Some((
+                "rollout".to_string(),
+                "4.1.16 has only reached 5% of clusters".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn describes_the_checks_evaluated() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let options = Options::from_iter(&[
+            "openshift-update",
+            "--listen",
+            "127.0.0.1:8080",
+            "--api-token-file",
+            "/var/run/secrets/token",
+            "--require-approval",
+            "--max-jitter",
+            "1h",
+        ]);
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let update = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default()
+            .pop()
+            .expect("update");
+        let mut state = ClusterState::new(&version, now);
+        state.approved = Some(update.version.clone());
+        state
+            .first_seen
+            .insert(update.version.clone(), now - chrono::Duration::days(1));
+
+        let checks = explain::checks(&options, &state, Some(&update));
+        let status = Status {
+            version: state.version.clone(),
+            reason: Some(policy::DecisionReason::Ready),
+            ..Default::default()
+        };
+        let text = describe(&status, &update, &checks, true);
+        assert!(text.contains(&format!("Candidate update: {} (", update.version)));
+        assert!(text.contains("Decision:         Ready"));
+        for check in &checks {
+            assert!(text.contains(&format!("  {:<20} pass  {}\n", check.gate, check.reason)));
+        }
+        assert!(text.contains(&format!(
+            "  approval             pass  {} was approved\n",
+            update.version
+        )));
+        assert!(text.contains("  jitter               pass  first seen "));
+        assert!(text.contains("  single-node          the API will be unavailable"));
+    }
+
+    #[test]
+    fn withdraws_updates_which_never_start() {
+        let fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let state = State {
+            attempted: Some(semver::Version::parse("4.1.16").expect("version")),
+            attempted_at: Some(fixture.clock.now() - chrono::Duration::hours(2)),
+            ..Default::default()
+        };
+        fixture.store.save(&state).expect("save state");
+
+        // Without a timeout, the update is simply requested again.
+        let patches = fixture.run(&[]);
+        assert!(patches[0].spec.desired_update.is_some());
+
+        // A single node is given longer.
+        fixture.single_node.set(true);
+        let patches = fixture.run(&["--abort-after", "1h"]);
+        assert!(patches
+            .iter()
+            .all(|patch| patch.spec.desired_update.is_some()));
+        assert!(fixture
+            .store
+            .load()
+            .expect("load state")
+            .poisoned
+            .is_empty());
+        fixture.single_node.set(false);
+
+        let patches = fixture.run(&["--abort-after", "1h"]);
+        assert_eq!(patches.len(), 1);
+        assert!(patches[0].spec.desired_update.is_none());
+        let state = fixture.store.load().expect("load state");
+        assert_eq!(state.attempted, None);
+        assert_eq!(
+            state.poisoned.values().collect::<Vec<_>>(),
+            vec!["not accepted in time"]
+        );
+    }
+
+    #[test]
+    fn reports_drift() {
+        let mut version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-rolled-back.json"
+        ))
+        .expect("valid fixture");
+        version.spec.desired_update = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .and_then(|updates| updates.into_iter().max());
+        let options = Options::from_iter(&["openshift-update", "--drift-threshold", "1h"]);
+        let mut state = State::default();
+        let now = Utc::now();
+
+        check_drift(&options, options.drift_threshold, &mut state, &version, now);
+        assert_eq!(state.drift_since, Some(now));
+        assert!(!state.drift_alerted);
+
+        check_drift(
+            &options,
+            options.drift_threshold,
+            &mut state,
+            &version,
+            now + chrono::Duration::hours(2),
+        );
+        assert_eq!(state.drift_since, Some(now));
+        assert!(state.drift_alerted);
+
+        version.spec.desired_update = None;
+        check_drift(&options, options.drift_threshold, &mut state, &version, now);
+        assert_eq!(state.drift_since, None);
+        assert!(!state.drift_alerted);
+    }
+
+    #[test]
+    fn matches_non_canonical_history() {
+        let mut version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let history = &mut version.status.as_mut().expect("status").history;
+        history[0].version = Some("v4.1".to_string());
+        let completed = semver::Version::parse("4.1.0").expect("version");
+        version.spec.desired_update = Some(ClusterUpdate {
+            force: false,
+            image: "quay.io/openshift-release-dev/ocp-release:4.1".to_string(),
+            version: completed.clone(),
+            raw_version: Some("v4.1".to_string()),
+        });
+        let options = Options::from_iter(&["openshift-update"]);
+        let now = Utc::now();
+
+        // The cluster is at the version it was asked for, however it spells it.
+        let mut state = State::default();
+        check_drift(&options, options.drift_threshold, &mut state, &version, now);
+        assert_eq!(state.drift_since, None);
+
+        let mut state = State {
+            observed: Some(completed.clone()),
+            applied: Some(completed.clone()),
+            ..Default::default()
+        };
+        assert!(!check_regression(&options, &mut state, &version));
+        assert_eq!(state.regression, None);
+
+        let mut state = State::default();
+        state.first_seen.insert(completed.clone(), now);
+        record_adoption(&mut state, &version);
+        assert!(state.adopted.contains_key(&completed));
+    }
+
+    #[test]
+    fn records_adoption() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let completed = version.status.as_ref().expect("status").history[0]
+            .completion_time
+            .expect("completed");
+        let mut state = State::default();
+        let adopted = semver::Version::parse("4.1.14").expect("version");
+        let first_seen = completed - chrono::Duration::days(3);
+        state.first_seen.insert(adopted.clone(), first_seen);
+
+        record_adoption(&mut state, &version);
+        assert!(state.first_seen.is_empty());
+        assert_eq!(state.adopted[&adopted].lag(), chrono::Duration::days(3));
+
+        // Only once.
+        record_adoption(&mut state, &version);
+        assert_eq!(state.adopted.len(), 1);
+    }
+
+    #[test]
+    fn reports_blocked_candidates_once_and_reminds() {
+        let now: DateTime<Utc> = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let options = Options::from_iter(&["openshift-update", "--blocked-reminder", "1d"]);
+        let update = ClusterUpdate {
+            version: semver::Version::parse("4.1.16").expect("valid version"),
+            image: "quay.io/openshift-release-dev/ocp-release:4.1.16".to_string(),
+            force: false,
+            raw_version: None,
+        };
+        let blocked = |reason: &str| Decision::Blocked {
+            reason: reason.to_string(),
+            update: update.clone(),
+            gate: "alerts".to_string(),
+        };
+        let mut state = State::default();
+
+        check_blocked(&options, &mut state, &blocked("1 alert is firing"), now);
+        let reported = |state: &State| state.blocked.as_ref().map(|blocked| blocked.reported_at);
+        assert_eq!(reported(&state), Some(now));
+
+        // Another alert firing isn't another situation, and isn't reminded of yet.
+        let later = now + chrono::Duration::hours(1);
+        check_blocked(&options, &mut state, &blocked("2 alerts are firing"), later);
+        assert_eq!(reported(&state), Some(now));
+
+        let later = now + chrono::Duration::days(1);
+        check_blocked(&options, &mut state, &blocked("2 alerts are firing"), later);
+        assert_eq!(reported(&state), Some(later));
+        assert_eq!(
+            state.blocked.as_ref().map(|blocked| blocked.since),
+            Some(now)
+        );
+
+        check_blocked(&options, &mut state, &Decision::Apply { update }, later);
+        assert_eq!(state.blocked, None);
+    }
+
+    #[test]
+    fn reminds_before_a_snooze_ends() {
+        let mut version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let now: DateTime<Utc> = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        version.metadata.annotations.insert(
+            gates::SNOOZED_UNTIL_ANNOTATION.to_string(),
+            "2019-09-24T00:00:00Z".to_string(),
+        );
+        let options = Options::from_iter(&["openshift-update"]);
+        let mut state = State::default();
+
+        let snooze = current_snooze(&options, &version);
+        check_snooze(&options, &mut state, snooze.as_ref(), now);
+        assert_eq!(
+            state.snoozed_until,
+            snooze.as_ref().map(|snooze| snooze.until)
+        );
+        assert!(!state.snooze_reminded);
+
+        check_snooze(
+            &options,
+            &mut state,
+            snooze.as_ref(),
+            now + chrono::Duration::days(6) + chrono::Duration::hours(1),
+        );
+        assert!(state.snooze_reminded);
+
+        check_snooze(
+            &options,
+            &mut state,
+            snooze.as_ref(),
+            now + chrono::Duration::days(7),
+        );
+        assert_eq!(state.snoozed_until, None);
+        assert!(!state.snooze_reminded);
+
+        // The policy may snooze for longer than the ClusterVersion.
+        let options = Options::from_iter(&[
+            "openshift-update",
+            "--snooze-until",
+            "2019-10-01T00:00:00Z",
+            "--snooze-reason",
+            "quarter-end freeze",
+        ]);
+        let snooze = current_snooze(&options, &version).expect("snooze");
+        assert_eq!(snooze.reason.as_deref(), Some("quarter-end freeze"));
+    }
+
+    #[test]
+    fn skips_prereleases() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        let version = semver::Version::parse("4.2.0-rc.1").expect("version");
+        let image = "quay.io/openshift-release-dev/ocp-release:4.2.0-rc.1".to_string();
+        fixture
+            .version
+            .status
+            .as_mut()
+            .and_then(|status| status.available_updates.as_mut())
+            .expect("available updates")
+            .push(ClusterUpdate {
+                force: false,
+                image: image.clone(),
+                version: version.clone(),
+                raw_version: None,
+            });
+        fixture.graph.nodes.push(openshift_update::graph::Node {
+            version,
+            payload: image,
+            metadata: Default::default(),
+        });
+
+        let patches = fixture.run(&[]);
+        let update = patches[0].spec.desired_update.as_ref().expect("update");
+        assert_eq!(update.version.to_string(), "4.1.16");
+
+        let patches = fixture.run(&["--allow-prerelease", "--min-patch-interval", "0s"]);
+        let update = patches[0].spec.desired_update.as_ref().expect("update");
+        assert_eq!(update.version.to_string(), "4.2.0-rc.1");
+    }
+
+    #[test]
+    #[cfg(feature = "cincinnati")]
+    fn skips_updates_outside_the_channel() {
+        let mut fixture = Fixture::new(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ));
+        fixture
+            .graph
+            .nodes
+            .retain(|node| node.version.to_string() != "4.1.16");
+        let patches = fixture.run(&[]);
+        let update = patches[0].spec.desired_update.as_ref().expect("update");
+        assert_eq!(update.version.to_string(), "4.1.15");
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording the inputs of the operator's decisions, and making the decisions again from them.
+//!
+//! With `--record`, each time the operator evaluates the ClusterVersion, what it reads to decide
+//! (the ClusterVersion, the update graph of its channel, the persisted state and the results of
+//! the checks of the rest of the cluster) is written to a file named after the time. `--replay`
+//! makes each of those decisions again, in order, the way `explain` would have at the time, and
+//! without contacting any cluster. The policy is taken from the options given to the replay, so
+//! they should match those of the operator which made the recordings. This is how a report of
+//! the operator picking an unexpected version can be reproduced from the user's recordings.
+
+use crate::explain::{self, Observed};
+use crate::options::Options;
+use crate::reconciler::channel_graph;
+use chrono::{DateTime, Utc};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{ClusterVersion, ClusterVersionClient};
+use openshift_update::credentials::Credentials;
+use openshift_update::graph::{Graph, GraphClient};
+use openshift_update::health::Health;
+use openshift_update::state::{State, StateStore};
+use openshift_update::Error;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// The inputs of a single decision.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Recording {
+    /// When the decision was made.
+    time: DateTime<Utc>,
+    #[serde(rename = "clusterVersion")]
+    version: ClusterVersion,
+    /// The update graph of the cluster's channel, if it was fetched.
+    graph: Option<Graph>,
+    /// The persisted state, as the data of its ConfigMap.
+    state: BTreeMap<String, String>,
+    #[serde(rename = "competingManagers")]
+    competing_managers: Vec<String>,
+    health: Vec<Verdict>,
+    #[serde(rename = "storageProvisioners")]
+    storage_provisioners: Vec<String>,
+    #[serde(rename = "errorBudget")]
+    error_budget: Vec<Verdict>,
+    credentials: Option<Credentials>,
+    #[serde(rename = "adminAcks", default)]
+    admin_acks: Vec<String>,
+}
+
+/// The result of one of the health checks.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Verdict {
+    gate: String,
+    passed: bool,
+    reason: String,
+}
+
+impl From<&Health> for Verdict {
+    fn from(health: &Health) -> Verdict {
+        Verdict {
+            gate: health.gate.to_string(),
+            passed: health.passed,
+            reason: health.reason.clone(),
+        }
+    }
+}
+
+impl From<&Verdict> for Health {
+    fn from(verdict: &Verdict) -> Health {
+        Health {
+            // A replay runs once and exits, so the few gate names it reads may as well live for
+            // as long as it does.
+            gate: Box::leak(verdict.gate.clone().into_boxed_str()),
+            passed: verdict.passed,
+            reason: verdict.reason.clone(),
+        }
+    }
+}
+
+/// The recorded update graph.
+struct Recorded<'a>(Option<&'a Graph>);
+
+impl GraphClient for Recorded<'_> {
+    fn fetch(&self, _upstream: &str, channel: &str, _version: &str) -> Result<Graph, Error> {
+        self.0.cloned().ok_or_else(|| {
+            Error::Config(format!(
+                "the update graph of the {} channel wasn't recorded",
+                channel
+            ))
+        })
+    }
+}
+
+/// Write what the decision about to be made on `version` is made from to a file in `dir`.
+/// Failures are only logged, since the recording mustn't keep the operator from acting.
+pub fn save(
+    dir: &Path,
+    options: &Options,
+    client: &APIClient,
+    versions: &dyn ClusterVersionClient,
+    store: &dyn StateStore,
+    graph: &dyn GraphClient,
+    version: &ClusterVersion,
+) {
+    let time = Utc::now();
+    let path = dir.join(format!("{}.json", time.format("%Y%m%dT%H%M%S%.3fZ")));
+    let result = recording(options, client, versions, store, graph, version, time)
+        .and_then(|recording| serde_json::to_vec_pretty(&recording).map_err(Error::from))
+        .and_then(|contents| {
+            fs::create_dir_all(dir)
+                .and_then(|_| fs::write(&path, contents))
+                .map_err(|error| {
+                    Error::Config(format!("failed to write {}: {}", path.display(), error))
+                })
+        });
+    match result {
+        Ok(()) => debug!("Recorded the inputs of the decision to {}", path.display()),
+        Err(error) => warn!("Failed to record the inputs of the decision: {}", error),
+    }
+}
+
+fn recording(
+    options: &Options,
+    client: &APIClient,
+    versions: &dyn ClusterVersionClient,
+    store: &dyn StateStore,
+    graph: &dyn GraphClient,
+    version: &ClusterVersion,
+    time: DateTime<Utc>,
+) -> Result<Recording, Error> {
+    let observed = explain::observe(options, client, versions)?;
+    // The graph is only fetched when there are updates for it to hold back, as in a decision.
+    let offered = version
+        .status
+        .as_ref()
+        .and_then(|status| status.available_updates.as_ref())
+        .is_some_and(|updates| !updates.is_empty());
+    let graph = if offered {
+        channel_graph(graph, version)?.map(|(_, graph)| graph)
+    } else {
+        None
+    };
+    Ok(Recording {
+        time,
+        version: version.clone(),
+        graph,
+        state: store.load()?.to_data(),
+        competing_managers: observed.competing_managers,
+        health: observed.health.iter().map(Verdict::from).collect(),
+        storage_provisioners: observed.storage_provisioners,
+        error_budget: observed.error_budget.iter().map(Verdict::from).collect(),
+        credentials: observed.credentials,
+        admin_acks: observed.admin_acks.into_iter().collect(),
+    })
+}
+
+/// Make the decisions recorded in `dir` again, oldest first, and print how each was made.
+pub fn replay(options: &Options, dir: &Path) -> Result<(), Error> {
+    let read_error = |error| Error::Config(format!("failed to read {}: {}", dir.display(), error));
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .map_err(read_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()
+        .map_err(read_error)?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "json")
+    });
+    paths.sort();
+    if paths.is_empty() {
+        return Err(Error::Config(format!(
+            "{} contains no recordings",
+            dir.display()
+        )));
+    }
+
+    for (i, path) in paths.iter().enumerate() {
+        let contents = fs::read(path).map_err(|error| {
+            Error::Config(format!("failed to read {}: {}", path.display(), error))
+        })?;
+        let recording: Recording = serde_json::from_slice(&contents)?;
+        let observed = Observed {
+            competing_managers: recording.competing_managers.clone(),
+            health: recording.health.iter().map(Health::from).collect(),
+            storage_provisioners: recording.storage_provisioners.clone(),
+            error_budget: recording.error_budget.iter().map(Health::from).collect(),
+            credentials: recording.credentials.clone(),
+            admin_acks: recording.admin_acks.iter().cloned().collect(),
+        };
+        let explanation = explain::explain(
+            options,
+            &Recorded(recording.graph.as_ref()),
+            &State::from_data(&recording.state),
+            &recording.version,
+            &observed,
+            recording.time,
+        )?;
+        if i > 0 {
+            println!();
+        }
+        println!("Recorded at {} ({})\n", recording.time, path.display());
+        print!("{}", explain::render(&explanation));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use structopt::StructOpt;
+
+    #[test]
+    fn replays_recorded_decisions() {
+        let dir = env::temp_dir().join(format!("openshift-update-record-{}", process::id()));
+        fs::create_dir_all(&dir).expect("create directory");
+        let options = Options::from_iter(&["openshift-update"]);
+        assert!(replay(&options, &dir).is_err());
+
+        let recording = Recording {
+            time: "2019-09-17T00:00:00Z".parse().expect("valid time"),
+            version: serde_json::from_str(include_str!(
+                "../tests/fixtures/clusterversion-available.json"
+            ))
+            .expect("valid fixture"),
+            graph: Some(
+                serde_json::from_str(include_str!("../tests/fixtures/graph.json"))
+                    .expect("valid fixture"),
+            ),
+            state: State::default().to_data(),
+            competing_managers: Vec::new(),
+            health: vec![Verdict {
+                gate: "alerts".to_string(),
+                passed: true,
+                reason: "no alerts are firing".to_string(),
+            }],
+            storage_provisioners: Vec::new(),
+            error_budget: Vec::new(),
+            credentials: None,
+            admin_acks: Vec::new(),
+        };
+        fs::write(
+            dir.join("20190917T000000.000Z.json"),
+            serde_json::to_vec(&recording).expect("serialize recording"),
+        )
+        .expect("write recording");
+        let replayed = replay(&options, &dir);
+        fs::remove_dir_all(&dir).expect("remove directory");
+        replayed.expect("replay");
+    }
+}
@@ -0,0 +1,474 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The contents of release payloads, and what changes between two of them.
+//!
+//! A release image lists the images of all of its components in `release-manifests/
+//! image-references`, an ImageStream with a tag per component. The file is read straight from the
+//! image's layers with the registry's HTTP API, so nothing has to be pulled to disk.
+
+use crate::retry::Backoff;
+use crate::secret::SecretFile;
+use crate::Error;
+use flate2::read::GzDecoder;
+use reqwest::header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+
+const IMAGE_REFERENCES: &str = "release-manifests/image-references";
+
+/// The annotation of an image-references tag listing the versions of what the image contains.
+const BUILD_VERSIONS_ANNOTATION: &str = "io.openshift.build.versions";
+
+const MANIFEST_TYPES: &str = "application/vnd.docker.distribution.manifest.v2+json, \
+                              application/vnd.docker.distribution.manifest.list.v2+json, \
+                              application/vnd.oci.image.manifest.v1+json, \
+                              application/vnd.oci.image.index.v1+json";
+
+/// The components of a release.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Release {
+    /// The image of each component, by name.
+    pub components: BTreeMap<String, String>,
+    /// The version of RHCOS the nodes run.
+    pub machine_os: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageStream {
+    spec: ImageStreamSpec,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageStreamSpec {
+    #[serde(default)]
+    tags: Vec<TagReference>,
+}
+
+#[derive(serde::Deserialize)]
+struct TagReference {
+    name: String,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    from: ObjectReference,
+}
+
+#[derive(serde::Deserialize)]
+struct ObjectReference {
+    name: String,
+}
+
+impl Release {
+    /// Read a release from its image-references.
+    pub fn from_image_references(json: &[u8]) -> Result<Release, Error> {
+        let stream: ImageStream = serde_json::from_slice(json)?;
+        let mut release = Release::default();
+        for tag in stream.spec.tags {
+            // e.g. "machine-os=410.84.202205191234-0"
+            let machine_os = tag
+                .annotations
+                .get(BUILD_VERSIONS_ANNOTATION)
+                .into_iter()
+                .flat_map(|versions| versions.split(','))
+                .find_map(|version| version.strip_prefix("machine-os="));
+            if let Some(machine_os) = machine_os {
+                release.machine_os = Some(machine_os.to_string());
+            }
+            release.components.insert(tag.name, tag.from.name);
+        }
+        Ok(release)
+    }
+}
+
+/// What changes when updating from one release to another.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The components whose images differ.
+    pub changed: Vec<String>,
+    #[serde(rename = "machineOS", skip_serializing_if = "Option::is_none")]
+    pub machine_os: Option<Bump>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Bump {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+pub fn diff(from: &Release, to: &Release) -> Diff {
+    let mut diff = Diff::default();
+    for (name, image) in &to.components {
+        match from.components.get(name) {
+            None => diff.added.push(name.clone()),
+            Some(previous) if previous != image => diff.changed.push(name.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = from
+        .components
+        .keys()
+        .filter(|name| !to.components.contains_key(*name))
+        .cloned()
+        .collect();
+    if from.machine_os != to.machine_os {
+        diff.machine_os = Some(Bump {
+            from: from.machine_os.clone(),
+            to: to.machine_os.clone(),
+        });
+    }
+    diff
+}
+
+/// Access to release images, which allows the comparison to be exercised against fixtures.
+pub trait ReleaseClient {
+    fn fetch(&self, image: &str) -> Result<Release, Error>;
+}
+
+/// A ReleaseClient which reads release images from their registries, anonymously unless it has
+/// credentials for the registry.
+pub struct RegistryClient {
+    pub http: reqwest::Client,
+    pub backoff: Backoff,
+    /// Credentials in the format of a pull secret's `.dockerconfigjson`.
+    pub auth: Option<SecretFile>,
+}
+
+#[derive(serde::Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    auths: HashMap<String, Auth>,
+}
+
+#[derive(serde::Deserialize)]
+struct Auth {
+    /// The base64-encoded username and password, separated by a colon.
+    auth: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Manifest {
+    /// The per-platform manifests of a manifest list (or OCI index).
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(serde::Deserialize)]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+#[derive(serde::Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Token {
+    token: String,
+}
+
+impl ReleaseClient for RegistryClient {
+    fn fetch(&self, image: &str) -> Result<Release, Error> {
+        let (registry, repository, reference) = parse_reference(image)?;
+        let base = format!("https://{}/v2/{}", registry, repository);
+        let auth = self.credentials(registry)?;
+        let auth = auth.as_deref();
+        let mut token = None;
+
+        let mut manifest: Manifest = self.get(
+            &format!("{}/manifests/{}", base, reference),
+            auth,
+            &mut token,
+        )?;
+        if let Some(platform) = manifest
+            .manifests
+            .iter()
+            .find(|manifest| {
+                manifest.platform.as_ref().is_some_and(|platform| {
+                    platform.os == "linux" && platform.architecture == "amd64"
+                })
+            })
+            .or_else(|| manifest.manifests.first())
+        {
+            let digest = platform.digest.clone();
+            manifest = self.get(&format!("{}/manifests/{}", base, digest), auth, &mut token)?;
+        }
+
+        // The manifests are added on top of the base image, so they are in one of the last layers.
+        for layer in manifest.layers.iter().rev() {
+            let blob = self.request(
+                &format!("{}/blobs/{}", base, layer.digest),
+                auth,
+                &mut token,
+            )?;
+            let found = find_file(GzDecoder::new(blob), IMAGE_REFERENCES)
+                .map_err(|err| Error::Release(format!("failed to read {}: {}", image, err)))?;
+            if let Some(contents) = found {
+                return Release::from_image_references(&contents);
+            }
+        }
+        Err(Error::Release(format!(
+            "{} doesn't contain {}",
+            image, IMAGE_REFERENCES
+        )))
+    }
+}
+
+impl RegistryClient {
+    /// The pull spec of the manifest which `image` currently refers to, by digest (e.g. for a
+    /// tag, whatever it points at right now). Images already referred to by digest are returned as
+    /// they are.
+    pub fn resolve(&self, image: &str) -> Result<String, Error> {
+        let (registry, repository, reference) = parse_reference(image)?;
+        if image.contains('@') {
+            return Ok(image.to_string());
+        }
+        let auth = self.credentials(registry)?;
+        let response = self.request(
+            &format!(
+                "https://{}/v2/{}/manifests/{}",
+                registry, repository, reference
+            ),
+            auth.as_deref(),
+            &mut None,
+        )?;
+        let digest = response
+            .headers()
+            .get("Docker-Content-Digest")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                Error::Release(format!(
+                    "{} didn't report the digest of {}",
+                    registry, image
+                ))
+            })?;
+        Ok(format!("{}/{}@{}", registry, repository, digest))
+    }
+
+    /// The credentials for the registry, if there are any.
+    fn credentials(&self, registry: &str) -> Result<Option<String>, Error> {
+        let file = match &self.auth {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let mut auths: AuthFile = serde_json::from_str(&file.read()?)?;
+        Ok(auths.auths.remove(registry).map(|auth| auth.auth))
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        auth: Option<&str>,
+        token: &mut Option<String>,
+    ) -> Result<T, Error> {
+        Ok(self.request(url, auth, token)?.json()?)
+    }
+
+    /// Make a request, fetching a token first if the registry asks for one. The token is
+    /// anonymous unless there are credentials (`auth`) for the registry.
+    fn request(
+        &self,
+        url: &str,
+        auth: Option<&str>,
+        token: &mut Option<String>,
+    ) -> Result<reqwest::Response, Error> {
+        self.backoff.retry(&format!("get {}", url), || {
+            let send = |token: &Option<String>| {
+                let request = self.http.get(url).header(ACCEPT, MANIFEST_TYPES);
+                match token {
+                    Some(token) => request.bearer_auth(token),
+                    None => request,
+                }
+                .send()
+            };
+
+            let response = send(token)?;
+            if response.status() != StatusCode::UNAUTHORIZED || token.is_some() {
+                return Ok(response.error_for_status()?);
+            }
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or_else(|| Error::Release(format!("{} requires credentials", url)))?;
+            let params = parse_challenge(challenge);
+            let realm = params
+                .iter()
+                .find(|(key, _)| key == "realm")
+                .map(|(_, value)| value.clone())
+                .ok_or_else(|| Error::Release(format!("{} sent no token realm", url)))?;
+            let query: Vec<&(String, String)> =
+                params.iter().filter(|(key, _)| key != "realm").collect();
+            let mut request = self.http.get(&realm).query(&query);
+            if let Some(auth) = auth {
+                request = request.header(AUTHORIZATION, format!("Basic {}", auth));
+            }
+            let issued: Token = request.send()?.error_for_status()?.json()?;
+            *token = Some(issued.token);
+            Ok(send(token)?.error_for_status()?)
+        })
+    }
+}
+
+/// Split a pull spec (e.g. quay.io/openshift-release-dev/ocp-release@sha256:...) into its
+/// registry, repository and tag or digest.
+fn parse_reference(image: &str) -> Result<(&str, &str, &str), Error> {
+    let invalid = || Error::Release(format!("invalid image reference {}", image));
+    let (registry, rest) = image.split_once('/').ok_or_else(invalid)?;
+    let (repository, reference) = match rest.split_once('@') {
+        Some(split) => split,
+        None => rest.rsplit_once(':').ok_or_else(invalid)?,
+    };
+    Ok((registry, repository, reference))
+}
+
+/// Parse the parameters of a `WWW-Authenticate: Bearer` challenge.
+fn parse_challenge(challenge: &str) -> Vec<(String, String)> {
+    challenge
+        .split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .map(|(key, value)| (key.to_string(), value.trim_matches('"').to_string()))
+        .collect()
+}
+
+/// Find a regular file in a tar archive, returning its contents.
+fn find_file<R: Read>(mut archive: R, path: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 512];
+    loop {
+        if let Err(err) = archive.read_exact(&mut header) {
+            return match err.kind() {
+                io::ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(err),
+            };
+        }
+        // The archive ends with empty blocks.
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+
+        let field = |range: std::ops::Range<usize>| {
+            let field = &header[range];
+            let end = field
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let size = u64::from_str_radix(field(124..136).trim(), 8)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid tar header"))?;
+        let name = match field(345..500) {
+            prefix if prefix.is_empty() => field(0..100),
+            prefix => format!("{}/{}", prefix, field(0..100)),
+        };
+
+        let regular = header[156] == b'0' || header[156] == 0;
+        if regular && name.trim_start_matches("./") == path {
+            let mut contents = Vec::with_capacity(size as usize);
+            archive.by_ref().take(size).read_to_end(&mut contents)?;
+            return Ok(Some(contents));
+        }
+        // Entries are padded to a whole number of blocks.
+        let padded = size.div_ceil(512) * 512;
+        io::copy(&mut archive.by_ref().take(padded), &mut io::sink())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(json: &str) -> Release {
+        Release::from_image_references(json.as_bytes()).expect("valid image references")
+    }
+
+    #[test]
+    fn diffs_releases() {
+        let from = release(include_str!(
+            "../tests/fixtures/image-references-4.1.14.json"
+        ));
+        let to = release(include_str!(
+            "../tests/fixtures/image-references-4.1.15.json"
+        ));
+        assert_eq!(from.machine_os.as_deref(), Some("410.8.20190830.0"));
+        assert_eq!(
+            diff(&from, &to),
+            Diff {
+                added: vec!["insights-operator".to_string()],
+                removed: vec!["service-catalog-controller-manager".to_string()],
+                changed: vec![
+                    "cluster-version-operator".to_string(),
+                    "machine-os-content".to_string()
+                ],
+                machine_os: Some(Bump {
+                    from: Some("410.8.20190830.0".to_string()),
+                    to: Some("410.8.20190904.0".to_string()),
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(
+            parse_reference("quay.io/openshift-release-dev/ocp-release@sha256:61ed")
+                .expect("valid"),
+            (
+                "quay.io",
+                "openshift-release-dev/ocp-release",
+                "sha256:61ed"
+            )
+        );
+        assert_eq!(
+            parse_reference("registry.local:5000/ocp/release:4.1.15").expect("valid"),
+            ("registry.local:5000", "ocp/release", "4.1.15")
+        );
+        assert!(parse_reference("ocp-release").is_err());
+    }
+
+    #[test]
+    fn finds_files_in_archives() {
+        let entry = |name: &str, contents: &[u8]| {
+            let mut header = [0u8; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            let size = format!("{:011o}\0", contents.len());
+            header[124..136].copy_from_slice(size.as_bytes());
+            header[156] = b'0';
+            let mut entry = header.to_vec();
+            entry.extend_from_slice(contents);
+            entry.resize(entry.len().div_ceil(512) * 512, 0);
+            entry
+        };
+        let mut archive = entry("release-manifests/0000_50_cvo.yaml", &[b'x'; 700]);
+        archive.extend(entry("./release-manifests/image-references", b"{}"));
+        archive.extend(vec![0; 1024]);
+
+        assert_eq!(
+            find_file(&archive[..], IMAGE_REFERENCES).expect("read archive"),
+            Some(b"{}".to_vec())
+        );
+        assert_eq!(
+            find_file(&archive[..], "release-manifests/release-metadata").expect("read archive"),
+            None
+        );
+    }
+}
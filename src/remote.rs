@@ -0,0 +1,250 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subcommands which control a running operator through its API.
+
+use crate::command::Command;
+use crate::output::Output;
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Remote {
+    #[structopt(long = "url", default_value = "http://localhost:8080")]
+    /// Base URL of the operator's API
+    pub url: String,
+
+    #[structopt(long = "api-token-file", parse(from_os_str))]
+    /// File containing the bearer token of the operator's API
+    pub api_token_file: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct Status {
+    #[structopt(flatten)]
+    pub remote: Remote,
+
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the status: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(StructOpt)]
+pub struct Queue {
+    #[structopt(flatten)]
+    pub remote: Remote,
+
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the queue: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(StructOpt)]
+pub struct FleetStatus {
+    #[structopt(flatten)]
+    pub remote: Remote,
+
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the fleet's status: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    Io(io::Error),
+    Api(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(err) => write!(f, "request failed: {}", err),
+            Error::Io(err) => write!(f, "failed to read API token: {}", err),
+            Error::Api(message) => write!(f, "operator responded: {}", message),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+pub fn run(command: &Command) -> Result<(), Error> {
+    let (remote, path) = match command {
+        Command::Status(status) => (&status.remote, "/status"),
+        Command::Queue(queue) => (&queue.remote, "/queue"),
+        Command::FleetStatus(fleet) => (&fleet.remote, "/fleet"),
+        Command::Pause(remote) => (remote, "/pause"),
+        Command::Resume(remote) => (remote, "/resume"),
+        Command::Approve(remote) => (remote, "/approve"),
+        _ => unreachable!("only remote commands use the API"),
+    };
+
+    let token = fs::read_to_string(&remote.api_token_file)?;
+    let url = format!("{}{}", remote.url.trim_end_matches('/'), path);
+    let client = reqwest::Client::new();
+    let request = match command {
+        Command::Status(_) | Command::Queue(_) | Command::FleetStatus(_) => client.get(&url),
+        _ => client.post(&url),
+    };
+
+    let mut response = request.bearer_auth(token.trim()).send()?;
+    let status = response.status();
+    if !status.is_success() {
+        // Errors which don't come from the operator itself (e.g. from a proxy in front of it)
+        // needn't be JSON.
+        let body = response.text().unwrap_or_default();
+        return Err(Error::Api(
+            match serde_json::from_str::<ErrorResponse>(&body) {
+                Ok(body) => body.error,
+                Err(_) => status.to_string(),
+            },
+        ));
+    }
+    let body: serde_json::Value = response.json()?;
+
+    match command {
+        Command::Status(status) => status.output.print(&body, render_status),
+        Command::Queue(queue) => queue.output.print(&body, render_queue),
+        Command::FleetStatus(fleet) => fleet.output.print(&body, render_fleet),
+        Command::Approve(_) => {
+            if let Some(version) = body.get("approved").and_then(|version| version.as_str()) {
+                println!("Approved the update to {}", version);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// A row per queued update, with when it would be applied or what holds it back.
+fn render_queue(queue: &serde_json::Value) -> String {
+    let mut text = format!(
+        "{:<24} {:<28} {:<16} {}\n",
+        "VERSION", "WHEN", "GATE", "REASON"
+    );
+    for queued in queue.as_array().into_iter().flatten() {
+        let field = |name: &str| queued.get(name).and_then(|value| value.as_str());
+        let when = match (field("notBefore"), field("gate")) {
+            (_, Some(_)) => "when unblocked",
+            (Some(not_before), None) => not_before,
+            (None, None) => "now",
+        };
+        writeln!(
+            text,
+            "{:<24} {:<28} {:<16} {}",
+            field("version").unwrap_or("-"),
+            when,
+            field("gate").unwrap_or("-"),
+            field("reason").unwrap_or("-")
+        )
+        .expect("write to string");
+    }
+    text
+}
+
+/// A row per cluster of the fleet, with its version, candidate, gate and last result.
+fn render_fleet(clusters: &serde_json::Value) -> String {
+    let mut text = format!(
+        "{:<40} {:<16} {:<16} {:<12} {:<20} {}\n",
+        "CLUSTER", "VERSION", "CANDIDATE", "GATE", "LAST RESULT", "REASON"
+    );
+    for cluster in clusters.as_array().into_iter().flatten() {
+        let field = |name: &str| cluster.get(name).and_then(|value| value.as_str());
+        writeln!(
+            text,
+            "{:<40} {:<16} {:<16} {:<12} {:<20} {}",
+            field("name").unwrap_or("-"),
+            field("version").unwrap_or("-"),
+            field("candidate").unwrap_or("-"),
+            field("gate").unwrap_or("-"),
+            field("lastResult").unwrap_or("-"),
+            field("reason").unwrap_or("-")
+        )
+        .expect("write to string");
+    }
+    text
+}
+
+/// A line per field of the operator's status, followed by a row per ClusterOperator.
+fn render_status(status: &serde_json::Value) -> String {
+    let mut text = String::new();
+    for (field, value) in status.as_object().into_iter().flatten() {
+        // These are tables of their own (the fleet's with fleet-status).
+        if field == "operators" || field == "clusters" {
+            continue;
+        }
+        let value = match value {
+            serde_json::Value::Null => "-".to_string(),
+            serde_json::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        writeln!(text, "{:<24} {}", field, value).expect("write to string");
+    }
+
+    let operators = status
+        .get("operators")
+        .and_then(|operators| operators.as_array());
+    if let Some(operators) = operators.filter(|operators| !operators.is_empty()) {
+        let behind = operators
+            .iter()
+            .filter(|operator| operator.get("atTarget") != Some(&serde_json::Value::Bool(true)))
+            .count();
+        writeln!(
+            text,
+            "\nClusterOperators ({} of {} not at the cluster's version)",
+            behind,
+            operators.len()
+        )
+        .expect("write to string");
+        writeln!(
+            text,
+            "{:<40} {:<24} {:<10} {:<12} DEGRADED",
+            "NAME", "VERSION", "AVAILABLE", "PROGRESSING"
+        )
+        .expect("write to string");
+        for operator in operators {
+            let field = |name: &str| operator.get(name).and_then(|value| value.as_str());
+            writeln!(
+                text,
+                "{:<40} {:<24} {:<10} {:<12} {}",
+                field("name").unwrap_or("-"),
+                field("version").unwrap_or("-"),
+                field("available").unwrap_or("-"),
+                field("progressing").unwrap_or("-"),
+                field("degraded").unwrap_or("-")
+            )
+            .expect("write to string");
+        }
+    }
+    text
+}
@@ -0,0 +1,252 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exponential backoff for requests to flaky APIs.
+//!
+//! During a control-plane rollout the API server comes and goes, so failed requests are retried
+//! with an exponentially growing, randomized delay rather than immediately.
+
+use crate::Error;
+use rand::Rng;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    pub initial: Duration,
+    /// The longest delay between two attempts.
+    pub max: Duration,
+    /// How many times a failed request is retried before giving up.
+    pub retries: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(300),
+            retries: 5,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the given retry (counting from zero).
+    ///
+    /// The delay is chosen uniformly between zero and the exponential bound ("full jitter"), so
+    /// that clients which failed together don't retry together.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let bound = self
+            .initial
+            .checked_mul(2u32.saturating_pow(retry))
+            .map_or(self.max, |bound| bound.min(self.max));
+        let millis = bound.as_millis() as u64;
+        if millis == 0 {
+            return bound;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0, millis + 1))
+    }
+
+    /// Run `f` until it succeeds, it fails with an error that isn't worth retrying, or the
+    /// retries are exhausted.
+    pub fn retry<T, F>(&self, what: &str, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        let mut retry = 0;
+        loop {
+            match f() {
+                Err(error) if retry < self.retries && retryable(&error) => {
+                    let delay = if throttled(&error) {
+                        // The Kubernetes client drops the Retry-After header (and the
+                        // retryAfterSeconds in the body), so the longest delay is used instead.
+                        self.max
+                    } else {
+                        self.delay(retry)
+                    };
+                    warn!(
+                        "Failed to {} (retrying in {}): {}",
+                        what,
+                        humantime::format_duration(Duration::from_secs(delay.as_secs())),
+                        error
+                    );
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Counts consecutive failures of the reconcile loop, and trips ("opens") once there have been
+/// too many in a row so that the operator can report itself unready and slow down.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    failures: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    Opened,
+    Closed,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            failures: 0,
+        }
+    }
+
+    /// Record the outcome of an attempt, returning the change of state it caused, if any. The
+    /// first success closes the breaker again.
+    pub fn record(&mut self, success: bool) -> Option<Transition> {
+        let was_open = self.is_open();
+        if success {
+            self.failures = 0;
+        } else {
+            self.failures = self.failures.saturating_add(1);
+        }
+        match (was_open, self.is_open()) {
+            (false, true) => Some(Transition::Opened),
+            (true, false) => Some(Transition::Closed),
+            _ => None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.failures >= self.threshold
+    }
+
+    /// The number of consecutive failures.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// Whether the request might succeed if it were made again.
+pub fn retryable(error: &Error) -> bool {
+    match error {
+        Error::Api(error) => match error.kind() {
+            kube::ErrorKind::Api(error) => error.code == 429 || error.code >= 500,
+            kube::ErrorKind::RequestSend | kube::ErrorKind::RequestParse => true,
+            _ => false,
+        },
+        Error::Http(error) => {
+            error.is_timeout()
+                || error
+                    .status()
+                    .is_some_and(|status| status.as_u16() == 429 || status.is_server_error())
+        }
+        _ => false,
+    }
+}
+
+/// Whether the object was modified since it was read, so the request was made against a stale
+/// copy and has to be rebuilt from a fresh one rather than simply repeated.
+pub fn conflict(error: &Error) -> bool {
+    match error {
+        Error::Api(error) => error.api_error().is_some_and(|error| error.code == 409),
+        _ => false,
+    }
+}
+
+/// Whether the API server refused the client's credentials, which have probably expired or been
+/// rotated.
+pub fn unauthorized(error: &Error) -> bool {
+    match error {
+        Error::Api(error) => error.api_error().is_some_and(|error| error.code == 401),
+        _ => false,
+    }
+}
+
+/// Whether the requested object doesn't exist.
+pub fn not_found(error: &Error) -> bool {
+    match error {
+        Error::Api(error) => error.api_error().is_some_and(|error| error.code == 404),
+        _ => false,
+    }
+}
+
+/// Whether the request was refused because the client is making too many.
+fn throttled(error: &Error) -> bool {
+    match error {
+        Error::Api(error) => error.api_error().is_some_and(|error| error.code == 429),
+        Error::Http(error) => error.status().is_some_and(|status| status.as_u16() == 429),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_is_bounded() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            retries: 5,
+        };
+        for retry in 0..40 {
+            let bound = Duration::from_secs(2u64.saturating_pow(retry).min(10));
+            assert!(backoff.delay(retry) <= bound);
+        }
+    }
+
+    #[test]
+    fn gives_up() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(0),
+            max: Duration::from_millis(0),
+            retries: 3,
+        };
+        let mut attempts = 0;
+        let result: Result<(), Error> = backoff.retry("test", || {
+            attempts += 1;
+            Err(Error::Api(kube::ErrorKind::RequestSend.into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn breaker_opens_and_closes() {
+        let mut breaker = CircuitBreaker::new(3);
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(false), None);
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.record(false), Some(Transition::Opened));
+        assert_eq!(breaker.record(false), None);
+        assert!(breaker.is_open());
+        assert_eq!(breaker.failures(), 4);
+        assert_eq!(breaker.record(true), Some(Transition::Closed));
+        assert_eq!(breaker.failures(), 0);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let mut attempts = 0;
+        let result: Result<(), Error> = Backoff::default().retry("test", || {
+            attempts += 1;
+            Err(Error::Config("broken".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Phased rollouts coordinated by a central service.
+//!
+//! Before a cluster adopts a new version, the rollout service is asked what percentage of the
+//! fleet should have it by now: `GET <url>?channel=<channel>&version=<current version>` returns
+//! an object mapping versions to percentages, such as `{"4.1.16": 25, "4.1.15": 100}`. Each
+//! cluster has a stable position within the fleet, derived from its ID, and only updates once the
+//! percentage has reached it. Versions which the service doesn't list aren't being rolled out.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::http::HttpClient;
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use crate::Error;
+use reqwest::header::ACCEPT;
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+/// The share of the fleet, from 0 to 100, which should have each version.
+pub type Percentages = HashMap<semver::Version, f64>;
+
+/// Access to the rollout service, which allows decisions to be exercised against fixtures.
+pub trait RolloutClient {
+    /// Fetch the percentages for a cluster on `channel` at `version`.
+    fn percentages(&self, url: &str, channel: &str, version: &str) -> Result<Percentages, Error>;
+}
+
+impl RolloutClient for HttpClient {
+    fn percentages(&self, url: &str, channel: &str, version: &str) -> Result<Percentages, Error> {
+        let percentages: HashMap<String, f64> = self.backoff.retry("fetch rollout", || {
+            Ok(self
+                .http
+                .get(url)
+                .query(&[("channel", channel), ("version", version)])
+                .header(ACCEPT, "application/json")
+                .send()?
+                .error_for_status()?
+                .json()?)
+        })?;
+        Ok(percentages
+            .into_iter()
+            .filter_map(|(version, percentage)| match version.parse() {
+                Ok(version) => Some((version, percentage)),
+                Err(error) => {
+                    warn!("Ignoring rollout of invalid version {}: {}", version, error);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// The cluster's position within the fleet, from 0 (the first to update) up to 100.
+///
+/// As with the jitter, FNV is used so that the position doesn't move between releases.
+pub fn position(cluster_id: &str) -> f64 {
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(cluster_id.as_bytes());
+    (hasher.finish() % 10_000) as f64 / 100.0
+}
+
+/// Block updates until the rollout of their version has reached the cluster.
+///
+/// Clusters without an ID are last in line, so they only update once a rollout is complete.
+pub struct Rollout {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for Rollout {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        let (update, percentages) =
+            match (self.inner.evaluate(current, candidates), &current.rollout) {
+                (Decision::Apply { update }, Some(percentages)) => (update, percentages),
+                (decision, _) => return decision,
+            };
+
+        let percentage = percentages.get(&update.version).cloned().unwrap_or(0.0);
+        let position = current.cluster_id.as_deref().map_or(100.0, position);
+        if position < percentage || percentage >= 100.0 {
+            Decision::Apply { update }
+        } else {
+            Decision::Blocked {
+                gate: "rollout".to_string(),
+                reason: format!(
+                    "rolled out to {}% of the fleet, and this cluster is at {:.2}%",
+                    percentage, position
+                ),
+                update,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clusterversion::ClusterVersion;
+    use crate::policy::Latest;
+
+    #[test]
+    fn waits_for_the_rollout() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        let mut state = ClusterState::new(&version, "2019-09-17T00:00:00Z".parse().expect("time"));
+        let policy = Rollout {
+            inner: Box::new(Latest),
+        };
+        let newest = candidates.iter().max().expect("candidate").version.clone();
+        let position = position(state.cluster_id.as_deref().expect("cluster ID"));
+
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        state.rollout = Some(Percentages::new());
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked { gate, .. } if gate == "rollout"
+        ));
+
+        state.rollout = Some(vec![(newest.clone(), position)].into_iter().collect());
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked { .. }
+        ));
+
+        state.rollout = Some(vec![(newest, position + 0.01)].into_iter().collect());
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+}
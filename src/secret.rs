@@ -0,0 +1,93 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Credentials read from files, such as the keys of mounted Secrets.
+//!
+//! Keeping credentials in files keeps them out of the process's arguments and environment (and
+//! out of the policy ConfigMap). The kubelet updates mounted Secrets in place when they are
+//! rotated, so a file is read again whenever it changes instead of once at startup.
+
+use crate::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A credential kept in a file, which is re-read whenever the file changes.
+#[derive(Clone, Debug)]
+pub struct SecretFile {
+    path: PathBuf,
+    /// The modification time and length of the file when it was last read, and its contents.
+    cached: Arc<Mutex<Option<(SystemTime, u64, String)>>>,
+}
+
+impl SecretFile {
+    pub fn new(path: &Path) -> SecretFile {
+        SecretFile {
+            path: path.to_path_buf(),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The contents of the file, without surrounding whitespace.
+    pub fn read(&self) -> Result<String, Error> {
+        let failed =
+            |error| Error::Config(format!("failed to read {}: {}", self.path.display(), error));
+        let metadata = fs::metadata(&self.path).map_err(failed)?;
+        let modified = metadata.modified().map_err(failed)?;
+
+        let mut cached = self.cached.lock().expect("secret lock");
+        match &*cached {
+            Some((time, len, contents)) if *time == modified && *len == metadata.len() => {
+                Ok(contents.clone())
+            }
+            previous => {
+                let contents = fs::read_to_string(&self.path)
+                    .map_err(failed)?
+                    .trim()
+                    .to_string();
+                if previous.is_some() {
+                    info!("Reloaded {}", self.path.display());
+                }
+                *cached = Some((modified, metadata.len(), contents.clone()));
+                Ok(contents)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn reads_the_file_again_once_it_changes() {
+        let path = env::temp_dir().join(format!("openshift-update-secret-{}", process::id()));
+        fs::write(&path, "first\n").expect("write secret");
+        let secret = SecretFile::new(&path);
+        assert_eq!(secret.read().expect("read secret"), "first");
+
+        fs::write(&path, "rotated\n").expect("write secret");
+        assert_eq!(secret.read().expect("read secret"), "rotated");
+
+        fs::remove_file(&path).expect("remove secret");
+        assert!(secret.read().is_err());
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keeping the operator itself up to date.
+//!
+//! With `--self-update-image`, the operator follows a tag of its own image (e.g. `:stable`) and
+//! moves its Deployment to whichever digest the tag points at. The move is held back by the same
+//! checks as the cluster's updates (while the cluster is updating, paused or snoozed) and, with
+//! `--self-update-days`, to a range of days of the month. Before the Deployment is touched, the
+//! new image is staged: it runs `check-config` with the operator's own arguments in a Pod of its
+//! own, and the Deployment is only moved if that succeeds within `--self-update-timeout`.
+
+use crate::explain::gate_checks;
+use crate::options::Options;
+use chrono::Utc;
+use kube::api::{DeleteParams, PatchParams, PostParams, RawApi};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{ClusterVersionClient, KubeClient};
+use openshift_update::kubeapi;
+use openshift_update::kubeconfig;
+use openshift_update::metrics;
+use openshift_update::policy::ClusterState;
+use openshift_update::release::RegistryClient;
+use openshift_update::secret::SecretFile;
+use openshift_update::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the Pod which stages the new image.
+pub(crate) const STAGING_POD: &str = "openshift-update-self-update";
+
+/// How often the staging Pod is checked on.
+const STAGING_POLL: Duration = Duration::from_secs(5);
+
+/// The checks which also hold back the operator's own updates.
+const GATES: &[&str] = &["update-in-progress", "paused", "snooze"];
+
+/// Check for a new image every `interval`, forever.
+pub fn schedule(options: &Options, image: &str, interval: Duration) {
+    loop {
+        match update(options, image) {
+            Ok(Some(target)) => info!("Moved the operator to {}", target),
+            Ok(None) => {}
+            Err(error) => error!("Failed to update the operator: {}", error),
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Move the operator's Deployment to the image `image` refers to, if it isn't there yet and
+/// nothing holds it back, returning the new image if it was moved.
+fn update(options: &Options, image: &str) -> Result<Option<String>, Error> {
+    // The credentials are loaded afresh on every run, since they may have been rotated since the
+    // last one.
+    let client = APIClient::new(kubeconfig::load()?);
+    let registry = RegistryClient {
+        http: options.http(&client)?,
+        backoff: options.backoff(),
+        auth: options.registry_auth_file.as_deref().map(SecretFile::new),
+    };
+    let target = registry.resolve(image)?;
+
+    let reference = &options.self_update_deployment;
+    let deployments = RawApi::customResource("deployments")
+        .group("apps")
+        .version("v1")
+        .within(&reference.namespace);
+    let request = deployments.get(&reference.name)?;
+    let deployment: serde_json::Value =
+        kubeapi::call("get", "deployments", || client.request(request))?;
+    let container = deployment["spec"]["template"]["spec"]["containers"]
+        .as_array()
+        .and_then(|containers| {
+            containers
+                .iter()
+                .find(|container| container["name"] == options.self_update_container.as_str())
+        })
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "Deployment {} has no container named {}",
+                reference, options.self_update_container
+            ))
+        })?;
+    let current = container["image"].as_str().unwrap_or_default();
+    metrics::set(
+        "openshift_update_self_update_pending",
+        "Whether the operator's image differs from the one it follows.",
+        &[],
+        if current == target { 0.0 } else { 1.0 },
+    );
+    if current == target {
+        return Ok(None);
+    }
+
+    let versions = KubeClient::new(
+        client.clone(),
+        &options.cluster_version_name,
+        options.backoff(),
+    )?;
+    let version = versions.get()?;
+    let now = Utc::now();
+    let mut state = ClusterState::new(&version, now);
+    state.snoozed = crate::reconciler::current_snooze(options, &version);
+    let mut blocked: Vec<String> = gate_checks(options, &state)
+        .into_iter()
+        .filter(|check| !check.passed && GATES.contains(&check.gate))
+        .map(|check| check.reason)
+        .collect();
+    if let Some(days) = options.self_update_days.filter(|days| !days.contains(now)) {
+        blocked.push(format!(
+            "outside the allowed days, until {}",
+            days.next(now)
+        ));
+    }
+    if !blocked.is_empty() {
+        info!(
+            "Not moving the operator to {}: {}",
+            target,
+            blocked.join("; ")
+        );
+        return Ok(None);
+    }
+
+    info!("Staging {} before moving the operator to it", target);
+    stage(&client, options, &deployment, container, &target)?;
+
+    let patch = serde_json::json!({
+        "spec": { "template": { "spec": { "containers": [{
+            "name": options.self_update_container,
+            "image": target,
+        }]}}}
+    });
+    let request = deployments.patch(
+        &reference.name,
+        &PatchParams::default(),
+        serde_json::to_vec(&patch)?,
+    )?;
+    kubeapi::call("patch", "deployments", || {
+        client.request::<serde_json::Value>(request)
+    })?;
+    Ok(Some(target))
+}
+
+/// Run `check-config` from the new image, with the operator's own arguments and the rest of its
+/// Pod, and wait for it to succeed. The Pod is deleted afterwards either way.
+fn stage(
+    client: &APIClient,
+    options: &Options,
+    deployment: &serde_json::Value,
+    container: &serde_json::Value,
+    target: &str,
+) -> Result<(), Error> {
+    let namespace = &options.self_update_deployment.namespace;
+    let pods = RawApi::v1Pod().within(namespace);
+    let mut spec = deployment["spec"]["template"]["spec"].clone();
+    let mut staged = container.clone();
+    staged["image"] = target.into();
+    let mut args = container["args"].as_array().cloned().unwrap_or_default();
+    args.push("check-config".into());
+    staged["args"] = args.into();
+    for probe in &["readinessProbe", "livenessProbe", "startupProbe"] {
+        if let Some(staged) = staged.as_object_mut() {
+            staged.remove(*probe);
+        }
+    }
+    spec["containers"] = serde_json::json!([staged]);
+    spec["restartPolicy"] = "Never".into();
+    let pod = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": { "name": STAGING_POD, "namespace": namespace },
+        "spec": spec,
+    });
+
+    // A Pod left behind by an earlier run would keep the new one from being created.
+    remove(client, &pods);
+    let request = pods.create(&PostParams::default(), serde_json::to_vec(&pod)?)?;
+    kubeapi::call("create", "pods", || {
+        client.request::<serde_json::Value>(request)
+    })?;
+
+    let deadline = Instant::now() + options.self_update_timeout;
+    let result = loop {
+        let request = pods.get(STAGING_POD)?;
+        let pod: serde_json::Value = kubeapi::call("get", "pods", || client.request(request))?;
+        match pod["status"]["phase"].as_str() {
+            Some("Succeeded") => break Ok(()),
+            Some("Failed") => {
+                break Err(Error::Config(format!(
+                    "{} failed its staged check-config",
+                    target
+                )))
+            }
+            _ if Instant::now() >= deadline => {
+                break Err(Error::Config(format!(
+                    "{} didn't finish its staged check-config within {}",
+                    target,
+                    humantime::format_duration(options.self_update_timeout)
+                )))
+            }
+            _ => thread::sleep(STAGING_POLL),
+        }
+    };
+    remove(client, &pods);
+    result
+}
+
+/// Delete the staging Pod, if there is one.
+fn remove(client: &APIClient, pods: &RawApi) {
+    let request = match pods.delete(STAGING_POD, &DeleteParams::default()) {
+        Ok(request) => request,
+        Err(error) => return error!("Failed to delete {}: {}", STAGING_POD, error),
+    };
+    if let Err(error) = kubeapi::call("delete", "pods", || {
+        client.request::<serde_json::Value>(request)
+    }) {
+        debug!("Failed to delete {}: {}", STAGING_POD, error);
+    }
+}
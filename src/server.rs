@@ -0,0 +1,547 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small HTTP API for inspecting and controlling the operator.
+//!
+//! Every request other than the `GET /readyz` probe must carry the configured bearer token.
+//! `GET /status` returns the operator's most recent decision, `GET /queue` the updates it intends
+//! to apply in order and `GET /metrics` its metrics in the Prometheus format, while `POST /pause`,
+//! `/resume`, `/approve` and `/trigger` control it. Pausing and triggering are done by annotating
+//! the ClusterVersion, so they take effect (and wake up the operator) the same way as they would
+//! if made with kubectl. Approvals are kept in the status and, with --state-namespace, saved in
+//! the state ConfigMap, so that they survive restarts. The controls make their requests of the
+//! Kubernetes API from threads of their own rather than the server's. The same calls are served
+//! over gRPC with --grpc-listen (see [`crate::grpc`]).
+//!
+//! When a fleet is managed (with --acm, --hosted-clusters or --ocm), `GET /fleet` returns where
+//! each of its clusters stands instead. The local ClusterVersion isn't what's being updated then,
+//! so only the status, the fleet and the metrics are served.
+//!
+//! The validating admission webhooks are served by a listener of their own, since the API server
+//! only calls webhooks over HTTPS (see [`crate::tls`]). `POST /validate/policy` reviews changes to
+//! the policy ConfigMap, so that an invalid policy is rejected instead of ignored, and
+//! `POST /validate/clusterversion` reviews edits of the ClusterVersion (see [`crate::admission`]).
+//! The API server doesn't have the token, so the reviews don't need it; they change nothing.
+
+use crate::admission;
+use crate::policyconfig::{self, Reference};
+use crate::status::Status;
+use crate::tls;
+use chrono::Utc;
+use futures::future;
+use futures::sync::oneshot;
+use hyper::rt::{self, Future, Stream};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Chunk, Method, Request, Response, Server, StatusCode};
+use kube::api::{Api, PatchParams};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{self, ClusterVersion};
+use openshift_update::gates::PAUSED_ANNOTATION;
+use openshift_update::kubeapi;
+use openshift_update::kubeconfig;
+use openshift_update::metrics;
+use openshift_update::retry;
+use openshift_update::secret::SecretFile;
+use openshift_update::state::{ConfigMapStore, StateStore};
+use openshift_update::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio_tcp::TcpListener;
+
+/// Annotation updated on every trigger, which makes the operator's watch fire.
+const TRIGGER_ANNOTATION: &str = "upgrade.crawford.dev/triggered";
+
+/// What the control API (over HTTP or gRPC) serves, and which it acts on.
+pub(crate) struct Context {
+    /// The bearer token clients must present, which is read again when it's rotated.
+    token: SecretFile,
+    /// Name of the ClusterVersion.
+    name: String,
+    versions: Mutex<Api<ClusterVersion>>,
+    pub status: Arc<Mutex<Status>>,
+    /// Where approvals are saved, with --state-namespace, so that they survive restarts.
+    store: Option<ConfigMapStore>,
+    /// Whether a fleet is managed rather than the local cluster.
+    pub fleet: bool,
+}
+
+/// Why a request was refused, as the HTTP status to respond with and a message.
+pub(crate) struct Refusal(pub StatusCode, pub String);
+
+impl Refusal {
+    fn new(code: StatusCode, message: &str) -> Refusal {
+        Refusal(code, message.to_string())
+    }
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Serve the API on `addr` from a background thread.
+pub fn spawn(
+    addr: SocketAddr,
+    token: SecretFile,
+    client: APIClient,
+    name: String,
+    status: Arc<Mutex<Status>>,
+    fleet: bool,
+    store: Option<ConfigMapStore>,
+) -> Result<(), hyper::Error> {
+    let context = Arc::new(Context::new(token, client, name, status, fleet, store));
+    let server = Server::try_bind(&addr)?
+        .serve(move || {
+            let context = context.clone();
+            service_fn(move |request: Request<Body>| -> ResponseFuture {
+                let context = context.clone();
+                // Only the controls make requests of the Kubernetes API.
+                if request.method() == Method::POST {
+                    Box::new(blocking(move || context.handle(&request)).or_else(|_| {
+                        Ok(error(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "the request failed",
+                        ))
+                    }))
+                } else {
+                    Box::new(future::ok(context.handle(&request)))
+                }
+            })
+        })
+        .map_err(|error| error!("Failed to serve API: {}", error));
+
+    info!("Serving API on {}", addr);
+    thread::spawn(move || hyper::rt::run(server));
+    Ok(())
+}
+
+/// Run `work` on a thread of its own, for work which blocks (as requests of the Kubernetes API
+/// do) and so would hold up the other connections if it ran on the server's threads. The future
+/// fails if `work` panics.
+pub(crate) fn blocking<T, F>(work: F) -> impl Future<Item = T, Error = oneshot::Canceled>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        // The receiver is only gone if the client is.
+        let _ = sender.send(work());
+    });
+    receiver
+}
+
+/// Serve the admission webhooks over HTTPS on `addr` from a background thread. `policy` is the
+/// policy ConfigMap, if there is one.
+pub fn spawn_webhooks(
+    addr: SocketAddr,
+    identity: tls::Identity,
+    policy: Option<Reference>,
+) -> Result<(), String> {
+    identity.check()?;
+    let listener = TcpListener::bind(&addr)
+        .map_err(|error| format!("unable to listen on {}: {}", addr, error))?;
+    let policy = Arc::new(policy);
+    let server = listener
+        .incoming()
+        .then(|result| {
+            if let Err(error) = &result {
+                warn!("Failed to accept a webhook connection: {}", error);
+            }
+            Ok::<_, ()>(result.ok())
+        })
+        .filter_map(|stream| stream)
+        .for_each(move |stream| {
+            let policy = policy.clone();
+            let connection = identity
+                .accept(stream)
+                .map_err(|error| debug!("TLS handshake with a webhook client failed: {}", error))
+                .and_then(move |stream| {
+                    Http::new()
+                        .serve_connection(
+                            stream,
+                            service_fn(move |request| webhook(&policy, request)),
+                        )
+                        .map_err(|error| debug!("Failed to serve a webhook client: {}", error))
+                });
+            rt::spawn(connection);
+            Ok(())
+        });
+
+    info!("Serving admission webhooks on {}", addr);
+    thread::spawn(move || hyper::rt::run(server));
+    Ok(())
+}
+
+/// Route a request to one of the webhooks.
+fn webhook(policy: &Option<Reference>, request: Request<Body>) -> ResponseFuture {
+    let policy = policy.clone();
+    match (request.method(), request.uri().path()) {
+        (&Method::POST, "/validate/policy") => Box::new(
+            request
+                .into_body()
+                .concat2()
+                .map(move |body| validate_policy(policy.as_ref(), &body)),
+        ),
+        (&Method::POST, "/validate/clusterversion") => Box::new(
+            request
+                .into_body()
+                .concat2()
+                .map(|body| validate_cluster_version(&body)),
+        ),
+        (_, "/validate/policy") | (_, "/validate/clusterversion") => Box::new(future::ok(error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed",
+        ))),
+        _ => Box::new(future::ok(error(StatusCode::NOT_FOUND, "not found"))),
+    }
+}
+
+impl Context {
+    pub fn new(
+        token: SecretFile,
+        client: APIClient,
+        name: String,
+        status: Arc<Mutex<Status>>,
+        fleet: bool,
+        store: Option<ConfigMapStore>,
+    ) -> Context {
+        Context {
+            token,
+            name,
+            versions: Mutex::new(clusterversion::api(client)),
+            status,
+            fleet,
+            store,
+        }
+    }
+
+    fn handle(&self, request: &Request<Body>) -> Response<Body> {
+        // Probes are made by the kubelet, which doesn't have the token.
+        if (request.method(), request.uri().path()) == (&Method::GET, "/readyz") {
+            return self.ready();
+        }
+
+        if let Err(refusal) = self.authorize(request.headers()) {
+            return refused(refusal);
+        }
+
+        let path = request.uri().path();
+        let refusal = match path {
+            "/fleet" if !self.fleet => Err(Refusal::new(
+                StatusCode::NOT_FOUND,
+                "only served with --acm, --hosted-clusters or --ocm",
+            )),
+            "/queue" | "/pause" | "/resume" | "/approve" | "/trigger" => self.local(),
+            _ => Ok(()),
+        };
+        if let Err(refusal) = refusal {
+            return refused(refusal);
+        }
+
+        match (request.method(), path) {
+            (&Method::GET, "/status") => {
+                let status = self.status.lock().expect("status lock").clone();
+                respond(StatusCode::OK, &status)
+            }
+            (&Method::GET, "/queue") => {
+                let queue = self.status.lock().expect("status lock").queue.clone();
+                respond(StatusCode::OK, &queue)
+            }
+            (&Method::GET, "/fleet") => {
+                let clusters = self.status.lock().expect("status lock").clusters.clone();
+                respond(StatusCode::OK, &clusters)
+            }
+            (&Method::GET, "/metrics") => Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics::render()))
+                .expect("valid response"),
+            (&Method::POST, "/pause") => self.annotated(self.pause(true)),
+            (&Method::POST, "/resume") => self.annotated(self.pause(false)),
+            (&Method::POST, "/approve") => match self.approve() {
+                Ok(version) => respond(StatusCode::OK, &serde_json::json!({ "approved": version })),
+                Err(refusal) => refused(refusal),
+            },
+            (&Method::POST, "/trigger") => {
+                self.annotated(self.annotate(TRIGGER_ANNOTATION, Some(Utc::now().to_rfc3339())))
+            }
+            (_, "/status")
+            | (_, "/queue")
+            | (_, "/fleet")
+            | (_, "/metrics")
+            | (_, "/pause")
+            | (_, "/resume")
+            | (_, "/approve")
+            | (_, "/trigger") => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    /// Ready unless the operator has failed too many times in a row.
+    fn ready(&self) -> Response<Body> {
+        let status = self.status.lock().expect("status lock");
+        if status.ready {
+            respond(StatusCode::OK, &serde_json::json!({}))
+        } else {
+            error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &format!("{} consecutive failures", status.consecutive_failures),
+            )
+        }
+    }
+
+    /// Refuse clients which don't present the bearer token.
+    pub fn authorize(&self, headers: &header::HeaderMap) -> Result<(), Refusal> {
+        let token = self.token.read().map_err(|err| {
+            error!("Failed to read API token: {}", err);
+            Refusal::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to read API token",
+            )
+        })?;
+        let authorized = headers.get(header::AUTHORIZATION).is_some_and(|value| {
+            constant_time_eq(value.as_bytes(), format!("Bearer {}", token).as_bytes())
+        });
+        if authorized {
+            Ok(())
+        } else {
+            Err(Refusal::new(
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid bearer token",
+            ))
+        }
+    }
+
+    /// Refuse to control the local cluster while a fleet is managed.
+    pub fn local(&self) -> Result<(), Refusal> {
+        if self.fleet {
+            Err(Refusal::new(
+                StatusCode::NOT_FOUND,
+                "not served for a fleet",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Approve the current candidate, if there is one, returning it.
+    pub fn approve(&self) -> Result<semver::Version, Refusal> {
+        let approved = {
+            let mut status = self.status.lock().expect("status lock");
+            status.approved = status.candidate.clone();
+            status.approved.clone()
+        };
+
+        match approved {
+            Some(version) => {
+                info!("Update to {} approved", version);
+                if let Some(store) = &self.store {
+                    let saved = store.load().and_then(|mut saved| {
+                        saved.approved = Some(version.clone());
+                        store.save(&saved)
+                    });
+                    if let Err(err) = saved {
+                        error!("Failed to save the approval: {}", err);
+                        return Err(Refusal(StatusCode::BAD_GATEWAY, err.to_string()));
+                    }
+                }
+                self.annotate(TRIGGER_ANNOTATION, Some(Utc::now().to_rfc3339()))?;
+                Ok(version)
+            }
+            None => Err(Refusal::new(
+                StatusCode::CONFLICT,
+                "no update is awaiting approval",
+            )),
+        }
+    }
+
+    /// Pause (or resume) updates by annotating the ClusterVersion.
+    pub fn pause(&self, paused: bool) -> Result<(), Refusal> {
+        self.annotate(
+            PAUSED_ANNOTATION,
+            Some("true".to_string()).filter(|_| paused),
+        )
+    }
+
+    fn annotated(&self, result: Result<(), Refusal>) -> Response<Body> {
+        match result {
+            Ok(()) => respond(StatusCode::OK, &serde_json::json!({})),
+            Err(refusal) => refused(refusal),
+        }
+    }
+
+    /// Set (or with `None`, remove) an annotation on the ClusterVersion.
+    fn annotate(&self, annotation: &str, value: Option<String>) -> Result<(), Refusal> {
+        let patch = serde_json::json!({
+            "metadata": { "annotations": { annotation: value } }
+        });
+        let patch = serde_json::to_vec(&patch).expect("Serialize to JSON");
+        let mut versions = self.versions.lock().expect("versions lock");
+        let result = match kubeapi::call("patch", "clusterversions", || {
+            versions.patch(&self.name, &PatchParams::default(), patch.clone())
+        })
+        .map_err(Error::from)
+        {
+            Err(ref err) if retry::unauthorized(err) => {
+                // The credentials have probably been rotated since the server was started.
+                warn!("Credentials were rejected; reloading them");
+                kubeconfig::load().and_then(|config| {
+                    *versions = clusterversion::api(APIClient::new(config));
+                    kubeapi::call("patch", "clusterversions", || {
+                        versions.patch(&self.name, &PatchParams::default(), patch)
+                    })
+                    .map_err(Error::from)
+                })
+            }
+            result => result,
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                error!("Failed to annotate ClusterVersion: {}", err);
+                Err(Refusal(StatusCode::BAD_GATEWAY, err.to_string()))
+            }
+        }
+    }
+}
+
+/// Review a change to a ConfigMap, which is sent as an AdmissionReview.
+fn validate_policy(policy: Option<&Reference>, body: &Chunk) -> Response<Body> {
+    let reference = match policy {
+        Some(reference) => reference,
+        None => return error(StatusCode::NOT_FOUND, "no policy ConfigMap is configured"),
+    };
+    match serde_json::from_slice(body) {
+        Ok(review) => respond(
+            StatusCode::OK,
+            &policyconfig::review(&review, reference, crate::options::command_line()),
+        ),
+        Err(err) => error(
+            StatusCode::BAD_REQUEST,
+            &format!("invalid AdmissionReview: {}", err),
+        ),
+    }
+}
+
+/// Review an edit of the ClusterVersion, which is sent as an AdmissionReview.
+fn validate_cluster_version(body: &Chunk) -> Response<Body> {
+    match serde_json::from_slice(body) {
+        Ok(review) => respond(
+            StatusCode::OK,
+            &admission::review(&review, &crate::options::command_line()),
+        ),
+        Err(err) => error(
+            StatusCode::BAD_REQUEST,
+            &format!("invalid AdmissionReview: {}", err),
+        ),
+    }
+}
+
+/// Whether the two are equal, taking as long to tell whichever byte they differ in, so that
+/// guesses at the token can't be timed to find how much of them is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn respond<T: serde::Serialize>(code: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(body).expect("Serialize to JSON"),
+        ))
+        .expect("valid response")
+}
+
+fn error(code: StatusCode, message: &str) -> Response<Body> {
+    respond(code, &serde_json::json!({ "error": message }))
+}
+
+fn refused(Refusal(code, message): Refusal) -> Response<Body> {
+    error(code, &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"Bearer s3cret", b"Bearer s3cret"));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3creT"));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3cre"));
+        assert!(!constant_time_eq(b"", b"Bearer s3cret"));
+    }
+
+    #[test]
+    fn blocks_on_threads_of_its_own() {
+        let server = thread::current().id();
+        let worker = blocking(move || thread::current().id() != server);
+        assert_eq!(worker.wait(), Ok(true));
+    }
+
+    #[test]
+    fn serves_the_fleet() {
+        let dir =
+            std::env::temp_dir().join(format!("openshift-update-fleet-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create directory");
+        let token = dir.join("token");
+        std::fs::write(&token, "s3cret").expect("write token");
+        let status = Status {
+            clusters: vec![crate::status::FleetCluster {
+                name: "spoke-1".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let context = |fleet| {
+            Context::new(
+                SecretFile::new(&token),
+                APIClient::new(kube::config::Configuration::new(
+                    "http://localhost".to_string(),
+                    reqwest::Client::new(),
+                )),
+                "version".to_string(),
+                Arc::new(Mutex::new(status.clone())),
+                fleet,
+                None,
+            )
+        };
+        let request = |method, path| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header::AUTHORIZATION, "Bearer s3cret")
+                .body(Body::empty())
+                .expect("valid request")
+        };
+
+        let response = context(true).handle(&request(Method::GET, "/fleet"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().concat2().wait().expect("body");
+        let clusters: serde_json::Value = serde_json::from_slice(&body).expect("JSON");
+        assert_eq!(clusters[0]["name"], "spoke-1");
+        assert_eq!(
+            context(true)
+                .handle(&request(Method::POST, "/pause"))
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            context(false)
+                .handle(&request(Method::GET, "/fleet"))
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decisions made against a ClusterVersion and policy read from files, at a chosen time.
+//!
+//! This is for testing a policy in CI before it's rolled out: the decision is made the same way
+//! as `explain` would make it, without contacting any cluster, and the exit code is the one
+//! `--once` would exit with. The checks of the rest of the cluster (health, storage, credentials
+//! and so on) are taken to pass.
+
+use crate::explain::{self, Explanation, Observed};
+use crate::options::Options;
+use crate::output::Output;
+use crate::policyconfig;
+use chrono::{DateTime, TimeZone, Utc};
+use openshift_update::clusterversion::ClusterVersion;
+use openshift_update::graph::{Graph, GraphClient, Node};
+use openshift_update::policy::Decision;
+use openshift_update::state::State;
+use openshift_update::Error;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Simulate {
+    #[structopt(long = "clusterversion", parse(from_os_str))]
+    /// File of the ClusterVersion to decide on, in YAML or JSON (e.g. from
+    /// `oc get clusterversion version -o yaml`)
+    pub cluster_version: PathBuf,
+
+    #[structopt(long = "policy", parse(from_os_str))]
+    /// File of a policy ConfigMap (see --policy-config-map), or of just its data, which overrides
+    /// the options
+    pub policy: Option<PathBuf>,
+
+    #[structopt(long = "graph", parse(from_os_str))]
+    /// File of the update graph of the cluster's channel, in JSON. Without it, the channel is
+    /// taken to contain every available update
+    pub graph: Option<PathBuf>,
+
+    #[structopt(long = "state", parse(from_os_str))]
+    /// File of the state ConfigMap (see --state-namespace), or of just its data, for when each
+    /// update was first seen and which have failed before
+    pub state: Option<PathBuf>,
+
+    #[structopt(long = "at", parse(try_from_str = parse_time))]
+    /// Time at which the decision is made, in RFC 3339 with or without the seconds (e.g.
+    /// 2024-03-02T03:00Z), instead of now
+    pub at: Option<DateTime<Utc>>,
+
+    #[structopt(long = "output", default_value = "table")]
+    /// Format of the decision: "table", "json" or "yaml"
+    pub output: Output,
+}
+
+/// A file of a ConfigMap's data, which is either the whole ConfigMap or only its data.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum DataFile {
+    ConfigMap { data: BTreeMap<String, String> },
+    Data(BTreeMap<String, String>),
+}
+
+/// The update graph given with --graph, or the one made up from the available updates.
+struct Given(Graph);
+
+impl GraphClient for Given {
+    fn fetch(&self, _upstream: &str, _channel: &str, _version: &str) -> Result<Graph, Error> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Print the decision, and return it for the exit code. `command_line` returns the options as
+/// they were given, which the policy file is applied to.
+pub fn run<F>(command_line: F, command: &Simulate) -> Result<Decision, Error>
+where
+    F: Fn() -> Options,
+{
+    let explanation = simulate(command_line, command)?;
+    command.output.print(&explanation, explain::render);
+    Ok(explanation.decision)
+}
+
+fn simulate<F>(command_line: F, command: &Simulate) -> Result<Explanation, Error>
+where
+    F: Fn() -> Options,
+{
+    let version: ClusterVersion = serde_yaml::from_str(&read(&command.cluster_version)?)
+        .map_err(|error| invalid(&command.cluster_version, error))?;
+    let options = match &command.policy {
+        Some(path) => policyconfig::apply(command_line(), &data(path)?).map_err(|problems| {
+            Error::Config(format!("{}: {}", path.display(), problems.join("; ")))
+        })?,
+        None => command_line(),
+    };
+    let saved = match &command.state {
+        Some(path) => State::from_data(&data(path)?),
+        None => State::default(),
+    };
+    let graph = match &command.graph {
+        Some(path) => serde_json::from_str(&read(path)?).map_err(|error| invalid(path, error))?,
+        None => Graph {
+            nodes: version
+                .status
+                .as_ref()
+                .and_then(|status| status.available_updates.as_ref())
+                .into_iter()
+                .flatten()
+                .map(|update| Node {
+                    version: update.version.clone(),
+                    payload: update.image.clone(),
+                    metadata: BTreeMap::new(),
+                })
+                .collect(),
+            edges: Vec::new(),
+        },
+    };
+
+    explain::explain(
+        &options,
+        &Given(graph),
+        &saved,
+        &version,
+        &Observed::default(),
+        command.at.unwrap_or_else(Utc::now),
+    )
+}
+
+/// Parse a time in RFC 3339, where the seconds may be left out.
+fn parse_time(time: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    time.parse().or_else(|error| {
+        Utc.datetime_from_str(time, "%Y-%m-%dT%H:%MZ")
+            .map_err(|_| error)
+    })
+}
+
+fn data(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    match serde_yaml::from_str(&read(path)?).map_err(|error| invalid(path, error))? {
+        DataFile::ConfigMap { data } | DataFile::Data(data) => Ok(data),
+    }
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path)
+        .map_err(|error| Error::Config(format!("failed to read {}: {}", path.display(), error)))
+}
+
+fn invalid(path: &Path, error: impl std::fmt::Display) -> Error {
+    Error::Config(format!("{} is invalid: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn parses_times_without_seconds() {
+        let expected: DateTime<Utc> = "2024-03-02T03:00:00Z".parse().expect("valid time");
+        assert_eq!(parse_time("2024-03-02T03:00Z"), Ok(expected));
+        assert_eq!(parse_time("2024-03-02T03:00:00Z"), Ok(expected));
+        assert!(parse_time("2024-03-02").is_err());
+    }
+
+    #[test]
+    fn simulates_the_policy() {
+        let file =
+            |name| env::temp_dir().join(format!("openshift-update-{}-{}", name, process::id()));
+        let (policy, state) = (file("policy"), file("state"));
+        fs::write(&policy, "data:\n  max-jitter: 1d\n").expect("write policy");
+        fs::write(&state, "first-seen.4.1.16: \"2019-09-17T00:00:00Z\"\n").expect("write state");
+        let options = || Options::from_iter(&["openshift-update"]);
+        let command = |at: &str| Simulate {
+            cluster_version: "tests/fixtures/clusterversion-available.json".into(),
+            policy: Some(policy.clone()),
+            graph: None,
+            state: Some(state.clone()),
+            at: Some(parse_time(at).expect("valid time")),
+            output: Output::Table,
+        };
+
+        let delayed = simulate(options, &command("2019-09-17T00:00Z")).expect("simulate");
+        let later = simulate(options, &command("2019-09-19T00:00Z")).expect("simulate");
+        fs::remove_file(&policy).expect("remove policy");
+        fs::remove_file(&state).expect("remove state");
+        assert!(matches!(delayed.decision, Decision::Delayed { .. }));
+        assert!(matches!(later.decision, Decision::Apply { .. }));
+        assert_eq!(
+            later
+                .decision
+                .update()
+                .map(|update| update.version.to_string()),
+            Some("4.1.16".to_string())
+        );
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Gating on the error budgets of service-level objectives.
+//!
+//! An update is planned disruption, which shouldn't be piled onto an ongoing reliability problem.
+//! Each objective is read from a YAML file, along with a PromQL query for its error ratio over a
+//! `$window` and the burn rates (how many times faster than sustainable the budget is being
+//! spent) allowed over each window:
+//!
+//! ```yaml
+//! - name: api-availability
+//!   objective: 0.999
+//!   errorRatio: >
+//!     sum(rate(apiserver_request_total{code=~"5.."}[$window]))
+//!     / sum(rate(apiserver_request_total[$window]))
+//!   windows:
+//!     - window: 1h
+//!       maxBurnRate: 14.4
+//!     - window: 6h
+//!       maxBurnRate: 6
+//! ```
+//!
+//! While any objective burns faster than allowed over any of its windows, [`ProtectErrorBudget`]
+//! blocks every update other than those published by a security advisory.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::health::{Health, HealthCheck};
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use crate::retry::Backoff;
+use crate::secret::SecretFile;
+use crate::Error;
+use reqwest::header::ACCEPT;
+use std::fs;
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct Objective {
+    pub name: String,
+    /// The share of requests (or time) which must succeed, e.g. 0.999.
+    pub objective: f64,
+    /// The ratio of errors over `$window`.
+    #[serde(rename = "errorRatio")]
+    pub error_ratio: String,
+    pub windows: Vec<Window>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct Window {
+    /// A Prometheus duration, e.g. "1h".
+    pub window: String,
+    #[serde(rename = "maxBurnRate")]
+    pub max_burn_rate: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Objectives {
+    pub objectives: Vec<Objective>,
+}
+
+impl Objectives {
+    /// Read the objectives from a YAML file.
+    pub fn read(path: &str) -> Result<Objectives, String> {
+        let file = fs::read_to_string(path)
+            .map_err(|error| format!("failed to read {}: {}", path, error))?;
+        Objectives::parse(&file).map_err(|error| format!("{}: {}", path, error))
+    }
+
+    pub fn parse(yaml: &str) -> Result<Objectives, String> {
+        let objectives: Vec<Objective> =
+            serde_yaml::from_str(yaml).map_err(|error| error.to_string())?;
+        for objective in &objectives {
+            if !(objective.objective > 0.0 && objective.objective < 1.0) {
+                return Err(format!(
+                    "{}: the objective must be between 0 and 1, not {}",
+                    objective.name, objective.objective
+                ));
+            }
+            if objective.windows.is_empty() {
+                return Err(format!("{}: no windows", objective.name));
+            }
+        }
+        Ok(Objectives { objectives })
+    }
+}
+
+/// A Prometheus-compatible query API, such as the cluster's Thanos querier.
+#[derive(Clone)]
+pub struct Prometheus {
+    pub http: reqwest::Client,
+    /// Base URL of the API, e.g. "https://thanos-querier.openshift-monitoring.svc:9091".
+    pub url: String,
+    pub token: Option<SecretFile>,
+    pub backoff: Backoff,
+}
+
+#[derive(serde::Deserialize)]
+struct Response {
+    data: Data,
+}
+
+#[derive(serde::Deserialize)]
+struct Data {
+    result: Vec<Sample>,
+}
+
+#[derive(serde::Deserialize)]
+struct Sample {
+    /// The time and the value, which Prometheus sends as a string.
+    value: (f64, String),
+}
+
+impl Prometheus {
+    /// The value of an instant query, which must return a single sample, or `None` if it
+    /// returns none.
+    pub fn query(&self, query: &str) -> Result<Option<f64>, Error> {
+        let url = format!("{}/api/v1/query", self.url.trim_end_matches('/'));
+        let response: Response = self.backoff.retry("query Prometheus", || {
+            let mut request = self
+                .http
+                .get(&url)
+                .query(&[("query", query)])
+                .header(ACCEPT, "application/json");
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token.read()?);
+            }
+            Ok(request.send()?.error_for_status()?.json()?)
+        })?;
+        match response.data.result.as_slice() {
+            [] => Ok(None),
+            [sample] => sample
+                .value
+                .1
+                .parse()
+                .map(Some)
+                .map_err(|_| Error::Policy(format!("{:?} is not a number", sample.value.1))),
+            samples => Err(Error::Policy(format!(
+                "the query returned {} series rather than one",
+                samples.len()
+            ))),
+        }
+    }
+}
+
+/// Fails while the objective's budget burns faster than allowed over any of its windows.
+pub struct Budget {
+    pub prometheus: Prometheus,
+    pub objective: Objective,
+}
+
+impl HealthCheck for Budget {
+    fn gate(&self) -> &'static str {
+        "error-budget"
+    }
+
+    fn check(&self) -> Result<Health, Error> {
+        let mut ratios = Vec::new();
+        for window in &self.objective.windows {
+            let query = self
+                .objective
+                .error_ratio
+                .replace("$window", &window.window);
+            ratios.push(self.prometheus.query(&query)?);
+        }
+        Ok(health(&self.objective, &ratios))
+    }
+}
+
+/// The verdict on the objective, given its error ratio over each of its windows (if there was
+/// any traffic in them).
+pub fn health(objective: &Objective, ratios: &[Option<f64>]) -> Health {
+    let budget = 1.0 - objective.objective;
+    let burning: Vec<String> = objective
+        .windows
+        .iter()
+        .zip(ratios)
+        .filter_map(|(window, ratio)| {
+            let burn_rate = ratio.unwrap_or_default() / budget;
+            if burn_rate > window.max_burn_rate {
+                Some(format!(
+                    "{:.1}x over {} (at most {}x)",
+                    burn_rate, window.window, window.max_burn_rate
+                ))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Health {
+        gate: "error-budget",
+        passed: burning.is_empty(),
+        reason: if burning.is_empty() {
+            format!("{} is within its error budget", objective.name)
+        } else {
+            format!(
+                "{} is burning its error budget {}",
+                objective.name,
+                burning.join(", ")
+            )
+        },
+    }
+}
+
+/// Block updates while an error budget is burning too fast, unless they fix security issues.
+pub struct ProtectErrorBudget {
+    pub inner: Box<dyn UpgradePolicy>,
+}
+
+impl UpgradePolicy for ProtectErrorBudget {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } if !current.security.contains(&update.version) => {
+                match current.error_budget.iter().find(|health| !health.passed) {
+                    Some(burning) => Decision::Blocked {
+                        update,
+                        gate: burning.gate.to_string(),
+                        reason: burning.reason.clone(),
+                    },
+                    None => Decision::Apply { update },
+                }
+            }
+            decision => decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clusterversion::ClusterVersion;
+    use crate::policy::Latest;
+
+    const OBJECTIVES: &str = r#"
+- name: api-availability
+  objective: 0.999
+  errorRatio: sum(rate(errors[$window])) / sum(rate(requests[$window]))
+  windows:
+    - window: 1h
+      maxBurnRate: 14.4
+    - window: 6h
+      maxBurnRate: 6
+"#;
+
+    #[test]
+    fn judges_burn_rates() {
+        let objectives = Objectives::parse(OBJECTIVES).expect("valid objectives");
+        let objective = &objectives.objectives[0];
+
+        assert!(health(objective, &[Some(0.001), None]).passed);
+        let burning = health(objective, &[Some(0.02), Some(0.001)]);
+        assert!(!burning.passed);
+        assert_eq!(
+            burning.reason,
+            "api-availability is burning its error budget 20.0x over 1h (at most 14.4x)"
+        );
+
+        assert!(Objectives::parse(
+            "- name: x\n  objective: 99.9\n  errorRatio: up\n  windows: []\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn lets_security_updates_through() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        let mut state = ClusterState::new(
+            &version,
+            "2019-09-17T00:00:00Z".parse().expect("valid time"),
+        );
+        let policy = ProtectErrorBudget {
+            inner: Box::new(Latest),
+        };
+        let objectives = Objectives::parse(OBJECTIVES).expect("valid objectives");
+        state.error_budget = vec![health(&objectives.objectives[0], &[Some(0.02), None])];
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked { gate, .. } if gate == "error-budget"
+        ));
+
+        let newest = candidates.iter().max().expect("candidate");
+        state.security.insert(newest.version.clone());
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SNMPv2c traps for updates starting, completing and failing.
+//!
+//! This is for network operations centers which only watch traps. The traps and the objects
+//! they carry are described by mibs/OPENSHIFT-UPDATE-MIB.txt. The MIB has no enterprise arc of
+//! its own, so it's rooted in the Net-SNMP playpen unless it's moved (along with
+//! --snmp-enterprise-oid) into an arc of the site's own. Below that root:
+//!
+//! - `.1.1` updateStarted, sent when the operator requests an update
+//! - `.1.2` updateCompleted, sent when the cluster finishes updating
+//! - `.1.3` updateFailed, sent when an update is abandoned or fails its checks
+//! - `.2.1` clusterID, `.2.2` version and `.2.3` reason, the objects bound to each trap
+//!
+//! Traps are unacknowledged UDP datagrams, so one which is lost is gone; they complement the
+//! other notifiers rather than replace them.
+
+use crate::identity::Identity;
+use crate::notify::{Event, Notifier};
+use crate::Error;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::Instant;
+
+/// sysUpTime.0, which every trap starts with.
+const SYS_UP_TIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+/// snmpTrapOID.0, the binding naming the trap.
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+// The BER tags used by traps.
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const TIME_TICKS: u8 = 0x43;
+const SNMPV2_TRAP: u8 = 0xa7;
+
+/// An object identifier, given in dotted form (e.g. "1.3.6.1.4.1.8072").
+#[derive(Clone, Debug, PartialEq)]
+pub struct Oid(pub Vec<u32>);
+
+impl FromStr for Oid {
+    type Err = String;
+
+    fn from_str(oid: &str) -> Result<Self, Self::Err> {
+        let arcs = oid
+            .trim_start_matches('.')
+            .split('.')
+            .map(str::parse)
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("{:?} is not a dotted object identifier", oid))?;
+        match arcs.as_slice() {
+            [first, second, ..] if (*first < 2 && *second < 40) || *first == 2 => Ok(Oid(arcs)),
+            _ => Err(format!("{:?} is not a valid object identifier", oid)),
+        }
+    }
+}
+
+impl fmt::Display for Oid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arcs: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", arcs.join("."))
+    }
+}
+
+impl Oid {
+    fn child(&self, arcs: &[u32]) -> Vec<u32> {
+        self.0.iter().chain(arcs).cloned().collect()
+    }
+}
+
+pub struct Snmp {
+    /// The trap receiver, as "<host>:<port>" (usually port 162).
+    pub target: String,
+    pub community: String,
+    /// The root of the MIB.
+    pub enterprise: Oid,
+    /// When the operator started, which sysUpTime counts from.
+    pub started: Instant,
+}
+
+/// The trap for the event, and the version and reason it binds, if there's one.
+fn trap(event: &Event) -> Option<(u32, String, String)> {
+    match event {
+        Event::PatchApplied { version, .. } => Some((1, version.to_string(), event.to_string())),
+        Event::Completed { version } => Some((2, version.clone(), event.to_string())),
+        Event::Poisoned { version, reason } => Some((3, version.to_string(), reason.clone())),
+        Event::Verified {
+            version,
+            passed: false,
+            failures,
+        } => Some((3, version.to_string(), failures.join("; "))),
+        _ => None,
+    }
+}
+
+impl Snmp {
+    /// The message carrying the trap, encoded in BER.
+    fn message(
+        &self,
+        trap: u32,
+        identity: &Identity,
+        version: &str,
+        reason: &str,
+        request: i32,
+    ) -> Vec<u8> {
+        // sysUpTime is in hundredths of a second, and wraps after about 497 days.
+        let uptime = (self.started.elapsed().as_millis() / 10) as u32;
+        let cluster = identity.cluster_id.as_deref().unwrap_or_default();
+        let bindings = [
+            binding(SYS_UP_TIME, tlv(TIME_TICKS, &unsigned(uptime))),
+            binding(
+                SNMP_TRAP_OID,
+                tlv(OBJECT_IDENTIFIER, &oid(&self.enterprise.child(&[1, trap]))),
+            ),
+            binding(
+                &self.enterprise.child(&[2, 1, 0]),
+                tlv(OCTET_STRING, cluster.as_bytes()),
+            ),
+            binding(
+                &self.enterprise.child(&[2, 2, 0]),
+                tlv(OCTET_STRING, version.as_bytes()),
+            ),
+            binding(
+                &self.enterprise.child(&[2, 3, 0]),
+                tlv(OCTET_STRING, reason.as_bytes()),
+            ),
+        ]
+        .concat();
+        let pdu = [
+            tlv(INTEGER, &integer(request)),
+            tlv(INTEGER, &integer(0)),
+            tlv(INTEGER, &integer(0)),
+            tlv(SEQUENCE, &bindings),
+        ]
+        .concat();
+        let message = [
+            // SNMPv2c is version 1 on the wire.
+            tlv(INTEGER, &integer(1)),
+            tlv(OCTET_STRING, self.community.as_bytes()),
+            tlv(SNMPV2_TRAP, &pdu),
+        ]
+        .concat();
+        tlv(SEQUENCE, &message)
+    }
+}
+
+impl Notifier for Snmp {
+    fn name(&self) -> &str {
+        "SNMP"
+    }
+
+    fn send(&self, event: &Event, identity: &Identity) -> Result<(), Error> {
+        let (trap, version, reason) = match trap(event) {
+            Some(trap) => trap,
+            None => return Ok(()),
+        };
+        let failed = |error: std::io::Error| {
+            Error::Config(format!(
+                "failed to send a trap to {}: {}",
+                self.target, error
+            ))
+        };
+        let target = self
+            .target
+            .to_socket_addrs()
+            .map_err(failed)?
+            .next()
+            .ok_or_else(|| Error::Config(format!("{} has no addresses", self.target)))?;
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let request = rand::random::<i32>() & i32::MAX;
+        let message = self.message(trap, identity, &version, &reason, request);
+        UdpSocket::bind(local)
+            .and_then(|socket| socket.send_to(&message, target))
+            .map_err(failed)?;
+        Ok(())
+    }
+}
+
+fn binding(name: &[u32], value: Vec<u8>) -> Vec<u8> {
+    tlv(
+        SEQUENCE,
+        &[tlv(OBJECT_IDENTIFIER, &oid(name)), value].concat(),
+    )
+}
+
+/// A tag, the length of the contents in definite form, and the contents.
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .skip_while(|byte| **byte == 0)
+            .cloned()
+            .collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend(bytes);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+/// The shortest two's complement encoding of the integer.
+fn integer(value: i32) -> Vec<u8> {
+    let mut bytes = value.to_be_bytes().to_vec();
+    while bytes.len() > 1
+        && ((bytes[0] == 0x00 && bytes[1] & 0x80 == 0)
+            || (bytes[0] == 0xff && bytes[1] & 0x80 != 0))
+    {
+        bytes.remove(0);
+    }
+    bytes
+}
+
+/// An unsigned integer, which needs a leading zero when its top bit is set.
+fn unsigned(value: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value
+        .to_be_bytes()
+        .iter()
+        .skip_while(|byte| **byte == 0)
+        .cloned()
+        .collect();
+    if bytes.first().is_none_or(|byte| byte & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    bytes
+}
+
+/// The contents of an object identifier: the first two arcs combined, and each arc in base 128
+/// with the top bit set on all but its last byte.
+fn oid(arcs: &[u32]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let first = arcs[0] * 40 + arcs.get(1).cloned().unwrap_or_default();
+    for arc in std::iter::once(first).chain(arcs.iter().skip(2).cloned()) {
+        let mut bytes = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            bytes.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        encoded.extend(bytes.iter().rev());
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_ber() {
+        assert_eq!(oid(SYS_UP_TIME), vec![0x2b, 6, 1, 2, 1, 1, 3, 0]);
+        assert_eq!(
+            oid(&[1, 3, 6, 1, 4, 1, 8072]),
+            vec![0x2b, 6, 1, 4, 1, 0xbf, 0x08]
+        );
+        assert_eq!(integer(0), vec![0]);
+        assert_eq!(integer(128), vec![0, 0x80]);
+        assert_eq!(integer(-1), vec![0xff]);
+        assert_eq!(unsigned(0), vec![0]);
+        assert_eq!(unsigned(0x8000_0000), vec![0, 0x80, 0, 0, 0]);
+        assert_eq!(
+            &tlv(OCTET_STRING, &[0; 200])[..3],
+            &[OCTET_STRING, 0x81, 200]
+        );
+    }
+
+    #[test]
+    fn traps_updates() {
+        let enterprise: Oid = ".1.3.6.1.4.1.8072.9999".parse().expect("valid OID");
+        assert_eq!(enterprise.to_string(), "1.3.6.1.4.1.8072.9999");
+        assert!("1.3.six".parse::<Oid>().is_err());
+        assert!("3.1".parse::<Oid>().is_err());
+
+        let version = semver::Version::parse("4.1.16").expect("valid version");
+        let event = Event::Poisoned {
+            version: version.clone(),
+            reason: "abandoned for 4.1.15".to_string(),
+        };
+        assert_eq!(
+            trap(&event),
+            Some((3, "4.1.16".to_string(), "abandoned for 4.1.15".to_string()))
+        );
+        assert_eq!(trap(&Event::Recovered), None);
+
+        let snmp = Snmp {
+            target: "127.0.0.1:162".to_string(),
+            community: "public".to_string(),
+            enterprise,
+            started: Instant::now(),
+        };
+        let message = snmp.message(3, &Identity::default(), "4.1.16", "abandoned", 1);
+        assert_eq!(&message[..3], &[SEQUENCE, 0x81, message.len() as u8 - 3]);
+        // The version, the community, then the trap.
+        assert_eq!(&message[3..6], &[INTEGER, 1, 1]);
+        assert_eq!(&message[6..14], b"\x04\x06public");
+        assert_eq!(message[14], SNMPV2_TRAP);
+        let trap_oid = tlv(OBJECT_IDENTIFIER, &oid(&snmp.enterprise.child(&[1, 3])));
+        assert!(message
+            .windows(trap_oid.len())
+            .any(|window| window == trap_oid));
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snoozing the cluster's automatic updates through the annotations of the ClusterVersion.
+//!
+//! The snooze is kept on the ClusterVersion, rather than in the operator, so that anyone looking
+//! at the cluster can see until when, and why, its updates were deferred. The operator resumes
+//! them by itself once the snooze has ended.
+
+use crate::options::Options;
+use chrono::Utc;
+use kube::api::PatchParams;
+use kube::client::APIClient;
+use openshift_update::clusterversion;
+use openshift_update::gates::{SNOOZED_UNTIL_ANNOTATION, SNOOZE_REASON_ANNOTATION};
+use openshift_update::Error;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Snooze {
+    #[structopt(
+        parse(try_from_str = humantime::parse_duration),
+        required_unless = "cancel"
+    )]
+    /// How long to defer updates for (e.g. 7d)
+    pub duration: Option<Duration>,
+
+    #[structopt(long = "reason")]
+    /// Why updates are deferred, which is reported along with the snooze
+    pub reason: Option<String>,
+
+    #[structopt(long = "cancel", conflicts_with_all = &["duration", "reason"])]
+    /// End the current snooze now
+    pub cancel: bool,
+}
+
+pub fn run(client: APIClient, options: &Options, command: &Snooze) -> Result<(), Error> {
+    let until = match (command.cancel, command.duration) {
+        (false, Some(duration)) => Some(
+            Utc::now()
+                + chrono::Duration::from_std(duration)
+                    .map_err(|err| Error::Config(format!("invalid duration: {}", err)))?,
+        ),
+        _ => None,
+    };
+
+    let patch = serde_json::json!({
+        "metadata": {
+            "annotations": {
+                SNOOZED_UNTIL_ANNOTATION: until.map(|until| until.to_rfc3339()),
+                SNOOZE_REASON_ANNOTATION: until.and(command.reason.as_ref()),
+            }
+        }
+    });
+    clusterversion::api(client).patch(
+        &options.cluster_version_name,
+        &PatchParams::default(),
+        serde_json::to_vec(&patch)?,
+    )?;
+
+    match until {
+        Some(until) => println!("Snoozed updates until {}", until),
+        None => println!("Cancelled the snooze; updates will resume"),
+    }
+    if options
+        .snooze_until
+        .is_some_and(|configured| until.is_none_or(|until| configured > until))
+    {
+        println!(
+            "Note that --snooze-until defers updates until {}",
+            options.snooze_until.expect("snooze")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,499 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operator state which has to survive restarts.
+//!
+//! The state is kept in a ConfigMap, as one key per fact, so that admins can inspect and edit it
+//! with kubectl. For example, a poisoned version is cleared by deleting its key. Keeping it
+//! there means that restarts and rescheduling don't reset the jitter of pending updates or
+//! repeat events which were already emitted.
+
+use crate::kubeapi;
+use crate::policy::Decision;
+use crate::retry::{self, Backoff};
+use crate::Error;
+use chrono::{DateTime, Utc};
+use kube::api::{PostParams, RawApi};
+use kube::client::APIClient;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Name of the ConfigMap holding the state.
+pub const CONFIG_MAP: &str = "openshift-update-state";
+
+const ATTEMPTED_KEY: &str = "attempted";
+const ATTEMPTED_AT_KEY: &str = "attempted-at";
+const DECISION_KEY: &str = "decision";
+const DRIFT_SINCE_KEY: &str = "drift-since";
+const DRIFT_ALERTED_KEY: &str = "drift-alerted";
+const BLOCKED_KEY: &str = "blocked";
+const PATCHED_AT_KEY: &str = "patched-at";
+const OBSERVED_KEY: &str = "observed";
+const APPLIED_KEY: &str = "applied";
+const APPROVED_KEY: &str = "approved";
+const REGRESSION_KEY: &str = "regression";
+const SNOOZED_UNTIL_KEY: &str = "snoozed-until";
+const SNOOZE_REMINDED_KEY: &str = "snooze-reminded";
+const FIRST_SEEN_PREFIX: &str = "first-seen.";
+const POISONED_PREFIX: &str = "poisoned.";
+const ADOPTED_PREFIX: &str = "adopted.";
+const DURATION_PREFIX: &str = "duration.";
+
+/// How many adoptions are remembered.
+pub const ADOPTIONS_KEPT: usize = 20;
+
+/// How many durations of updates are remembered.
+pub const DURATIONS_KEPT: usize = 10;
+
+/// A candidate held back by one of the gates, which has been reported.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Blocked {
+    pub version: semver::Version,
+    pub gate: String,
+    /// When the gate started holding it back.
+    pub since: DateTime<Utc>,
+    /// When that was last reported, first or as a reminder.
+    #[serde(rename = "reportedAt")]
+    pub reported_at: DateTime<Utc>,
+}
+
+/// When a version became available to the cluster and when the cluster completed updating to it.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Adoption {
+    #[serde(rename = "firstSeen")]
+    pub first_seen: DateTime<Utc>,
+    pub adopted: DateTime<Utc>,
+}
+
+impl Adoption {
+    /// How long the version was available before the cluster had adopted it.
+    pub fn lag(&self) -> chrono::Duration {
+        self.adopted - self.first_seen
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct State {
+    /// The update most recently requested by the operator, until it completes or is abandoned.
+    pub attempted: Option<semver::Version>,
+    /// When the attempted update was first requested.
+    pub attempted_at: Option<DateTime<Utc>>,
+    /// When the desired update was last patched, whether or not it changed.
+    pub patched_at: Option<DateTime<Utc>>,
+    /// The most recent decision.
+    pub decision: Option<Decision>,
+    /// Since when the desired version has differed from the completed one.
+    pub drift_since: Option<DateTime<Utc>>,
+    /// Whether the current drift has been reported.
+    pub drift_alerted: bool,
+    /// The candidate being held back, while one is.
+    pub blocked: Option<Blocked>,
+    /// The end of the snooze which has been reported, while there is one.
+    pub snoozed_until: Option<DateTime<Utc>>,
+    /// Whether the end of the current snooze has been reminded of.
+    pub snooze_reminded: bool,
+    /// The version the cluster was last seen at, for noticing it moving backwards.
+    pub observed: Option<semver::Version>,
+    /// The update the operator most recently saw completed, which stays in the cluster's history
+    /// unless something other than an update replaced it.
+    pub applied: Option<semver::Version>,
+    /// The update most recently approved through the API, with --require-approval.
+    pub approved: Option<semver::Version>,
+    /// How the cluster's version moved without the operator causing it, until that's
+    /// acknowledged.
+    pub regression: Option<String>,
+    /// When each of the candidates was first seen.
+    pub first_seen: BTreeMap<semver::Version, DateTime<Utc>>,
+    /// Versions which failed to apply and were abandoned, along with why. These are never
+    /// selected automatically.
+    pub poisoned: BTreeMap<semver::Version, String>,
+    /// The most recent versions the cluster adopted, after they were seen to be available.
+    pub adopted: BTreeMap<semver::Version, Adoption>,
+    /// How long the most recent updates the operator requested took to complete, in seconds,
+    /// for estimating how long the next ones will take.
+    pub durations: BTreeMap<semver::Version, i64>,
+}
+
+impl State {
+    /// Read the state from the data of its ConfigMap, skipping (and logging) unreadable keys.
+    pub fn from_data(data: &BTreeMap<String, String>) -> State {
+        let mut state = State::default();
+        for (key, value) in data {
+            let parsed = if key == ATTEMPTED_KEY {
+                semver::Version::parse(value)
+                    .map(|version| state.attempted = Some(version))
+                    .map_err(|error| error.to_string())
+            } else if key == ATTEMPTED_AT_KEY {
+                value
+                    .parse()
+                    .map(|time| state.attempted_at = Some(time))
+                    .map_err(|error: chrono::ParseError| error.to_string())
+            } else if key == PATCHED_AT_KEY {
+                value
+                    .parse()
+                    .map(|time| state.patched_at = Some(time))
+                    .map_err(|error: chrono::ParseError| error.to_string())
+            } else if key == OBSERVED_KEY {
+                semver::Version::parse(value)
+                    .map(|version| state.observed = Some(version))
+                    .map_err(|error| error.to_string())
+            } else if key == APPLIED_KEY {
+                semver::Version::parse(value)
+                    .map(|version| state.applied = Some(version))
+                    .map_err(|error| error.to_string())
+            } else if key == APPROVED_KEY {
+                semver::Version::parse(value)
+                    .map(|version| state.approved = Some(version))
+                    .map_err(|error| error.to_string())
+            } else if key == REGRESSION_KEY {
+                state.regression = Some(value.clone());
+                Ok(())
+            } else if key == DECISION_KEY {
+                serde_json::from_str(value)
+                    .map(|decision| state.decision = Some(decision))
+                    .map_err(|error| error.to_string())
+            } else if key == DRIFT_SINCE_KEY {
+                value
+                    .parse()
+                    .map(|time| state.drift_since = Some(time))
+                    .map_err(|error: chrono::ParseError| error.to_string())
+            } else if key == DRIFT_ALERTED_KEY {
+                value
+                    .parse()
+                    .map(|alerted| state.drift_alerted = alerted)
+                    .map_err(|error: std::str::ParseBoolError| error.to_string())
+            } else if key == BLOCKED_KEY {
+                serde_json::from_str(value)
+                    .map(|blocked| state.blocked = Some(blocked))
+                    .map_err(|error| error.to_string())
+            } else if key == SNOOZED_UNTIL_KEY {
+                value
+                    .parse()
+                    .map(|time| state.snoozed_until = Some(time))
+                    .map_err(|error: chrono::ParseError| error.to_string())
+            } else if key == SNOOZE_REMINDED_KEY {
+                value
+                    .parse()
+                    .map(|reminded| state.snooze_reminded = reminded)
+                    .map_err(|error: std::str::ParseBoolError| error.to_string())
+            } else if let Some(version) = key.strip_prefix(FIRST_SEEN_PREFIX) {
+                match (key_version(version), value.parse()) {
+                    (Ok(version), Ok(time)) => {
+                        state.first_seen.insert(version, time);
+                        Ok(())
+                    }
+                    (Err(error), _) => Err(error.to_string()),
+                    (_, Err(error)) => Err(error.to_string()),
+                }
+            } else if let Some(version) = key.strip_prefix(ADOPTED_PREFIX) {
+                match (key_version(version), serde_json::from_str(value)) {
+                    (Ok(version), Ok(adoption)) => {
+                        state.adopted.insert(version, adoption);
+                        Ok(())
+                    }
+                    (Err(error), _) => Err(error.to_string()),
+                    (_, Err(error)) => Err(error.to_string()),
+                }
+            } else if let Some(version) = key.strip_prefix(DURATION_PREFIX) {
+                match (key_version(version), value.parse()) {
+                    (Ok(version), Ok(seconds)) => {
+                        state.durations.insert(version, seconds);
+                        Ok(())
+                    }
+                    (Err(error), _) => Err(error.to_string()),
+                    (_, Err(error)) => Err(error.to_string()),
+                }
+            } else if let Some(version) = key.strip_prefix(POISONED_PREFIX) {
+                key_version(version)
+                    .map(|version| {
+                        state.poisoned.insert(version, value.clone());
+                    })
+                    .map_err(|error| error.to_string())
+            } else {
+                warn!("Ignoring unknown key {} in state", key);
+                Ok(())
+            };
+            if let Err(error) = parsed {
+                warn!("Ignoring invalid key {} in state: {}", key, error);
+            }
+        }
+        state
+    }
+
+    /// The data of the state's ConfigMap.
+    pub fn to_data(&self) -> BTreeMap<String, String> {
+        let mut data = BTreeMap::new();
+        if let Some(attempted) = &self.attempted {
+            data.insert(ATTEMPTED_KEY.to_string(), attempted.to_string());
+        }
+        if let Some(attempted_at) = &self.attempted_at {
+            data.insert(ATTEMPTED_AT_KEY.to_string(), attempted_at.to_rfc3339());
+        }
+        if let Some(patched_at) = &self.patched_at {
+            data.insert(PATCHED_AT_KEY.to_string(), patched_at.to_rfc3339());
+        }
+        if let Some(observed) = &self.observed {
+            data.insert(OBSERVED_KEY.to_string(), observed.to_string());
+        }
+        if let Some(applied) = &self.applied {
+            data.insert(APPLIED_KEY.to_string(), applied.to_string());
+        }
+        if let Some(approved) = &self.approved {
+            data.insert(APPROVED_KEY.to_string(), approved.to_string());
+        }
+        if let Some(regression) = &self.regression {
+            data.insert(REGRESSION_KEY.to_string(), regression.clone());
+        }
+        if let Some(decision) = &self.decision {
+            data.insert(
+                DECISION_KEY.to_string(),
+                serde_json::to_string(decision).expect("Serialize to JSON"),
+            );
+        }
+        if let Some(drift_since) = &self.drift_since {
+            data.insert(DRIFT_SINCE_KEY.to_string(), drift_since.to_rfc3339());
+        }
+        if self.drift_alerted {
+            data.insert(DRIFT_ALERTED_KEY.to_string(), true.to_string());
+        }
+        if let Some(blocked) = &self.blocked {
+            data.insert(
+                BLOCKED_KEY.to_string(),
+                serde_json::to_string(blocked).expect("Serialize to JSON"),
+            );
+        }
+        if let Some(snoozed_until) = &self.snoozed_until {
+            data.insert(SNOOZED_UNTIL_KEY.to_string(), snoozed_until.to_rfc3339());
+        }
+        if self.snooze_reminded {
+            data.insert(SNOOZE_REMINDED_KEY.to_string(), true.to_string());
+        }
+        for (version, seen) in &self.first_seen {
+            data.insert(version_key(FIRST_SEEN_PREFIX, version), seen.to_rfc3339());
+        }
+        for (version, reason) in &self.poisoned {
+            data.insert(version_key(POISONED_PREFIX, version), reason.clone());
+        }
+        for (version, adoption) in &self.adopted {
+            data.insert(
+                version_key(ADOPTED_PREFIX, version),
+                serde_json::to_string(adoption).expect("Serialize to JSON"),
+            );
+        }
+        for (version, seconds) in &self.durations {
+            data.insert(version_key(DURATION_PREFIX, version), seconds.to_string());
+        }
+        data
+    }
+}
+
+/// The key of a fact about `version`. ConfigMap keys may only hold alphanumerics, '-', '_' and
+/// '.', so the '+' before a version's build metadata (as in "4.1.14+1", which is how a custom
+/// release's "4.1.14.1" is parsed) is written as '_', which versions never contain.
+fn version_key(prefix: &str, version: &semver::Version) -> String {
+    format!("{}{}", prefix, version.to_string().replace('+', "_"))
+}
+
+/// The version in a key written by `version_key`.
+fn key_version(version: &str) -> Result<semver::Version, semver::SemVerError> {
+    semver::Version::parse(&version.replace('_', "+"))
+}
+
+pub trait StateStore {
+    fn load(&self) -> Result<State, Error>;
+    fn save(&self, state: &State) -> Result<(), Error>;
+}
+
+/// A StateStore which forgets everything when the operator exits.
+#[derive(Default)]
+pub struct MemoryStore(RefCell<State>);
+
+impl StateStore for MemoryStore {
+    fn load(&self) -> Result<State, Error> {
+        Ok(self.0.borrow().clone())
+    }
+
+    fn save(&self, state: &State) -> Result<(), Error> {
+        self.0.replace(state.clone());
+        Ok(())
+    }
+}
+
+// kube only provides a typed ConfigMap with its openapi feature, which isn't worth the
+// dependency for the one field that's needed.
+#[derive(serde::Deserialize)]
+pub(crate) struct ConfigMap {
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+}
+
+/// A StateStore backed by a ConfigMap, which is created on the first save.
+pub struct ConfigMapStore {
+    client: APIClient,
+    api: RawApi,
+    backoff: Backoff,
+}
+
+impl ConfigMapStore {
+    pub fn new(client: APIClient, namespace: &str, backoff: Backoff) -> ConfigMapStore {
+        ConfigMapStore {
+            client,
+            api: RawApi::v1ConfigMap().within(namespace),
+            backoff,
+        }
+    }
+}
+
+impl StateStore for ConfigMapStore {
+    fn load(&self) -> Result<State, Error> {
+        match self.backoff.retry("get state", || {
+            let request = self.api.get(CONFIG_MAP)?;
+            Ok(kubeapi::call("get", "configmaps", || {
+                self.client.request::<ConfigMap>(request)
+            })?)
+        }) {
+            Ok(config_map) => Ok(State::from_data(&config_map.data)),
+            Err(ref error) if retry::not_found(error) => Ok(State::default()),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn save(&self, state: &State) -> Result<(), Error> {
+        let body = serde_json::to_vec(&serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": { "name": CONFIG_MAP },
+            "data": state.to_data(),
+        }))?;
+        self.backoff.retry("save state", || {
+            let request = self
+                .api
+                .replace(CONFIG_MAP, &PostParams::default(), body.clone())?;
+            match kubeapi::call("update", "configmaps", || {
+                self.client.request::<ConfigMap>(request)
+            }) {
+                Ok(_) => Ok(()),
+                Err(error) => {
+                    let error = Error::from(error);
+                    if !retry::not_found(&error) {
+                        return Err(error);
+                    }
+                    let request = self.api.create(&PostParams::default(), body.clone())?;
+                    kubeapi::call("create", "configmaps", || {
+                        self.client.request::<ConfigMap>(request)
+                    })?;
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut state = State {
+            attempted: Some(semver::Version::parse("4.1.16").expect("version")),
+            attempted_at: Some("2019-09-17T00:05:11Z".parse().expect("time")),
+            patched_at: Some("2019-09-17T00:06:00Z".parse().expect("time")),
+            decision: Some(Decision::InProgress),
+            drift_since: Some("2019-09-17T00:05:11Z".parse().expect("time")),
+            drift_alerted: true,
+            blocked: Some(Blocked {
+                version: semver::Version::parse("4.1.17").expect("version"),
+                gate: "alerts".to_string(),
+                since: "2019-09-18T12:00:00Z".parse().expect("time"),
+                reported_at: "2019-09-19T12:00:00Z".parse().expect("time"),
+            }),
+            snoozed_until: Some("2019-09-24T00:00:00Z".parse().expect("time")),
+            snooze_reminded: true,
+            observed: Some(semver::Version::parse("4.1.15").expect("version")),
+            applied: Some(semver::Version::parse("4.1.14").expect("version")),
+            approved: Some(semver::Version::parse("4.1.17").expect("version")),
+            regression: Some("moved backwards from 4.1.16 to 4.1.15".to_string()),
+            ..Default::default()
+        };
+        state.first_seen.insert(
+            semver::Version::parse("4.1.17").expect("version"),
+            "2019-09-18T12:00:00Z".parse().expect("time"),
+        );
+        state.poisoned.insert(
+            semver::Version::parse("4.1.15").expect("version"),
+            "abandoned for 4.1.14".to_string(),
+        );
+        state.adopted.insert(
+            semver::Version::parse("4.1.14").expect("version"),
+            Adoption {
+                first_seen: "2019-09-10T00:00:00Z".parse().expect("time"),
+                adopted: "2019-09-12T06:00:00Z".parse().expect("time"),
+            },
+        );
+        state
+            .durations
+            .insert(semver::Version::parse("4.1.14").expect("version"), 3600);
+
+        let data = state.to_data();
+        assert_eq!(data["attempted"], "4.1.16");
+        assert_eq!(data["poisoned.4.1.15"], "abandoned for 4.1.14");
+        assert_eq!(data["first-seen.4.1.17"], "2019-09-18T12:00:00+00:00");
+        assert_eq!(State::from_data(&data), state);
+    }
+
+    #[test]
+    fn encodes_versions_in_keys() {
+        let mut state = State::default();
+        let custom = semver::Version::parse("4.1.14+1.ci").expect("version");
+        state
+            .poisoned
+            .insert(custom.clone(), "abandoned".to_string());
+        state.first_seen.insert(
+            semver::Version::parse("4.2.0-rc.1").expect("version"),
+            "2019-09-18T12:00:00Z".parse().expect("time"),
+        );
+        state.durations.insert(custom.clone(), 3600);
+
+        // As the API server validates ConfigMap keys.
+        let data = state.to_data();
+        for key in data.keys() {
+            assert!(
+                key.len() <= 253
+                    && key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-._".contains(c)),
+                "{} isn't a valid key",
+                key
+            );
+        }
+        assert_eq!(data["poisoned.4.1.14_1.ci"], "abandoned");
+        let read = State::from_data(&data);
+        assert_eq!(read, state);
+        let poisoned: Vec<_> = read
+            .poisoned
+            .keys()
+            .map(|version| version.to_string())
+            .collect();
+        assert_eq!(poisoned, vec!["4.1.14+1.ci"]);
+    }
+
+    #[test]
+    fn ignores_invalid_keys() {
+        let mut data = BTreeMap::new();
+        data.insert("poisoned.latest".to_string(), "".to_string());
+        data.insert("unknown".to_string(), "".to_string());
+        assert_eq!(State::from_data(&data), State::default());
+    }
+}
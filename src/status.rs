@@ -0,0 +1,190 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The operator's most recent decision and what led to it, as served by the API.
+
+use crate::explain::Check;
+use crate::watch::{self, ClusterOperator};
+use chrono::{DateTime, Utc};
+#[cfg(feature = "fleet")]
+use openshift_update::clusterversion::HistoricalEntry;
+use openshift_update::durations::{Durations, Estimate};
+use openshift_update::gates::Snooze;
+use openshift_update::policy::{Decision, DecisionReason, Queued};
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct Status {
+    pub version: Option<String>,
+    #[serde(rename = "singleNode")]
+    pub single_node: bool,
+    pub paused: bool,
+    /// Until when, and why, every automatic update is deferred, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snoozed: Option<Snooze>,
+    pub decision: Option<Decision>,
+    /// The code of why the decision was made.
+    pub reason: Option<DecisionReason>,
+    pub candidate: Option<semver::Version>,
+    /// Every candidate, in the order they would be applied, with what each is waiting on.
+    pub queue: Vec<Queued>,
+    #[serde(rename = "notBefore")]
+    pub not_before: Option<DateTime<Utc>>,
+    pub approved: Option<semver::Version>,
+    #[serde(rename = "evaluatedAt")]
+    pub evaluated_at: Option<DateTime<Utc>>,
+    pub ready: bool,
+    #[serde(rename = "consecutiveFailures")]
+    pub consecutive_failures: u32,
+    /// Why the cluster failed to retrieve its available updates, if it did.
+    #[serde(rename = "retrievalFailure", skip_serializing_if = "Option::is_none")]
+    pub retrieval_failure: Option<String>,
+    /// The results of verifying the cluster after the last update, if it was verified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Vec<Check>>,
+    /// How long the last update the operator requested took, if one has completed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub durations: Option<Durations>,
+    /// How much longer the update in progress is expected to take, if one is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimate: Option<Estimate>,
+    /// The version and availability of each ClusterOperator, as of the last evaluation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub operators: Vec<Operator>,
+    /// With --acm, --hosted-clusters or --ocm, where each of the fleet's clusters stands, as of
+    /// the last pass over the fleet.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub clusters: Vec<FleetCluster>,
+}
+
+/// Where one of a fleet's clusters stands.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct FleetCluster {
+    pub name: String,
+    pub version: Option<String>,
+    /// The update which would be requested next, if there is one.
+    pub candidate: Option<semver::Version>,
+    /// What holds back the cluster's update, if anything (e.g. "paused" or "jitter").
+    pub gate: Option<String>,
+    /// Why, or what was done instead.
+    pub reason: Option<String>,
+    /// The last update to have finished, and whether it completed (e.g. "4.1.15 Completed").
+    #[serde(rename = "lastResult")]
+    pub last_result: Option<String>,
+}
+
+#[cfg(feature = "fleet")]
+impl FleetCluster {
+    pub fn new(name: &str) -> FleetCluster {
+        FleetCluster {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the gate holding back the cluster's update.
+    pub fn hold(&mut self, gate: &str, reason: String) {
+        self.gate = Some(gate.to_string());
+        self.reason = Some(reason);
+    }
+}
+
+/// The last update in the history to have finished, and its state.
+#[cfg(feature = "fleet")]
+pub fn last_result(history: &[HistoricalEntry]) -> Option<String> {
+    let entry = history
+        .iter()
+        .find(|entry| entry.completion_time.is_some())?;
+    Some(format!(
+        "{} {}",
+        entry.version.as_deref().unwrap_or("-"),
+        entry.state.as_deref().unwrap_or("Unknown")
+    ))
+}
+
+/// Where one of the ClusterOperators is, for telling whether all of them reached the version.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Operator {
+    pub name: String,
+    pub version: Option<String>,
+    /// Whether the operator reports the version the cluster is at or is updating to.
+    #[serde(rename = "atTarget")]
+    pub at_target: bool,
+    pub available: String,
+    pub progressing: String,
+    pub degraded: String,
+}
+
+impl Operator {
+    pub fn new(operator: &ClusterOperator, target: Option<&str>) -> Operator {
+        let version = watch::operator_version(operator);
+        Operator {
+            name: operator.metadata.name.clone(),
+            version: version.map(String::from),
+            at_target: version.is_some() && version == target,
+            available: watch::condition(operator, "Available").to_string(),
+            progressing: watch::condition(operator, "Progressing").to_string(),
+            degraded: watch::condition(operator, "Degraded").to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_operators_with_the_target() {
+        let operator: ClusterOperator = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "dns" },
+            "spec": {},
+            "status": {
+                "conditions": [{ "type": "Available", "status": "True" }],
+                "versions": [{ "name": "operator", "version": "4.1.16" }],
+            },
+        }))
+        .expect("valid operator");
+        let current = Operator::new(&operator, Some("4.1.16"));
+        assert!(current.at_target);
+        assert_eq!(current.version.as_deref(), Some("4.1.16"));
+        assert_eq!(current.available, "True");
+        assert_eq!(current.degraded, "Unknown");
+        assert!(!Operator::new(&operator, Some("4.2.0")).at_target);
+        assert!(!Operator::new(&operator, None).at_target);
+    }
+
+    #[test]
+    #[cfg(feature = "fleet")]
+    fn finds_the_last_result() {
+        let history: Vec<HistoricalEntry> = serde_json::from_value(serde_json::json!([
+            { "state": "Partial", "version": "4.1.16" },
+            {
+                "state": "Partial",
+                "version": "4.1.15",
+                "completionTime": "2019-09-17T00:00:00Z",
+            },
+            {
+                "state": "Completed",
+                "version": "4.1.14",
+                "completionTime": "2019-09-10T00:00:00Z",
+            },
+        ]))
+        .expect("valid history");
+        assert_eq!(last_result(&history).as_deref(), Some("4.1.15 Partial"));
+        assert_eq!(
+            last_result(&history[2..]).as_deref(),
+            Some("4.1.14 Completed")
+        );
+        assert_eq!(last_result(&history[..1]), None);
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Manual steps which have to be acknowledged before particular updates.
+//!
+//! Some updates need something done outside of the cluster first, like a firewall change for a
+//! new endpoint or a vendor notified of a new minor version. Each such step is named along with
+//! the change of minor version it precedes, and updates making that change are blocked until the
+//! step has been acknowledged. A step is acknowledged either by annotating the ClusterVersion
+//! with `manual-steps.upgrade.crawford.dev/<name>` set to the minor version being updated to, or
+//! the way OpenShift's own acknowledgements are given: by setting `<name>` to "true" in the
+//! openshift-config/admin-acks ConfigMap. An annotation only acknowledges the one change of minor
+//! version it names, so a step which precedes several has to be acknowledged for each.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::kubeapi;
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use crate::retry::{self, Backoff};
+use crate::state::ConfigMap;
+use crate::Error;
+use kube::api::{ObjectMeta, RawApi};
+use kube::client::APIClient;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Prefix of the annotations of the ClusterVersion acknowledging steps, followed by the name of
+/// the step.
+pub const ACKNOWLEDGED_PREFIX: &str = "manual-steps.upgrade.crawford.dev/";
+
+/// The ConfigMap in which cluster administrators acknowledge what OpenShift asks them to.
+const ADMIN_ACKS_NAMESPACE: &str = "openshift-config";
+const ADMIN_ACKS: &str = "admin-acks";
+
+/// A step which has to be acknowledged before updating from the minor version `from` (or from
+/// any other, without it) to the minor version `to`. Given as "<name>=<from>-><to>" or
+/// "<name>=<to>", e.g. "firewall-change=4.12->4.13".
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManualStep {
+    pub name: String,
+    pub from: Option<(u64, u64)>,
+    pub to: (u64, u64),
+}
+
+impl FromStr for ManualStep {
+    type Err = String;
+
+    fn from_str(step: &str) -> Result<Self, Self::Err> {
+        let (name, transition) = step
+            .split_once('=')
+            .ok_or_else(|| format!("{:?} is not of the form <name>=<from>-><to>", step))?;
+        // The name is the name part of an annotation's key.
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.is_empty()
+            || name.len() > 63
+            || !name.chars().all(valid)
+            || !name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            || !name.ends_with(|c: char| c.is_ascii_alphanumeric())
+        {
+            return Err(format!("{:?} is not a valid name of a step", name));
+        }
+        let minor = |version: &str| {
+            minor(version)
+                .ok_or_else(|| format!("{:?} is not a minor version (e.g. 4.13)", version))
+        };
+        let (from, to) = match transition.split_once("->") {
+            Some(("*", to)) => (None, minor(to)?),
+            Some((from, to)) => (Some(minor(from)?), minor(to)?),
+            None => (None, minor(transition)?),
+        };
+        Ok(ManualStep {
+            name: name.to_string(),
+            from,
+            to,
+        })
+    }
+}
+
+impl fmt::Display for ManualStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.from {
+            Some((major, minor)) => write!(f, "{}={}.{}->", self.name, major, minor)?,
+            None => write!(f, "{}=", self.name)?,
+        }
+        write!(f, "{}.{}", self.to.0, self.to.1)
+    }
+}
+
+impl ManualStep {
+    /// Whether the step has to be done before updating from `current` to `update`. Without a
+    /// `from`, only updates into the `to` minor version need it, not those within it.
+    pub fn precedes(&self, current: Option<&str>, update: &semver::Version) -> bool {
+        let current = current.and_then(minor);
+        (update.major, update.minor) == self.to
+            && match self.from {
+                Some(from) => current == Some(from),
+                None => current != Some(self.to),
+            }
+    }
+
+    /// Whether the step has been acknowledged for the update to `update`.
+    pub fn acknowledged(&self, current: &ClusterState, update: &semver::Version) -> bool {
+        current.admin_acks.contains(&self.name)
+            || current
+                .step_acks
+                .get(&self.name)
+                .and_then(|version| minor(version))
+                == Some((update.major, update.minor))
+    }
+}
+
+/// The major and minor parts of a version (e.g. "4.13" or "4.13.2").
+fn minor(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.split('-').next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Returns the versions the steps were acknowledged for through the object's annotations, by the
+/// name of the step.
+pub fn acknowledgements(metadata: &ObjectMeta) -> HashMap<String, String> {
+    metadata
+        .annotations
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(ACKNOWLEDGED_PREFIX)?;
+            Some((name.to_string(), value.clone()))
+        })
+        .collect()
+}
+
+/// Why the update must wait for manual steps, if it must.
+pub fn pending(
+    steps: &[ManualStep],
+    current: &ClusterState,
+    update: &semver::Version,
+) -> Option<String> {
+    let pending: Vec<&str> = steps
+        .iter()
+        .filter(|step| step.precedes(current.version.as_deref(), update))
+        .filter(|step| !step.acknowledged(current, update))
+        .map(|step| step.name.as_str())
+        .collect();
+    if pending.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "manual steps not yet acknowledged: {}; once each is done, annotate the ClusterVersion \
+         with {}<name>={}.{} or set <name> to \"true\" in {}/{}",
+        pending.join(", "),
+        ACKNOWLEDGED_PREFIX,
+        update.major,
+        update.minor,
+        ADMIN_ACKS_NAMESPACE,
+        ADMIN_ACKS
+    ))
+}
+
+/// Access to the cluster administrators' acknowledgements, which allows decisions to be
+/// exercised against fixtures.
+pub trait AckClient {
+    /// The keys of the admin-acks ConfigMap which are set to "true".
+    fn admin_acks(&self) -> Result<HashSet<String>, Error>;
+}
+
+pub struct KubeAcks {
+    pub client: APIClient,
+    pub backoff: Backoff,
+}
+
+impl AckClient for KubeAcks {
+    fn admin_acks(&self) -> Result<HashSet<String>, Error> {
+        let result = self.backoff.retry("get the admin acks", || {
+            let request = RawApi::v1ConfigMap()
+                .within(ADMIN_ACKS_NAMESPACE)
+                .get(ADMIN_ACKS)?;
+            Ok(kubeapi::call("get", "configmaps", || {
+                self.client.request::<ConfigMap>(request)
+            })?)
+        });
+        match result {
+            Ok(config_map) => Ok(config_map
+                .data
+                .into_iter()
+                .filter(|(_, value)| value.trim() == "true")
+                .map(|(key, _)| key)
+                .collect()),
+            // Clusters from before admin acks were introduced don't have the ConfigMap.
+            Err(ref error) if retry::not_found(error) => Ok(HashSet::new()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Block updates until the manual steps which precede them have been acknowledged.
+pub struct ManualSteps {
+    pub inner: Box<dyn UpgradePolicy>,
+    pub steps: Vec<ManualStep>,
+}
+
+impl UpgradePolicy for ManualSteps {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } => match pending(&self.steps, current, &update.version) {
+                Some(reason) => Decision::Blocked {
+                    reason,
+                    update,
+                    gate: "manual-steps".to_string(),
+                },
+                None => Decision::Apply { update },
+            },
+            decision => decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clusterversion::ClusterVersion;
+    use crate::policy::Latest;
+
+    fn version(version: &str) -> semver::Version {
+        semver::Version::parse(version).expect("valid version")
+    }
+
+    #[test]
+    fn parses_steps() {
+        let step: ManualStep = "firewall-change=4.12->4.13".parse().expect("valid step");
+        assert_eq!(step.from, Some((4, 12)));
+        assert_eq!(step.to, (4, 13));
+        assert_eq!(step.to_string(), "firewall-change=4.12->4.13");
+        let step: ManualStep = "vendor=*->4.13".parse().expect("valid step");
+        assert_eq!(step.from, None);
+        assert_eq!(step.to_string(), "vendor=4.13");
+        assert!("vendor".parse::<ManualStep>().is_err());
+        assert!("-vendor=4.13".parse::<ManualStep>().is_err());
+        assert!("vendor=latest".parse::<ManualStep>().is_err());
+    }
+
+    #[test]
+    fn precedes_changes_of_minor_version() {
+        let step: ManualStep = "vendor=4.2".parse().expect("valid step");
+        assert!(step.precedes(Some("4.1.14"), &version("4.2.0")));
+        assert!(!step.precedes(Some("4.2.0"), &version("4.2.1")));
+        assert!(!step.precedes(Some("4.1.14"), &version("4.1.15")));
+        let step: ManualStep = "firewall=4.0->4.2".parse().expect("valid step");
+        assert!(!step.precedes(Some("4.1.14"), &version("4.2.0")));
+    }
+
+    #[test]
+    fn blocks_until_acknowledged() {
+        let mut cluster: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let now = "2019-09-17T00:00:00Z".parse().expect("valid time");
+        let candidates = vec![ClusterUpdate {
+            version: version("4.2.0"),
+            image: "quay.io/openshift-release-dev/ocp-release:4.2.0".to_string(),
+            force: false,
+            raw_version: None,
+        }];
+        let policy = ManualSteps {
+            inner: Box::new(Latest),
+            steps: vec![
+                "firewall=4.1->4.2".parse().expect("valid step"),
+                "vendor=4.2".parse().expect("valid step"),
+            ],
+        };
+
+        let mut current = ClusterState::new(&cluster, now);
+        match policy.evaluate(&current, &candidates) {
+            Decision::Blocked { gate, reason, .. } => {
+                assert_eq!(gate, "manual-steps");
+                assert!(reason.starts_with("manual steps not yet acknowledged: firewall, vendor;"));
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+
+        // An acknowledgement of another minor version doesn't count.
+        cluster.metadata.annotations.insert(
+            format!("{}firewall", ACKNOWLEDGED_PREFIX),
+            "4.1".to_string(),
+        );
+        current.step_acks = acknowledgements(&cluster.metadata);
+        current.admin_acks.insert("vendor".to_string());
+        assert!(pending(&policy.steps, &current, &candidates[0].version)
+            .is_some_and(|reason| reason.contains(": firewall;")));
+
+        cluster.metadata.annotations.insert(
+            format!("{}firewall", ACKNOWLEDGED_PREFIX),
+            "4.2".to_string(),
+        );
+        current.step_acks = acknowledgements(&cluster.metadata);
+        assert!(matches!(
+            policy.evaluate(&current, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+}
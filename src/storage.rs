@@ -0,0 +1,293 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compatibility of the cluster's storage integrations with the releases it may update to.
+//!
+//! Releases drop support for storage integrations from time to time (most notably the in-tree
+//! volume plugins, as they are migrated to CSI), and a cluster still relying on one must not be
+//! updated past that point until it has moved off it. The matrix of when each provisioner is
+//! deprecated and removed is supplied as a YAML file:
+//!
+//! ```yaml
+//! - provisioner: kubernetes.io/cinder
+//!   deprecatedIn: "4.10"
+//!   removedIn: "4.11"
+//!   message: migrate the volumes to cinder.csi.openstack.org first
+//! ```
+//!
+//! Each provisioner of a StorageClass and each installed CSIDriver is checked against it. Updates
+//! to a minor version which removes one of them are blocked, while deprecations are only
+//! reported.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::kubeapi;
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use crate::retry::Backoff;
+use crate::Error;
+use kube::api::{ListParams, RawApi};
+use kube::client::APIClient;
+use std::fs;
+
+/// When support for a provisioner is deprecated and removed, each as a minor version (e.g.
+/// "4.11").
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct Rule {
+    pub provisioner: String,
+    #[serde(rename = "deprecatedIn")]
+    pub deprecated_in: Option<String>,
+    #[serde(rename = "removedIn")]
+    pub removed_in: Option<String>,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Matrix {
+    pub rules: Vec<Rule>,
+}
+
+/// The provisioners which the update removes or deprecates, each with its rule's message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Verdict {
+    pub removed: Vec<String>,
+    pub deprecated: Vec<String>,
+}
+
+/// StorageClasses and CSIDrivers, of which only the names and provisioners are needed.
+#[derive(serde::Deserialize)]
+struct List {
+    items: Vec<Item>,
+}
+
+#[derive(serde::Deserialize)]
+struct Item {
+    metadata: Metadata,
+    provisioner: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Metadata {
+    name: String,
+}
+
+impl Matrix {
+    /// Read the matrix from a YAML file.
+    pub fn read(path: &str) -> Result<Matrix, String> {
+        let file = fs::read_to_string(path)
+            .map_err(|error| format!("failed to read {}: {}", path, error))?;
+        Matrix::parse(&file).map_err(|error| format!("{}: {}", path, error))
+    }
+
+    pub fn parse(yaml: &str) -> Result<Matrix, String> {
+        let rules: Vec<Rule> = serde_yaml::from_str(yaml).map_err(|error| error.to_string())?;
+        for rule in &rules {
+            for version in rule.deprecated_in.iter().chain(&rule.removed_in) {
+                if minor(version).is_none() {
+                    return Err(format!(
+                        "{}: {:?} is not a minor version (e.g. \"4.11\")",
+                        rule.provisioner, version
+                    ));
+                }
+            }
+        }
+        Ok(Matrix { rules })
+    }
+
+    /// Judge an update to `target` of a cluster using the provisioners.
+    pub fn verdict(&self, provisioners: &[String], target: &semver::Version) -> Verdict {
+        let reached = |version: &Option<String>| {
+            version
+                .as_deref()
+                .and_then(minor)
+                .is_some_and(|version| (target.major, target.minor) >= version)
+        };
+        let describe = |rule: &Rule| match rule.message.as_str() {
+            "" => rule.provisioner.clone(),
+            message => format!("{} ({})", rule.provisioner, message),
+        };
+
+        let mut verdict = Verdict::default();
+        for rule in &self.rules {
+            if !provisioners.contains(&rule.provisioner) {
+                continue;
+            }
+            if reached(&rule.removed_in) {
+                verdict.removed.push(describe(rule));
+            } else if reached(&rule.deprecated_in) {
+                verdict.deprecated.push(describe(rule));
+            }
+        }
+        verdict
+    }
+}
+
+/// The major and minor components of a version such as "4.11".
+fn minor(version: &str) -> Option<(u64, u64)> {
+    let (major, minor) = version.trim().split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Access to the cluster's storage integrations, which allows decisions to be exercised against
+/// fixtures.
+pub trait StorageClient {
+    /// The provisioners of the cluster's StorageClasses and the names of its CSIDrivers, sorted
+    /// and without duplicates.
+    fn provisioners(&self) -> Result<Vec<String>, Error>;
+}
+
+pub struct KubeStorage {
+    pub client: APIClient,
+    pub backoff: Backoff,
+}
+
+impl StorageClient for KubeStorage {
+    fn provisioners(&self) -> Result<Vec<String>, Error> {
+        provisioners(&self.client, &self.backoff)
+    }
+}
+
+fn provisioners(client: &APIClient, backoff: &Backoff) -> Result<Vec<String>, Error> {
+    let list = |resource: &str| {
+        backoff.retry(&format!("list {}", resource), || {
+            let request = RawApi::customResource(resource)
+                .group("storage.k8s.io")
+                .version("v1")
+                .list(&ListParams::default())?;
+            let list = kubeapi::call("list", resource, || client.request::<List>(request))?;
+            Ok(list.items)
+        })
+    };
+
+    let mut provisioners: Vec<String> = list("storageclasses")?
+        .into_iter()
+        .filter_map(|class| class.provisioner)
+        .collect();
+    provisioners.extend(
+        list("csidrivers")?
+            .into_iter()
+            .map(|driver| driver.metadata.name),
+    );
+    provisioners.sort();
+    provisioners.dedup();
+    Ok(provisioners)
+}
+
+/// Block updates to a version which has removed support for one of the cluster's provisioners.
+pub struct Storage {
+    pub inner: Box<dyn UpgradePolicy>,
+    pub matrix: Matrix,
+}
+
+impl UpgradePolicy for Storage {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } => {
+                let verdict = self
+                    .matrix
+                    .verdict(&current.storage_provisioners, &update.version);
+                if verdict.removed.is_empty() {
+                    Decision::Apply { update }
+                } else {
+                    Decision::Blocked {
+                        reason: format!(
+                            "{} no longer supports {}",
+                            update.version,
+                            verdict.removed.join(", ")
+                        ),
+                        update,
+                        gate: "storage".to_string(),
+                    }
+                }
+            }
+            decision => decision,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clusterversion::ClusterVersion;
+    use crate::policy::Latest;
+
+    const MATRIX: &str = r#"
+- provisioner: kubernetes.io/cinder
+  deprecatedIn: "4.1"
+  removedIn: "4.2"
+  message: migrate to cinder.csi.openstack.org
+- provisioner: kubernetes.io/aws-ebs
+  removedIn: "4.1"
+"#;
+
+    #[test]
+    fn judges_provisioners() {
+        let matrix = Matrix::parse(MATRIX).expect("valid matrix");
+        let provisioners = vec!["kubernetes.io/cinder".to_string()];
+        let version = |version| semver::Version::parse(version).expect("version");
+
+        assert_eq!(
+            matrix.verdict(&provisioners, &version("4.0.9")),
+            Verdict::default()
+        );
+        assert_eq!(
+            matrix.verdict(&provisioners, &version("4.1.16")).deprecated,
+            vec!["kubernetes.io/cinder (migrate to cinder.csi.openstack.org)"]
+        );
+        assert_eq!(
+            matrix.verdict(&provisioners, &version("4.2.0")).removed,
+            vec!["kubernetes.io/cinder (migrate to cinder.csi.openstack.org)"]
+        );
+
+        assert!(Matrix::parse("- provisioner: x\n  removedIn: next\n").is_err());
+    }
+
+    #[test]
+    fn blocks_removed_provisioners() {
+        let version: ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        let mut state = ClusterState::new(
+            &version,
+            "2019-09-17T00:00:00Z".parse().expect("valid time"),
+        );
+        let policy = Storage {
+            inner: Box::new(Latest),
+            matrix: Matrix::parse(MATRIX).expect("valid matrix"),
+        };
+
+        state.storage_provisioners = vec!["kubernetes.io/cinder".to_string()];
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+
+        state
+            .storage_provisioners
+            .push("kubernetes.io/aws-ebs".to_string());
+        assert_eq!(
+            policy.evaluate(&state, &candidates),
+            Decision::Blocked {
+                update: candidates.iter().max().cloned().expect("candidate"),
+                gate: "storage".to_string(),
+                reason: "4.1.16 no longer supports kubernetes.io/aws-ebs".to_string(),
+            }
+        );
+    }
+}
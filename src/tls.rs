@@ -0,0 +1,221 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS for the admission webhooks, which the API server only calls over HTTPS.
+//!
+//! The certificate and its key are loaded again for every connection, so that a certificate which
+//! has been rotated (e.g. by the service CA operator, which rewrites its Secret well before the
+//! old certificate expires) is served without restarting the operator.
+
+use futures::{Async, Future, Poll};
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod,
+    SslStream,
+};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The certificate (followed by any intermediates) and key to serve, as PEM files.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Identity {
+    fn acceptor(&self) -> Result<SslAcceptor, ErrorStack> {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        acceptor.set_certificate_chain_file(&self.cert)?;
+        acceptor.set_private_key_file(&self.key, SslFiletype::PEM)?;
+        acceptor.check_private_key()?;
+        Ok(acceptor.build())
+    }
+
+    /// Make sure that the certificate and key can be loaded and belong together, so that a
+    /// mistake is reported when the operator starts rather than by the API server.
+    pub fn check(&self) -> Result<(), String> {
+        self.acceptor().map(|_| ()).map_err(|error| {
+            format!(
+                "unable to load {} and {}: {}",
+                self.cert.display(),
+                self.key.display(),
+                error
+            )
+        })
+    }
+
+    /// The handshake of a connection which has just been accepted.
+    pub fn accept<S: Read + Write>(&self, stream: S) -> Handshake<S> {
+        match self.acceptor() {
+            Ok(acceptor) => Handshake(State::Start(acceptor, stream)),
+            Err(error) => Handshake(State::Failed(io::Error::other(error))),
+        }
+    }
+}
+
+enum State<S> {
+    Start(SslAcceptor, S),
+    Handshaking(MidHandshakeSslStream<S>),
+    Failed(io::Error),
+    Done,
+}
+
+/// A TLS handshake, which finishes with the stream to serve.
+///
+/// The handshake is only started once the future is first polled, so that the socket waits on
+/// the task serving its connection rather than the one accepting connections.
+pub struct Handshake<S>(State<S>);
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream<S>, io::Error> {
+        let result = match mem::replace(&mut self.0, State::Done) {
+            State::Start(acceptor, stream) => acceptor.accept(stream),
+            State::Handshaking(stream) => stream.handshake(),
+            State::Failed(error) => return Err(error),
+            State::Done => panic!("handshake polled after it finished"),
+        };
+        match result {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            // The socket would have blocked, and wakes the task once it's ready.
+            Err(HandshakeError::WouldBlock(stream)) => {
+                self.0 = State::Handshaking(stream);
+                Ok(Async::NotReady)
+            }
+            Err(HandshakeError::SetupFailure(error)) => Err(io::Error::other(error)),
+            Err(HandshakeError::Failure(stream)) => Err(io::Error::other(stream.into_error())),
+        }
+    }
+}
+
+/// A connection over which TLS has been negotiated.
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => {}
+            Err(ref error)
+                if error.code() == ErrorCode::WANT_READ
+                    || error.code() == ErrorCode::WANT_WRITE =>
+            {
+                return Ok(Async::NotReady)
+            }
+            // The client may well have closed the connection already.
+            Err(error) => debug!("Failed to shut down TLS: {}", error),
+        }
+        self.0.get_mut().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use openssl::x509::{X509Name, X509};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    /// A self-signed certificate and its key, written to a directory of their own.
+    fn identity(name: &str) -> Identity {
+        let key = PKey::from_rsa(Rsa::generate(2048).expect("generate key")).expect("key");
+        let mut subject = X509Name::builder().expect("name");
+        subject.append_entry_by_text("CN", "localhost").expect("CN");
+        let subject = subject.build();
+        let mut cert = X509::builder().expect("certificate");
+        cert.set_version(2).expect("version");
+        cert.set_subject_name(&subject).expect("subject");
+        cert.set_issuer_name(&subject).expect("issuer");
+        cert.set_pubkey(&key).expect("public key");
+        cert.set_not_before(&Asn1Time::days_from_now(0).expect("time"))
+            .expect("not before");
+        cert.set_not_after(&Asn1Time::days_from_now(1).expect("time"))
+            .expect("not after");
+        cert.sign(&key, MessageDigest::sha256()).expect("sign");
+
+        let dir =
+            std::env::temp_dir().join(format!("openshift-update-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create directory");
+        let identity = Identity {
+            cert: dir.join("tls.crt"),
+            key: dir.join("tls.key"),
+        };
+        std::fs::write(&identity.cert, cert.build().to_pem().expect("PEM")).expect("write");
+        std::fs::write(&identity.key, key.private_key_to_pem_pkcs8().expect("PEM")).expect("write");
+        identity
+    }
+
+    #[test]
+    fn serves_tls() {
+        let identity = identity("serves-tls");
+        identity.check().expect("valid identity");
+        let (server, client) = UnixStream::pair().expect("socket pair");
+        let client = thread::spawn(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).expect("connector");
+            connector.set_verify(SslVerifyMode::NONE);
+            let mut stream = connector
+                .build()
+                .connect("localhost", client)
+                .expect("handshake");
+            stream.write_all(b"ping").expect("write");
+            let mut reply = [0; 4];
+            stream.read_exact(&mut reply).expect("read");
+            reply
+        });
+
+        // A blocking socket never would block, so the handshake finishes in one poll.
+        let mut stream = match identity.accept(server).poll().expect("handshake") {
+            Async::Ready(stream) => stream,
+            Async::NotReady => panic!("handshake didn't finish"),
+        };
+        let mut request = [0; 4];
+        stream.read_exact(&mut request).expect("read");
+        assert_eq!(&request, b"ping");
+        stream.write_all(b"pong").expect("write");
+        assert_eq!(&client.join().expect("client"), b"pong");
+
+        let mismatched = Identity {
+            key: self::identity("mismatched").key,
+            ..identity
+        };
+        assert!(mismatched.check().is_err());
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Credentials fetched from HashiCorp Vault.
+//!
+//! The operator logs into Vault with its service account token through the Kubernetes auth
+//! method and writes each configured secret to a file, where it is picked up like any other
+//! credential file (see [`crate::secret`]). The secrets are fetched again before the shortest of
+//! their leases (and the login's) runs out, so nothing long-lived has to be mounted into the pod.
+//!
+//! A secret is given as `PATH#KEY=FILE`, for example
+//! `secret/data/openshift-update#ocm-token=/run/secrets/ocm-token`. Both versions of the
+//! key/value secrets engine are understood.
+
+use crate::retry::Backoff;
+use crate::Error;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// The token of the pod's service account, which Vault reviews to log in.
+pub const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// How often the secrets are fetched when neither they nor the login have a lease.
+const DEFAULT_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// The shortest time between two fetches, however short the leases are.
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// A secret to fetch from Vault and the file to write it to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    pub path: String,
+    pub key: String,
+    pub file: PathBuf,
+}
+
+impl FromStr for Mapping {
+    type Err = String;
+
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected PATH#KEY=FILE, not {:?}", mapping);
+        let (secret, file) = mapping.split_once('=').ok_or_else(invalid)?;
+        let (path, key) = secret.split_once('#').ok_or_else(invalid)?;
+        if path.is_empty() || key.is_empty() || file.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Mapping {
+            path: path.trim_matches('/').to_string(),
+            key: key.to_string(),
+            file: PathBuf::from(file),
+        })
+    }
+}
+
+pub struct Vault {
+    pub http: reqwest::Client,
+    /// Base URL of Vault, e.g. "https://vault.example.com:8200".
+    pub addr: String,
+    /// The role to log in as.
+    pub role: String,
+    /// Where the Kubernetes auth method is mounted.
+    pub mount: String,
+    pub backoff: Backoff,
+}
+
+#[derive(serde::Deserialize)]
+struct LoginResponse {
+    auth: Auth,
+}
+
+#[derive(serde::Deserialize)]
+struct Auth {
+    client_token: String,
+    #[serde(default)]
+    lease_duration: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct SecretResponse {
+    #[serde(default)]
+    lease_duration: u64,
+    data: serde_json::Value,
+}
+
+impl Vault {
+    /// Fetch the secrets and write them to their files, returning how long until they should be
+    /// fetched again.
+    pub fn sync(&self, mappings: &[Mapping]) -> Result<Duration, Error> {
+        let jwt = fs::read_to_string(SERVICE_ACCOUNT_TOKEN).map_err(|error| {
+            Error::Config(format!(
+                "failed to read {}: {}",
+                SERVICE_ACCOUNT_TOKEN, error
+            ))
+        })?;
+        let login: LoginResponse = self.backoff.retry("log into Vault", || {
+            Ok(self
+                .http
+                .post(&format!(
+                    "{}/v1/auth/{}/login",
+                    self.addr.trim_end_matches('/'),
+                    self.mount.trim_matches('/')
+                ))
+                .json(&serde_json::json!({ "role": self.role, "jwt": jwt.trim() }))
+                .send()?
+                .error_for_status()?
+                .json()?)
+        })?;
+
+        let mut leases = vec![login.auth.lease_duration];
+        let mut secrets: HashMap<&str, SecretResponse> = HashMap::new();
+        for mapping in mappings {
+            if !secrets.contains_key(mapping.path.as_str()) {
+                let secret: SecretResponse =
+                    self.backoff.retry(&format!("read {}", mapping.path), || {
+                        Ok(self
+                            .http
+                            .get(&format!(
+                                "{}/v1/{}",
+                                self.addr.trim_end_matches('/'),
+                                mapping.path
+                            ))
+                            .header("X-Vault-Token", login.auth.client_token.as_str())
+                            .send()?
+                            .error_for_status()?
+                            .json()?)
+                    })?;
+                leases.push(secret.lease_duration);
+                secrets.insert(&mapping.path, secret);
+            }
+            let value =
+                value(&secrets[mapping.path.as_str()].data, &mapping.key).ok_or_else(|| {
+                    Error::Config(format!("{} has no key {}", mapping.path, mapping.key))
+                })?;
+            write(mapping, &value)?;
+        }
+        Ok(refresh(&leases))
+    }
+}
+
+/// Keep the secrets up to date from a background thread.
+pub fn schedule(vault: &Vault, mappings: &[Mapping], mut delay: Duration) {
+    let mut failures = 0;
+    loop {
+        thread::sleep(delay);
+        delay = match vault.sync(mappings) {
+            Ok(refresh) => {
+                debug!("Fetched the secrets from Vault");
+                failures = 0;
+                refresh
+            }
+            Err(error) => {
+                error!("Failed to fetch the secrets from Vault: {}", error);
+                failures += 1;
+                vault.backoff.delay(failures)
+            }
+        };
+    }
+}
+
+/// The value of a key of a secret, from either version of the key/value secrets engine.
+fn value(data: &serde_json::Value, key: &str) -> Option<String> {
+    // Version 2 nests the secret's data next to its metadata.
+    let data = match (data.get("data"), data.get("metadata")) {
+        (Some(nested), Some(_)) => nested,
+        _ => data,
+    };
+    match data.get(key)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// How long until the secrets are fetched again: two thirds of the way through the shortest lease.
+fn refresh(leases: &[u64]) -> Duration {
+    leases
+        .iter()
+        .filter(|lease| **lease > 0)
+        .min()
+        .map_or(DEFAULT_REFRESH, |lease| Duration::from_secs(lease * 2 / 3))
+        .max(MIN_REFRESH)
+}
+
+/// Replace the file with the value, so that readers never see it half written.
+fn write(mapping: &Mapping, value: &str) -> Result<(), Error> {
+    let failed = |error| {
+        Error::Config(format!(
+            "failed to write {}: {}",
+            mapping.file.display(),
+            error
+        ))
+    };
+    let staged = mapping.file.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&staged)
+        .map_err(failed)?;
+    file.write_all(value.as_bytes()).map_err(failed)?;
+    fs::rename(&staged, &mapping.file).map_err(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mappings() {
+        assert_eq!(
+            "secret/data/openshift-update#ocm-token=/run/openshift-update/ocm-token".parse(),
+            Ok(Mapping {
+                path: "secret/data/openshift-update".to_string(),
+                key: "ocm-token".to_string(),
+                file: PathBuf::from("/run/openshift-update/ocm-token"),
+            })
+        );
+        assert!("secret/data/openshift-update=/run/ocm-token"
+            .parse::<Mapping>()
+            .is_err());
+        assert!("secret/openshift-update#ocm-token"
+            .parse::<Mapping>()
+            .is_err());
+    }
+
+    #[test]
+    fn reads_either_version_of_the_key_value_engine() {
+        let v1 = serde_json::json!({ "ocm-token": "offline" });
+        let v2 = serde_json::json!({
+            "data": { "ocm-token": "offline" },
+            "metadata": { "version": 3 },
+        });
+        assert_eq!(value(&v1, "ocm-token"), Some("offline".to_string()));
+        assert_eq!(value(&v2, "ocm-token"), Some("offline".to_string()));
+        assert_eq!(value(&v2, "api-token"), None);
+    }
+
+    #[test]
+    fn refreshes_before_the_shortest_lease() {
+        assert_eq!(refresh(&[3600, 0, 768]), Duration::from_secs(512));
+        assert_eq!(refresh(&[0]), DEFAULT_REFRESH);
+        assert_eq!(refresh(&[10]), MIN_REFRESH);
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cadence rules which depend on how far an update moves the cluster.
+//!
+//! Updates are classed as z-streams (within a minor version), minor updates, and EUS updates,
+//! which are minor updates away from an even minor version, as made when moving between extended
+//! update support releases. Each class can be given its own rule: z-streams can wait a number of
+//! days after they are first offered, minor updates can be limited to certain days of the month,
+//! and EUS updates can require approval.
+
+use crate::clusterversion::{self, ClusterUpdate};
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, TimeZone, Utc};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How far an update moves the cluster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Class {
+    ZStream,
+    Minor,
+    Eus,
+}
+
+/// Class the update from `from` to `to`.
+pub fn classify(from: &semver::Version, to: &semver::Version) -> Class {
+    if (from.major, from.minor) == (to.major, to.minor) {
+        Class::ZStream
+    } else if from.major == to.major && from.minor.is_multiple_of(2) {
+        Class::Eus
+    } else {
+        Class::Minor
+    }
+}
+
+/// A range of days of the month, written as "1-7" (or "1" for a single day).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Days {
+    first: u32,
+    last: u32,
+}
+
+impl Days {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        self.first <= time.day() && time.day() <= self.last
+    }
+
+    /// The start of the next day within the range after `time`.
+    pub fn next(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let midnight = Utc
+            .ymd(time.year(), time.month(), time.day())
+            .and_hms(0, 0, 0);
+        // Every range starts within two months, even one which some months are too short for.
+        (1..=62)
+            .map(|days| midnight + ChronoDuration::days(days))
+            .find(|day| self.contains(*day))
+            .expect("day of the month within two months")
+    }
+}
+
+impl FromStr for Days {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |day: &str| match day.trim().parse() {
+            Ok(day) if (1..=31).contains(&day) => Ok(day),
+            _ => Err(format!("invalid day of the month '{}' in '{}'", day, s)),
+        };
+
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if first > last {
+            return Err(format!("days '{}' end before they start", s));
+        }
+        Ok(Days { first, last })
+    }
+}
+
+impl fmt::Display for Days {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Apply the rule for the class of each update the inner policy chooses.
+///
+/// Clusters whose version can't be read are left to the inner policy.
+pub struct Velocity {
+    pub inner: Box<dyn UpgradePolicy>,
+    /// How long z-streams wait after they are first offered.
+    pub z_stream_delay: Option<Duration>,
+    /// The days of the month on which minor (and EUS) updates may start.
+    pub minor_days: Option<Days>,
+    /// Whether EUS updates must be approved.
+    pub eus_approval: bool,
+}
+
+impl UpgradePolicy for Velocity {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        let update = match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } => update,
+            decision => return decision,
+        };
+        let class = match current
+            .version
+            .as_deref()
+            .and_then(clusterversion::parse_version)
+        {
+            Some(version) => classify(&version, &update.version),
+            None => return Decision::Apply { update },
+        };
+
+        match (class, self.z_stream_delay, self.minor_days) {
+            (Class::ZStream, Some(delay), _) => {
+                let seen = current
+                    .first_seen
+                    .get(&update.version)
+                    .cloned()
+                    .unwrap_or(current.now);
+                let not_before = seen + ChronoDuration::from_std(delay).expect("z-stream delay");
+                if current.now < not_before {
+                    return Decision::Delayed {
+                        update,
+                        not_before,
+                        window: false,
+                    };
+                }
+            }
+            (Class::Minor, _, Some(days)) | (Class::Eus, _, Some(days))
+                if !days.contains(current.now) =>
+            {
+                return Decision::Delayed {
+                    not_before: days.next(current.now),
+                    update,
+                    window: true,
+                };
+            }
+            _ => {}
+        }
+        if class == Class::Eus
+            && self.eus_approval
+            && current.approved.as_ref() != Some(&update.version)
+        {
+            return Decision::AwaitingApproval { update };
+        }
+        Decision::Apply { update }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{DecisionReason, Latest};
+
+    fn state(version: &str, now: &str) -> ClusterState {
+        let version: crate::clusterversion::ClusterVersion =
+            serde_json::from_value(serde_json::json!({
+                "metadata": { "name": "version" },
+                "spec": {},
+                "status": {
+                    "history": [{ "version": version, "completionTime": "2019-09-01T00:00:00Z" }],
+                },
+            }))
+            .expect("valid fixture");
+        ClusterState::new(&version, now.parse().expect("valid time"))
+    }
+
+    fn candidates(version: &str) -> Vec<ClusterUpdate> {
+        serde_json::from_value(serde_json::json!([
+            { "version": version, "image": format!("quay.io/release:{}", version) },
+        ]))
+        .expect("valid candidates")
+    }
+
+    fn policy(z_stream_delay: Option<&str>, minor_days: Option<&str>) -> Velocity {
+        Velocity {
+            inner: Box::new(Latest),
+            z_stream_delay: z_stream_delay
+                .map(|delay| humantime::parse_duration(delay).expect("valid delay")),
+            minor_days: minor_days.map(|days| days.parse().expect("valid days")),
+            eus_approval: true,
+        }
+    }
+
+    #[test]
+    fn classifies_updates() {
+        let version = |version| semver::Version::parse(version).expect("version");
+        assert_eq!(
+            classify(&version("4.1.14"), &version("4.1.16")),
+            Class::ZStream
+        );
+        assert_eq!(
+            classify(&version("4.1.14"), &version("4.2.0")),
+            Class::Minor
+        );
+        assert_eq!(classify(&version("4.2.3"), &version("4.3.0")), Class::Eus);
+    }
+
+    #[test]
+    fn delays_by_class() {
+        let policy = policy(Some("7d"), Some("1-7"));
+
+        let z_stream = candidates("4.1.16");
+        let mut current = state("4.1.14", "2019-09-17T00:00:00Z");
+        assert_eq!(
+            policy.evaluate(&current, &z_stream).reason(),
+            DecisionReason::SoakPending
+        );
+        current.first_seen.insert(
+            z_stream[0].version.clone(),
+            "2019-09-09T00:00:00Z".parse().expect("valid time"),
+        );
+        assert!(matches!(
+            policy.evaluate(&current, &z_stream),
+            Decision::Apply { .. }
+        ));
+
+        let minor = candidates("4.2.0");
+        let decision = policy.evaluate(&current, &minor);
+        assert_eq!(decision.reason(), DecisionReason::WindowClosed);
+        match decision {
+            Decision::Delayed { not_before, .. } => {
+                assert_eq!(not_before.to_rfc3339(), "2019-10-01T00:00:00+00:00")
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+        let current = state("4.1.14", "2019-10-03T00:00:00Z");
+        assert!(matches!(
+            policy.evaluate(&current, &minor),
+            Decision::Apply { .. }
+        ));
+
+        let mut current = state("4.2.3", "2019-10-03T00:00:00Z");
+        let eus = candidates("4.3.0");
+        assert!(matches!(
+            policy.evaluate(&current, &eus),
+            Decision::AwaitingApproval { .. }
+        ));
+        current.approved = Some(eus[0].version.clone());
+        assert!(matches!(
+            policy.evaluate(&current, &eus),
+            Decision::Apply { .. }
+        ));
+    }
+
+    #[test]
+    fn parses_days() {
+        assert_eq!(
+            "1-7".parse::<Days>().map(|d| d.to_string()),
+            Ok("1-7".into())
+        );
+        assert_eq!(
+            "15".parse::<Days>().map(|d| d.to_string()),
+            Ok("15-15".into())
+        );
+        assert!("7-1".parse::<Days>().is_err());
+        assert!("0-32".parse::<Days>().is_err());
+
+        let days: Days = "29-31".parse().expect("valid days");
+        let now = "2019-01-31T12:00:00Z".parse().expect("valid time");
+        assert_eq!(days.next(now).to_rfc3339(), "2019-03-29T00:00:00+00:00");
+    }
+}
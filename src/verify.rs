@@ -0,0 +1,407 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the cluster once an update has completed.
+//!
+//! The cluster-version operator considers an update complete once every component has rolled
+//! out, which doesn't necessarily mean that the cluster works. With `--verify-updates`, a few
+//! cheap checks follow each update the operator applied: that the API server responds promptly,
+//! that every ClusterOperator is Available at the new version, that every node is Ready with the
+//! new version's kubelet, and that each of the `--verify-url`s can be reached. The results end
+//! the update's trail of events, and are also kept in the operator's status and metrics.
+//!
+//! A healthy control plane doesn't mean that traffic reaches the workloads, so once the API
+//! server and ingress operators are healthy, the ingress canary's route and each of the
+//! `--verify-route`s are also requested over HTTPS. Their certificates have to be trusted by the
+//! operator's CA bundle.
+//!
+//! With `--check-dns`, the cluster's DNS is checked again too, since it often suffers from the
+//! nodes rebooting.
+
+use crate::explain::Check;
+use crate::policyconfig::Reference;
+use crate::watch::{operator_version, ClusterOperator};
+use kube::api::{Api, ListParams, Object, RawApi, Void};
+use kube::client::APIClient;
+use openshift_update::clusterversion::ClusterStatusCondition;
+use openshift_update::dns::Dns;
+use openshift_update::health;
+use openshift_update::kubeapi;
+use openshift_update::protobuf;
+use openshift_update::retry;
+use openshift_update::Error;
+use std::time::{Duration, Instant};
+
+/// How long the API server may take to report itself ready.
+const API_SERVER_DEADLINE: Duration = Duration::from_secs(5);
+
+/// The route which the ingress operator maintains for checking that ingress works end to end.
+const CANARY_NAMESPACE: &str = "openshift-ingress-canary";
+const CANARY_ROUTE: &str = "canary";
+
+/// The ClusterOperators which have to be healthy before routes can be expected to work.
+const INGRESS_PREREQUISITES: &[&str] = &["kube-apiserver", "ingress"];
+
+#[derive(serde::Deserialize)]
+struct Route {
+    spec: RouteSpec,
+}
+
+#[derive(serde::Deserialize)]
+struct RouteSpec {
+    host: String,
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub(crate) struct NodeStatus {
+    #[serde(default)]
+    conditions: Vec<ClusterStatusCondition>,
+    #[serde(rename = "nodeInfo", default)]
+    node_info: NodeInfo,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct NodeInfo {
+    #[serde(rename = "kubeletVersion", default)]
+    kubelet_version: String,
+}
+
+pub(crate) type Node = Object<Void, NodeStatus>;
+
+#[derive(serde::Deserialize)]
+struct NodeList {
+    items: Vec<Node>,
+}
+
+/// Verification of the cluster at a version, which allows the reconcile logic to be exercised
+/// against fixtures.
+pub(crate) trait Verifier {
+    fn verify(&self, version: &semver::Version) -> Vec<Check>;
+}
+
+pub(crate) struct KubeVerifier {
+    pub client: APIClient,
+    pub http: reqwest::Client,
+    pub urls: Vec<String>,
+    pub routes: Vec<Reference>,
+    pub dns: Option<Dns>,
+}
+
+impl Verifier for KubeVerifier {
+    fn verify(&self, version: &semver::Version) -> Vec<Check> {
+        let failed = |gate, error: Error| Check {
+            gate,
+            passed: false,
+            reason: format!("unable to check: {}", error),
+        };
+
+        let mut checks = vec![self.api_server()];
+        let operators = self.operators();
+        checks.push(match &operators {
+            Ok(operators) => operators_check(operators, version),
+            Err(error) => failed("cluster-operators", Error::Policy(error.to_string())),
+        });
+        checks.push(match self.nodes() {
+            Ok(nodes) => nodes_check(&nodes, version),
+            Err(error) => failed("nodes", error),
+        });
+        if let Some(dns) = &self.dns {
+            let health = health::verdict(dns);
+            checks.push(Check {
+                gate: health.gate,
+                passed: health.passed,
+                reason: health.reason,
+            });
+        }
+        checks.extend(self.urls.iter().map(|url| self.url("url", url)));
+        // Until ingress itself is healthy, the routes are bound to fail, which would say nothing
+        // new.
+        if operators.is_ok_and(|operators| healthy(&operators, INGRESS_PREREQUISITES)) {
+            checks.extend(self.routes());
+        }
+        checks
+    }
+}
+
+impl KubeVerifier {
+    fn api_server(&self) -> Check {
+        let started = Instant::now();
+        let request = http::Request::get("/readyz")
+            .body(Vec::new())
+            .expect("valid request");
+        let result = kubeapi::call("get", "readyz", || self.client.request_text(request));
+        let elapsed = started.elapsed();
+        let (passed, reason) = match result {
+            Ok(_) if elapsed <= API_SERVER_DEADLINE => {
+                (true, format!("ready after {}ms", elapsed.as_millis()))
+            }
+            Ok(_) => (
+                false,
+                format!(
+                    "ready, but only after {}ms (at most {}ms)",
+                    elapsed.as_millis(),
+                    API_SERVER_DEADLINE.as_millis()
+                ),
+            ),
+            Err(error) => (false, format!("not ready: {}", error)),
+        };
+        Check {
+            gate: "api-server",
+            passed,
+            reason,
+        }
+    }
+
+    pub(crate) fn operators(&self) -> Result<Vec<ClusterOperator>, Error> {
+        let operators =
+            Api::<ClusterOperator>::customResource(self.client.clone(), "clusteroperators")
+                .group("config.openshift.io")
+                .version("v1");
+        Ok(kubeapi::call("list", "clusteroperators", || {
+            operators.list(&ListParams::default())
+        })?
+        .items)
+    }
+
+    fn nodes(&self) -> Result<Vec<Node>, Error> {
+        let request = RawApi::v1Node().list(&ListParams::default())?;
+        let list: NodeList = kubeapi::call("list", "nodes", || {
+            protobuf::list(&self.client, request, &protobuf::NODE_LIST)
+        })?;
+        Ok(list.items)
+    }
+
+    /// Request the canary route (if the cluster has one) and each of the configured routes.
+    fn routes(&self) -> Vec<Check> {
+        let canary = Reference {
+            namespace: CANARY_NAMESPACE.to_string(),
+            name: CANARY_ROUTE.to_string(),
+        };
+        let mut checks = Vec::new();
+        match self.route(&canary) {
+            Ok(url) => checks.push(self.url("routes", &url)),
+            Err(ref error) if retry::not_found(error) => {
+                debug!("The cluster has no ingress canary");
+            }
+            Err(error) => checks.push(Check {
+                gate: "routes",
+                passed: false,
+                reason: format!("unable to read route {}: {}", canary, error),
+            }),
+        }
+        for route in &self.routes {
+            checks.push(match self.route(route) {
+                Ok(url) => self.url("routes", &url),
+                Err(error) => Check {
+                    gate: "routes",
+                    passed: false,
+                    reason: format!("unable to read route {}: {}", route, error),
+                },
+            });
+        }
+        checks
+    }
+
+    /// The HTTPS URL of a route.
+    fn route(&self, route: &Reference) -> Result<String, Error> {
+        let request = RawApi::customResource("routes")
+            .group("route.openshift.io")
+            .version("v1")
+            .within(&route.namespace)
+            .get(&route.name)?;
+        let route = kubeapi::call("get", "routes", || self.client.request::<Route>(request))?;
+        Ok(format!("https://{}{}", route.spec.host, route.spec.path))
+    }
+
+    fn url(&self, gate: &'static str, url: &str) -> Check {
+        let (passed, reason) = match self
+            .http
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => (true, format!("{} responded {}", url, response.status())),
+            Err(error) => (false, format!("{}: {}", url, error)),
+        };
+        Check {
+            gate,
+            passed,
+            reason,
+        }
+    }
+}
+
+/// Whether each of the named ClusterOperators is Available and not Degraded.
+fn healthy(operators: &[ClusterOperator], names: &[&str]) -> bool {
+    names.iter().all(|name| {
+        let conditions = operators
+            .iter()
+            .find(|operator| operator.metadata.name == *name)
+            .and_then(|operator| operator.status.as_ref())
+            .map(|status| status.conditions.as_slice())
+            .unwrap_or_default();
+        let condition = |type_| {
+            conditions
+                .iter()
+                .find(|condition| condition.type_ == type_)
+                .map(|condition| condition.status.as_str())
+        };
+        condition("Available") == Some("True") && condition("Degraded") != Some("True")
+    })
+}
+
+/// Whether every ClusterOperator is Available at the version.
+pub(crate) fn operators_check(operators: &[ClusterOperator], version: &semver::Version) -> Check {
+    let version = version.to_string();
+    let lagging: Vec<String> = operators
+        .iter()
+        .filter(|operator| {
+            let available = operator
+                .status
+                .as_ref()
+                .and_then(|status| {
+                    status
+                        .conditions
+                        .iter()
+                        .find(|condition| condition.type_ == "Available")
+                })
+                .is_some_and(|condition| condition.status == "True");
+            !available || operator_version(operator) != Some(version.as_str())
+        })
+        .map(|operator| operator.metadata.name.clone())
+        .collect();
+    Check {
+        gate: "cluster-operators",
+        passed: lagging.is_empty(),
+        reason: if lagging.is_empty() {
+            format!("all {} are Available at {}", operators.len(), version)
+        } else {
+            format!("not Available at {}: {}", version, lagging.join(", "))
+        },
+    }
+}
+
+/// The Kubernetes minor version which OpenShift's minor version ships, e.g. 13 for 4.1.
+fn kubernetes_minor(version: &semver::Version) -> Option<u64> {
+    if version.major == 4 {
+        Some(version.minor + 12)
+    } else {
+        None
+    }
+}
+
+/// Whether every node is Ready with the kubelet of the version.
+pub(crate) fn nodes_check(nodes: &[Node], version: &semver::Version) -> Check {
+    let expected = kubernetes_minor(version);
+    let lagging: Vec<String> = nodes
+        .iter()
+        .filter_map(|node| {
+            let status = node.status.clone().unwrap_or_default();
+            let ready = status
+                .conditions
+                .iter()
+                .any(|condition| condition.type_ == "Ready" && condition.status == "True");
+            // Kubelets report versions such as "v1.13.4+d4ce02c1d".
+            let kubelet = status.node_info.kubelet_version;
+            let minor = kubelet
+                .trim_start_matches('v')
+                .split('.')
+                .nth(1)
+                .and_then(|minor| minor.parse().ok());
+            match (
+                ready,
+                expected.is_none_or(|expected| minor == Some(expected)),
+            ) {
+                (true, true) => None,
+                (false, _) => Some(format!("{} (not Ready)", node.metadata.name)),
+                (true, false) => Some(format!("{} (kubelet {})", node.metadata.name, kubelet)),
+            }
+        })
+        .collect();
+    Check {
+        gate: "nodes",
+        passed: lagging.is_empty(),
+        reason: match (lagging.is_empty(), expected) {
+            (true, Some(minor)) => {
+                format!("all {} are Ready with kubelet 1.{}", nodes.len(), minor)
+            }
+            (true, None) => format!("all {} are Ready", nodes.len()),
+            (false, _) => format!("lagging: {}", lagging.join(", ")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_operators_and_nodes() {
+        let version = semver::Version::parse("4.1.16").expect("version");
+        let operators: Vec<ClusterOperator> = serde_json::from_value(serde_json::json!([
+            {
+                "metadata": { "name": "dns" },
+                "spec": {},
+                "status": {
+                    "conditions": [{ "type": "Available", "status": "True" }],
+                    "versions": [{ "name": "operator", "version": "4.1.16" }],
+                },
+            },
+            {
+                "metadata": { "name": "ingress" },
+                "spec": {},
+                "status": {
+                    "conditions": [{ "type": "Available", "status": "True" }],
+                    "versions": [{ "name": "operator", "version": "4.1.15" }],
+                },
+            },
+        ]))
+        .expect("valid operators");
+        let check = operators_check(&operators, &version);
+        assert!(!check.passed);
+        assert_eq!(check.reason, "not Available at 4.1.16: ingress");
+        assert!(operators_check(&operators[..1], &version).passed);
+        assert!(healthy(&operators, &["dns", "ingress"]));
+        assert!(!healthy(&operators, &["kube-apiserver"]));
+
+        let node = |name, ready, kubelet| {
+            serde_json::json!({
+                "metadata": { "name": name },
+                "spec": {},
+                "status": {
+                    "conditions": [{ "type": "Ready", "status": ready }],
+                    "nodeInfo": { "kubeletVersion": kubelet },
+                },
+            })
+        };
+        let nodes: Vec<Node> = serde_json::from_value(serde_json::json!([
+            node("master-0", "True", "v1.13.4+d4ce02c1d"),
+            node("worker-0", "False", "v1.13.4+d4ce02c1d"),
+            node("worker-1", "True", "v1.12.4+509916ce1"),
+        ]))
+        .expect("valid nodes");
+        let check = nodes_check(&nodes, &version);
+        assert!(!check.passed);
+        assert_eq!(
+            check.reason,
+            "lagging: worker-0 (not Ready), worker-1 (kubelet v1.12.4+509916ce1)"
+        );
+        assert_eq!(
+            nodes_check(&nodes[..1], &version).reason,
+            "all 1 are Ready with kubelet 1.13"
+        );
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A live view of an update's progress on the terminal.
+//!
+//! The ClusterVersion, ClusterOperators and MachineConfigPools are each followed by a reflector
+//! on its own thread, and the screen is redrawn from their caches every couple of seconds.
+
+use chrono::Utc;
+use kube::api::{self, Api, Reflector};
+use kube::client::APIClient;
+use openshift_update::clusterversion::{self, ClusterStatusCondition, ClusterVersion};
+use openshift_update::durations;
+use openshift_update::Error;
+use std::fmt::Write;
+use std::thread;
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub(crate) struct ClusterOperatorStatus {
+    #[serde(default)]
+    pub conditions: Vec<ClusterStatusCondition>,
+    #[serde(default)]
+    pub versions: Vec<OperandVersion>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub(crate) struct OperandVersion {
+    pub name: String,
+    pub version: String,
+}
+
+pub(crate) type ClusterOperator = api::Object<api::Void, ClusterOperatorStatus>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct MachineConfigPoolStatus {
+    #[serde(rename = "machineCount", default)]
+    machine_count: u32,
+    #[serde(rename = "updatedMachineCount", default)]
+    updated_machine_count: u32,
+    #[serde(rename = "readyMachineCount", default)]
+    ready_machine_count: u32,
+    #[serde(rename = "degradedMachineCount", default)]
+    degraded_machine_count: u32,
+}
+
+type MachineConfigPool = api::Object<api::Void, MachineConfigPoolStatus>;
+
+pub fn run(client: APIClient, name: &str) -> Result<(), Error> {
+    let versions = follow(
+        Reflector::new(clusterversion::api(client.clone()))
+            .fields(&format!("metadata.name=={}", name))
+            .init()?,
+    );
+    let operators = follow(
+        Reflector::new(
+            Api::<ClusterOperator>::customResource(client.clone(), "clusteroperators")
+                .group("config.openshift.io")
+                .version("v1"),
+        )
+        .init()?,
+    );
+    let pools = follow(
+        Reflector::new(
+            Api::<MachineConfigPool>::customResource(client, "machineconfigpools")
+                .group("machineconfiguration.openshift.io")
+                .version("v1"),
+        )
+        .init()?,
+    );
+
+    loop {
+        let screen = render(&versions.read()?, &operators.read()?, &pools.read()?);
+        // Clear the screen and move the cursor home before drawing.
+        print!("\x1b[2J\x1b[H{}", screen);
+        thread::sleep(REFRESH_INTERVAL);
+    }
+}
+
+/// Keep a reflector up to date from a background thread.
+fn follow<K>(reflector: Reflector<K>) -> Reflector<K>
+where
+    K: Clone + serde::de::DeserializeOwned + kube::api::KubeObject + Send + Sync + 'static,
+{
+    let background = reflector.clone();
+    thread::spawn(move || loop {
+        if let Err(error) = background.poll() {
+            error!("Failed to poll reflector: {}", error);
+            thread::sleep(REFRESH_INTERVAL);
+        }
+    });
+    reflector
+}
+
+fn render(
+    versions: &[ClusterVersion],
+    operators: &[ClusterOperator],
+    pools: &[MachineConfigPool],
+) -> String {
+    let mut screen = String::new();
+
+    let status = versions
+        .first()
+        .and_then(|version| version.status.clone())
+        .unwrap_or_default();
+    let latest = status.history.first().cloned().unwrap_or_default();
+    let target = latest.version.clone().unwrap_or_default();
+    let previous = status
+        .history
+        .iter()
+        .skip(1)
+        .find(|entry| entry.completion_time.is_some())
+        .and_then(|entry| entry.version.clone());
+
+    match (&previous, latest.completion_time) {
+        (_, Some(_)) => writeln!(screen, "Cluster version {} (up to date)", target),
+        (Some(previous), None) => writeln!(screen, "Updating from {} to {}", previous, target),
+        (None, None) => writeln!(screen, "Installing {}", target),
+    }
+    .expect("write to string");
+    if let Some(progressing) = status
+        .conditions
+        .iter()
+        .find(|condition| condition.type_ == "Progressing")
+    {
+        if let Some(message) = &progressing.message {
+            writeln!(screen, "  {}", message).expect("write to string");
+        }
+    }
+
+    let done = operators
+        .iter()
+        .filter(|operator| operator_version(operator) == Some(target.as_str()))
+        .count();
+    if let (Some(started), None) = (latest.started_time, latest.completion_time) {
+        let now = Utc::now();
+        let elapsed = (now - started).to_std().unwrap_or_default();
+        write!(screen, "  Elapsed: {}", format_duration(elapsed)).expect("write to string");
+        let machines = pools.iter().filter_map(|pool| pool.status.as_ref()).fold(
+            (0, 0),
+            |(updated, total), status| {
+                (
+                    updated + status.updated_machine_count,
+                    total + status.machine_count,
+                )
+            },
+        );
+        let past = durations::past(&status.history);
+        if let Some(estimate) = durations::estimate(&past, started, machines, now) {
+            let remaining = Duration::from_secs(estimate.remaining as u64);
+            write!(screen, ", ETA: {}", format_duration(remaining)).expect("write to string");
+        }
+        writeln!(screen).expect("write to string");
+    }
+
+    writeln!(
+        screen,
+        "\nClusterOperators ({}/{} at {})",
+        done,
+        operators.len(),
+        target
+    )
+    .expect("write to string");
+    writeln!(
+        screen,
+        "  {:<40} {:<24} {:<10} {:<12} {:<9}",
+        "NAME", "VERSION", "AVAILABLE", "PROGRESSING", "DEGRADED"
+    )
+    .expect("write to string");
+    for operator in operators {
+        writeln!(
+            screen,
+            "  {:<40} {:<24} {:<10} {:<12} {:<9}",
+            operator.metadata.name,
+            operator_version(operator).unwrap_or(""),
+            condition(operator, "Available"),
+            condition(operator, "Progressing"),
+            condition(operator, "Degraded")
+        )
+        .expect("write to string");
+    }
+
+    writeln!(screen, "\nMachineConfigPools").expect("write to string");
+    writeln!(
+        screen,
+        "  {:<40} {:<10} {:<8} {:<9}",
+        "NAME", "UPDATED", "READY", "DEGRADED"
+    )
+    .expect("write to string");
+    for pool in pools {
+        let status = pool.status.clone().unwrap_or_default();
+        writeln!(
+            screen,
+            "  {:<40} {:<10} {:<8} {:<9}",
+            pool.metadata.name,
+            format!("{}/{}", status.updated_machine_count, status.machine_count),
+            status.ready_machine_count,
+            status.degraded_machine_count
+        )
+        .expect("write to string");
+    }
+
+    screen
+}
+
+/// The version reported by the operator itself, as opposed to its operands.
+pub(crate) fn operator_version(operator: &ClusterOperator) -> Option<&str> {
+    operator.status.as_ref().and_then(|status| {
+        status
+            .versions
+            .iter()
+            .find(|version| version.name == "operator")
+            .map(|version| version.version.as_str())
+    })
+}
+
+/// The status of one of the operator's conditions: "True", "False" or "Unknown".
+pub(crate) fn condition<'a>(operator: &'a ClusterOperator, type_: &str) -> &'a str {
+    operator
+        .status
+        .as_ref()
+        .and_then(|status| {
+            status
+                .conditions
+                .iter()
+                .find(|condition| condition.type_ == type_)
+        })
+        .map(|condition| condition.status.as_str())
+        .unwrap_or("Unknown")
+}
+
+fn format_duration(duration: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
+}
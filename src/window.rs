@@ -0,0 +1,237 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Maintenance windows, outside of which updates are held back.
+
+use crate::clusterversion::ClusterUpdate;
+use crate::policy::{ClusterState, Decision, UpgradePolicy};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A daily maintenance window in UTC, written as "HH:MM-HH:MM".
+///
+/// Windows whose end is before their start wrap around midnight. A window which starts where it
+/// ends would be either empty or the whole day, so it's rejected; a window which is always open
+/// is no window at all. Since the window is in UTC, it doesn't move when daylight saving time
+/// starts or ends, so a window meant for local hours is an hour off for part of the year.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        let time = time.time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// How long the window is open each day.
+    pub fn length(&self) -> Duration {
+        let length = self.end.signed_duration_since(self.start);
+        let length = if length < ChronoDuration::zero() {
+            length + ChronoDuration::days(1)
+        } else {
+            length
+        };
+        length.to_std().expect("positive length")
+    }
+
+    /// The earliest time, no earlier than `time`, which is `offset` into one of the window's
+    /// openings. An offset no shorter than the window is never inside it.
+    pub fn next(&self, time: DateTime<Utc>, offset: Duration) -> DateTime<Utc> {
+        let offset = ChronoDuration::from_std(offset).expect("offset");
+        // The window which opened the day before may still be open.
+        let mut next = (time.date() - ChronoDuration::days(1))
+            .and_time(self.start)
+            .expect("valid time")
+            + offset;
+        while next < time {
+            next = next + ChronoDuration::days(1);
+        }
+        next
+    }
+}
+
+/// Hold back updates while the maintenance window is closed, until it next opens.
+pub struct MaintenanceWindow {
+    pub inner: Box<dyn UpgradePolicy>,
+    pub window: Window,
+}
+
+impl UpgradePolicy for MaintenanceWindow {
+    fn evaluate(&self, current: &ClusterState, candidates: &[ClusterUpdate]) -> Decision {
+        match self.inner.evaluate(current, candidates) {
+            Decision::Apply { update } if !self.window.contains(current.now) => Decision::Delayed {
+                not_before: self.window.next(current.now, Duration::from_secs(0)),
+                update,
+                window: true,
+            },
+            decision => decision,
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|err| format!("invalid time '{}' in window '{}': {}", time, s, err))
+        };
+
+        let mut parts = s.splitn(2, '-');
+        match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) => {
+                let window = Window {
+                    start: parse(start)?,
+                    end: parse(end)?,
+                };
+                if window.start == window.end {
+                    return Err(format!("window '{}' starts where it ends", s));
+                }
+                Ok(window)
+            }
+            _ => Err(format!("window '{}' is not of the form HH:MM-HH:MM", s)),
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .expect("valid time")
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn contains_times() {
+        let window: Window = "01:00-05:00".parse().expect("valid window");
+        assert!(window.contains(at("2019-09-17T01:00:00Z")));
+        assert!(window.contains(at("2019-09-17T04:59:59Z")));
+        assert!(!window.contains(at("2019-09-17T05:00:00Z")));
+        assert!(!window.contains(at("2019-09-17T00:59:59Z")));
+        assert_eq!(window.to_string(), "01:00-05:00");
+    }
+
+    #[test]
+    fn wraps_around_midnight() {
+        let window: Window = "22:00-02:00".parse().expect("valid window");
+        assert!(window.contains(at("2019-09-17T22:00:00Z")));
+        assert!(window.contains(at("2019-09-17T23:59:59Z")));
+        assert!(window.contains(at("2019-09-18T00:00:00Z")));
+        assert!(window.contains(at("2019-09-18T01:59:59Z")));
+        assert!(!window.contains(at("2019-09-18T02:00:00Z")));
+        assert!(!window.contains(at("2019-09-17T21:59:59Z")));
+        assert!(!window.contains(at("2019-09-17T12:00:00Z")));
+    }
+
+    #[test]
+    fn finds_the_next_opening() {
+        let window: Window = "22:00-02:00".parse().expect("valid window");
+        assert_eq!(window.length(), Duration::from_secs(4 * 60 * 60));
+        let hour = Duration::from_secs(60 * 60);
+        assert_eq!(
+            window.next(at("2019-09-17T12:00:00Z"), hour),
+            at("2019-09-17T23:00:00Z")
+        );
+        // The window which opened the day before is still open, but already past the offset.
+        assert_eq!(
+            window.next(at("2019-09-18T00:30:00Z"), Duration::from_secs(0)),
+            at("2019-09-18T22:00:00Z")
+        );
+        assert_eq!(
+            window.next(at("2019-09-18T00:30:00Z"), 3 * hour),
+            at("2019-09-18T01:00:00Z")
+        );
+        assert_eq!(
+            window.next(at("2019-09-17T23:00:00Z"), hour),
+            at("2019-09-17T23:00:00Z")
+        );
+    }
+
+    #[test]
+    fn delays_updates_until_the_window_opens() {
+        let version: crate::clusterversion::ClusterVersion = serde_json::from_str(include_str!(
+            "../tests/fixtures/clusterversion-available.json"
+        ))
+        .expect("valid fixture");
+        let candidates = version
+            .status
+            .as_ref()
+            .and_then(|status| status.available_updates.clone())
+            .unwrap_or_default();
+        let policy = MaintenanceWindow {
+            inner: Box::new(crate::policy::Latest),
+            window: "01:00-05:00".parse().expect("valid window"),
+        };
+
+        let state = ClusterState::new(&version, at("2019-09-17T12:00:00Z"));
+        match policy.evaluate(&state, &candidates) {
+            Decision::Delayed {
+                not_before, window, ..
+            } => {
+                assert_eq!(not_before, at("2019-09-18T01:00:00Z"));
+                assert!(window);
+            }
+            decision => panic!("unexpected decision {:?}", decision),
+        }
+        let state = ClusterState::new(&version, at("2019-09-18T01:00:00Z"));
+        assert!(matches!(
+            policy.evaluate(&state, &candidates),
+            Decision::Apply { .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        assert!("03:00-03:00".parse::<Window>().is_err());
+        assert!("03:00".parse::<Window>().is_err());
+        assert!("03:00-25:00".parse::<Window>().is_err());
+        assert!(" 03:00 - 04:00 ".parse::<Window>().is_ok());
+    }
+
+    #[test]
+    fn ignores_daylight_saving_time() {
+        // New York moved from EST (-05:00) to EDT (-04:00) at 02:00 on 2019-03-10, which doesn't
+        // move a window in UTC: 06:00-07:00 is 01:00-02:00 local time before and 02:00-03:00
+        // after.
+        let window: Window = "06:00-07:00".parse().expect("valid window");
+        assert!(window.contains(at("2019-03-10T01:30:00-05:00")));
+        assert!(!window.contains(at("2019-03-10T03:30:00-04:00")));
+        assert!(window.contains(at("2019-03-11T02:30:00-04:00")));
+        assert!(!window.contains(at("2019-03-11T01:30:00-04:00")));
+    }
+}
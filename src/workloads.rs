@@ -0,0 +1,213 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A health check of the pods running on the cluster.
+//!
+//! Pods stuck crash-looping or failing to pull their images are a cheap proxy for whether the
+//! cluster is healthy enough to be disturbed: a few are normal on a busy cluster, but many more
+//! than usual suggest an ongoing incident.
+
+use crate::health::{Health, HealthCheck};
+use crate::kubeapi;
+use crate::protobuf;
+use crate::retry::Backoff;
+use crate::Error;
+use kube::api::{ListParams, Object, RawApi, Void};
+use kube::client::APIClient;
+
+/// Reasons for which a container is waiting which mean that it is stuck.
+const STUCK: &[&str] = &[
+    "CrashLoopBackOff",
+    "ImagePullBackOff",
+    "ErrImagePull",
+    "CreateContainerConfigError",
+];
+
+/// How many of the stuck pods are named in the reason.
+const WORST: usize = 5;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct PodStatus {
+    #[serde(rename = "initContainerStatuses", default)]
+    pub init_container_statuses: Vec<ContainerStatus>,
+    #[serde(rename = "containerStatuses", default)]
+    pub container_statuses: Vec<ContainerStatus>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ContainerStatus {
+    #[serde(rename = "restartCount", default)]
+    pub restart_count: u32,
+    #[serde(default)]
+    pub state: ContainerState,
+}
+
+pub type Pod = Object<Void, PodStatus>;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ContainerState {
+    pub waiting: Option<ContainerStateWaiting>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ContainerStateWaiting {
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(serde::Deserialize)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+/// Fails while more than `max` pods in the namespaces (or, with none, anywhere) are stuck.
+pub struct StuckPods {
+    pub client: APIClient,
+    pub namespaces: Vec<String>,
+    pub max: usize,
+    pub backoff: Backoff,
+}
+
+impl HealthCheck for StuckPods {
+    fn gate(&self) -> &'static str {
+        "stuck-pods"
+    }
+
+    fn check(&self) -> Result<Health, Error> {
+        let apis = if self.namespaces.is_empty() {
+            vec![RawApi::v1Pod()]
+        } else {
+            self.namespaces
+                .iter()
+                .map(|namespace| RawApi::v1Pod().within(namespace))
+                .collect()
+        };
+        let mut pods = Vec::new();
+        for api in apis {
+            pods.extend(self.backoff.retry("list pods", || {
+                let request = api.list(&ListParams::default())?;
+                let list: PodList = kubeapi::call("list", "pods", || {
+                    protobuf::list(&self.client, request, &protobuf::POD_LIST)
+                })?;
+                Ok(list.items)
+            })?);
+        }
+        Ok(health(&pods, self.max))
+    }
+}
+
+/// Why the pod is stuck, and how many times its containers have restarted, if it is.
+fn stuck(pod: &Pod) -> Option<(&str, u32)> {
+    let status = pod.status.as_ref()?;
+    let statuses = || {
+        status
+            .init_container_statuses
+            .iter()
+            .chain(&status.container_statuses)
+    };
+    let reason = statuses()
+        .filter_map(|status| status.state.waiting.as_ref())
+        .map(|waiting| waiting.reason.as_str())
+        .find(|reason| STUCK.contains(reason))?;
+    Some((reason, statuses().map(|status| status.restart_count).sum()))
+}
+
+/// The verdict on the pods, naming the worst of them (those restarted most) if there are too many
+/// stuck.
+pub fn health(pods: &[Pod], max: usize) -> Health {
+    let mut stuck: Vec<(&Pod, &str, u32)> = pods
+        .iter()
+        .filter_map(|pod| stuck(pod).map(|(reason, restarts)| (pod, reason, restarts)))
+        .collect();
+    stuck.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+
+    if stuck.len() <= max {
+        return Health {
+            gate: "stuck-pods",
+            passed: true,
+            reason: format!(
+                "{} of {} pods are stuck (at most {})",
+                stuck.len(),
+                pods.len(),
+                max
+            ),
+        };
+    }
+    let worst: Vec<String> = stuck
+        .iter()
+        .take(WORST)
+        .map(|(pod, reason, restarts)| {
+            format!(
+                "{}/{} ({}, {} restarts)",
+                pod.metadata.namespace.as_deref().unwrap_or_default(),
+                pod.metadata.name,
+                reason,
+                restarts
+            )
+        })
+        .collect();
+    Health {
+        gate: "stuck-pods",
+        passed: false,
+        reason: format!(
+            "{} pods are stuck (at most {}), including {}",
+            stuck.len(),
+            max,
+            worst.join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(name: &str, reason: Option<&str>, restarts: u32) -> serde_json::Value {
+        let state = match reason {
+            Some(reason) => serde_json::json!({ "waiting": { "reason": reason } }),
+            None => serde_json::json!({ "running": {} }),
+        };
+        serde_json::json!({
+            "metadata": { "name": name, "namespace": "shop" },
+            "spec": {},
+            "status": {
+                "containerStatuses": [{ "restartCount": restarts, "state": state }],
+            },
+        })
+    }
+
+    #[test]
+    fn names_the_worst_stuck_pods() {
+        let pods: Vec<Pod> = serde_json::from_value(serde_json::json!([
+            pod("web", Some("CrashLoopBackOff"), 3),
+            pod("api", None, 0),
+            pod("worker", Some("ImagePullBackOff"), 0),
+            pod("cron", Some("CrashLoopBackOff"), 40),
+            pod("starting", Some("ContainerCreating"), 0),
+        ]))
+        .expect("valid pods");
+
+        let healthy = health(&pods, 3);
+        assert!(healthy.passed);
+        assert_eq!(healthy.reason, "3 of 5 pods are stuck (at most 3)");
+
+        let unhealthy = health(&pods, 1);
+        assert!(!unhealthy.passed);
+        assert_eq!(
+            unhealthy.reason,
+            "3 pods are stuck (at most 1), including shop/cron (CrashLoopBackOff, 40 restarts), \
+             shop/web (CrashLoopBackOff, 3 restarts), shop/worker (ImagePullBackOff, 0 restarts)"
+        );
+    }
+}
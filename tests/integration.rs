@@ -0,0 +1,156 @@
+// Copyright 2019 Alex Crawford
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end tests of the local reconcile loop against a real API server.
+//!
+//! These run the binary against whatever cluster the current kubeconfig points at, so they are
+//! only built with the `integration-tests` feature. A throwaway cluster (e.g. kind) with the
+//! ClusterVersion CRD installed is expected:
+//!
+//!     kind create cluster
+//!     kubectl apply -f tests/fixtures/clusterversion-crd.yaml
+//!     cargo test --features integration-tests --test integration
+//!
+//! The tests replace the cluster's ClusterVersion.
+
+#![cfg(feature = "integration-tests")]
+
+use kube::api::{Api, DeleteParams, PatchParams, PostParams};
+use kube::client::APIClient;
+use kube::config;
+use openshift_update::clusterversion::ClusterVersion;
+use openshift_update::gates::PAUSED_ANNOTATION;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FIXTURE: &str = include_str!("fixtures/clusterversion-available.json");
+
+struct Operator(Child);
+
+impl Drop for Operator {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn api() -> Api<ClusterVersion> {
+    let client = APIClient::new(config::load_kube_config().expect("kubeconfig"));
+    Api::customResource(client, "clusterversions")
+        .group("config.openshift.io")
+        .version("v1")
+}
+
+/// Replace the cluster's ClusterVersion with the fixture, with the given annotations.
+fn install(api: &Api<ClusterVersion>, annotations: serde_json::Value) {
+    let _ = api.delete("version", &DeleteParams::default());
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while api.get("version").is_ok() {
+        assert!(Instant::now() < deadline, "ClusterVersion was not deleted");
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let fixture: serde_json::Value = serde_json::from_str(FIXTURE).expect("valid fixture");
+    let object = serde_json::json!({
+        "apiVersion": fixture["apiVersion"],
+        "kind": fixture["kind"],
+        "metadata": { "name": "version", "annotations": annotations },
+        "spec": fixture["spec"],
+    });
+    api.create(
+        &PostParams::default(),
+        serde_json::to_vec(&object).expect("Serialize to JSON"),
+    )
+    .expect("create ClusterVersion");
+    api.patch_status(
+        "version",
+        &PatchParams::default(),
+        serde_json::to_vec(&serde_json::json!({ "status": fixture["status"] }))
+            .expect("Serialize to JSON"),
+    )
+    .expect("set ClusterVersion status");
+}
+
+fn annotate(api: &Api<ClusterVersion>, annotation: &str, value: Option<&str>) {
+    api.patch(
+        "version",
+        &PatchParams::default(),
+        serde_json::to_vec(&serde_json::json!({
+            "metadata": { "annotations": { annotation: value } }
+        }))
+        .expect("Serialize to JSON"),
+    )
+    .expect("annotate ClusterVersion");
+}
+
+fn desired_version(api: &Api<ClusterVersion>) -> Option<String> {
+    api.get("version")
+        .expect("get ClusterVersion")
+        .spec
+        .desired_update
+        .map(|update| update.version.to_string())
+}
+
+/// Wait up to `timeout` for the ClusterVersion to request an update.
+fn wait_for_update(api: &Api<ClusterVersion>, timeout: Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(version) = desired_version(api) {
+            return Some(version);
+        }
+        if Instant::now() > deadline {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+#[test]
+fn reconcile() {
+    let api = api();
+    install(&api, serde_json::json!({ PAUSED_ANNOTATION: "true" }));
+
+    let _operator = Operator(
+        Command::new(env!("CARGO_BIN_EXE_openshift-update"))
+            .arg("-vv")
+            .spawn()
+            .expect("start operator"),
+    );
+    thread::sleep(Duration::from_secs(2));
+
+    // Changing the ClusterVersion makes the operator re-evaluate it, but it must hold off while
+    // paused.
+    annotate(&api, "test.crawford.dev/poke", Some("1"));
+    assert_eq!(wait_for_update(&api, Duration::from_secs(5)), None);
+
+    annotate(&api, PAUSED_ANNOTATION, None);
+    assert_eq!(
+        wait_for_update(&api, Duration::from_secs(30)).as_deref(),
+        Some("4.1.16")
+    );
+
+    let version = api.get("version").expect("get ClusterVersion");
+    assert_eq!(
+        version.spec.cluster_id.as_deref(),
+        Some("0c9f9f1b-0cf5-4ea0-8b2f-bd2cb1e0fdd5"),
+        "patch must not clobber the rest of the spec"
+    );
+    assert!(
+        version
+            .status
+            .is_some_and(|status| !status.history.is_empty()),
+        "patch must not clobber the status"
+    );
+}